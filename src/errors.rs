@@ -1,4 +1,5 @@
 use std::net::Ipv4Addr;
+use std::net::SocketAddr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -45,14 +46,46 @@ pub enum PistolErrors {
     },
     #[error("serde json error")]
     SerdeJsonError(#[from] serde_json::Error),
+    #[error(
+        "the network {network} is too large to sweep, the prefix should be at least /{min_prefix}"
+    )]
+    NetworkTooLarge { network: String, min_prefix: u8 },
+    #[error(
+        "invalid ip range {range}, it should be 4 octets of numbers or ranges like 192.168.1.1-254"
+    )]
+    InvalidIpRange { range: String },
+    #[error(
+        "invalid source port range {start}-{end}, it should be a non-empty range in 1..=65535"
+    )]
+    InvalidPortRange { start: u16, end: u16 },
+    #[error("socks5 proxy {proxy} error: {reason}")]
+    Socks5ProxyError { proxy: SocketAddr, reason: String },
+    #[error("only the connect scan can go through the proxy")]
+    ProxyUnsupportedMethod,
+    #[error("invalid ip options length {len}, it should be at most 40 bytes")]
+    InvalidIpOptionsLength { len: usize },
 
     /* SERVICE DETECT ERRORS */
     #[error("parse int error")]
     ParseIntError(#[from] std::num::ParseIntError),
+    #[error("service db parser error at line [{line}]")]
+    ServiceDBParseError {
+        line: String,
+        #[source]
+        source: std::num::ParseIntError,
+    },
+    #[error("the service is already identified as {service}, no fingerprint is needed")]
+    ServiceIdentified { service: String },
+    #[error("the service sent no response to fingerprint")]
+    NoServiceResponse,
 
     /* LAYERS ERRORS */
-    #[error("create datalink channel failed")]
-    CreateDatalinkChannelFailed,
+    #[error("open the datalink channel on interface {iface} failed")]
+    DatalinkChannelError {
+        iface: String,
+        #[source]
+        source: std::io::Error,
+    },
     #[error("can not found the target mac address, please make sure the target is alive")]
     CanNotFoundMacAddress,
     #[error("can not found the route's mac address")]
@@ -63,13 +96,35 @@ pub enum PistolErrors {
     CanNotFoundSourceAddress,
     #[error("can not found router address")]
     CanNotFoundRouterAddress,
+    #[error("resolve the hostname {hostname} timed out")]
+    DnsQueryTimeout { hostname: String },
+    #[error("invalid dns name {name}")]
+    InvalidDnsName { name: String },
+    #[error("invalid dns response from {server}")]
+    InvalidDnsResponse { server: SocketAddr },
+    #[error("dns server {server} failed the query of {name} with rcode {rcode}")]
+    DnsQueryFailed {
+        server: SocketAddr,
+        name: String,
+        rcode: u8,
+    },
+    #[error("invalid vlan id {vlan_id}, it should be in 1..=4094")]
+    InvalidVlanId { vlan_id: u16 },
+    #[error("the packet size {size} exceeds the mtu {mtu} of interface {interface}")]
+    PacketExceedsMtu {
+        interface: String,
+        size: usize,
+        mtu: u32,
+    },
 
     /* ROUTE ERRORS */
-    #[error("subnetwork error")]
+    #[error("regex error")]
     RegexError(#[from] regex::Error),
+    #[error("run system command [{cmd}] failed: {stderr}")]
+    SystemCommandFailed { cmd: String, stderr: String },
 
     /* OTHER ERRORS */
-    #[error("std error")]
+    #[error("io error")]
     IOError(#[from] std::io::Error),
     #[error("subnetwork error")]
     SubnetworkErrors(#[from] subnetwork::SubnetworkErrors),
@@ -77,4 +132,9 @@ pub enum PistolErrors {
     SetLoggerError(#[from] log::SetLoggerError),
     #[error("hex error")]
     FromHexError(#[from] hex::FromHexError),
+    #[error("ipnetwork error")]
+    IpNetworkError(#[from] pnet::ipnetwork::IpNetworkError),
+    #[cfg(feature = "bincode")]
+    #[error("bincode error")]
+    BincodeError(#[from] bincode::Error),
 }
@@ -20,6 +20,14 @@ use crate::layers::Layer4MatchIcmpv6;
 use crate::layers::LayersMatch;
 use crate::layers::ICMPV6_ER_HEADER_SIZE;
 use crate::layers::IPV6_HEADER_SIZE;
+#[cfg(target_os = "linux")]
+use crate::ping::icmpv6::dgram_icmpv6_socket;
+#[cfg(target_os = "linux")]
+use crate::ping::icmpv6::raw_socket_available;
+#[cfg(target_os = "linux")]
+use crate::ping::icmpv6::send_icmpv6_ping_packet_dgram;
+#[cfg(target_os = "linux")]
+use crate::ping::PingStatus;
 
 pub fn send_icmpv6_ping_packet(
     src_ipv6: Ipv6Addr,
@@ -27,6 +35,15 @@ pub fn send_icmpv6_ping_packet(
     ttl: u8,
     timeout: Duration,
 ) -> Result<bool, PistolErrors> {
+    // without raw socket only the echo reply of the final hop can be seen
+    #[cfg(target_os = "linux")]
+    if !raw_socket_available() {
+        if let Ok(socket) = dgram_icmpv6_socket() {
+            let (ping_status, _) =
+                send_icmpv6_ping_packet_dgram(socket, src_ipv6, dst_ipv6, ttl, timeout)?;
+            return Ok(ping_status == PingStatus::Up);
+        }
+    }
     const ICMPV6_DATA_SIZE: usize = 16;
     let mut rng = rand::thread_rng();
     // ipv6 header
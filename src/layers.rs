@@ -1,7 +1,10 @@
 // use chrono::Local;
+use dns_lookup::lookup_addr;
 use dns_lookup::lookup_host;
 use log::debug;
+use log::warn;
 use pnet::datalink;
+use pnet::datalink::Channel;
 use pnet::datalink::Channel::Ethernet;
 use pnet::datalink::DataLinkReceiver;
 use pnet::datalink::DataLinkSender;
@@ -15,6 +18,7 @@ use pnet::packet::ethernet::EtherType;
 use pnet::packet::ethernet::EtherTypes;
 use pnet::packet::ethernet::EthernetPacket;
 use pnet::packet::ethernet::MutableEthernetPacket;
+use pnet::packet::icmp;
 use pnet::packet::icmp::IcmpCode;
 use pnet::packet::icmp::IcmpPacket;
 use pnet::packet::icmp::IcmpType;
@@ -30,24 +34,47 @@ use pnet::packet::icmpv6::Icmpv6Type;
 use pnet::packet::icmpv6::Icmpv6Types;
 use pnet::packet::icmpv6::MutableIcmpv6Packet;
 use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4;
 use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::MutableIpv4Packet;
 use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::tcp;
 use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp;
 use pnet::packet::udp::UdpPacket;
+use pnet::packet::vlan::MutableVlanPacket;
 use pnet::packet::Packet;
+#[cfg(unix)]
+use socket2::Domain;
+#[cfg(unix)]
+use socket2::Protocol;
+#[cfg(unix)]
+use socket2::SockAddr;
+#[cfg(unix)]
+use socket2::Socket;
+#[cfg(unix)]
+use socket2::Type;
+use std::cell::RefCell;
+use std::io;
+#[cfg(unix)]
+use std::io::Read;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::sync::mpsc::channel;
+use std::thread;
 use std::time::Duration;
-use std::time::Instant;
 use subnetwork::Ipv6;
 
 use crate::errors::PistolErrors;
 // use crate::route::SystemNetCache;
+use crate::utils::clock_elapsed;
+use crate::utils::clock_now;
 use crate::utils::dst_ipv4_in_local;
 use crate::utils::dst_ipv6_in_local;
 use crate::utils::find_interface_by_ip;
+use crate::utils::interface_mtu;
 use crate::utils::system_cache_default_route;
 use crate::utils::system_cache_default_route6;
 use crate::utils::system_cache_search_mac;
@@ -55,6 +82,7 @@ use crate::utils::system_cache_search_route;
 use crate::utils::system_cache_update;
 
 pub const ETHERNET_HEADER_SIZE: usize = 14;
+pub const VLAN_HEADER_SIZE: usize = 4;
 pub const IPV4_HEADER_SIZE: usize = 20;
 pub const IPV6_HEADER_SIZE: usize = 40;
 pub const TCP_HEADER_SIZE: usize = 20;
@@ -69,6 +97,203 @@ pub const ICMPV6_RS_HEADER_SIZE: usize = 16;
 pub const ICMPV6_ER_HEADER_SIZE: usize = 8;
 pub const ICMPV6_NI_HEADER_SIZE: usize = 32;
 
+thread_local! {
+    // The reply captured by `capture_reply` on this thread, None if the capture is not enabled.
+    static CAPTURED_REPLY: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+    // The crafting options of the probes sent by `with_probe_options` on this thread.
+    static PROBE_OPTIONS: RefCell<Option<ProbeOptions>> = const { RefCell::new(None) };
+}
+
+/// Run `f` and return the last reply packet received by it on this thread (if `enabled`),
+/// it is the layer 3 packet of the raw sockets or the payload of the system sockets.
+pub fn capture_reply<T>(enabled: bool, f: impl FnOnce() -> T) -> (T, Option<Vec<u8>>) {
+    if !enabled {
+        return (f(), None);
+    }
+    CAPTURED_REPLY.with(|c| *c.borrow_mut() = Some(Vec::new()));
+    let ret = f();
+    let reply = CAPTURED_REPLY.with(|c| c.borrow_mut().take());
+    (ret, reply.filter(|r| !r.is_empty()))
+}
+
+/// Keep the reply if `capture_reply` is running on this thread.
+pub fn record_reply(reply: &[u8]) {
+    CAPTURED_REPLY.with(|c| {
+        if let Some(captured) = c.borrow_mut().as_mut() {
+            if !reply.is_empty() {
+                *captured = reply.to_vec();
+            }
+        }
+    });
+}
+
+pub const IPV4_OPTIONS_MAX_SIZE: usize = 40;
+
+/// The crafting options of the ipv4 probes, like the `--ip-options` of nmap.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeOptions {
+    /// The raw ip options (record route, timestamp, source route...) inserted into the ipv4 header,
+    /// padded with zeros (end of options list) to a multiple of 4 bytes.
+    pub ip_options: Option<Vec<u8>>,
+    /// Write a wrong tcp/udp/icmp checksum, like the `--badsum` of nmap.
+    /// The real host drops these probes, so a response comes from a middlebox (firewall, IDS/IPS or
+    /// transparent proxy) which does not validate the checksum.
+    pub bad_checksum: bool,
+    /// Send the ipv4 probes through the ip layer raw socket (IP_HDRINCL), the kernel resolves the next hop.
+    /// It is used anyway when the source interface has no link layer (ppp, tun).
+    pub ip_layer_send: bool,
+    /// Send the frames to this mac address instead of the resolved next hop (no arp or ndp),
+    /// only for the switch security tests on the local segment, e.g. whether the switch floods,
+    /// drops or delivers the frames to an unknown or a stale mac address.
+    /// The target only gets the probes if the frames reach it, the ip layer send is not used.
+    pub dst_mac_override: Option<MacAddr>,
+}
+
+impl ProbeOptions {
+    pub fn validate(&self) -> Result<(), PistolErrors> {
+        if let Some(ip_options) = &self.ip_options {
+            if ip_options.len() > IPV4_OPTIONS_MAX_SIZE {
+                return Err(PistolErrors::InvalidIpOptionsLength {
+                    len: ip_options.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Apply the options to the ipv4 packet.
+    pub fn craft_ipv4(&self, packet: &[u8]) -> Result<Vec<u8>, PistolErrors> {
+        self.validate()?;
+        let mut packet = match &self.ip_options {
+            Some(ip_options) if !ip_options.is_empty() => insert_ipv4_options(packet, ip_options)?,
+            _ => packet.to_vec(),
+        };
+        if self.bad_checksum {
+            corrupt_ipv4_checksum(&mut packet)?;
+        }
+        Ok(packet)
+    }
+    /// Apply the options to the ipv6 packet, the ip options are only for ipv4.
+    pub fn craft_ipv6(&self, packet: &[u8]) -> Result<Vec<u8>, PistolErrors> {
+        let mut packet = packet.to_vec();
+        if self.bad_checksum {
+            corrupt_ipv6_checksum(&mut packet)?;
+        }
+        Ok(packet)
+    }
+}
+
+/// Run `f` with the ipv4 probes sent by it on this thread crafted by `options`.
+pub fn with_probe_options<T>(options: Option<&ProbeOptions>, f: impl FnOnce() -> T) -> T {
+    let options = match options {
+        Some(o) => o.clone(),
+        None => return f(),
+    };
+    PROBE_OPTIONS.with(|o| *o.borrow_mut() = Some(options));
+    let ret = f();
+    PROBE_OPTIONS.with(|o| *o.borrow_mut() = None);
+    ret
+}
+
+/// Insert the ip options after the fixed ipv4 header, then fix the IHL, total length and checksum.
+pub fn insert_ipv4_options(packet: &[u8], ip_options: &[u8]) -> Result<Vec<u8>, PistolErrors> {
+    if ip_options.len() > IPV4_OPTIONS_MAX_SIZE {
+        return Err(PistolErrors::InvalidIpOptionsLength {
+            len: ip_options.len(),
+        });
+    }
+    let ipv4_packet = match Ipv4Packet::new(packet) {
+        Some(p) => p,
+        None => return Err(PistolErrors::GetIpv4PacketFailed),
+    };
+    let header_len = ipv4_packet.get_header_length() as usize * 4;
+    let options_len = ip_options.len().div_ceil(4) * 4;
+    if header_len + options_len > IPV4_HEADER_SIZE + IPV4_OPTIONS_MAX_SIZE {
+        return Err(PistolErrors::InvalidIpOptionsLength {
+            len: header_len - IPV4_HEADER_SIZE + options_len,
+        });
+    }
+
+    let mut buff = Vec::with_capacity(packet.len() + options_len);
+    buff.extend_from_slice(&packet[..header_len]);
+    buff.extend_from_slice(ip_options);
+    buff.resize(header_len + options_len, 0);
+    buff.extend_from_slice(ipv4_packet.payload());
+    let mut ip_header = match MutableIpv4Packet::new(&mut buff) {
+        Some(p) => p,
+        None => return Err(PistolErrors::GetIpv4PacketFailed),
+    };
+    ip_header.set_header_length(((header_len + options_len) / 4) as u8);
+    ip_header.set_total_length(ip_header.packet().len() as u16);
+    let checksum = ipv4::checksum(&ip_header.to_immutable());
+    ip_header.set_checksum(checksum);
+    Ok(buff)
+}
+
+/// The wrong checksum which is never the right one, and never 0 (no checksum of udp).
+fn bad_checksum(checksum: u16) -> u16 {
+    match checksum.wrapping_add(1) {
+        0 => 1,
+        c => c,
+    }
+}
+
+/// Overwrite the checksum of the layer 4 header in the ipv4 packet with a wrong one.
+fn corrupt_ipv4_checksum(packet: &mut [u8]) -> Result<(), PistolErrors> {
+    let (header_len, protocol, src_ipv4, dst_ipv4) = match Ipv4Packet::new(packet) {
+        Some(p) => (
+            p.get_header_length() as usize * 4,
+            p.get_next_level_protocol(),
+            p.get_source(),
+            p.get_destination(),
+        ),
+        None => return Err(PistolErrors::GetIpv4PacketFailed),
+    };
+    let layer4 = &mut packet[header_len..];
+    let (offset, checksum) = match protocol {
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(layer4) {
+            Some(p) => (16, tcp::ipv4_checksum(&p, &src_ipv4, &dst_ipv4)),
+            None => return Err(PistolErrors::GetTcpPacketFailed),
+        },
+        IpNextHeaderProtocols::Udp => match UdpPacket::new(layer4) {
+            Some(p) => (6, udp::ipv4_checksum(&p, &src_ipv4, &dst_ipv4)),
+            None => return Err(PistolErrors::GetUdpPacketFailed),
+        },
+        IpNextHeaderProtocols::Icmp => match IcmpPacket::new(layer4) {
+            Some(p) => (2, icmp::checksum(&p)),
+            None => return Err(PistolErrors::GetIcmpPacketFailed),
+        },
+        _ => return Ok(()),
+    };
+    layer4[offset..offset + 2].copy_from_slice(&bad_checksum(checksum).to_be_bytes());
+    Ok(())
+}
+
+/// Overwrite the checksum of the layer 4 header in the ipv6 packet with a wrong one.
+fn corrupt_ipv6_checksum(packet: &mut [u8]) -> Result<(), PistolErrors> {
+    let (protocol, src_ipv6, dst_ipv6) = match Ipv6Packet::new(packet) {
+        Some(p) => (p.get_next_header(), p.get_source(), p.get_destination()),
+        None => return Err(PistolErrors::GetIpv6PacketFailed),
+    };
+    let layer4 = &mut packet[IPV6_HEADER_SIZE..];
+    let (offset, checksum) = match protocol {
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(layer4) {
+            Some(p) => (16, tcp::ipv6_checksum(&p, &src_ipv6, &dst_ipv6)),
+            None => return Err(PistolErrors::GetTcpPacketFailed),
+        },
+        IpNextHeaderProtocols::Udp => match UdpPacket::new(layer4) {
+            Some(p) => (6, udp::ipv6_checksum(&p, &src_ipv6, &dst_ipv6)),
+            None => return Err(PistolErrors::GetUdpPacketFailed),
+        },
+        IpNextHeaderProtocols::Icmpv6 => match Icmpv6Packet::new(layer4) {
+            Some(p) => (2, icmpv6::checksum(&p, &src_ipv6, &dst_ipv6)),
+            None => return Err(PistolErrors::GetIcmpv6PacketFailed),
+        },
+        _ => return Ok(()),
+    };
+    layer4[offset..offset + 2].copy_from_slice(&bad_checksum(checksum).to_be_bytes());
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Layer2Match {
     pub src_mac: Option<MacAddr>,         // response packet src mac
@@ -538,14 +763,145 @@ impl LayersMatch {
     }
 }
 
+/// Opens the datalink channel which the ethernet frames are sent and received through.
+pub trait ChannelOpener {
+    fn open(&self, interface: &NetworkInterface) -> io::Result<Channel>;
+}
+
+/// Default opener, the datalink channel of pnet.
+pub struct DatalinkChannelOpener;
+
+impl ChannelOpener for DatalinkChannelOpener {
+    fn open(&self, interface: &NetworkInterface) -> io::Result<Channel> {
+        let cfg = pnet::datalink::Config::default();
+        datalink::channel(interface, cfg)
+    }
+}
+
+/// The sender and the receiver of the ethernet datalink channel.
+type EthernetChannel = (Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>);
+
 fn datalink_channel(
+    opener: &dyn ChannelOpener,
     interface: &NetworkInterface,
-) -> Result<Option<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)>, PistolErrors> {
-    let cfg = pnet::datalink::Config::default();
-    match datalink::channel(&interface, cfg) {
-        Ok(Ethernet(tx, rx)) => Ok(Some((tx, rx))),
-        Ok(_) => Ok(None),
-        Err(e) => Err(e.into()),
+) -> Result<EthernetChannel, PistolErrors> {
+    let source = match opener.open(interface) {
+        Ok(Ethernet(tx, rx)) => return Ok((tx, rx)),
+        // e.g. the tun interface has no ethernet header
+        Ok(_) => io::Error::new(io::ErrorKind::Unsupported, "not an ethernet channel"),
+        Err(e) => e,
+    };
+    Err(PistolErrors::DatalinkChannelError {
+        iface: interface.name.clone(),
+        source,
+    })
+}
+
+/// The datalink channel (raw socket) can be opened on the interface, it usually needs the root privileges.
+pub fn raw_socket_available(interface: &NetworkInterface) -> bool {
+    datalink_channel(&DatalinkChannelOpener, interface).is_ok()
+}
+
+/// Send the ipv4 packet (with the header) through the ip layer raw socket,
+/// the kernel builds the link layer, so no next hop mac address is needed.
+/// The responses are wrapped in an ethernet frame without the mac addresses for the `layers_match`.
+#[cfg(unix)]
+fn ipv4_raw_send(
+    ipv4_buff: &[u8],
+    layers_match: &[LayersMatch],
+    timeout: Duration,
+) -> Result<(Vec<u8>, Duration), PistolErrors> {
+    let ipv4_packet = match Ipv4Packet::new(ipv4_buff) {
+        Some(p) => p,
+        None => {
+            return Err(PistolErrors::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid ipv4 packet",
+            )))
+        }
+    };
+    let dst_addr = SockAddr::from(std::net::SocketAddr::new(
+        ipv4_packet.get_destination().into(),
+        0,
+    ));
+    let protocol = ipv4_packet.get_next_level_protocol();
+    // the responses of the probe, and the icmp errors about it
+    let mut protocols = vec![protocol];
+    if protocol != IpNextHeaderProtocols::Icmp {
+        protocols.push(IpNextHeaderProtocols::Icmp);
+    }
+    let mut receivers = Vec::new();
+    for p in protocols {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::from(p.0 as i32)))?;
+        socket.set_nonblocking(true)?;
+        receivers.push(socket);
+    }
+    // IPPROTO_RAW implies the IP_HDRINCL, the packet is sent as it is
+    let sender = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::from(255)))?;
+    let send_time = clock_now();
+    sender.send_to(ipv4_buff, &dst_addr)?;
+    if timeout == Duration::new(0, 0) {
+        return Ok((vec![], Duration::new(0, 0)));
+    }
+
+    let mut recv_buff = [0u8; 65535];
+    while clock_elapsed(send_time) <= timeout {
+        let mut received = false;
+        for socket in &receivers {
+            let n = match (&*socket).read(&mut recv_buff) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            };
+            received = true;
+            let mut ethernet_buff = vec![0u8; ETHERNET_HEADER_SIZE + n];
+            ethernet_buff[12..14].copy_from_slice(&EtherTypes::Ipv4.0.to_be_bytes());
+            ethernet_buff[ETHERNET_HEADER_SIZE..].copy_from_slice(&recv_buff[..n]);
+            for m in layers_match {
+                if m.do_match(&ethernet_buff) {
+                    debug!("match found: {:?}", m);
+                    return Ok((ethernet_buff, clock_elapsed(send_time)));
+                }
+            }
+        }
+        if !received {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    Ok((vec![], clock_elapsed(send_time)))
+}
+
+/// The windows raw socket can not send the tcp packets.
+#[cfg(not(unix))]
+fn ipv4_raw_send(
+    _: &[u8],
+    _: &[LayersMatch],
+    _: Duration,
+) -> Result<(Vec<u8>, Duration), PistolErrors> {
+    Err(PistolErrors::IOError(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the ip layer raw socket send is not supported on this platform",
+    )))
+}
+
+/// Fall back to the ip layer raw socket if the datalink channel can not be opened,
+/// only the plain ipv4 packet can be sent this way (no vlan tag or spoofed mac).
+fn datalink_fallback(
+    error: PistolErrors,
+    ipv4_buff: &[u8],
+    layers_match: &[LayersMatch],
+    timeout: Duration,
+) -> Result<(Vec<u8>, Duration), PistolErrors> {
+    match &error {
+        // the ip layer can not send to the overridden mac address
+        PistolErrors::DatalinkChannelError { iface, source } if dst_mac_override().is_none() => {
+            warn!(
+                "open the datalink channel on {} failed ({}), send through the ip layer",
+                iface, source
+            );
+            ipv4_raw_send(ipv4_buff, layers_match, timeout)
+        }
+        _ => Err(error),
     }
 }
 
@@ -568,38 +924,143 @@ pub fn _print_packet_as_wireshark_format(buff: &[u8]) {
     println!("");
 }
 
-pub fn layer2_send(
+/// Build the ethernet frame, with the 802.1Q tag if `vlan` is set.
+fn build_ethernet_frame(
     dst_mac: MacAddr,
-    interface: NetworkInterface,
-    send_buff: &[u8],
+    src_mac: MacAddr,
     ethernet_type: EtherType,
+    payload: &[u8],
+    vlan: Option<u16>,
+) -> Result<Vec<u8>, PistolErrors> {
+    let mut ethernet_buff = [0u8; ETHERNET_BUFF_SIZE];
+    let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buff).unwrap();
+    ethernet_packet.set_destination(dst_mac);
+    ethernet_packet.set_source(src_mac);
+    let final_buff = match vlan {
+        Some(vlan_id) => {
+            // 0 and 4095 are reserved
+            if vlan_id == 0 || vlan_id >= 4095 {
+                return Err(PistolErrors::InvalidVlanId { vlan_id });
+            }
+            ethernet_packet.set_ethertype(EtherTypes::Vlan);
+            let mut vlan_buff = vec![0u8; VLAN_HEADER_SIZE + payload.len()];
+            let mut vlan_packet = MutableVlanPacket::new(&mut vlan_buff).unwrap();
+            vlan_packet.set_vlan_identifier(vlan_id);
+            vlan_packet.set_ethertype(ethernet_type);
+            vlan_packet.set_payload(payload);
+            ethernet_packet.set_payload(&vlan_buff);
+            ethernet_buff[..(ETHERNET_HEADER_SIZE + vlan_buff.len())].to_vec()
+        }
+        None => {
+            ethernet_packet.set_ethertype(ethernet_type);
+            ethernet_packet.set_payload(payload);
+            ethernet_buff[..(ETHERNET_HEADER_SIZE + payload.len())].to_vec()
+        }
+    };
+    Ok(final_buff)
+}
+
+/// The spoofed `src_mac` is used instead of the interface mac if it is set.
+fn layer2_src_mac(
+    dst_mac: MacAddr,
+    interface: &NetworkInterface,
+    src_mac: Option<MacAddr>,
+) -> Result<MacAddr, PistolErrors> {
+    match src_mac {
+        Some(m) => Ok(m),
+        None => {
+            if dst_mac == MacAddr::zero() {
+                Ok(MacAddr::zero())
+            } else {
+                match interface.mac {
+                    Some(m) => Ok(m),
+                    None => Err(PistolErrors::CanNotFoundMacAddress),
+                }
+            }
+        }
+    }
+}
+
+/// The ethernet frame `layer2_send` builds around the payload.
+pub struct Layer2Frame<'a> {
+    pub dst_mac: MacAddr,
+    /// Overrides the interface mac for the spoofing test,
+    /// the replies may never come back if the switch port security drops the spoofed frames,
+    /// or the replies are sent to the spoofed mac.
+    pub src_mac: Option<MacAddr>,
+    pub ethernet_type: EtherType,
+    /// Tags the frame with the 802.1Q vlan id,
+    /// the interface (trunk port) must support the tagging, otherwise the frame is dropped.
+    pub vlan: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Layer2Frame<'a> {
+    pub fn new(dst_mac: MacAddr, ethernet_type: EtherType, payload: &'a [u8]) -> Layer2Frame<'a> {
+        Layer2Frame {
+            dst_mac,
+            src_mac: None,
+            ethernet_type,
+            vlan: None,
+            payload,
+        }
+    }
+}
+
+pub fn layer2_send(
+    interface: NetworkInterface,
+    frame: Layer2Frame,
     layers_match: Vec<LayersMatch>,
     timeout: Duration,
 ) -> Result<(Vec<u8>, Duration), PistolErrors> {
-    let (mut sender, mut receiver) = match datalink_channel(&interface)? {
-        Some((s, r)) => (s, r),
-        None => return Err(PistolErrors::CreateDatalinkChannelFailed),
-    };
-    let src_mac = if dst_mac == MacAddr::zero() {
-        MacAddr::zero()
-    } else {
-        match interface.mac {
-            Some(m) => m,
-            None => return Err(PistolErrors::CanNotFoundMacAddress),
+    layer2_send_with(
+        &DatalinkChannelOpener,
+        interface,
+        frame,
+        layers_match,
+        timeout,
+    )
+}
+
+/// The `layer2_send` with the datalink channel opened by `opener`.
+pub fn layer2_send_with(
+    opener: &dyn ChannelOpener,
+    interface: NetworkInterface,
+    frame: Layer2Frame,
+    layers_match: Vec<LayersMatch>,
+    timeout: Duration,
+) -> Result<(Vec<u8>, Duration), PistolErrors> {
+    let Layer2Frame {
+        dst_mac,
+        src_mac,
+        ethernet_type,
+        vlan,
+        payload: send_buff,
+    } = frame;
+    let (mut sender, mut receiver) = match datalink_channel(opener, &interface) {
+        Ok(c) => c,
+        Err(e) if ethernet_type == EtherTypes::Ipv4 && vlan.is_none() && src_mac.is_none() => {
+            return datalink_fallback(e, send_buff, &layers_match, timeout);
         }
+        Err(e) => return Err(e),
     };
+    let src_mac = layer2_src_mac(dst_mac, &interface, src_mac)?;
 
-    let mut ethernet_buff = [0u8; ETHERNET_BUFF_SIZE];
-    // let mut ethernet_buff = [0u8; ETHERNET_HEADER_SIZE + send_buff.len()];
-    let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buff).unwrap();
-    ethernet_packet.set_destination(dst_mac);
-    ethernet_packet.set_source(src_mac);
-    ethernet_packet.set_ethertype(ethernet_type);
-    ethernet_packet.set_payload(send_buff);
+    // the kernel would truncate or drop the frame silently
+    match interface_mtu(&interface) {
+        Some(mtu) if send_buff.len() > mtu as usize => {
+            return Err(PistolErrors::PacketExceedsMtu {
+                interface: interface.name.clone(),
+                size: send_buff.len(),
+                mtu,
+            });
+        }
+        _ => (),
+    }
 
-    let final_buff = ethernet_buff[..(ETHERNET_HEADER_SIZE + send_buff.len())].to_vec();
+    let final_buff = build_ethernet_frame(dst_mac, src_mac, ethernet_type, send_buff, vlan)?;
     // _print_packet_as_wireshark_format(&final_buff);
-    let send_time = Instant::now();
+    let send_time = clock_now();
     match sender.send_to(&final_buff, Some(interface)) {
         Some(r) => match r {
             Err(e) => return Err(e.into()),
@@ -608,10 +1069,10 @@ pub fn layer2_send(
         None => (),
     }
 
-    let start_time = Instant::now();
+    let start_time = clock_now();
     if timeout != Duration::new(0, 0) {
         loop {
-            if start_time.elapsed() > timeout {
+            if clock_elapsed(start_time) > timeout {
                 break;
             }
             let buff = match receiver.next() {
@@ -622,14 +1083,14 @@ pub fn layer2_send(
                 match m.do_match(buff) {
                     true => {
                         debug!("match found: {:?}", m);
-                        let rtt = send_time.elapsed();
+                        let rtt = clock_elapsed(send_time);
                         return Ok((buff.to_vec(), rtt));
                     }
                     false => (),
                 }
             }
         }
-        Ok((vec![], start_time.elapsed()))
+        Ok((vec![], clock_elapsed(start_time)))
     } else {
         // not recv any response for flood attack enffience
         Ok((vec![], Duration::new(0, 0)))
@@ -691,41 +1152,71 @@ fn arp(
     let layers_match = LayersMatch::Layer3Match(layer3);
 
     let (ret, rtt) = layer2_send(
-        MacAddr::broadcast(),
         interface,
-        &arp_buff,
-        ethernet_type,
+        Layer2Frame::new(MacAddr::broadcast(), ethernet_type, &arp_buff),
         vec![layers_match],
         timeout,
     )?;
     Ok((get_mac_from_arp(&ret), rtt))
 }
 
-pub fn system_route(
-    src_ipv4: Ipv4Addr,
-    dst_ipv4: Ipv4Addr,
-    timeout: Duration,
-) -> Result<(MacAddr, NetworkInterface), PistolErrors> {
-    let interface = match find_interface_by_ip(src_ipv4.into()) {
+/// The interface the packets to `dst_addr` are sent through.
+fn route_interface(src_addr: IpAddr, dst_addr: IpAddr) -> Result<NetworkInterface, PistolErrors> {
+    let interface = match find_interface_by_ip(src_addr) {
         Some(i) => i,
         None => {
-            let interface = match system_cache_search_route(dst_ipv4.into()) {
+            let interface = match system_cache_search_route(dst_addr) {
                 Some(i) => i,
                 None => {
                     // The system route table not contain this ipaddr,
                     // so send it to the default route.
-                    let default_route = match system_cache_default_route() {
-                        Some(d) => d,
-                        None => return Err(PistolErrors::CanNotFoundRouterAddress),
+                    let default_route = match dst_addr {
+                        IpAddr::V4(_) => system_cache_default_route(),
+                        IpAddr::V6(_) => system_cache_default_route6(),
                     };
-                    default_route.dev
+                    match default_route {
+                        Some(d) => d.dev,
+                        None => return Err(PistolErrors::CanNotFoundRouterAddress),
+                    }
                 }
             };
             interface
         }
     };
+    Ok(interface)
+}
 
-    let dst_mac = match system_cache_search_mac(dst_ipv4.into()) {
+/// The destination mac address overridden by the `ProbeOptions` of this thread.
+fn dst_mac_override() -> Option<MacAddr> {
+    PROBE_OPTIONS.with(|o| o.borrow().as_ref().and_then(|o| o.dst_mac_override))
+}
+
+/// The mac address and the interface of the next hop to `dst_addr`,
+/// the overridden mac address is used without the arp or ndp resolution.
+fn next_hop(
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    timeout: Duration,
+) -> Result<(MacAddr, NetworkInterface), PistolErrors> {
+    if let Some(dst_mac) = dst_mac_override() {
+        let interface = route_interface(src_addr, dst_addr)?;
+        return Ok((dst_mac, interface));
+    }
+    match (src_addr, dst_addr) {
+        (IpAddr::V4(src_ipv4), IpAddr::V4(dst_ipv4)) => system_route(src_ipv4, dst_ipv4, timeout),
+        (IpAddr::V6(src_ipv6), IpAddr::V6(dst_ipv6)) => system_route6(src_ipv6, dst_ipv6, timeout),
+        _ => Err(PistolErrors::CanNotFoundRouterAddress),
+    }
+}
+
+pub fn system_route(
+    src_ipv4: Ipv4Addr,
+    dst_ipv4: Ipv4Addr,
+    timeout: Duration,
+) -> Result<(MacAddr, NetworkInterface), PistolErrors> {
+    let interface = route_interface(src_ipv4.into(), dst_ipv4.into())?;
+
+    let dst_mac = match system_cache_search_mac(dst_ipv4.into(), Some(interface.index)) {
         Some(m) => m,
         None => {
             if dst_ipv4_in_local(dst_ipv4) {
@@ -733,7 +1224,7 @@ pub fn system_route(
                     (Some(m), _rtt) => m,
                     (_, _) => return Err(PistolErrors::CanNotFoundMacAddress),
                 };
-                system_cache_update(dst_ipv4.into(), dst_mac);
+                system_cache_update(dst_ipv4.into(), dst_mac, Some(interface.index));
                 dst_mac
             } else {
                 let default_route = match system_cache_default_route() {
@@ -742,11 +1233,18 @@ pub fn system_route(
                 };
                 let dst_mac = match default_route.via {
                     IpAddr::V4(default_route_ipv4) => {
-                        let dst_mac = match system_cache_search_mac(default_route_ipv4.into()) {
+                        let dst_mac = match system_cache_search_mac(
+                            default_route_ipv4.into(),
+                            Some(interface.index),
+                        ) {
                             Some(m) => m,
                             None => match arp(src_ipv4, default_route_ipv4, timeout)? {
                                 (Some(m), _rtt) => {
-                                    system_cache_update(default_route_ipv4.into(), m);
+                                    system_cache_update(
+                                        default_route_ipv4.into(),
+                                        m,
+                                        Some(interface.index),
+                                    );
                                     m
                                 }
                                 (_, _) => return Err(PistolErrors::CanNotFoundRouteMacAddress),
@@ -763,6 +1261,23 @@ pub fn system_route(
     Ok((dst_mac, interface))
 }
 
+/// The interface of the source address has the link layer (ethernet) to send the frames through,
+/// the point-to-point interface (ppp, tun) has no mac address and no next hop to resolve.
+fn link_layer_available(src_ipv4: Ipv4Addr) -> bool {
+    match find_interface_by_ip(src_ipv4.into()) {
+        Some(i) if i.is_loopback() => true,
+        Some(i) => {
+            let has_mac = match i.mac {
+                Some(m) => m != MacAddr::zero(),
+                None => false,
+            };
+            has_mac && !i.is_point_to_point()
+        }
+        // let the route decide
+        None => true,
+    }
+}
+
 pub fn layer3_ipv4_send(
     src_ipv4: Ipv4Addr,
     dst_ipv4: Ipv4Addr,
@@ -777,29 +1292,54 @@ pub fn layer3_ipv4_send(
     //     dst_ipv4,
     //     system_time.timestamp_millis()
     // );
-    let (dst_mac, interface) = system_route(src_ipv4, dst_ipv4, timeout)?;
-    // let ret = system_route(src_ipv4, dst_ipv4, timeout);
+    let options = PROBE_OPTIONS.with(|o| o.borrow().clone());
+    let payload = match &options {
+        Some(options) => options.craft_ipv4(payload)?,
+        None => payload.to_vec(),
+    };
+    let ip_layer_send = match options {
+        Some(options) => options.ip_layer_send,
+        None => false,
+    };
+    // the overridden mac address needs the link layer
+    if (ip_layer_send || !link_layer_available(src_ipv4)) && dst_mac_override().is_none() {
+        debug!("send to {} through the ip layer", dst_ipv4);
+        let (layer2_buff, rtt) = ipv4_raw_send(&payload, &layers_match, timeout)?;
+        let layer3_buff = layer2_payload(&layer2_buff);
+        record_reply(&layer3_buff);
+        return Ok((layer3_buff, rtt));
+    }
+    let (dst_mac, interface) = match next_hop(src_ipv4.into(), dst_ipv4.into(), timeout) {
+        Ok(r) => r,
+        // the arp request can not be sent either
+        Err(e @ PistolErrors::DatalinkChannelError { .. }) => {
+            let (layer2_buff, rtt) = datalink_fallback(e, &payload, &layers_match, timeout)?;
+            let layer3_buff = layer2_payload(&layer2_buff);
+            record_reply(&layer3_buff);
+            return Ok((layer3_buff, rtt));
+        }
+        Err(e) => return Err(e),
+    };
     // let system_time2 = Local::now();
     // println!(
     //     "layer3 {}, end: {}",
     //     dst_ipv4,
     //     system_time2.timestamp_millis()
     // );
-    // let (dst_mac, interface) = ret?;
 
     debug!("convert dst ipv4: {} to mac: {}", dst_ipv4, dst_mac);
     debug!("use this interface to send data: {}", interface.name);
     let ethernet_type = EtherTypes::Ipv4;
 
     let (layer2_buff, rtt) = layer2_send(
-        dst_mac,
         interface,
-        payload,
-        ethernet_type,
+        Layer2Frame::new(dst_mac, ethernet_type, &payload),
         layers_match,
         timeout,
     )?;
-    Ok((layer2_payload(&layer2_buff), rtt))
+    let layer3_buff = layer2_payload(&layer2_buff);
+    record_reply(&layer3_buff);
+    Ok((layer3_buff, rtt))
 }
 
 pub fn multicast_mac(ip: Ipv6Addr) -> MacAddr {
@@ -884,10 +1424,8 @@ fn ndp_ns(
 
     let ethernet_type = EtherTypes::Ipv6;
     let (r, rtt) = layer2_send(
-        multicast_mac(dst_ipv6),
         interface.clone(),
-        &ipv6_buff,
-        ethernet_type,
+        Layer2Frame::new(multicast_mac(dst_ipv6), ethernet_type, &ipv6_buff),
         vec![layers_match],
         timeout,
     )?;
@@ -975,10 +1513,8 @@ fn ndp_rs(
     let dst_mac = MacAddr(33, 33, 00, 00, 00, 02);
     let ethernet_type = EtherTypes::Ipv6;
     let (r, rtt) = layer2_send(
-        dst_mac,
         interface.clone(),
-        &ipv6_buff,
-        ethernet_type,
+        Layer2Frame::new(dst_mac, ethernet_type, &ipv6_buff),
         vec![layers_match],
         timeout,
     )?;
@@ -999,26 +1535,9 @@ pub fn system_route6(
     dst_ipv6: Ipv6Addr,
     timeout: Duration,
 ) -> Result<(MacAddr, NetworkInterface), PistolErrors> {
-    let interface = match find_interface_by_ip(src_ipv6.into()) {
-        Some(i) => i,
-        None => {
-            let interface = match system_cache_search_route(dst_ipv6.into()) {
-                Some(i) => i,
-                None => {
-                    // The system route table not contain this ipaddr,
-                    // so send it to the default route.
-                    let default_route = match system_cache_default_route6() {
-                        Some(d) => d,
-                        None => return Err(PistolErrors::CanNotFoundRouterAddress),
-                    };
-                    default_route.dev
-                }
-            };
-            interface
-        }
-    };
+    let interface = route_interface(src_ipv6.into(), dst_ipv6.into())?;
 
-    let dst_mac = match system_cache_search_mac(dst_ipv6.into()) {
+    let dst_mac = match system_cache_search_mac(dst_ipv6.into(), Some(interface.index)) {
         Some(m) => m,
         None => {
             if dst_ipv6_in_local(dst_ipv6) {
@@ -1026,7 +1545,7 @@ pub fn system_route6(
                     (Some(m), _rtt) => m,
                     (_, _) => return Err(PistolErrors::CanNotFoundMacAddress),
                 };
-                system_cache_update(dst_ipv6.into(), dst_mac);
+                system_cache_update(dst_ipv6.into(), dst_mac, Some(interface.index));
                 dst_mac
             } else {
                 let default_route = match system_cache_default_route6() {
@@ -1035,11 +1554,18 @@ pub fn system_route6(
                 };
                 let dst_mac = match default_route.via {
                     IpAddr::V6(default_route_ipv6) => {
-                        let dst_mac = match system_cache_search_mac(default_route_ipv6.into()) {
+                        let dst_mac = match system_cache_search_mac(
+                            default_route_ipv6.into(),
+                            Some(interface.index),
+                        ) {
                             Some(m) => m,
                             None => match ndp_rs(src_ipv6, timeout)? {
                                 (Some(m), _rtt) => {
-                                    system_cache_update(default_route_ipv6.into(), m);
+                                    system_cache_update(
+                                        default_route_ipv6.into(),
+                                        m,
+                                        Some(interface.index),
+                                    );
                                     m
                                 }
                                 (_, _) => return Err(PistolErrors::CanNotFoundRouteMacAddress),
@@ -1063,19 +1589,23 @@ pub fn layer3_ipv6_send(
     layers_match: Vec<LayersMatch>,
     timeout: Duration,
 ) -> Result<(Vec<u8>, Duration), PistolErrors> {
-    let (dst_mac, interface) = system_route6(src_ipv6, dst_ipv6, timeout)?;
+    let (dst_mac, interface) = next_hop(src_ipv6.into(), dst_ipv6.into(), timeout)?;
     debug!("convert dst ipv6: {} to mac: {}", dst_ipv6, dst_mac);
     debug!("use this interface to send data: {}", interface.name);
     let ethernet_type = EtherTypes::Ipv6;
+    let payload = match PROBE_OPTIONS.with(|o| o.borrow().clone()) {
+        Some(options) => options.craft_ipv6(payload)?,
+        None => payload.to_vec(),
+    };
     let (layer2_buff, rtt) = layer2_send(
-        dst_mac,
         interface,
-        payload,
-        ethernet_type,
+        Layer2Frame::new(dst_mac, ethernet_type, &payload),
         layers_match,
         timeout,
     )?;
-    Ok((layer2_payload(&layer2_buff), rtt))
+    let layer3_buff = layer2_payload(&layer2_buff);
+    record_reply(&layer3_buff);
+    Ok((layer3_buff, rtt))
 }
 
 /// Queries the IP address of a domain name and returns.
@@ -1084,9 +1614,415 @@ pub fn dns_query(hostname: &str) -> Result<Vec<IpAddr>, PistolErrors> {
     Ok(ips)
 }
 
+/// Queries the name of the address, None if it has no name.
+pub(crate) fn dns_reverse_query(addr: IpAddr) -> Result<Option<String>, PistolErrors> {
+    let name = lookup_addr(&addr)?;
+    // the numeric address is returned if the address has no name
+    match name.parse::<IpAddr>() {
+        Ok(_) => Ok(None),
+        Err(_) => Ok(Some(name)),
+    }
+}
+
+/// Runs the system resolver query and gives up after the timeout,
+/// the system resolver can not be interrupted so the lookup thread is left to finish on its own.
+fn system_query_timeout<T: Send + 'static>(
+    name: &str,
+    timeout: Option<Duration>,
+    query: impl FnOnce() -> Result<T, PistolErrors> + Send + 'static,
+) -> Result<T, PistolErrors> {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return query(),
+    };
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let _ = tx.send(query());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(ret) => ret,
+        Err(_) => Err(PistolErrors::DnsQueryTimeout {
+            hostname: name.to_string(),
+        }),
+    }
+}
+
+/// Same as `dns_query` but gives up after the timeout.
+pub(crate) fn dns_query_timeout(
+    hostname: &str,
+    timeout: Option<Duration>,
+) -> Result<Vec<IpAddr>, PistolErrors> {
+    let name = hostname.to_string();
+    system_query_timeout(hostname, timeout, move || dns_query(&name))
+}
+
+/// Same as `dns_reverse_query` but gives up after the timeout.
+pub(crate) fn dns_reverse_query_timeout(
+    addr: IpAddr,
+    timeout: Option<Duration>,
+) -> Result<Option<String>, PistolErrors> {
+    system_query_timeout(&addr.to_string(), timeout, move || dns_reverse_query(addr))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::interfaces;
+    use pnet::packet::vlan::VlanPacket;
+    use pnet::packet::MutablePacket;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Instant;
+    #[test]
+    fn test_layer2_src_mac_spoof() {
+        let mut interface = interfaces().into_iter().next().unwrap();
+        let interface_mac = MacAddr::new(0x48, 0x5f, 0x08, 0xe0, 0x13, 0x94);
+        interface.mac = Some(interface_mac);
+        let dst_mac = MacAddr::new(0x00, 0x50, 0x56, 0xfb, 0x1d, 0x74);
+        let spoofed_mac = MacAddr::new(0x02, 0x00, 0x00, 0x00, 0x00, 0x01);
+        let src_mac = layer2_src_mac(dst_mac, &interface, None).unwrap();
+        assert_eq!(src_mac, interface_mac);
+
+        let src_mac = layer2_src_mac(dst_mac, &interface, Some(spoofed_mac)).unwrap();
+        let payload = [1u8, 2, 3, 4];
+        let frame =
+            build_ethernet_frame(dst_mac, src_mac, EtherTypes::Ipv4, &payload, None).unwrap();
+        let ethernet_packet = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(ethernet_packet.get_source(), spoofed_mac);
+        assert_eq!(ethernet_packet.get_destination(), dst_mac);
+    }
+    /// The icmp echo request from the loopback address to itself.
+    fn loopback_echo_request() -> Vec<u8> {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let mut ipv4_buff = [0u8; IPV4_HEADER_SIZE + ICMP_HEADER_SIZE];
+        let mut ipv4_packet = MutableIpv4Packet::new(&mut ipv4_buff).unwrap();
+        ipv4_packet.set_version(4);
+        ipv4_packet.set_header_length(5);
+        ipv4_packet.set_total_length((IPV4_HEADER_SIZE + ICMP_HEADER_SIZE) as u16);
+        ipv4_packet.set_ttl(64);
+        ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        ipv4_packet.set_source(localhost);
+        ipv4_packet.set_destination(localhost);
+        let mut icmp_packet = icmp::MutableIcmpPacket::new(ipv4_packet.payload_mut()).unwrap();
+        icmp_packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
+        let checksum = icmp::checksum(&icmp_packet.to_immutable());
+        icmp_packet.set_checksum(checksum);
+        let checksum = ipv4::checksum(&ipv4_packet.to_immutable());
+        ipv4_packet.set_checksum(checksum);
+        ipv4_buff.to_vec()
+    }
+    fn echo_reply_match() -> LayersMatch {
+        LayersMatch::Layer4MatchIcmp(Layer4MatchIcmp {
+            layer3: None,
+            types: Some(icmp::IcmpTypes::EchoReply),
+            codes: None,
+        })
+    }
+    struct FailedOpener;
+    impl ChannelOpener for FailedOpener {
+        fn open(&self, _: &NetworkInterface) -> io::Result<Channel> {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "operation not permitted",
+            ))
+        }
+    }
+    /// Keeps the sent frames, receives nothing.
+    struct CaptureOpener {
+        frames: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+    struct CaptureSender {
+        frames: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+    struct EmptyReceiver;
+    impl DataLinkSender for CaptureSender {
+        fn build_and_send(
+            &mut self,
+            num_packets: usize,
+            packet_size: usize,
+            func: &mut dyn FnMut(&mut [u8]),
+        ) -> Option<io::Result<()>> {
+            for _ in 0..num_packets {
+                let mut packet = vec![0u8; packet_size];
+                func(&mut packet);
+                self.frames.lock().unwrap().push(packet);
+            }
+            Some(Ok(()))
+        }
+        fn send_to(
+            &mut self,
+            packet: &[u8],
+            _: Option<NetworkInterface>,
+        ) -> Option<io::Result<()>> {
+            self.frames.lock().unwrap().push(packet.to_vec());
+            Some(Ok(()))
+        }
+    }
+    impl DataLinkReceiver for EmptyReceiver {
+        fn next(&mut self) -> io::Result<&[u8]> {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "no frame"))
+        }
+    }
+    impl ChannelOpener for CaptureOpener {
+        fn open(&self, _: &NetworkInterface) -> io::Result<Channel> {
+            let sender = CaptureSender {
+                frames: self.frames.clone(),
+            };
+            Ok(Ethernet(Box::new(sender), Box::new(EmptyReceiver)))
+        }
+    }
+    #[test]
+    fn test_dst_mac_override() {
+        let override_mac = MacAddr::new(0x02, 0x00, 0x00, 0x00, 0x00, 0x02);
+        let options = ProbeOptions {
+            dst_mac_override: Some(override_mac),
+            ..Default::default()
+        };
+        let src_ipv4 = interfaces()
+            .iter()
+            .flat_map(|i| i.ips.iter().map(|ip| ip.ip()))
+            .find_map(|ip| match ip {
+                IpAddr::V4(ipv4) if !ipv4.is_loopback() => Some(ipv4),
+                _ => None,
+            });
+        let src_ipv4 = match src_ipv4 {
+            Some(s) => s,
+            None => return,
+        };
+        // no host answers the arp request of the documentation address
+        let dst_ipv4 = Ipv4Addr::new(192, 0, 2, 1);
+        let start_time = Instant::now();
+        let (dst_mac, interface) = with_probe_options(Some(&options), || {
+            next_hop(src_ipv4.into(), dst_ipv4.into(), Duration::from_secs(3))
+        })
+        .unwrap();
+        assert_eq!(dst_mac, override_mac);
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let opener = CaptureOpener {
+            frames: frames.clone(),
+        };
+        let payload = [0x45u8; IPV4_HEADER_SIZE];
+        let frame = Layer2Frame::new(dst_mac, EtherTypes::Ipv4, &payload);
+        layer2_send_with(&opener, interface, frame, vec![], Duration::new(0, 0)).unwrap();
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.len(), 1);
+        let ethernet_packet = EthernetPacket::new(&frames[0]).unwrap();
+        assert_eq!(ethernet_packet.get_destination(), override_mac);
+        assert_eq!(ethernet_packet.payload(), &payload);
+    }
+    #[test]
+    fn test_datalink_channel_failed() {
+        let interface = interfaces().into_iter().find(|i| i.is_loopback()).unwrap();
+        let assert_channel_error = |ret: Result<_, PistolErrors>| match ret {
+            Err(PistolErrors::DatalinkChannelError { iface, source }) => {
+                assert_eq!(iface, interface.name);
+                assert_eq!(source.kind(), io::ErrorKind::PermissionDenied);
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("the channel is opened"),
+        };
+        assert_channel_error(datalink_channel(&FailedOpener, &interface).map(|_| ()));
+
+        let timeout = Duration::from_secs(1);
+        // the arp request has no ip layer to fall back to
+        let frame = Layer2Frame::new(MacAddr::broadcast(), EtherTypes::Arp, &[0u8; 28]);
+        let ret = layer2_send_with(&FailedOpener, interface.clone(), frame, vec![], timeout);
+        assert_channel_error(ret.map(|_| ()));
+    }
+    #[test]
+    fn test_datalink_fallback() {
+        // the ipv4 packet is sent through the ip layer raw socket
+        if let Err(e) = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)) {
+            println!(
+                "skip the ip layer fallback test without the root privileges: {}",
+                e
+            );
+            return;
+        }
+        let interface = interfaces().into_iter().find(|i| i.is_loopback()).unwrap();
+        let echo_request = loopback_echo_request();
+        let frame = Layer2Frame::new(MacAddr::zero(), EtherTypes::Ipv4, &echo_request);
+        let (ret, _) = layer2_send_with(
+            &FailedOpener,
+            interface,
+            frame,
+            vec![echo_reply_match()],
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let reply = layer2_payload(&ret);
+        let reply_packet = Ipv4Packet::new(&reply).unwrap();
+        assert_eq!(reply_packet.get_source(), Ipv4Addr::LOCALHOST);
+    }
+    #[test]
+    #[ignore = "needs the root privileges to open the raw socket"]
+    fn test_ip_layer_send() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let options = ProbeOptions {
+            ip_layer_send: true,
+            ..Default::default()
+        };
+        let ret = with_probe_options(Some(&options), || {
+            layer3_ipv4_send(
+                localhost,
+                localhost,
+                &loopback_echo_request(),
+                vec![echo_reply_match()],
+                Duration::from_secs(1),
+            )
+        });
+        // no ethernet header, the ip layer packet is returned like the datalink send
+        let (reply, _) = ret.unwrap();
+        let reply_packet = Ipv4Packet::new(&reply).unwrap();
+        assert_eq!(reply_packet.get_source(), localhost);
+        let icmp_packet = IcmpPacket::new(reply_packet.payload()).unwrap();
+        assert_eq!(icmp_packet.get_icmp_type(), icmp::IcmpTypes::EchoReply);
+    }
+    #[test]
+    fn test_insert_ipv4_options() {
+        use pnet::packet::ipv4::checksum;
+        use pnet::packet::tcp::MutableTcpPacket;
+        let mut buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE];
+        let mut ip_header = MutableIpv4Packet::new(&mut buff).unwrap();
+        ip_header.set_version(4);
+        ip_header.set_header_length(5);
+        ip_header.set_total_length((IPV4_HEADER_SIZE + TCP_HEADER_SIZE) as u16);
+        ip_header.set_ttl(64);
+        ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ip_header.set_source(Ipv4Addr::new(192, 168, 1, 2));
+        ip_header.set_destination(Ipv4Addr::new(192, 168, 1, 1));
+        let mut tcp_header = MutableTcpPacket::new(&mut buff[IPV4_HEADER_SIZE..]).unwrap();
+        tcp_header.set_source(45678);
+        tcp_header.set_destination(80);
+
+        // record route with 2 slots, padded to 12 bytes
+        let ip_options = [0x07, 0x0b, 0x04, 0, 0, 0, 0, 0, 0, 0, 0];
+        let options = ProbeOptions {
+            ip_options: Some(ip_options.to_vec()),
+            bad_checksum: false,
+            ip_layer_send: false,
+            dst_mac_override: None,
+        };
+        let packet = options.craft_ipv4(&buff).unwrap();
+        let ipv4_packet = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(ipv4_packet.get_header_length(), 8);
+        assert_eq!(ipv4_packet.get_total_length() as usize, packet.len());
+        assert_eq!(packet.len(), IPV4_HEADER_SIZE + 12 + TCP_HEADER_SIZE);
+        assert_eq!(&ipv4_packet.get_options_raw()[..11], &ip_options);
+        assert_eq!(ipv4_packet.get_options_raw()[11], 0);
+        assert_eq!(ipv4_packet.get_checksum(), checksum(&ipv4_packet));
+        let tcp_packet = TcpPacket::new(ipv4_packet.payload()).unwrap();
+        assert_eq!(tcp_packet.get_source(), 45678);
+        assert_eq!(tcp_packet.get_destination(), 80);
+
+        let options = ProbeOptions {
+            ip_options: Some(vec![1; 41]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            options.craft_ipv4(&buff),
+            Err(PistolErrors::InvalidIpOptionsLength { len: 41 })
+        ));
+        assert_eq!(ProbeOptions::default().craft_ipv4(&buff).unwrap(), buff);
+    }
+    #[test]
+    fn test_bad_checksum() {
+        use pnet::packet::ipv6::MutableIpv6Packet;
+        use pnet::packet::tcp::MutableTcpPacket;
+        use pnet::packet::udp::MutableUdpPacket;
+        let src_ipv4 = Ipv4Addr::new(192, 168, 1, 2);
+        let dst_ipv4 = Ipv4Addr::new(192, 168, 1, 1);
+        let mut buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE];
+        let mut ip_header = MutableIpv4Packet::new(&mut buff).unwrap();
+        ip_header.set_version(4);
+        ip_header.set_header_length(5);
+        ip_header.set_total_length((IPV4_HEADER_SIZE + TCP_HEADER_SIZE) as u16);
+        ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ip_header.set_source(src_ipv4);
+        ip_header.set_destination(dst_ipv4);
+        let mut tcp_header = MutableTcpPacket::new(&mut buff[IPV4_HEADER_SIZE..]).unwrap();
+        tcp_header.set_source(45678);
+        tcp_header.set_destination(80);
+        tcp_header.set_data_offset(5);
+        let checksum = tcp::ipv4_checksum(&tcp_header.to_immutable(), &src_ipv4, &dst_ipv4);
+        tcp_header.set_checksum(checksum);
+
+        let options = ProbeOptions {
+            bad_checksum: true,
+            ..Default::default()
+        };
+        let packet = options.craft_ipv4(&buff).unwrap();
+        let tcp_packet = TcpPacket::new(&packet[IPV4_HEADER_SIZE..]).unwrap();
+        assert_ne!(tcp_packet.get_checksum(), checksum);
+        assert_ne!(
+            tcp_packet.get_checksum(),
+            tcp::ipv4_checksum(&tcp_packet, &src_ipv4, &dst_ipv4)
+        );
+        // only the checksum is changed
+        assert_eq!(
+            &packet[..IPV4_HEADER_SIZE + 16],
+            &buff[..IPV4_HEADER_SIZE + 16]
+        );
+        assert_eq!(
+            &packet[IPV4_HEADER_SIZE + 18..],
+            &buff[IPV4_HEADER_SIZE + 18..]
+        );
+        let packet = ProbeOptions::default().craft_ipv4(&buff).unwrap();
+        let tcp_packet = TcpPacket::new(&packet[IPV4_HEADER_SIZE..]).unwrap();
+        assert_eq!(tcp_packet.get_checksum(), checksum);
+
+        let src_ipv6: Ipv6Addr = "fe80::1".parse().unwrap();
+        let dst_ipv6: Ipv6Addr = "fe80::2".parse().unwrap();
+        let mut buff = [0u8; IPV6_HEADER_SIZE + UDP_HEADER_SIZE];
+        let mut ip_header = MutableIpv6Packet::new(&mut buff).unwrap();
+        ip_header.set_version(6);
+        ip_header.set_payload_length(UDP_HEADER_SIZE as u16);
+        ip_header.set_next_header(IpNextHeaderProtocols::Udp);
+        ip_header.set_source(src_ipv6);
+        ip_header.set_destination(dst_ipv6);
+        let mut udp_header = MutableUdpPacket::new(&mut buff[IPV6_HEADER_SIZE..]).unwrap();
+        udp_header.set_source(45678);
+        udp_header.set_destination(53);
+        udp_header.set_length(UDP_HEADER_SIZE as u16);
+        let packet = options.craft_ipv6(&buff).unwrap();
+        let udp_packet = UdpPacket::new(&packet[IPV6_HEADER_SIZE..]).unwrap();
+        assert_ne!(udp_packet.get_checksum(), 0);
+        assert_ne!(
+            udp_packet.get_checksum(),
+            udp::ipv6_checksum(&udp_packet, &src_ipv6, &dst_ipv6)
+        );
+    }
+    #[test]
+    fn test_build_ethernet_frame_vlan() {
+        let dst_mac = MacAddr::new(0x00, 0x50, 0x56, 0xfb, 0x1d, 0x74);
+        let src_mac = MacAddr::new(0x48, 0x5f, 0x08, 0xe0, 0x13, 0x94);
+        let payload = [1u8, 2, 3, 4];
+        let frame =
+            build_ethernet_frame(dst_mac, src_mac, EtherTypes::Ipv4, &payload, Some(100)).unwrap();
+        assert_eq!(
+            frame.len(),
+            ETHERNET_HEADER_SIZE + VLAN_HEADER_SIZE + payload.len()
+        );
+        let ethernet_packet = EthernetPacket::new(&frame).unwrap();
+        // TPID
+        assert_eq!(ethernet_packet.get_ethertype(), EtherTypes::Vlan);
+        assert_eq!(&frame[12..14], &[0x81, 0x00]);
+        let vlan_packet = VlanPacket::new(ethernet_packet.payload()).unwrap();
+        assert_eq!(vlan_packet.get_vlan_identifier(), 100);
+        assert_eq!(vlan_packet.get_ethertype(), EtherTypes::Ipv4);
+        assert_eq!(vlan_packet.payload(), &payload);
+
+        let frame =
+            build_ethernet_frame(dst_mac, src_mac, EtherTypes::Ipv4, &payload, None).unwrap();
+        assert_eq!(frame.len(), ETHERNET_HEADER_SIZE + payload.len());
+        assert_eq!(&frame[12..14], &[0x08, 0x00]);
+
+        assert!(
+            build_ethernet_frame(dst_mac, src_mac, EtherTypes::Ipv4, &payload, Some(4095)).is_err()
+        );
+    }
     #[test]
     fn test_dns_query() {
         let hostname = "ipv6.sjtu.edu.cn";
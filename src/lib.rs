@@ -1,30 +1,36 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![doc = include_str!("lib.md")]
+use log::warn;
 use once_cell::sync::Lazy;
+use pnet::ipnetwork::Ipv6Network;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::fmt;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use subnetwork::Ipv4Pool;
 
 pub mod flood;
 pub mod hop;
 pub mod os;
+pub mod payloads;
 pub mod ping;
 pub mod scan;
 pub mod vs;
 // inner use only
 mod errors;
 mod layers;
+mod resolver;
 mod route;
 mod utils;
 
 use crate::errors::PistolErrors;
-use crate::route::SystemNetCache;
 
 // debug code
 // #[cfg(test)]
@@ -57,6 +63,9 @@ static SYSTEM_NET_CACHE: Lazy<Arc<Mutex<SystemNetCache>>> = Lazy::new(|| {
 });
 
 const DEFAULT_TIMEOUT: u64 = 3;
+// the wireless and cellular links have higher and less stable latency
+const DEFAULT_WIRELESS_TIMEOUT: u64 = 5;
+const DEFAULT_CELLULAR_TIMEOUT: u64 = 8;
 
 pub struct Logger {}
 
@@ -142,19 +151,33 @@ impl IpCheckMethods for IpAddr {
 pub struct Host {
     pub addr: IpAddr,
     pub ports: Vec<u16>,
+    /// The ports already known to be open (e.g. from a previous scan),
+    /// the scan reports them as open without the probes and the service scan detects them directly.
+    #[serde(default)]
+    pub known_open: Vec<u16>,
 }
 
 impl Host {
     pub fn new(addr: IpAddr, ports: Option<Vec<u16>>) -> Host {
         let h = match ports {
-            Some(p) => Host { addr, ports: p },
+            Some(p) => Host {
+                addr,
+                ports: p,
+                known_open: vec![],
+            },
             None => Host {
                 addr,
                 ports: vec![],
+                known_open: vec![],
             },
         };
         h
     }
+    /// Set the ports known to be open, see `Host::known_open`.
+    pub fn with_known_open(mut self, known_open: Vec<u16>) -> Host {
+        self.known_open = known_open;
+        self
+    }
 }
 
 impl fmt::Display for Host {
@@ -167,6 +190,15 @@ impl fmt::Display for Host {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Target {
     pub hosts: Vec<Host>,
+    /// The hostnames and their ports, the hosts of them are added by `Target::resolve_all`.
+    #[serde(default)]
+    pub hostnames: Vec<(String, Vec<u16>)>,
+    /// The addresses of the resolved hostnames, every hostname is resolved only once.
+    #[serde(default)]
+    resolved: HashMap<String, Vec<IpAddr>>,
+    /// The local hosts left out of the expanded target, with their positions.
+    #[serde(default)]
+    excluded: Vec<(usize, Host)>,
 }
 
 impl fmt::Display for Target {
@@ -197,9 +229,15 @@ impl Target {
     /// }
     /// ```
     pub fn new(hosts: Vec<Host>) -> Target {
-        Target { hosts }
+        Target {
+            hosts,
+            hostnames: Vec::new(),
+            resolved: HashMap::new(),
+            excluded: Vec::new(),
+        }
     }
     /// Scan a IPv4 subnet with same ports.
+    /// The addresses of the local interfaces are left out, see `Target::include_local_addrs`.
     /// ```rust
     /// use pistol::Target;
     /// use pistol::Host;
@@ -216,17 +254,370 @@ impl Target {
             let h = Host::new(ipv4_addr.into(), ports.clone());
             hosts.push(h);
         }
-        let target = Target { hosts };
+        let target = Target::new(hosts).exclude_local_addrs();
         Ok(target)
     }
+    /// Scan the IPv4 addresses written in the nmap-style octet ranges with same ports,
+    /// every octet is a number or an inclusive range, at most 65536 addresses (a /16).
+    /// The addresses of the local interfaces are left out, see `Target::include_local_addrs`.
+    /// ```rust
+    /// use pistol::Target;
+    ///
+    /// fn test() {
+    ///     // 192.168.1.1 to 192.168.1.254
+    ///     let target = Target::from_range("192.168.1.1-254", Some(vec![22])).unwrap();
+    ///     // 10.0.0.1 to 10.0.3.10, 40 hosts
+    ///     let target = Target::from_range("10.0.0-3.1-10", Some(vec![22])).unwrap();
+    /// }
+    /// ```
+    pub fn from_range(range: &str, ports: Option<Vec<u16>>) -> Result<Target, PistolErrors> {
+        let invalid = || PistolErrors::InvalidIpRange {
+            range: range.to_string(),
+        };
+        let octets: Vec<&str> = range.trim().split('.').collect();
+        if octets.len() != 4 {
+            return Err(invalid());
+        }
+        let mut octet_ranges = Vec::new();
+        for octet in octets {
+            let (start, end) = match octet.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => (octet, octet),
+            };
+            let start: u8 = start.parse().map_err(|_| invalid())?;
+            let end: u8 = end.parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            octet_ranges.push(start..=end);
+        }
+        let hosts_num: usize = octet_ranges.iter().map(|r| r.clone().count()).product();
+        if hosts_num > 1 << (32 - IPV4_RANGE_MIN_EXPAND) {
+            return Err(PistolErrors::NetworkTooLarge {
+                network: range.to_string(),
+                min_prefix: IPV4_RANGE_MIN_EXPAND,
+            });
+        }
+        let mut hosts = Vec::with_capacity(hosts_num);
+        for a in octet_ranges[0].clone() {
+            for b in octet_ranges[1].clone() {
+                for c in octet_ranges[2].clone() {
+                    for d in octet_ranges[3].clone() {
+                        let addr = Ipv4Addr::new(a, b, c, d);
+                        hosts.push(Host::new(addr.into(), ports.clone()));
+                    }
+                }
+            }
+        }
+        Ok(Target::new(hosts).exclude_local_addrs())
+    }
+    /// Scan a IPv6 prefix with same ports, the prefix is too large to enumerate (a /64 has 2^64 addresses),
+    /// so the hosts are picked by the `strategy`.
+    /// The addresses of the local interfaces are left out, see `Target::include_local_addrs`.
+    /// ```rust
+    /// use pistol::Target;
+    /// use pistol::Ipv6PrefixStrategy;
+    ///
+    /// fn test() {
+    ///     // 2001:db8::1 to 2001:db8::10
+    ///     let target = Target::from_ipv6_prefix("2001:db8::/64", Ipv6PrefixStrategy::LowBytes(16), Some(vec![22])).unwrap();
+    /// }
+    /// ```
+    pub fn from_ipv6_prefix(
+        prefix: &str,
+        strategy: Ipv6PrefixStrategy,
+        ports: Option<Vec<u16>>,
+    ) -> Result<Target, PistolErrors> {
+        let network = Ipv6Network::from_str(prefix)?;
+        let base = u128::from(network.network());
+        let host_mask = !u128::from(network.mask());
+        let suffixes: Vec<u128> = match strategy {
+            Ipv6PrefixStrategy::LowBytes(n) => {
+                // no more hosts than `All` expands
+                if n as u128 > 1 << (128 - IPV6_PREFIX_MIN_EXPAND) {
+                    return Err(PistolErrors::NetworkTooLarge {
+                        network: network.to_string(),
+                        min_prefix: IPV6_PREFIX_MIN_EXPAND,
+                    });
+                }
+                (1..=n as u128).take_while(|s| *s <= host_mask).collect()
+            }
+            Ipv6PrefixStrategy::KnownSuffixes => KNOWN_IPV6_SUFFIXES
+                .iter()
+                .map(|s| *s as u128)
+                .filter(|s| s & !host_mask == 0)
+                .collect(),
+            Ipv6PrefixStrategy::Explicit(suffixes) => suffixes
+                .into_iter()
+                .filter(|s| s & !host_mask == 0)
+                .collect(),
+            Ipv6PrefixStrategy::All => {
+                if network.prefix() < IPV6_PREFIX_MIN_EXPAND {
+                    return Err(PistolErrors::NetworkTooLarge {
+                        network: network.to_string(),
+                        min_prefix: IPV6_PREFIX_MIN_EXPAND,
+                    });
+                }
+                (0..=host_mask).collect()
+            }
+        };
+        let hosts = suffixes
+            .into_iter()
+            .map(|s| Host::new(Ipv6Addr::from(base | s).into(), ports.clone()))
+            .collect();
+        Ok(Target::new(hosts).exclude_local_addrs())
+    }
+    /// Iterate over all the hosts (both IPv4 and IPv6) that will be scanned, with their ports.
+    /// ```rust
+    /// use pistol::Target;
+    ///
+    /// fn test() {
+    ///     let target = Target::from_subnet("192.168.1.0/24", Some(vec![22])).unwrap();
+    ///     for host in target.iter() {
+    ///         println!("{} {:?}", host.addr, host.ports);
+    ///     }
+    /// }
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = TargetHost<'_>> {
+        self.hosts.iter().map(|h| TargetHost {
+            addr: h.addr,
+            ports: &h.ports,
+        })
+    }
+    /// Append one host (IPv4 or IPv6) to the target.
+    /// ```rust
+    /// use pistol::Target;
+    /// use pistol::Host;
+    /// use std::net::Ipv4Addr;
+    ///
+    /// fn test() {
+    ///     let mut target = Target::new(vec![]);
+    ///     for i in 1..10 {
+    ///         let host = Host::new(Ipv4Addr::new(192, 168, 1, i).into(), Some(vec![22]));
+    ///         target.push(host);
+    ///     }
+    /// }
+    /// ```
+    pub fn push(&mut self, host: Host) {
+        self.hosts.push(host);
+    }
+    /// Probing the scanner itself only shows the local stack (e.g. the own address is always up),
+    /// so the expanded targets leave the addresses of the local interfaces out.
+    fn exclude_local_addrs(mut self) -> Target {
+        let local_addrs = utils::local_addrs();
+        let mut hosts = Vec::new();
+        for (i, host) in self.hosts.into_iter().enumerate() {
+            if local_addrs.contains(&host.addr) {
+                self.excluded.push((i, host));
+            } else {
+                hosts.push(host);
+            }
+        }
+        self.hosts = hosts;
+        self
+    }
+    /// Put the addresses of the local interfaces left out of the expanded target back to their places.
+    /// ```rust
+    /// use pistol::Target;
+    ///
+    /// fn test() {
+    ///     let mut target = Target::from_subnet("192.168.1.0/24", Some(vec![22])).unwrap();
+    ///     target.include_local_addrs();
+    /// }
+    /// ```
+    pub fn include_local_addrs(&mut self) {
+        // the positions are ascending, so every host goes back to its original position
+        for (i, host) in self.excluded.drain(..) {
+            let i = i.min(self.hosts.len());
+            self.hosts.insert(i, host);
+        }
+    }
+    /// Append one hostname to the target, it is resolved by `Target::resolve_all`.
+    pub fn push_hostname(&mut self, hostname: &str, ports: Option<Vec<u16>>) {
+        let ports = ports.unwrap_or_default();
+        self.hostnames.push((hostname.to_string(), ports));
+    }
+    /// Resolve the hostnames not resolved yet and add the hosts of their addresses.
+    /// Every scan phase calls it, so resolve the target once before the phases (and clone it for each of them)
+    /// to send the dns queries only once and keep the same addresses even if the dns records change mid-scan.
+    /// ```rust
+    /// use pistol::Target;
+    /// use std::time::Duration;
+    ///
+    /// fn test() {
+    ///     let mut target = Target::new(vec![]);
+    ///     target.push_hostname("scanme.nmap.org", Some(vec![22, 80]));
+    ///     target.resolve_all(Some(Duration::from_secs(3))).unwrap();
+    ///     println!("{:?}", target.resolved("scanme.nmap.org"));
+    /// }
+    /// ```
+    pub fn resolve_all(&mut self, timeout: Option<Duration>) -> Result<(), PistolErrors> {
+        self.resolve_all_with(&SystemResolver, timeout)
+    }
+    /// Same as `Target::resolve_all` but the dns queries go to the resolver.
+    /// The hostname which can not be resolved is skipped with a warning (like nmap),
+    /// it is recorded without any address and not queried again.
+    /// ```rust
+    /// use pistol::DnsServerResolver;
+    /// use pistol::Target;
+    /// use std::time::Duration;
+    ///
+    /// fn test() {
+    ///     let resolver = DnsServerResolver::new("9.9.9.9:53".parse().unwrap());
+    ///     let mut target = Target::new(vec![]);
+    ///     target.push_hostname("scanme.nmap.org", Some(vec![22, 80]));
+    ///     target.resolve_all_with(&resolver, Some(Duration::from_secs(3))).unwrap();
+    /// }
+    /// ```
+    pub fn resolve_all_with(
+        &mut self,
+        resolver: &dyn Resolver,
+        timeout: Option<Duration>,
+    ) -> Result<(), PistolErrors> {
+        for (hostname, ports) in &self.hostnames {
+            if self.resolved.contains_key(hostname) {
+                continue;
+            }
+            let lookup_addrs = match resolver.lookup(hostname, timeout) {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("failed to resolve {}, skip it: {}", hostname, e);
+                    self.resolved.insert(hostname.clone(), Vec::new());
+                    continue;
+                }
+            };
+            let mut addrs = Vec::new();
+            for addr in lookup_addrs {
+                // the system resolver returns one address for every socket type
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+            for addr in &addrs {
+                if !self.hosts.iter().any(|h| h.addr == *addr) {
+                    self.hosts.push(Host::new(*addr, Some(ports.clone())));
+                }
+            }
+            self.resolved.insert(hostname.clone(), addrs);
+        }
+        Ok(())
+    }
+    /// The reverse dns names of the hosts, the hosts without a name are left out.
+    pub fn reverse_all(
+        &self,
+        resolver: &dyn Resolver,
+        timeout: Option<Duration>,
+    ) -> Result<HashMap<IpAddr, String>, PistolErrors> {
+        let mut names = HashMap::new();
+        for host in &self.hosts {
+            if let Some(name) = resolver.reverse(host.addr, timeout)? {
+                names.insert(host.addr, name);
+            }
+        }
+        Ok(names)
+    }
+    /// The addresses of the hostname, None if it is not resolved yet.
+    pub fn resolved(&self, hostname: &str) -> Option<&[IpAddr]> {
+        self.resolved.get(hostname).map(|a| a.as_slice())
+    }
+    /// The number of hosts (both IPv4 and IPv6) in the target.
+    pub fn len(&self) -> usize {
+        self.hosts.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+/// The smallest ipv6 prefix `Ipv6PrefixStrategy::All` expands (65536 addresses).
+const IPV6_PREFIX_MIN_EXPAND: u8 = 112;
+
+/// `Target::from_range` expands at most as many addresses as this ipv4 prefix has (65536 addresses).
+const IPV4_RANGE_MIN_EXPAND: u8 = 16;
+
+/// The interface identifiers often used by the manually configured hosts,
+/// the low addresses, the service ports written in hex digits and the wordy ones.
+const KNOWN_IPV6_SUFFIXES: &[u64] = &[
+    0x1,
+    0x2,
+    0x3,
+    0x4,
+    0x5,
+    0x10,
+    0x11,
+    0x20,
+    0x21,
+    0x22,
+    0x25,
+    0x53,
+    0x80,
+    0x100,
+    0x101,
+    0x443,
+    0x1000,
+    0x8080,
+    0xcafe,
+    0xbeef,
+    0xffff,
+    0x1_0001,
+    0xdead_beef,
+    0xface_b00c,
+    // the EUI-64 identifiers of the VRRP virtual macs 00:00:5e:00:01:01 and 00:00:5e:00:01:02
+    0x0200_5eff_fe00_0101,
+    0x0200_5eff_fe00_0102,
+];
+
+/// How `Target::from_ipv6_prefix` picks the hosts in the prefix.
+/// The suffixes outside the host part of the prefix are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ipv6PrefixStrategy {
+    /// The first `n` addresses after the network address, `::1` to `::n`, at most 65536 like `All`.
+    LowBytes(u32),
+    /// The common low and well-known interface identifiers.
+    KnownSuffixes,
+    /// The given interface identifiers.
+    Explicit(Vec<u128>),
+    /// Every address of the prefix, only for the prefix of at least /112.
+    All,
+}
+
+/// Append many hosts to the target.
+impl Extend<Host> for Target {
+    fn extend<I: IntoIterator<Item = Host>>(&mut self, hosts: I) {
+        self.hosts.extend(hosts);
+    }
+}
+
+/// The view of one host in the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetHost<'a> {
+    pub addr: IpAddr,
+    pub ports: &'a [u16],
+}
+
+impl TargetHost<'_> {
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
 }
 
 /* Scan */
 
+pub use layers::ProbeOptions;
 pub use scan::arp_scan;
 pub use scan::arp_scan_raw;
+pub use scan::os_hint;
+pub use scan::port_sweep;
+pub use scan::proxy_connect_scan_raw;
+pub use scan::resume_scan;
 pub use scan::scan;
 pub use scan::scan_raw;
+pub use scan::scan_with;
+pub use scan::scan_with_options;
+pub use scan::socks5::Socks5Proxy;
 pub use scan::tcp_ack_scan;
 pub use scan::tcp_ack_scan_raw;
 pub use scan::tcp_connect_scan;
@@ -247,18 +638,25 @@ pub use scan::tcp_xmas_scan;
 pub use scan::tcp_xmas_scan_raw;
 pub use scan::udp_scan;
 pub use scan::udp_scan_raw;
+pub use scan::ScanOptions;
 
 /* Ping */
 
 pub use ping::icmp_ping;
 pub use ping::icmp_ping_raw;
+pub use ping::icmp_ping_raw_with_options;
 pub use ping::ping;
+pub use ping::ping_each_source;
+pub use ping::ping_with_options;
 pub use ping::tcp_ack_ping;
 pub use ping::tcp_ack_ping_raw;
 pub use ping::tcp_syn_ping;
 pub use ping::tcp_syn_ping_raw;
 pub use ping::udp_ping;
 pub use ping::udp_ping_raw;
+pub use ping::udp_ping_raw_with_options;
+pub use ping::IcmpAcceptSet;
+pub use ping::PingOptions;
 
 /* Flood */
 
@@ -279,8 +677,260 @@ pub use flood::udp_flood_raw;
 
 pub use os::os_detect;
 pub use os::os_detect_raw;
+pub use vs::fingerprint_unknown;
+pub use vs::identify_service;
 pub use vs::vs_scan;
+pub use vs::vs_scan_banner_only;
 pub use vs::vs_scan_raw;
+pub use vs::vs_scan_raw_with_options;
+pub use vs::vs_scan_with_options;
+pub use vs::vscan::TLS_SERVICE;
+pub use vs::NullProbeRead;
+pub use vs::VsOptions;
 
 /* DNS */
 pub use layers::dns_query;
+pub use resolver::DnsServerResolver;
+pub use resolver::Resolver;
+pub use resolver::SystemResolver;
+
+/* Timeout */
+pub use utils::get_default_timeout_for;
+
+/* Clock */
+pub use utils::with_clock;
+pub use utils::Clock;
+pub use utils::MockClock;
+pub use utils::SystemClock;
+
+/* Timing */
+pub use utils::TimingTemplate;
+
+/* Source Address */
+
+/* Self Test */
+pub use utils::self_test;
+pub use utils::SelfTestReport;
+
+/* Local Listeners */
+pub use utils::local_listeners;
+pub use utils::Listener;
+pub use utils::ListenerProtocol;
+
+/* System Net Cache */
+pub use route::detect_mac_conflicts;
+pub use route::DefaultRouteSummary;
+pub use route::MacConflict;
+pub use route::NeighborChange;
+pub use route::NeighborKey;
+pub use route::NetCacheDiff;
+pub use route::SystemNetCache;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_target_iter() {
+        let host1 = Host::new(Ipv4Addr::new(192, 168, 1, 1).into(), Some(vec![22, 23]));
+        let host2 = Host::new(Ipv6Addr::LOCALHOST.into(), Some(vec![80]));
+        let mut target = Target::from_subnet("192.168.2.0/30", Some(vec![22])).unwrap();
+        target.hosts.push(host1);
+        target.hosts.push(host2);
+        let ipv4_num = target.iter().filter(|h| h.is_ipv4()).count();
+        let ipv6_num = target.iter().filter(|h| h.is_ipv6()).count();
+        assert_eq!(ipv6_num, 1);
+        assert_eq!(target.len(), ipv4_num + ipv6_num);
+        let last = target.iter().last().unwrap();
+        assert_eq!(last.addr, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(last.ports, &[80]);
+    }
+    #[test]
+    fn test_target_from_ipv6_prefix() {
+        let target =
+            Target::from_ipv6_prefix("2001:db8::/64", Ipv6PrefixStrategy::LowBytes(10), None)
+                .unwrap();
+        assert_eq!(target.len(), 10);
+        let first: IpAddr = "2001:db8::1".parse().unwrap();
+        let last: IpAddr = "2001:db8::a".parse().unwrap();
+        assert_eq!(target.hosts[0].addr, first);
+        assert_eq!(target.hosts[9].addr, last);
+
+        let target =
+            Target::from_ipv6_prefix("2001:db8::/120", Ipv6PrefixStrategy::KnownSuffixes, None)
+                .unwrap();
+        let addrs: Vec<String> = target.iter().map(|h| h.addr.to_string()).collect();
+        assert!(addrs.contains(&String::from("2001:db8::1")));
+        assert!(!addrs.contains(&String::from("2001:db8::dead:beef")));
+        let suffixes = vec![0x1, 0xdead_beef];
+        let target = Target::from_ipv6_prefix(
+            "2001:db8::/120",
+            Ipv6PrefixStrategy::Explicit(suffixes),
+            None,
+        )
+        .unwrap();
+        assert_eq!(target.len(), 1);
+
+        let ret = Target::from_ipv6_prefix("2001:db8::/64", Ipv6PrefixStrategy::All, None);
+        assert!(matches!(ret, Err(PistolErrors::NetworkTooLarge { .. })));
+        let target =
+            Target::from_ipv6_prefix("2001:db8::/126", Ipv6PrefixStrategy::All, None).unwrap();
+        assert_eq!(target.len(), 4);
+        let ret = Target::from_ipv6_prefix(
+            "2001:db8::/64",
+            Ipv6PrefixStrategy::LowBytes(u32::MAX),
+            None,
+        );
+        assert!(matches!(ret, Err(PistolErrors::NetworkTooLarge { .. })));
+    }
+    #[test]
+    fn test_target_from_range() {
+        let target = Target::from_range("192.168.1.1-10", Some(vec![22])).unwrap();
+        assert_eq!(target.len(), 10);
+        assert_eq!(
+            target.hosts[0].addr,
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(
+            target.hosts[9].addr,
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10))
+        );
+        assert_eq!(target.hosts[0].ports, vec![22]);
+
+        let target = Target::from_range("10.0.0-3.1-10", None).unwrap();
+        assert_eq!(target.len(), 40);
+        assert_eq!(
+            target.hosts[10].addr,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1))
+        );
+        assert_eq!(
+            target.hosts[39].addr,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 3, 10))
+        );
+
+        let target = Target::from_range("10.0.0.1", None).unwrap();
+        assert_eq!(target.len(), 1);
+
+        for range in [
+            "192.168.1",
+            "192.168.1.10-1",
+            "192.168.1.1-256",
+            "192.168.1.-5",
+        ] {
+            let ret = Target::from_range(range, None);
+            assert!(matches!(ret, Err(PistolErrors::InvalidIpRange { .. })));
+        }
+        let target = Target::from_range("10.0.0-255.0-255", None).unwrap();
+        assert_eq!(target.len() + target.excluded.len(), 65536);
+        for range in ["10.0-255.0-255.0-255", "0-255.0-255.0-255.0-255"] {
+            let ret = Target::from_range(range, None);
+            assert!(matches!(ret, Err(PistolErrors::NetworkTooLarge { .. })));
+        }
+    }
+    /// Answers `localhost` only and counts the queries.
+    struct CountingResolver {
+        queries: std::cell::Cell<usize>,
+    }
+    impl Resolver for CountingResolver {
+        fn lookup(
+            &self,
+            hostname: &str,
+            _timeout: Option<Duration>,
+        ) -> Result<Vec<IpAddr>, PistolErrors> {
+            self.queries.set(self.queries.get() + 1);
+            match hostname {
+                "localhost" => Ok(vec![Ipv4Addr::LOCALHOST.into()]),
+                _ => Err(PistolErrors::DnsQueryTimeout {
+                    hostname: hostname.to_string(),
+                }),
+            }
+        }
+        fn reverse(
+            &self,
+            _addr: IpAddr,
+            _timeout: Option<Duration>,
+        ) -> Result<Option<String>, PistolErrors> {
+            Ok(None)
+        }
+    }
+    #[test]
+    fn test_target_resolve_all() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut target = Target::new(vec![]);
+        target.push_hostname("localhost", Some(vec![port]));
+        target.push_hostname("unknown.invalid", Some(vec![port]));
+        assert_eq!(target.resolved("localhost"), None);
+        let resolver = CountingResolver {
+            queries: std::cell::Cell::new(0),
+        };
+        let timeout = Some(Duration::new(1, 0));
+        // the unresolvable hostname does not stop the others
+        target.resolve_all_with(&resolver, timeout).unwrap();
+        assert_eq!(resolver.queries.get(), 2);
+        let localhost: IpAddr = Ipv4Addr::LOCALHOST.into();
+        assert_eq!(target.resolved("localhost"), Some(&[localhost][..]));
+        assert_eq!(target.resolved("unknown.invalid"), Some(&[][..]));
+        let hosts = target.len();
+        assert_eq!(hosts, 1);
+
+        // the ping and the scan phases reuse the resolved addresses
+        let ret = ping::tcp_syn_ping(target.clone(), None, None, timeout, 1).unwrap();
+        assert!(ret.is_alive(&localhost, None));
+        let ret = scan::tcp_syn_scan(target.clone(), None, None, timeout, 1).unwrap();
+        assert_eq!(
+            ret.scans[&localhost][&port][0].port_status,
+            scan::PortStatus::Open
+        );
+        target.resolve_all_with(&resolver, timeout).unwrap();
+        assert_eq!(resolver.queries.get(), 2);
+        assert_eq!(target.len(), hosts);
+    }
+    #[test]
+    fn test_target_exclude_local_addrs() {
+        let local_ipv4 = utils::local_addrs().into_iter().find(|a| a.is_ipv4());
+        let local_ipv4 = match local_ipv4 {
+            Some(IpAddr::V4(a)) => a,
+            _ => {
+                println!("skip the test without the ipv4 interface address");
+                return;
+            }
+        };
+        let o = local_ipv4.octets();
+        let subnet = format!("{}.{}.{}.0/24", o[0], o[1], o[2]);
+        let local_addr: IpAddr = local_ipv4.into();
+        let mut target = Target::from_subnet(&subnet, None).unwrap();
+        assert!(target.iter().all(|h| h.addr != local_addr));
+        let range = format!("{}.{}.{}.0-255", o[0], o[1], o[2]);
+        let range_target = Target::from_range(&range, None).unwrap();
+        assert_eq!(range_target.len(), 255);
+        assert!(range_target.iter().all(|h| h.addr != local_addr));
+
+        let len = target.len();
+        target.include_local_addrs();
+        assert_eq!(target.len(), len + 1);
+        let addrs: Vec<IpAddr> = target.iter().map(|h| h.addr).collect();
+        assert!(addrs.contains(&local_addr));
+        assert!(addrs.windows(2).all(|w| w[0] < w[1]));
+
+        // the hosts given one by one are kept
+        let target = Target::new(vec![Host::new(local_addr, None)]);
+        assert_eq!(target.len(), 1);
+    }
+    #[test]
+    fn test_target_push() {
+        let mut target = Target::new(vec![]);
+        assert!(target.is_empty());
+        for i in 1..=4 {
+            target.push(Host::new(Ipv4Addr::new(192, 168, 1, i).into(), None));
+        }
+        target.push(Host::new(Ipv6Addr::LOCALHOST.into(), Some(vec![80])));
+        let hosts6 = (1..=3).map(|i| {
+            let addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, i);
+            Host::new(addr.into(), Some(vec![22]))
+        });
+        target.extend(hosts6);
+        assert_eq!(target.len(), 8);
+        assert_eq!(target.iter().filter(|h| h.is_ipv4()).count(), 4);
+        assert_eq!(target.iter().filter(|h| h.is_ipv6()).count(), 4);
+    }
+}
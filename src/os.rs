@@ -322,11 +322,12 @@ fn get_nmap_os_db() -> Result<Vec<NmapOSDB>, PistolErrors> {
 
 /// Detect target machine OS on IPv4 and IPv6.
 pub fn os_detect(
-    target: Target,
+    mut target: Target,
     src_addr: Option<IpAddr>,
     top_k: usize,
     timeout: Option<Duration>,
 ) -> Result<OSDetectResults, PistolErrors> {
+    target.resolve_all(timeout)?;
     let threads_num = target.hosts.len();
     let timeout = match timeout {
         Some(t) => t,
@@ -0,0 +1,193 @@
+// The well-known udp payloads, most of them are the same as the `nmap-payloads` file of nmap.
+// Many udp services only answer a protocol valid request, so an empty datagram gets nothing back.
+
+/// DNS standard query of the root NS records.
+/// Nmap sends a DNS server status request (opcode 2) here,
+/// but many resolvers silently drop it, while any resolver answers a standard query.
+pub const DNS_QUERY: &[u8] = &[
+    0x50, 0x49, // id
+    0x01, 0x00, // flags: recursion desired
+    0x00, 0x01, // questions
+    0x00, 0x00, // answer rrs
+    0x00, 0x00, // authority rrs
+    0x00, 0x00, // additional rrs
+    0x00, // name: <root>
+    0x00, 0x02, // type: NS
+    0x00, 0x01, // class: IN
+];
+
+/// NTP version 4 client request.
+pub const NTP_REQUEST: &[u8] = &[
+    0xe3, 0x00, 0x04, 0xfa, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc5, 0x4f, 0x23, 0x4b, 0x71, 0xb1, 0x52, 0xf3,
+];
+
+/// NetBIOS node status request of the wildcard name "*".
+pub const NETBIOS_NAME_QUERY: &[u8] = b"\x80\xf0\x00\x10\x00\x01\x00\x00\x00\x00\x00\x00\
+\x20CKAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\x00\x00\x21\x00\x01";
+
+/// SNMPv1 GetRequest of sysName.0 with the community "public".
+pub const SNMP_GET: &[u8] = b"\x30\x82\x00\x2f\x02\x01\x00\x04\x06public\
+\xa0\x82\x00\x20\x02\x04\x4c\x33\xa7\x56\x02\x01\x00\x02\x01\x00\
+\x30\x82\x00\x10\x30\x82\x00\x0c\x06\x08\x2b\x06\x01\x02\x01\x01\x05\x00\x05\x00";
+
+/// IKEv1 main mode with 4 transforms (3DES/DES with SHA/MD5, PSK, group 2).
+pub const IKE_MAIN_MODE: &[u8] = &[
+    // initiator cookie and responder cookie
+    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // next payload SA, version 1.0, main mode, flags, message id, length 192
+    0x01, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0,
+    // SA payload, length 164, DOI IPSEC, situation identity only
+    0x00, 0x00, 0x00, 0xa4, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    // proposal 1, length 152, ISAKMP, 4 transforms
+    0x00, 0x00, 0x00, 0x98, 0x01, 0x01, 0x00, 0x04,
+    // transform 1, 3DES-CBC, SHA, PSK, group 2
+    0x03, 0x00, 0x00, 0x24, 0x01, 0x01, 0x00, 0x00, 0x80, 0x01, 0x00, 0x05, 0x80, 0x02, 0x00, 0x02,
+    0x80, 0x03, 0x00, 0x01, 0x80, 0x04, 0x00, 0x02, 0x80, 0x0b, 0x00, 0x01, 0x00, 0x0c, 0x00, 0x04,
+    0x00, 0x00, 0x00, 0x01, // transform 2, 3DES-CBC, MD5, PSK, group 2
+    0x03, 0x00, 0x00, 0x24, 0x02, 0x01, 0x00, 0x00, 0x80, 0x01, 0x00, 0x05, 0x80, 0x02, 0x00, 0x01,
+    0x80, 0x03, 0x00, 0x01, 0x80, 0x04, 0x00, 0x02, 0x80, 0x0b, 0x00, 0x01, 0x00, 0x0c, 0x00, 0x04,
+    0x00, 0x00, 0x00, 0x01, // transform 3, DES-CBC, SHA, PSK, group 2
+    0x03, 0x00, 0x00, 0x24, 0x03, 0x01, 0x00, 0x00, 0x80, 0x01, 0x00, 0x01, 0x80, 0x02, 0x00, 0x02,
+    0x80, 0x03, 0x00, 0x01, 0x80, 0x04, 0x00, 0x02, 0x80, 0x0b, 0x00, 0x01, 0x00, 0x0c, 0x00, 0x04,
+    0x00, 0x00, 0x00, 0x01, // transform 4, DES-CBC, MD5, PSK, group 2
+    0x00, 0x00, 0x00, 0x24, 0x04, 0x01, 0x00, 0x00, 0x80, 0x01, 0x00, 0x01, 0x80, 0x02, 0x00, 0x01,
+    0x80, 0x03, 0x00, 0x01, 0x80, 0x04, 0x00, 0x02, 0x80, 0x0b, 0x00, 0x01, 0x00, 0x0c, 0x00, 0x04,
+    0x00, 0x00, 0x00, 0x01,
+];
+
+/// mDNS query of the PTR records of _services._dns-sd._udp.local.
+pub const MDNS_QUERY: &[u8] = b"\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+\x09_services\x07_dns-sd\x04_udp\x05local\x00\x00\x0c\x00\x01";
+
+/// The payload of the well-known udp port.
+pub fn for_port(port: u16) -> Option<&'static [u8]> {
+    match port {
+        53 => Some(DNS_QUERY),
+        123 => Some(NTP_REQUEST),
+        137 => Some(NETBIOS_NAME_QUERY),
+        161 => Some(SNMP_GET),
+        500 => Some(IKE_MAIN_MODE),
+        5353 => Some(MDNS_QUERY),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn u16_at(payload: &[u8], offset: usize) -> u16 {
+        u16::from_be_bytes([payload[offset], payload[offset + 1]])
+    }
+    fn u32_at(payload: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes([
+            payload[offset],
+            payload[offset + 1],
+            payload[offset + 2],
+            payload[offset + 3],
+        ])
+    }
+    /// Returns the labels of the name and the offset after it.
+    fn dns_name(payload: &[u8], mut offset: usize) -> (Vec<String>, usize) {
+        let mut labels = Vec::new();
+        loop {
+            let len = payload[offset] as usize;
+            offset += 1;
+            if len == 0 {
+                return (labels, offset);
+            }
+            labels.push(String::from_utf8(payload[offset..offset + len].to_vec()).unwrap());
+            offset += len;
+        }
+    }
+    /// BER tag and definite length, returns the offset of the value.
+    fn ber_header(payload: &[u8], offset: usize, tag: u8) -> (usize, usize) {
+        assert_eq!(payload[offset], tag);
+        match payload[offset + 1] {
+            0x82 => (u16_at(payload, offset + 2) as usize, offset + 4),
+            len => (len as usize, offset + 2),
+        }
+    }
+    #[test]
+    fn test_dns_query() {
+        let payload = for_port(53).unwrap();
+        assert_eq!(u16_at(payload, 4), 1); // questions
+        let (labels, offset) = dns_name(payload, 12);
+        assert!(labels.is_empty());
+        assert_eq!(u16_at(payload, offset), 2); // NS
+        assert_eq!(u16_at(payload, offset + 2), 1); // IN
+        assert_eq!(payload.len(), offset + 4);
+    }
+    #[test]
+    fn test_mdns_query() {
+        let payload = for_port(5353).unwrap();
+        assert_eq!(u16_at(payload, 4), 1);
+        let (labels, offset) = dns_name(payload, 12);
+        assert_eq!(labels, vec!["_services", "_dns-sd", "_udp", "local"]);
+        assert_eq!(u16_at(payload, offset), 12); // PTR
+        assert_eq!(payload.len(), offset + 4);
+    }
+    #[test]
+    fn test_ntp_request() {
+        let payload = for_port(123).unwrap();
+        assert_eq!(payload.len(), 48);
+        assert_eq!((payload[0] >> 3) & 0b111, 4); // version
+        assert_eq!(payload[0] & 0b111, 3); // client mode
+    }
+    #[test]
+    fn test_netbios_name_query() {
+        let payload = for_port(137).unwrap();
+        assert_eq!(u16_at(payload, 4), 1);
+        let (labels, offset) = dns_name(payload, 12);
+        // first level encoding, every half byte is added to 'A'
+        let name: Vec<u8> = labels[0]
+            .as_bytes()
+            .chunks(2)
+            .map(|c| ((c[0] - b'A') << 4) | (c[1] - b'A'))
+            .collect();
+        assert_eq!(name[0], b'*');
+        assert!(name[1..].iter().all(|b| *b == 0));
+        assert_eq!(u16_at(payload, offset), 0x21); // NBSTAT
+        assert_eq!(payload.len(), offset + 4);
+    }
+    #[test]
+    fn test_snmp_get() {
+        let payload = for_port(161).unwrap();
+        let (len, offset) = ber_header(payload, 0, 0x30);
+        assert_eq!(payload.len(), offset + len);
+        // version 1
+        let (len, offset) = ber_header(payload, offset, 0x02);
+        assert_eq!(&payload[offset..offset + len], &[0x00]);
+        let (len, offset) = ber_header(payload, offset + len, 0x04);
+        assert_eq!(&payload[offset..offset + len], b"public");
+        // GetRequest PDU
+        let (len, offset) = ber_header(payload, offset + len, 0xa0);
+        assert_eq!(payload.len(), offset + len);
+        // sysName.0
+        let oid = [0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x05, 0x00];
+        assert!(payload.windows(oid.len()).any(|w| w == oid));
+    }
+    #[test]
+    fn test_ike_main_mode() {
+        let payload = for_port(500).unwrap();
+        assert_eq!(u32_at(payload, 24) as usize, payload.len());
+        assert_eq!(payload[17], 0x10); // version 1.0
+        assert_eq!(payload[18], 2); // main mode
+                                    // SA payload
+        assert_eq!(u16_at(payload, 30) as usize, payload.len() - 28);
+        // proposal
+        let transforms = payload[47] as usize;
+        assert_eq!(u16_at(payload, 42) as usize, 8 + transforms * 36);
+        for i in 0..transforms {
+            let offset = 48 + i * 36;
+            let next_payload = if i + 1 == transforms { 0 } else { 3 };
+            assert_eq!(payload[offset], next_payload);
+            assert_eq!(u16_at(payload, offset + 2), 36);
+        }
+    }
+    #[test]
+    fn test_for_port_unknown() {
+        assert_eq!(for_port(125), None);
+    }
+}
@@ -3,13 +3,27 @@ use prettytable::row;
 use prettytable::Cell;
 use prettytable::Row;
 use prettytable::Table;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::ops::RangeInclusive;
 use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -17,41 +31,104 @@ pub mod icmp;
 pub mod icmpv6;
 
 use crate::errors::PistolErrors;
+use crate::payloads;
+use crate::scan::loopback_scan;
 use crate::scan::tcp;
 use crate::scan::tcp6;
 use crate::scan::udp;
 use crate::scan::udp6;
 use crate::scan::PortStatus;
+use crate::scan::ScanMethod;
+use crate::utils::clock_elapsed;
+use crate::utils::clock_now;
+use crate::utils::dispatch_probes;
 use crate::utils::find_source_addr;
-use crate::utils::find_source_addr6;
+use crate::utils::find_source_addr6_with;
+use crate::utils::get_cpu_num;
 use crate::utils::get_default_timeout;
+use crate::utils::get_default_timeout_by_src;
 use crate::utils::get_threads_pool;
+use crate::utils::local_addrs;
 use crate::utils::random_port;
+use crate::utils::random_port_in;
+use crate::utils::source_candidates;
+use crate::utils::Clock;
+use crate::utils::ProbeJob;
+use crate::utils::TimingTemplate;
+use crate::Host;
 use crate::Target;
 
-const SYN_PING_DEFAULT_PORT: u16 = 80;
+pub(crate) const SYN_PING_DEFAULT_PORT: u16 = 80;
 const ACK_PING_DEFAULT_PORT: u16 = 80;
 const UDP_PING_DEFAULT_PORT: u16 = 125;
+const PING_RESULTS_SHARDS: usize = 16;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PingStatus {
     Up,
     Down,
+    /// No response to the probe, e.g. the ACK dropped by a stateful firewall.
+    Filtered,
     Error,
 }
 
-#[derive(Debug, Clone)]
+/// The icmp (type, code) pairs of the replies which mean the host is up, for the icmp ping of the ipv4 hosts.
+/// The default is the echo reply only. With the time exceeded or the unreachable messages in the set,
+/// the replies of the other devices on the path are also accepted if they are about the probe of the host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IcmpAcceptSet {
+    pub pairs: HashSet<(u8, u8)>,
+}
+
+impl Default for IcmpAcceptSet {
+    fn default() -> Self {
+        // echo reply
+        IcmpAcceptSet::new(&[(0, 0)])
+    }
+}
+
+impl IcmpAcceptSet {
+    pub fn new(pairs: &[(u8, u8)]) -> IcmpAcceptSet {
+        IcmpAcceptSet {
+            pairs: pairs.iter().copied().collect(),
+        }
+    }
+    pub fn insert(&mut self, icmp_type: u8, icmp_code: u8) {
+        self.pairs.insert((icmp_type, icmp_code));
+    }
+    pub fn accepts(&self, icmp_type: u8, icmp_code: u8) -> bool {
+        self.pairs.contains(&(icmp_type, icmp_code))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HostPingResults {
     pub ping_status: PingStatus,
     pub ping_time_cost: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PingSummary {
+    pub total_hosts: usize,
+    pub alive: usize,
+    pub down: usize,
+    /// The hosts of which no test got any response.
+    pub filtered: usize,
+    pub error: usize,
+    pub avg_rtt: Duration,
+    pub min_rtt: Duration,
+    pub max_rtt: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PingResults {
     pub pings: HashMap<IpAddr, Vec<HostPingResults>>,
+    /// The source address used to probe each host.
+    pub source_addrs: HashMap<IpAddr, IpAddr>,
     pub avg_time_cost: f64,
     pub total_time_cost: f64,
     pub alive_hosts: usize,
+    #[serde(skip, default = "Instant::now")]
     start_time: Instant,
     tests: usize,
 }
@@ -60,6 +137,7 @@ impl PingResults {
     pub fn new() -> PingResults {
         PingResults {
             pings: HashMap::new(),
+            source_addrs: HashMap::new(),
             avg_time_cost: 0.0,
             alive_hosts: 0,
             start_time: Instant::now(),
@@ -67,6 +145,18 @@ impl PingResults {
             tests: 0,
         }
     }
+    /// Serialize to the compact binary format, e.g. to send the results of a worker process.
+    #[cfg(feature = "bincode")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PistolErrors> {
+        Ok(bincode::serialize(self)?)
+    }
+    /// Deserialize the results from `PingResults::to_bytes`.
+    #[cfg(feature = "bincode")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+    pub fn from_bytes(bytes: &[u8]) -> Result<PingResults, PistolErrors> {
+        Ok(bincode::deserialize(bytes)?)
+    }
     pub fn get_ping_status(&self, k: &IpAddr) -> Option<Vec<PingStatus>> {
         match self.pings.get(k) {
             Some(host_ping_status) => {
@@ -91,6 +181,114 @@ impl PingResults {
             None => None,
         }
     }
+    /// The results of the hosts in the order of `order` (e.g. the input file), unlike the display sorted by address.
+    /// The hosts not in `order` follow sorted by address, every host is yielded once.
+    pub fn iter_in_order(
+        &self,
+        order: &[IpAddr],
+    ) -> impl Iterator<Item = (IpAddr, &Vec<HostPingResults>)> + '_ {
+        let mut seen = HashSet::new();
+        let mut ret = Vec::new();
+        for ip in order {
+            if let Some(hpr) = self.pings.get(ip) {
+                if seen.insert(*ip) {
+                    ret.push((*ip, hpr));
+                }
+            }
+        }
+        let mut rest: Vec<(IpAddr, &Vec<HostPingResults>)> = self
+            .pings
+            .iter()
+            .filter(|(ip, _)| !seen.contains(*ip))
+            .map(|(ip, hpr)| (*ip, hpr))
+            .collect();
+        rest.sort_by_key(|(ip, _)| *ip);
+        ret.extend(rest);
+        ret.into_iter()
+    }
+    /// The source address which was actually used to probe the host.
+    pub fn source_for(&self, k: &IpAddr) -> Option<IpAddr> {
+        self.source_addrs.get(k).copied()
+    }
+    /// Returns the fraction of tests in which the host was considered up.
+    pub fn reliability(&self, k: &IpAddr) -> Option<f32> {
+        match self.pings.get(k) {
+            Some(host_ping_status) => {
+                if host_ping_status.is_empty() {
+                    return Some(0.0);
+                }
+                let mut up_num = 0;
+                for hpr in host_ping_status {
+                    if hpr.ping_status == PingStatus::Up {
+                        up_num += 1;
+                    }
+                }
+                Some(up_num as f32 / host_ping_status.len() as f32)
+            }
+            None => None,
+        }
+    }
+    /// Without threshold, the host is alive as long as one of the tests returns up.
+    /// With threshold, the reliability of the host must reach it (e.g. 0.75 means 3 of 4 tests).
+    pub fn is_alive(&self, k: &IpAddr, threshold: Option<f32>) -> bool {
+        match self.reliability(k) {
+            Some(r) => match threshold {
+                Some(t) => r > 0.0 && r >= t,
+                None => r > 0.0,
+            },
+            None => false,
+        }
+    }
+    /// A host is counted as alive if any test returns up,
+    /// as error if every test failed with an error, and as down otherwise.
+    /// The rtt values only take the tests which actually cost time into account.
+    pub fn summary(&self) -> PingSummary {
+        let mut alive = 0;
+        let mut down = 0;
+        let mut filtered = 0;
+        let mut error = 0;
+        let mut rtts = Vec::new();
+        for hpr in self.pings.values() {
+            let mut up_num = 0;
+            let mut filtered_num = 0;
+            let mut error_num = 0;
+            for h in hpr {
+                match h.ping_status {
+                    PingStatus::Up => up_num += 1,
+                    PingStatus::Down => (),
+                    PingStatus::Filtered => filtered_num += 1,
+                    PingStatus::Error => error_num += 1,
+                }
+                if h.ping_time_cost != Duration::new(0, 0) {
+                    rtts.push(h.ping_time_cost);
+                }
+            }
+            if up_num > 0 {
+                alive += 1;
+            } else if error_num > 0 && error_num == hpr.len() {
+                error += 1;
+            } else if filtered_num > 0 && filtered_num + error_num == hpr.len() {
+                filtered += 1;
+            } else {
+                down += 1;
+            }
+        }
+        let avg_rtt = if rtts.is_empty() {
+            Duration::new(0, 0)
+        } else {
+            rtts.iter().sum::<Duration>() / rtts.len() as u32
+        };
+        PingSummary {
+            total_hosts: self.pings.len(),
+            alive,
+            down,
+            filtered,
+            error,
+            avg_rtt,
+            min_rtt: rtts.iter().min().copied().unwrap_or_default(),
+            max_rtt: rtts.iter().max().copied().unwrap_or_default(),
+        }
+    }
     pub fn enrichment(&mut self) {
         // avg time cost
         let mut total_cost = 0.0;
@@ -118,6 +316,7 @@ impl PingResults {
         self.alive_hosts = alive_hosts;
         self.total_time_cost = self.start_time.elapsed().as_secs_f64();
     }
+    #[cfg(test)]
     fn insert(&mut self, dst_addr: IpAddr, ping_status: PingStatus, ping_time_cost: Duration) {
         let hpr = HostPingResults {
             ping_status,
@@ -136,6 +335,194 @@ impl PingResults {
     }
 }
 
+/// The result of one host probed from one source address.
+#[derive(Debug, Clone)]
+pub struct SourcePingResults {
+    pub src_addr: IpAddr,
+    pub ping_status: PingStatus,
+    pub ping_time_cost: Duration,
+}
+
+/// The results of `ping_each_source`, the sources of every host are in the order they were tried.
+#[derive(Debug, Clone, Default)]
+pub struct MultiSourcePingResults {
+    pub pings: HashMap<IpAddr, Vec<SourcePingResults>>,
+}
+
+impl MultiSourcePingResults {
+    pub fn new() -> MultiSourcePingResults {
+        MultiSourcePingResults::default()
+    }
+    pub fn get(&self, k: &IpAddr) -> Option<&Vec<SourcePingResults>> {
+        self.pings.get(k)
+    }
+    /// The source addresses the host answered.
+    pub fn replied_sources(&self, k: &IpAddr) -> Vec<IpAddr> {
+        match self.pings.get(k) {
+            Some(spr) => spr
+                .iter()
+                .filter(|s| s.ping_status == PingStatus::Up)
+                .map(|s| s.src_addr)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// The host answered some of the sources but not all of them.
+    pub fn is_asymmetric(&self, k: &IpAddr) -> bool {
+        let replied = self.replied_sources(k).len();
+        match self.pings.get(k) {
+            Some(spr) => replied > 0 && replied < spr.len(),
+            None => false,
+        }
+    }
+}
+
+/// The results of one probe as it comes from the probe thread: the host, the result and the start time.
+type PingMessage = (
+    IpAddr,
+    Result<(PingStatus, Duration), PistolErrors>,
+    Instant,
+);
+
+/// The status and the time cost of the probe, the errors are turned into the error (or down) status.
+fn ping_message_result(
+    pr: Result<(PingStatus, Duration), PistolErrors>,
+    cost: Instant,
+) -> (PingStatus, Duration) {
+    let tc = cost.elapsed();
+    match pr {
+        Ok((ping_status, rtt)) => (ping_status, rtt),
+        Err(e) => match e {
+            PistolErrors::CanNotFoundMacAddress => (PingStatus::Down, tc),
+            _ => {
+                warn!("ping error: {}", e);
+                (PingStatus::Error, tc)
+            }
+        },
+    }
+}
+
+/// The ping results written by many drain threads at once,
+/// the hosts are sharded by the address so the threads rarely wait for each other.
+struct SharedPingResults {
+    shards: Vec<Mutex<HashMap<IpAddr, Vec<HostPingResults>>>>,
+}
+
+impl SharedPingResults {
+    fn new(shards_num: usize) -> SharedPingResults {
+        let shards_num = shards_num.max(1);
+        SharedPingResults {
+            shards: (0..shards_num)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+    fn shard(&self, dst_addr: &IpAddr) -> &Mutex<HashMap<IpAddr, Vec<HostPingResults>>> {
+        let mut hasher = DefaultHasher::new();
+        dst_addr.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+    fn insert(&self, dst_addr: IpAddr, ping_status: PingStatus, ping_time_cost: Duration) {
+        let hpr = HostPingResults {
+            ping_status,
+            ping_time_cost,
+        };
+        // a poisoned shard only means another drain thread panicked, the map itself is fine
+        let mut shard = match self.shard(&dst_addr).lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        shard.entry(dst_addr).or_default().push(hpr);
+    }
+    /// Merge the shards into the results, the shards hold disjoint hosts.
+    fn finalize(self, ping_results: &mut PingResults) {
+        for shard in self.shards {
+            let shard = match shard.into_inner() {
+                Ok(s) => s,
+                Err(e) => e.into_inner(),
+            };
+            for (dst_addr, hpr) in shard {
+                ping_results.pings.entry(dst_addr).or_default().extend(hpr);
+            }
+        }
+    }
+}
+
+/// Receive `recv_size` results with `drain_threads` threads,
+/// the per-result work runs in parallel and the results are merged into `ping_results` at the end.
+fn drain_ping_results(
+    rx: Receiver<PingMessage>,
+    recv_size: usize,
+    drain_threads: usize,
+    ping_results: &mut PingResults,
+) {
+    let shared = SharedPingResults::new(PING_RESULTS_SHARDS);
+    // the receiver and the number of the results still to be received
+    let rx = Mutex::new((rx, recv_size));
+    thread::scope(|s| {
+        for _ in 0..drain_threads.max(1) {
+            s.spawn(|| loop {
+                let msg = {
+                    let mut rx = match rx.lock() {
+                        Ok(r) => r,
+                        Err(e) => e.into_inner(),
+                    };
+                    if rx.1 == 0 {
+                        break;
+                    }
+                    rx.1 -= 1;
+                    match rx.0.recv() {
+                        Ok(m) => m,
+                        Err(_) => break,
+                    }
+                };
+                let (dst_addr, pr, cost) = msg;
+                let (ping_status, rtt) = ping_message_result(pr, cost);
+                shared.insert(dst_addr, ping_status, rtt);
+            });
+        }
+    });
+    shared.finalize(ping_results);
+}
+
+/// The tests of one host finish in thread completion order,
+/// so the status is shown as counts (e.g. `up (3 up, 1 down)`) to keep the output stable.
+fn status_counts_str(hpr: &[HostPingResults]) -> String {
+    let mut up_num = 0;
+    let mut down_num = 0;
+    let mut filtered_num = 0;
+    let mut error_num = 0;
+    for h in hpr {
+        match h.ping_status {
+            PingStatus::Up => up_num += 1,
+            PingStatus::Down => down_num += 1,
+            PingStatus::Filtered => filtered_num += 1,
+            PingStatus::Error => error_num += 1,
+        }
+    }
+    let mut counts = Vec::new();
+    if up_num > 0 {
+        counts.push(format!("{} up", up_num));
+    }
+    if down_num > 0 {
+        counts.push(format!("{} down", down_num));
+    }
+    if filtered_num > 0 {
+        counts.push(format!("{} filtered", filtered_num));
+    }
+    if error_num > 0 {
+        counts.push(format!("{} error", error_num));
+    }
+    let status = if up_num > 0 {
+        "up"
+    } else if filtered_num > 0 && down_num == 0 {
+        "filtered"
+    } else {
+        "down"
+    };
+    format!("{} ({})", status, counts.join(", "))
+}
+
 impl fmt::Display for PingResults {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut table = Table::new();
@@ -158,23 +545,10 @@ impl fmt::Display for PingResults {
             pings.into_iter().map(|(i, p)| (*i, p)).collect();
         for (i, (ip, hpr)) in pings.into_iter().enumerate() {
             let mut host_avg_time_cost = 0.0;
-            let mut host_up_num = 0;
-            // let mut host_down_num = 0;
-            // let mut host_error_num = 0;
             for h in hpr {
-                match h.ping_status {
-                    PingStatus::Up => host_up_num += 1,
-                    PingStatus::Down => (),  // host_down_num += 1,
-                    PingStatus::Error => (), //host_error_num += 1,
-                };
                 host_avg_time_cost += h.ping_time_cost.as_secs_f64();
             }
-
-            let status_str = if host_up_num > 0 {
-                String::from("up")
-            } else {
-                String::from("down")
-            };
+            let status_str = status_counts_str(hpr);
 
             let rtt_str = format!("{:.2}ms", host_avg_time_cost * 1000.0 / self.tests as f64);
             table.add_row(row![c -> (i + 1), c -> ip, c -> status_str, c -> rtt_str]);
@@ -183,11 +557,12 @@ impl fmt::Display for PingResults {
         let help_info = "NOTE:\nThe target host is considered alive\nas long as one of the packets returns\na result that is considered to be alive.";
         table.add_row(Row::new(vec![Cell::new(&help_info).with_hspan(4)]));
 
+        let summary = self.summary();
         let summary = format!(
             "total used time: {:.2}ms\navg time cost: {:.1}ms\nalive hosts: {}",
             self.total_time_cost * 1000.0,
-            self.avg_time_cost * 1000.0,
-            self.alive_hosts
+            summary.avg_rtt.as_secs_f64() * 1000.0,
+            summary.alive
         );
         table.add_row(Row::new(vec![Cell::new(&summary).with_hspan(4)]));
         write!(f, "{}", table)
@@ -200,6 +575,97 @@ pub enum PingMethods {
     Ack,
     Udp,
     Icmp,
+    Icmpv6,
+}
+
+impl PingMethods {
+    /// Returns the ICMP ping method matching the address family of `addr`.
+    pub fn icmp_for(addr: IpAddr) -> PingMethods {
+        match addr {
+            IpAddr::V4(_) => PingMethods::Icmp,
+            IpAddr::V6(_) => PingMethods::Icmpv6,
+        }
+    }
+    /// Whether this method can be used against `addr`.
+    /// TCP and UDP methods work with both families, ICMP is split into `Icmp` (IPv4) and `Icmpv6` (IPv6).
+    pub fn supports(&self, addr: &IpAddr) -> bool {
+        match self {
+            PingMethods::Icmp => addr.is_ipv4(),
+            PingMethods::Icmpv6 => addr.is_ipv6(),
+            _ => true,
+        }
+    }
+    fn is_icmp(&self) -> bool {
+        matches!(self, PingMethods::Icmp | PingMethods::Icmpv6)
+    }
+}
+
+fn udp_default_payload(dst_port: u16) -> Vec<u8> {
+    match payloads::for_port(dst_port) {
+        Some(p) => p.to_vec(),
+        None => Vec::new(),
+    }
+}
+
+fn udp_ping_payload(udp_payloads: &HashMap<u16, Vec<u8>>, dst_port: u16) -> Vec<u8> {
+    match udp_payloads.get(&dst_port) {
+        Some(p) => p.clone(),
+        None => udp_default_payload(dst_port),
+    }
+}
+
+/// The RST to the ACK means the host is up whether the port is open or not,
+/// no response (or the icmp unreachable) means the ACK is filtered, usually by a stateful firewall.
+fn ack_ping_status(port_status: PortStatus) -> PingStatus {
+    match port_status {
+        PortStatus::Unfiltered => PingStatus::Up,
+        PortStatus::Filtered => PingStatus::Filtered,
+        _ => PingStatus::Down,
+    }
+}
+
+/// The icmp unreachable (udp port closed or filtered) can only come from the target itself,
+/// since the reply is matched by the target address, so it proves the host is alive.
+fn udp_ping_status(port_status: PortStatus, unreachable_up: bool) -> PingStatus {
+    match port_status {
+        PortStatus::Open => PingStatus::Up,
+        PortStatus::Closed | PortStatus::Filtered if unreachable_up => PingStatus::Up,
+        _ => PingStatus::Down,
+    }
+}
+
+/// Ping the loopback address through the system sockets, see `loopback_scan`.
+/// Any answer (even the port is closed) means the host is up.
+fn loopback_ping(
+    method: PingMethods,
+    dst_addr: IpAddr,
+    dst_port: Option<u16>,
+    options: &PingOptions,
+    timeout: Duration,
+) -> Result<(PingStatus, Duration), PistolErrors> {
+    match method {
+        PingMethods::Udp => {
+            let dst_port = dst_port.unwrap_or(UDP_PING_DEFAULT_PORT);
+            let udp_payload = udp_ping_payload(&options.udp_payloads, dst_port);
+            let (ret, _, rtt) =
+                loopback_scan(ScanMethod::Udp, dst_addr, dst_port, &udp_payload, timeout)?;
+            Ok((udp_ping_status(ret, true), rtt))
+        }
+        _ => {
+            let dst_port = match method {
+                PingMethods::Ack => dst_port.unwrap_or(ACK_PING_DEFAULT_PORT),
+                _ => dst_port.unwrap_or(SYN_PING_DEFAULT_PORT),
+            };
+            let addr = SocketAddr::new(dst_addr, dst_port);
+            let start_time = clock_now();
+            let ping_status = match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(_) => PingStatus::Up,
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => PingStatus::Up,
+                Err(_) => PingStatus::Down,
+            };
+            Ok((ping_status, clock_elapsed(start_time)))
+        }
+    }
 }
 
 fn threads_ping(
@@ -208,8 +674,12 @@ fn threads_ping(
     src_port: u16,
     dst_ipv4: Ipv4Addr,
     dst_port: Option<u16>,
+    options: &PingOptions,
     timeout: Duration,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
+    if dst_ipv4.is_loopback() {
+        return loopback_ping(method, dst_ipv4.into(), dst_port, options, timeout);
+    }
     let (ping_status, rtt) = match method {
         PingMethods::Syn => {
             let dst_port = match dst_port {
@@ -217,7 +687,7 @@ fn threads_ping(
                 None => SYN_PING_DEFAULT_PORT,
             };
 
-            let (ret, rtt) =
+            let (ret, _, rtt) =
                 tcp::send_syn_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?;
             match ret {
                 PortStatus::Open => (PingStatus::Up, rtt),
@@ -230,12 +700,9 @@ fn threads_ping(
                 None => ACK_PING_DEFAULT_PORT,
             };
 
-            let (ret, rtt) =
+            let (ret, _, rtt) =
                 tcp::send_ack_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?;
-            match ret {
-                PortStatus::Unfiltered => (PingStatus::Up, rtt),
-                _ => (PingStatus::Down, rtt),
-            }
+            (ack_ping_status(ret), rtt)
         }
         PingMethods::Udp => {
             let dst_port = match dst_port {
@@ -243,16 +710,25 @@ fn threads_ping(
                 None => UDP_PING_DEFAULT_PORT,
             };
 
-            let (ret, rtt) =
-                udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?;
-            match ret {
-                PortStatus::Open => (PingStatus::Up, rtt),
-                // PortStatus::OpenOrFiltered => (PingStatus::Up, rtt),
-                _ => (PingStatus::Down, rtt),
-            }
+            let udp_payload = udp_ping_payload(&options.udp_payloads, dst_port);
+            let (ret, _, rtt) = udp::send_udp_scan_packet(
+                src_ipv4,
+                src_port,
+                dst_ipv4,
+                dst_port,
+                &udp_payload,
+                timeout,
+            )?;
+            (udp_ping_status(ret, options.unreachable_up), rtt)
         }
-        PingMethods::Icmp => {
-            let (ret, rtt) = icmp::send_icmp_ping_packet(src_ipv4, dst_ipv4, timeout)?;
+        PingMethods::Icmp | PingMethods::Icmpv6 => {
+            let (ret, rtt) = icmp::send_icmp_ping_packet(
+                src_ipv4,
+                dst_ipv4,
+                options.dont_fragment,
+                &options.icmp_accept,
+                timeout,
+            )?;
             (ret, rtt)
         }
     };
@@ -265,8 +741,12 @@ fn threads_ping6(
     src_port: u16,
     dst_ipv6: Ipv6Addr,
     dst_port: Option<u16>,
+    options: &PingOptions,
     timeout: Duration,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
+    if dst_ipv6.is_loopback() {
+        return loopback_ping(method, dst_ipv6.into(), dst_port, options, timeout);
+    }
     let (ping_status, rtt) = match method {
         PingMethods::Syn => {
             let dst_port = match dst_port {
@@ -274,7 +754,7 @@ fn threads_ping6(
                 None => SYN_PING_DEFAULT_PORT,
             };
 
-            let (ret, rtt) =
+            let (ret, _, rtt) =
                 tcp6::send_syn_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?;
             match ret {
                 PortStatus::Open => (PingStatus::Up, rtt),
@@ -287,12 +767,9 @@ fn threads_ping6(
                 None => ACK_PING_DEFAULT_PORT,
             };
 
-            let (ret, rtt) =
+            let (ret, _, rtt) =
                 tcp6::send_ack_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?;
-            match ret {
-                PortStatus::Unfiltered => (PingStatus::Up, rtt),
-                _ => (PingStatus::Down, rtt),
-            }
+            (ack_ping_status(ret), rtt)
         }
         PingMethods::Udp => {
             let dst_port = match dst_port {
@@ -300,19 +777,135 @@ fn threads_ping6(
                 None => UDP_PING_DEFAULT_PORT,
             };
 
-            let (ret, rtt) =
-                udp6::send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?;
+            let udp_payload = udp_ping_payload(&options.udp_payloads, dst_port);
+            let (ret, _, rtt) = udp6::send_udp_scan_packet(
+                src_ipv6,
+                src_port,
+                dst_ipv6,
+                dst_port,
+                &udp_payload,
+                timeout,
+            )?;
             match ret {
-                PortStatus::Open => (PingStatus::Up, rtt),
                 PortStatus::OpenOrFiltered => (PingStatus::Up, rtt),
-                _ => (PingStatus::Down, rtt),
+                _ => (udp_ping_status(ret, options.unreachable_up), rtt),
             }
         }
-        PingMethods::Icmp => icmpv6::send_icmpv6_ping_packet(src_ipv6, dst_ipv6, timeout)?,
+        PingMethods::Icmp | PingMethods::Icmpv6 => {
+            icmpv6::send_icmpv6_ping_packet(src_ipv6, dst_ipv6, timeout)?
+        }
     };
     Ok((ping_status, rtt))
 }
 
+/// The settings of `ping_with_options`, the default one is the plain ping of `ping` with all its arguments None.
+#[derive(Debug, Clone)]
+pub struct PingOptions {
+    pub src_addr: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    pub timeout: Option<Duration>,
+    /// The number of probes sent to every host, 1 by default.
+    pub tests: usize,
+    /// The source port is a random one in this range if the `src_port` is not given.
+    pub src_port_range: Option<RangeInclusive<u16>>,
+    /// Select the temporary (privacy extensions) ipv6 address as the source instead of the stable one
+    /// when `src_addr` is not given, see `find_source_addr6_with`.
+    pub prefer_temporary_ipv6: bool,
+    /// Many udp services only answer a valid request, this maps the port to the payload the udp ping sends to it,
+    /// the ports not in the map use the well-known payload from `payloads::for_port` or nothing.
+    pub udp_payloads: HashMap<u16, Vec<u8>>,
+    /// The icmp unreachable reply of the target (port closed) also means the host is up for the udp ping.
+    pub unreachable_up: bool,
+    /// Which replies of the icmp ping mean the host is up on the unusual networks (echo reply only by default).
+    pub icmp_accept: IcmpAcceptSet,
+    /// Set the don't fragment bit of the icmp echo requests (ipv4 raw socket only),
+    /// the probe too big for the path is dropped instead of fragmented, for the path mtu probing.
+    /// The echo request is not marked by default, like the ping program.
+    pub dont_fragment: bool,
+    /// The timing template sets the timeout, parallelism and scan delay not given explicitly.
+    pub timing: Option<TimingTemplate>,
+    /// The time source of the rtt measurement of every probe, the system clock by default, see `with_clock`.
+    pub clock: Option<Arc<dyn Clock + Send + Sync>>,
+}
+
+impl Default for PingOptions {
+    fn default() -> PingOptions {
+        PingOptions {
+            src_addr: None,
+            src_port: None,
+            timeout: None,
+            tests: 1,
+            src_port_range: None,
+            prefer_temporary_ipv6: false,
+            udp_payloads: HashMap::new(),
+            unreachable_up: false,
+            icmp_accept: IcmpAcceptSet::default(),
+            dont_fragment: false,
+            timing: None,
+            clock: None,
+        }
+    }
+}
+
+impl PingOptions {
+    pub fn with_src_addr(mut self, src_addr: IpAddr) -> PingOptions {
+        self.src_addr = Some(src_addr);
+        self
+    }
+    pub fn with_src_port(mut self, src_port: u16) -> PingOptions {
+        self.src_port = Some(src_port);
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> PingOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+    pub fn with_tests(mut self, tests: usize) -> PingOptions {
+        self.tests = tests;
+        self
+    }
+    pub fn with_src_port_range(mut self, src_port_range: RangeInclusive<u16>) -> PingOptions {
+        self.src_port_range = Some(src_port_range);
+        self
+    }
+    pub fn with_prefer_temporary_ipv6(mut self, prefer_temporary_ipv6: bool) -> PingOptions {
+        self.prefer_temporary_ipv6 = prefer_temporary_ipv6;
+        self
+    }
+    pub fn with_udp_payload(mut self, dst_port: u16, payload: &[u8]) -> PingOptions {
+        self.udp_payloads.insert(dst_port, payload.to_vec());
+        self
+    }
+    pub fn with_unreachable_up(mut self, unreachable_up: bool) -> PingOptions {
+        self.unreachable_up = unreachable_up;
+        self
+    }
+    pub fn with_icmp_accept(mut self, icmp_accept: IcmpAcceptSet) -> PingOptions {
+        self.icmp_accept = icmp_accept;
+        self
+    }
+    pub fn with_dont_fragment(mut self, dont_fragment: bool) -> PingOptions {
+        self.dont_fragment = dont_fragment;
+        self
+    }
+    pub fn with_timing(mut self, timing: TimingTemplate) -> PingOptions {
+        self.timing = Some(timing);
+        self
+    }
+    pub fn with_clock(mut self, clock: Arc<dyn Clock + Send + Sync>) -> PingOptions {
+        self.clock = Some(clock);
+        self
+    }
+    fn source_port(&self) -> Result<u16, PistolErrors> {
+        match (self.src_port, &self.src_port_range) {
+            (Some(p), _) => Ok(p),
+            (None, Some(range)) => random_port_in(range),
+            (None, None) => Ok(random_port()),
+        }
+    }
+}
+
+/// General ping function.
 pub fn ping(
     target: Target,
     method: PingMethods,
@@ -320,25 +913,55 @@ pub fn ping(
     src_port: Option<u16>,
     timeout: Option<Duration>,
     tests: usize,
+) -> Result<PingResults, PistolErrors> {
+    let options = PingOptions {
+        src_addr,
+        src_port,
+        timeout,
+        tests,
+        ..Default::default()
+    };
+    ping_with_options(target, method, &options)
+}
+
+/// The `ping` with the `options`, see `PingOptions`.
+pub fn ping_with_options(
+    mut target: Target,
+    method: PingMethods,
+    options: &PingOptions,
 ) -> Result<PingResults, PistolErrors> {
     let mut ping_results = PingResults::new();
+    let src_addr = options.src_addr;
+    let tests = options.tests;
+    let timing = options.timing;
+    let timeout = options.timeout.or(timing.and_then(|t| t.timeout()));
+    target.resolve_all(timeout)?;
+    let scan_delay = timing.and_then(|t| t.scan_delay());
 
     let threads_num = target.hosts.len() * tests;
-    let src_port = match src_port {
-        Some(p) => p,
-        None => random_port(),
+    let threads_num = match timing {
+        Some(t) => t.threads_num(threads_num),
+        None => threads_num,
     };
+    let src_port = options.source_port()?;
+    let clock = options.clock.clone();
+    let options = Arc::new(options.clone());
 
     let pool = get_threads_pool(threads_num);
     let (tx, rx) = channel();
+    let mut probes = Vec::new();
     let mut recv_size = 0;
-    let timeout = match timeout {
-        Some(t) => t,
-        None => get_default_timeout(),
-    };
+    // without the timeout, the default one of the source interface is used
+    let mut src_timeouts: HashMap<IpAddr, Duration> = HashMap::new();
 
     for host in target.hosts {
         let dst_addr = host.addr;
+        // The ICMP variant always follows the address family of the host.
+        let method = if method.is_icmp() {
+            PingMethods::icmp_for(dst_addr)
+        } else {
+            method
+        };
         match dst_addr {
             IpAddr::V4(dst_ipv4) => {
                 for _ in 0..tests {
@@ -348,81 +971,233 @@ pub fn ping(
                         Some(s) => s,
                         None => return Err(PistolErrors::CanNotFoundSourceAddress),
                     };
+                    ping_results.source_addrs.insert(dst_addr, src_ipv4.into());
+                    let timeout = match timeout {
+                        Some(t) => t,
+                        None => *src_timeouts
+                            .entry(src_ipv4.into())
+                            .or_insert_with(|| get_default_timeout_by_src(src_ipv4.into())),
+                    };
                     let dst_port = if host.ports.len() > 0 {
                         Some(host.ports[0])
                     } else {
                         None
                     };
-                    let dst_port = if method != PingMethods::Icmp {
-                        dst_port
-                    } else {
-                        None
-                    };
-                    pool.execute(move || {
+                    let dst_port = if !method.is_icmp() { dst_port } else { None };
+                    let options = options.clone();
+                    let job: ProbeJob = Box::new(move || {
                         let cost = Instant::now(); // for error situation
-                        let ret =
-                            threads_ping(method, src_ipv4, src_port, dst_ipv4, dst_port, timeout);
+                        let ret = threads_ping(
+                            method, src_ipv4, src_port, dst_ipv4, dst_port, &options, timeout,
+                        );
                         match tx.send((dst_addr, ret, cost)) {
                             _ => (),
                         }
                     });
+                    probes.push((dst_addr, job));
                 }
             }
             IpAddr::V6(dst_ipv6) => {
                 for _ in 0..tests {
                     let tx = tx.clone();
                     recv_size += 1;
-                    let src_ipv6 = match find_source_addr6(src_addr, dst_ipv6)? {
+                    let src_ipv6 = match find_source_addr6_with(
+                        src_addr,
+                        dst_ipv6,
+                        options.prefer_temporary_ipv6,
+                    )? {
                         Some(s) => s,
                         None => return Err(PistolErrors::CanNotFoundSourceAddress),
                     };
+                    ping_results.source_addrs.insert(dst_addr, src_ipv6.into());
+                    let timeout = match timeout {
+                        Some(t) => t,
+                        None => *src_timeouts
+                            .entry(src_ipv6.into())
+                            .or_insert_with(|| get_default_timeout_by_src(src_ipv6.into())),
+                    };
                     let dst_port = if host.ports.len() > 0 {
                         Some(host.ports[0])
                     } else {
                         None
                     };
-                    let dst_port = if method != PingMethods::Icmp {
-                        dst_port
-                    } else {
-                        None
-                    };
-                    pool.execute(move || {
+                    let dst_port = if !method.is_icmp() { dst_port } else { None };
+                    let options = options.clone();
+                    let job: ProbeJob = Box::new(move || {
                         let cost = Instant::now(); // for error situation
-                        let ret =
-                            threads_ping6(method, src_ipv6, src_port, dst_ipv6, dst_port, timeout);
+                        let ret = threads_ping6(
+                            method, src_ipv6, src_port, dst_ipv6, dst_port, &options, timeout,
+                        );
                         match tx.send((dst_addr, ret, cost)) {
                             _ => (),
                         }
                     });
+                    probes.push((dst_addr, job));
                 }
             }
         }
     }
 
-    let iter = rx.into_iter().take(recv_size);
+    dispatch_probes(&pool, probes, scan_delay, clock);
 
-    for (dst_ipv4, pr, cost) in iter {
-        let tc = cost.elapsed();
-        match pr {
-            Ok((ping_status, rtt)) => {
-                ping_results.insert(dst_ipv4, ping_status, rtt);
-            }
-            Err(e) => match e {
-                PistolErrors::CanNotFoundMacAddress => {
-                    ping_results.insert(dst_ipv4, PingStatus::Down, tc)
-                }
-                _ => {
-                    warn!("ping error: {}", e);
-                    ping_results.insert(dst_ipv4, PingStatus::Error, tc);
-                }
-            },
-        }
-    }
+    // more drain threads than the shards only wait for each other
+    let drain_threads = threads_num.min(get_cpu_num()).min(PING_RESULTS_SHARDS);
+    drain_ping_results(rx, recv_size, drain_threads, &mut ping_results);
 
     ping_results.enrichment();
     Ok(ping_results)
 }
 
+/// Probes the host from one source address, like `ping`: (source, destination, destination port).
+type SourceProbe = dyn Fn(IpAddr, IpAddr, Option<u16>) -> Result<(PingStatus, Duration), PistolErrors>
+    + Send
+    + Sync;
+
+/// Ping every host from each of its sources, `sources_for` returns the sources of the host.
+fn ping_sources_with(
+    hosts: Vec<Host>,
+    sources_for: impl Fn(IpAddr) -> Vec<IpAddr>,
+    probe: Arc<SourceProbe>,
+) -> MultiSourcePingResults {
+    let mut jobs = Vec::new();
+    for host in hosts {
+        let dst_port = host.ports.first().copied();
+        for (i, src_addr) in sources_for(host.addr).into_iter().enumerate() {
+            jobs.push((host.addr, dst_port, i, src_addr));
+        }
+    }
+    let pool = get_threads_pool(jobs.len());
+    let (tx, rx) = channel();
+    let recv_size = jobs.len();
+    for (dst_addr, dst_port, i, src_addr) in jobs {
+        let tx = tx.clone();
+        let probe = probe.clone();
+        pool.execute(move || {
+            let cost = Instant::now(); // for error situation
+            let ret = probe(src_addr, dst_addr, dst_port);
+            let _ = tx.send((dst_addr, i, src_addr, ret, cost));
+        });
+    }
+
+    let mut results: HashMap<IpAddr, Vec<(usize, SourcePingResults)>> = HashMap::new();
+    for (dst_addr, i, src_addr, ret, cost) in rx.into_iter().take(recv_size) {
+        let (ping_status, ping_time_cost) = ping_message_result(ret, cost);
+        let spr = SourcePingResults {
+            src_addr,
+            ping_status,
+            ping_time_cost,
+        };
+        results.entry(dst_addr).or_default().push((i, spr));
+    }
+    let mut ret = MultiSourcePingResults::new();
+    for (dst_addr, mut spr) in results {
+        spr.sort_by_key(|(i, _)| *i);
+        ret.pings
+            .insert(dst_addr, spr.into_iter().map(|(_, s)| s).collect());
+    }
+    ret
+}
+
+/// Ping every host from each applicable source address of the multi-homed local host,
+/// to find out which sources the host answers (e.g. the asymmetric routing or the source filtering).
+/// The sources are the local addresses of the same family (and the same scope for ipv6),
+/// the source `ping` would use goes first. Only the `src_addrs` are tried if given.
+/// ```rust
+/// use pistol::ping::PingMethods;
+/// use pistol::ping_each_source;
+/// use pistol::Host;
+/// use pistol::Target;
+/// use std::net::Ipv4Addr;
+/// use std::time::Duration;
+///
+/// fn test() {
+///     let dst_addr = Ipv4Addr::new(192, 168, 5, 5).into();
+///     let target = Target::new(vec![Host::new(dst_addr, Some(vec![80]))]);
+///     let timeout = Some(Duration::new(1, 0));
+///     let ret = ping_each_source(target, PingMethods::Syn, None, None, timeout).unwrap();
+///     println!("{:?}", ret.replied_sources(&dst_addr));
+/// }
+/// ```
+pub fn ping_each_source(
+    mut target: Target,
+    method: PingMethods,
+    src_addrs: Option<Vec<IpAddr>>,
+    src_port: Option<u16>,
+    timeout: Option<Duration>,
+) -> Result<MultiSourcePingResults, PistolErrors> {
+    target.resolve_all(timeout)?;
+    let src_port = src_port.unwrap_or(random_port());
+    let local_addrs = src_addrs.unwrap_or(local_addrs());
+    let sources_for = |dst_addr: IpAddr| {
+        let mut sources = source_candidates(dst_addr, &local_addrs);
+        // the ipv4 sources have no order of their own, the routed one goes first
+        if let IpAddr::V4(dst_ipv4) = dst_addr {
+            if let Ok(Some(s)) = find_source_addr(None, dst_ipv4) {
+                if let Some(i) = sources.iter().position(|a| *a == IpAddr::V4(s)) {
+                    let s = sources.remove(i);
+                    sources.insert(0, s);
+                }
+            }
+        }
+        sources
+    };
+    let options = PingOptions::default();
+    let probe = move |src_addr: IpAddr, dst_addr: IpAddr, dst_port: Option<u16>| {
+        let method = if method.is_icmp() {
+            PingMethods::icmp_for(dst_addr)
+        } else {
+            method
+        };
+        let dst_port = if !method.is_icmp() { dst_port } else { None };
+        let timeout = timeout.unwrap_or(get_default_timeout_by_src(src_addr));
+        match (src_addr, dst_addr) {
+            (IpAddr::V4(src_ipv4), IpAddr::V4(dst_ipv4)) => threads_ping(
+                method, src_ipv4, src_port, dst_ipv4, dst_port, &options, timeout,
+            ),
+            (IpAddr::V6(src_ipv6), IpAddr::V6(dst_ipv6)) => threads_ping6(
+                method, src_ipv6, src_port, dst_ipv6, dst_port, &options, timeout,
+            ),
+            _ => Err(PistolErrors::CanNotFoundSourceAddress),
+        }
+    };
+    Ok(ping_sources_with(
+        target.hosts,
+        sources_for,
+        Arc::new(probe),
+    ))
+}
+
+/// Ping a single host and port with the `options`, like `ping_with_options`, the raw functions are built on it.
+fn ping_raw_with_options(
+    method: PingMethods,
+    dst_addr: IpAddr,
+    dst_port: Option<u16>,
+    options: &PingOptions,
+) -> Result<(PingStatus, Duration), PistolErrors> {
+    let src_port = options.source_port()?;
+    let timeout = match options.timeout {
+        Some(t) => t,
+        None => get_default_timeout(),
+    };
+    match dst_addr {
+        IpAddr::V4(dst_ipv4) => match find_source_addr(options.src_addr, dst_ipv4)? {
+            Some(src_ipv4) => threads_ping(
+                method, src_ipv4, src_port, dst_ipv4, dst_port, options, timeout,
+            ),
+            None => Err(PistolErrors::CanNotFoundSourceAddress),
+        },
+        IpAddr::V6(dst_ipv6) => {
+            match find_source_addr6_with(options.src_addr, dst_ipv6, options.prefer_temporary_ipv6)?
+            {
+                Some(src_ipv6) => threads_ping6(
+                    method, src_ipv6, src_port, dst_ipv6, dst_port, options, timeout,
+                ),
+                None => Err(PistolErrors::CanNotFoundSourceAddress),
+            }
+        }
+    }
+}
+
 /// TCP SYN Ping.
 /// This ping probe stays away from being similar to a SYN port scan, and to keep the probe stealthy,
 /// we chose to have the user manually provide a port number that is open on the target machine instead of traversing all ports.
@@ -444,45 +1219,19 @@ pub fn tcp_syn_ping_raw(
     src_port: Option<u16>,
     timeout: Option<Duration>,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
-    let src_port = match src_port {
-        Some(p) => p,
-        None => random_port(),
-    };
-    let timeout = match timeout {
-        Some(t) => t,
-        None => get_default_timeout(),
+    let options = PingOptions {
+        src_addr,
+        src_port,
+        timeout,
+        ..Default::default()
     };
-    match dst_addr {
-        IpAddr::V4(dst_ipv4) => match find_source_addr(src_addr, dst_ipv4)? {
-            Some(src_ipv4) => {
-                let (ret, rtt) =
-                    tcp::send_syn_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?;
-                let (s, rtt) = match ret {
-                    PortStatus::Open => (PingStatus::Up, rtt),
-                    _ => (PingStatus::Down, rtt),
-                };
-                Ok((s, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-        IpAddr::V6(dst_ipv6) => match find_source_addr6(src_addr, dst_ipv6)? {
-            Some(src_ipv6) => {
-                let (ret, rtt) =
-                    tcp6::send_syn_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?;
-                let (s, rtt) = match ret {
-                    PortStatus::Open => (PingStatus::Up, rtt),
-                    _ => (PingStatus::Down, rtt),
-                };
-                Ok((s, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-    }
+    ping_raw_with_options(PingMethods::Syn, dst_addr, Some(dst_port), &options)
 }
 
 /// TCP ACK Ping.
 /// This ping probe stays away from being similar to a ACK port scan, and to keep the probe stealthy,
 /// we chose to have the user manually provide a port number that is open on the target machine instead of traversing all ports.
+/// The RST means up, no response means the ACK is dropped on the way and is reported as `PingStatus::Filtered`.
 pub fn tcp_ack_ping(
     target: Target,
     src_addr: Option<IpAddr>,
@@ -501,45 +1250,20 @@ pub fn tcp_ack_ping_raw(
     src_port: Option<u16>,
     timeout: Option<Duration>,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
-    let src_port = match src_port {
-        Some(p) => p,
-        None => random_port(),
-    };
-    let timeout = match timeout {
-        Some(t) => t,
-        None => get_default_timeout(),
+    let options = PingOptions {
+        src_addr,
+        src_port,
+        timeout,
+        ..Default::default()
     };
-    match dst_addr {
-        IpAddr::V4(dst_ipv4) => match find_source_addr(src_addr, dst_ipv4)? {
-            Some(src_ipv4) => {
-                let (ret, rtt) =
-                    tcp::send_ack_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?;
-                let (s, rtt) = match ret {
-                    PortStatus::Unfiltered => (PingStatus::Up, rtt),
-                    _ => (PingStatus::Down, rtt),
-                };
-                Ok((s, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-        IpAddr::V6(dst_ipv6) => match find_source_addr6(src_addr, dst_ipv6)? {
-            Some(src_ipv6) => {
-                let (ret, rtt) =
-                    tcp6::send_ack_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?;
-                let (s, rtt) = match ret {
-                    PortStatus::Unfiltered => (PingStatus::Up, rtt),
-                    _ => (PingStatus::Down, rtt),
-                };
-                Ok((s, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-    }
+    ping_raw_with_options(PingMethods::Ack, dst_addr, Some(dst_port), &options)
 }
 
 /// UDP Ping.
 /// This ping probe stays away from being similar to a UDP port scan, and to keep the probe stealthy,
 /// we chose to have the user manually provide a port number that is open on the target machine instead of traversing all ports.
+/// The payload sent to the port is the well-known one from `payloads::for_port` or nothing,
+/// use `ping_with_options` with the `udp_payloads` and `unreachable_up` of `PingOptions` for the others.
 pub fn udp_ping(
     target: Target,
     src_addr: Option<IpAddr>,
@@ -558,42 +1282,22 @@ pub fn udp_ping_raw(
     src_port: Option<u16>,
     timeout: Option<Duration>,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
-    let src_port = match src_port {
-        Some(p) => p,
-        None => random_port(),
-    };
-    let timeout = match timeout {
-        Some(t) => t,
-        None => get_default_timeout(),
+    let options = PingOptions {
+        src_addr,
+        src_port,
+        timeout,
+        ..Default::default()
     };
-    match dst_addr {
-        IpAddr::V4(dst_ipv4) => match find_source_addr(src_addr, dst_ipv4)? {
-            Some(src_ipv4) => {
-                let (ret, rtt) =
-                    udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?;
-                let (s, rtt) = match ret {
-                    PortStatus::Open => (PingStatus::Up, rtt),
-                    // PortStatus::OpenOrFiltered => (PingStatus::Up, rtt),
-                    _ => (PingStatus::Down, rtt),
-                };
-                Ok((s, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-        IpAddr::V6(dst_ipv6) => match find_source_addr6(src_addr, dst_ipv6)? {
-            Some(src_ipv6) => {
-                let (ret, rtt) =
-                    udp6::send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?;
-                let (s, rtt) = match ret {
-                    PortStatus::Open => (PingStatus::Up, rtt),
-                    // PortStatus::OpenOrFiltered => (PingStatus::Up, rtt),
-                    _ => (PingStatus::Down, rtt),
-                };
-                Ok((s, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-    }
+    udp_ping_raw_with_options(dst_addr, dst_port, &options)
+}
+
+/// The `udp_ping_raw` with the `options`, see `PingOptions`.
+pub fn udp_ping_raw_with_options(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    options: &PingOptions,
+) -> Result<(PingStatus, Duration), PistolErrors> {
+    ping_raw_with_options(PingMethods::Udp, dst_addr, Some(dst_port), options)
 }
 
 /// ICMP Ping.
@@ -627,26 +1331,20 @@ pub fn icmp_ping_raw(
     src_addr: Option<IpAddr>,
     timeout: Option<Duration>,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
-    let timeout = match timeout {
-        Some(t) => t,
-        None => get_default_timeout(),
+    let options = PingOptions {
+        src_addr,
+        timeout,
+        ..Default::default()
     };
-    match dst_addr {
-        IpAddr::V4(dst_ipv4) => match find_source_addr(src_addr, dst_ipv4)? {
-            Some(src_ipv4) => {
-                let (ret, rtt) = icmp::send_icmp_ping_packet(src_ipv4, dst_ipv4, timeout)?;
-                Ok((ret, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-        IpAddr::V6(dst_ipv6) => match find_source_addr6(src_addr, dst_ipv6)? {
-            Some(src_ipv6) => {
-                let (ret, rtt) = icmpv6::send_icmpv6_ping_packet(src_ipv6, dst_ipv6, timeout)?;
-                Ok((ret, rtt))
-            }
-            None => Err(PistolErrors::CanNotFoundSourceAddress),
-        },
-    }
+    icmp_ping_raw_with_options(dst_addr, &options)
+}
+
+/// The `icmp_ping_raw` with the `options`, see `PingOptions`.
+pub fn icmp_ping_raw_with_options(
+    dst_addr: IpAddr,
+    options: &PingOptions,
+) -> Result<(PingStatus, Duration), PistolErrors> {
+    ping_raw_with_options(PingMethods::icmp_for(dst_addr), dst_addr, None, options)
 }
 
 #[cfg(test)]
@@ -659,6 +1357,403 @@ mod tests {
     use crate::TEST_IPV6_LOCAL;
     use subnetwork::CrossIpv4Pool;
     #[test]
+    fn test_drain_ping_results() {
+        let start = Instant::now();
+        let mut input = Vec::new();
+        for i in 0..200u32 {
+            let dst_addr: IpAddr = Ipv4Addr::from(0x0a00_0000 + i % 37).into();
+            let pr = match i % 5 {
+                0 => Err(PistolErrors::CanNotFoundMacAddress),
+                1 => Ok((PingStatus::Down, Duration::from_millis(i as u64))),
+                _ => Ok((PingStatus::Up, Duration::from_millis(i as u64))),
+            };
+            input.push((dst_addr, pr, start));
+        }
+        let sorted = |ping_results: &PingResults| {
+            let mut pings: Vec<(IpAddr, Vec<String>)> = ping_results
+                .pings
+                .iter()
+                .map(|(addr, hpr)| {
+                    let mut v: Vec<String> = hpr
+                        .iter()
+                        // the time cost of the errors is measured at the drain time
+                        .map(|h| match h.ping_status {
+                            PingStatus::Up => format!("up {:?}", h.ping_time_cost),
+                            ref s => format!("{:?}", s),
+                        })
+                        .collect();
+                    v.sort();
+                    (*addr, v)
+                })
+                .collect();
+            pings.sort();
+            pings
+        };
+
+        // serial
+        let mut serial = PingResults::new();
+        let (tx, rx) = channel();
+        for msg in input.iter().map(|(a, pr, c)| (*a, clone_result(pr), *c)) {
+            tx.send(msg).unwrap();
+        }
+        for (dst_addr, pr, cost) in rx.into_iter().take(input.len()) {
+            let (ping_status, rtt) = ping_message_result(pr, cost);
+            serial.insert(dst_addr, ping_status, rtt);
+        }
+
+        // concurrent
+        let mut concurrent = PingResults::new();
+        let (tx, rx) = channel();
+        for msg in input.iter().map(|(a, pr, c)| (*a, clone_result(pr), *c)) {
+            tx.send(msg).unwrap();
+        }
+        drain_ping_results(rx, input.len(), 8, &mut concurrent);
+
+        assert_eq!(serial.pings.len(), 37);
+        assert_eq!(sorted(&serial), sorted(&concurrent));
+        assert_eq!(serial.summary().alive, concurrent.summary().alive);
+        assert_eq!(serial.summary().down, concurrent.summary().down);
+    }
+    fn clone_result(
+        pr: &Result<(PingStatus, Duration), PistolErrors>,
+    ) -> Result<(PingStatus, Duration), PistolErrors> {
+        match pr {
+            Ok(r) => Ok(r.clone()),
+            Err(_) => Err(PistolErrors::CanNotFoundMacAddress),
+        }
+    }
+    #[test]
+    fn test_icmp_dont_fragment() {
+        use pnet::packet::ipv4::checksum;
+        use pnet::packet::ipv4::Ipv4Flags;
+        use pnet::packet::ipv4::Ipv4Packet;
+        let localhost = Ipv4Addr::LOCALHOST;
+        assert!(!PingOptions::default().dont_fragment);
+        let packet = icmp::build_echo_request(localhost, localhost, false);
+        let ipv4_packet = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(ipv4_packet.get_flags(), 0);
+        assert_eq!(ipv4_packet.get_checksum(), checksum(&ipv4_packet));
+
+        let packet = icmp::build_echo_request(localhost, localhost, true);
+        let ipv4_packet = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(ipv4_packet.get_flags(), Ipv4Flags::DontFragment);
+        assert_eq!(ipv4_packet.get_checksum(), checksum(&ipv4_packet));
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_icmp_ping_dgram() {
+        let socket = match icmp::dgram_icmp_socket() {
+            Ok(s) => s,
+            Err(e) => {
+                // not allowed by net.ipv4.ping_group_range
+                println!("skip the datagram icmp test: {}", e);
+                return;
+            }
+        };
+        let localhost = Ipv4Addr::LOCALHOST;
+        let timeout = Duration::new(1, 0);
+        let (ping_status, _) =
+            icmp::send_icmp_ping_packet_dgram(socket, localhost, localhost, timeout).unwrap();
+        assert_eq!(ping_status, PingStatus::Up);
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_icmpv6_ping_dgram() {
+        let socket = match icmpv6::dgram_icmpv6_socket() {
+            Ok(s) => s,
+            Err(e) => {
+                // not allowed by net.ipv4.ping_group_range
+                println!("skip the datagram icmpv6 test: {}", e);
+                return;
+            }
+        };
+        let localhost = Ipv6Addr::LOCALHOST;
+        let timeout = Duration::new(1, 0);
+        let (ping_status, _) =
+            icmpv6::send_icmpv6_ping_packet_dgram(socket, localhost, localhost, 64, timeout)
+                .unwrap();
+        assert_eq!(ping_status, PingStatus::Up);
+    }
+    #[test]
+    fn test_udp_ping_dns() {
+        // the local dns server only answers the valid dns query
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dst_port = socket.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut buff = [0u8; 512];
+            while let Ok((n, src_addr)) = socket.recv_from(&mut buff) {
+                // the header of a query (QR bit unset) with a question
+                let query = &buff[..n];
+                if n > 12 && query[2] & 0x80 == 0 && u16::from_be_bytes([query[4], query[5]]) > 0 {
+                    let mut response = query.to_vec();
+                    response[2] |= 0x80;
+                    socket.send_to(&response, src_addr).unwrap();
+                }
+            }
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let options = PingOptions::default().with_timeout(Duration::new(1, 0));
+        // the unknown port gets the empty datagram
+        let (ret, _rtt) = udp_ping_raw_with_options(dst_addr, dst_port, &options).unwrap();
+        assert_eq!(ret, PingStatus::Down);
+        let dns_query = payloads::for_port(53).unwrap();
+        let options = options.with_udp_payload(dst_port, dns_query);
+        let (ret, _rtt) = udp_ping_raw_with_options(dst_addr, dst_port, &options).unwrap();
+        assert_eq!(ret, PingStatus::Up);
+    }
+    #[test]
+    fn test_udp_ping_unreachable_up() {
+        // the closed port of the target answers icmp port unreachable
+        assert_eq!(udp_ping_status(PortStatus::Closed, false), PingStatus::Down);
+        assert_eq!(udp_ping_status(PortStatus::Closed, true), PingStatus::Up);
+        assert_eq!(udp_ping_status(PortStatus::Filtered, true), PingStatus::Up);
+        // no response at all
+        assert_eq!(
+            udp_ping_status(PortStatus::OpenOrFiltered, true),
+            PingStatus::Down
+        );
+        assert_eq!(udp_ping_status(PortStatus::Open, false), PingStatus::Up);
+    }
+    #[test]
+    fn test_ping_source_address_error() {
+        // the ipv6 source address can not be used with the ipv4 target
+        let src_addr = Some(Ipv6Addr::LOCALHOST.into());
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let ret = tcp_syn_ping_raw(dst_addr, 80, src_addr, None, None);
+        assert!(matches!(ret, Err(PistolErrors::CanNotFoundSourceAddress)));
+
+        let target = Target::new(vec![Host::new(dst_addr, None)]);
+        let ret = icmp_ping(target, src_addr, None, None, 1);
+        assert!(matches!(ret, Err(PistolErrors::CanNotFoundSourceAddress)));
+    }
+    #[test]
+    fn test_ping_reliability() {
+        let flaky: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let solid: IpAddr = Ipv4Addr::new(192, 168, 1, 11).into();
+        let rtt = Duration::from_millis(10);
+        let mut ret = PingResults::new();
+        ret.insert(flaky, PingStatus::Up, rtt);
+        for _ in 0..3 {
+            ret.insert(flaky, PingStatus::Down, rtt);
+        }
+        for _ in 0..4 {
+            ret.insert(solid, PingStatus::Up, rtt);
+        }
+        assert_eq!(ret.reliability(&flaky), Some(0.25));
+        assert_eq!(ret.reliability(&solid), Some(1.0));
+        assert_eq!(ret.reliability(&TEST_IPV4_LOCAL.into()), None);
+
+        assert!(ret.is_alive(&flaky, None));
+        assert!(!ret.is_alive(&flaky, Some(0.5)));
+        assert!(ret.is_alive(&solid, Some(1.0)));
+    }
+    #[test]
+    fn test_ping_summary() {
+        let up: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let down: IpAddr = Ipv4Addr::new(192, 168, 1, 11).into();
+        let error: IpAddr = Ipv4Addr::new(192, 168, 1, 12).into();
+        let mut ret = PingResults::new();
+        ret.insert(up, PingStatus::Down, Duration::from_millis(30));
+        ret.insert(up, PingStatus::Up, Duration::from_millis(10));
+        ret.insert(down, PingStatus::Down, Duration::from_millis(20));
+        ret.insert(down, PingStatus::Error, Duration::new(0, 0));
+        ret.insert(error, PingStatus::Error, Duration::new(0, 0));
+        let summary = ret.summary();
+        assert_eq!(summary.total_hosts, 3);
+        assert_eq!(summary.alive, 1);
+        assert_eq!(summary.down, 1);
+        assert_eq!(summary.error, 1);
+        assert_eq!(summary.min_rtt, Duration::from_millis(10));
+        assert_eq!(summary.max_rtt, Duration::from_millis(30));
+        assert_eq!(summary.avg_rtt, Duration::from_millis(20));
+    }
+    #[test]
+    fn test_ping_iter_in_order() {
+        let hosts: Vec<IpAddr> = vec![
+            Ipv4Addr::new(192, 168, 1, 30).into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+            Ipv4Addr::new(192, 168, 1, 2).into(),
+        ];
+        let extra: IpAddr = Ipv4Addr::new(172, 16, 0, 1).into();
+        let mut ret = PingResults::new();
+        for ip in hosts.iter().rev().chain([&extra]) {
+            ret.insert(*ip, PingStatus::Up, Duration::from_millis(10));
+        }
+        // the unknown and the repeated addresses are skipped
+        let unknown: IpAddr = Ipv4Addr::new(8, 8, 8, 8).into();
+        let order = [hosts[0], unknown, hosts[1], hosts[0], hosts[2]];
+        let ips: Vec<IpAddr> = ret.iter_in_order(&order).map(|(ip, _)| ip).collect();
+        assert_eq!(ips, vec![hosts[0], hosts[1], hosts[2], extra]);
+        let (_, hpr) = ret.iter_in_order(&order).next().unwrap();
+        assert_eq!(hpr[0].ping_status, PingStatus::Up);
+    }
+    #[test]
+    fn test_ping_each_source() {
+        // the multi-homed host
+        let lan: IpAddr = Ipv4Addr::new(192, 168, 1, 5).into();
+        let vpn: IpAddr = Ipv4Addr::new(10, 8, 0, 5).into();
+        let ula: IpAddr = "fd00:1:2:3::5".parse().unwrap();
+        let global: IpAddr = "2001:db8:0:10::5".parse().unwrap();
+        let link_local: IpAddr = "fe80::5".parse().unwrap();
+        let local_addrs = vec![
+            lan,
+            vpn,
+            ula,
+            link_local,
+            global,
+            Ipv4Addr::LOCALHOST.into(),
+        ];
+
+        // the remote host only answers the vpn address, the ipv6 host answers both
+        let dst_ipv4: IpAddr = Ipv4Addr::new(172, 16, 0, 1).into();
+        let dst_ipv6: IpAddr = "2001:db8:0:10::1".parse().unwrap();
+        let unknown: IpAddr = Ipv4Addr::new(172, 16, 0, 2).into();
+        let probe = move |src_addr: IpAddr, dst_addr: IpAddr, dst_port: Option<u16>| {
+            assert_eq!(dst_port, Some(80));
+            match (src_addr, dst_addr) {
+                (s, d) if s == vpn && d == dst_ipv4 => {
+                    Ok((PingStatus::Up, Duration::from_millis(5)))
+                }
+                (_, d) if d == dst_ipv6 => Ok((PingStatus::Up, Duration::from_millis(5))),
+                (_, d) if d == unknown => Err(PistolErrors::CanNotFoundSourceAddress),
+                _ => Ok((PingStatus::Down, Duration::from_millis(5))),
+            }
+        };
+        let hosts = vec![
+            Host::new(dst_ipv4, Some(vec![80])),
+            Host::new(dst_ipv6, Some(vec![80])),
+            Host::new(unknown, Some(vec![80])),
+        ];
+        let ret = ping_sources_with(
+            hosts,
+            |dst_addr| source_candidates(dst_addr, &local_addrs),
+            Arc::new(probe),
+        );
+
+        let tried: Vec<IpAddr> = ret
+            .get(&dst_ipv4)
+            .unwrap()
+            .iter()
+            .map(|s| s.src_addr)
+            .collect();
+        assert_eq!(tried, vec![lan, vpn]);
+        assert_eq!(ret.replied_sources(&dst_ipv4), vec![vpn]);
+        assert!(ret.is_asymmetric(&dst_ipv4));
+        // the link-local address is not tried, the longest prefix goes first
+        let tried: Vec<IpAddr> = ret
+            .get(&dst_ipv6)
+            .unwrap()
+            .iter()
+            .map(|s| s.src_addr)
+            .collect();
+        assert_eq!(tried, vec![global, ula]);
+        assert!(!ret.is_asymmetric(&dst_ipv6));
+        assert!(ret.replied_sources(&unknown).is_empty());
+        assert!(ret
+            .get(&unknown)
+            .unwrap()
+            .iter()
+            .all(|s| s.ping_status == PingStatus::Error));
+    }
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_ping_results_bytes() {
+        let mut ret = PingResults::new();
+        for i in 0..1000u32 {
+            let dst_addr: IpAddr = Ipv4Addr::from(0x0a000000 + i).into();
+            let ping_status = match i % 4 {
+                0 => PingStatus::Up,
+                1 => PingStatus::Down,
+                2 => PingStatus::Filtered,
+                _ => PingStatus::Error,
+            };
+            ret.insert(dst_addr, ping_status, Duration::from_micros(i as u64));
+            ret.source_addrs
+                .insert(dst_addr, Ipv4Addr::new(192, 168, 1, 5).into());
+        }
+        ret.enrichment();
+        let bytes = ret.to_bytes().unwrap();
+        let back = PingResults::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            serde_json::to_value(&back).unwrap(),
+            serde_json::to_value(&ret).unwrap()
+        );
+        assert_eq!(back.summary(), ret.summary());
+    }
+    #[test]
+    fn test_ack_ping_status() {
+        // the RST from the live host
+        assert_eq!(ack_ping_status(PortStatus::Unfiltered), PingStatus::Up);
+        // the ACK dropped by the firewall
+        assert_eq!(ack_ping_status(PortStatus::Filtered), PingStatus::Filtered);
+
+        let rst: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let dropped: IpAddr = Ipv4Addr::new(192, 168, 1, 11).into();
+        let mut ret = PingResults::new();
+        ret.insert(
+            rst,
+            ack_ping_status(PortStatus::Unfiltered),
+            Duration::from_millis(10),
+        );
+        ret.insert(
+            dropped,
+            ack_ping_status(PortStatus::Filtered),
+            Duration::from_secs(1),
+        );
+        ret.insert(
+            dropped,
+            ack_ping_status(PortStatus::Filtered),
+            Duration::from_secs(1),
+        );
+        let summary = ret.summary();
+        assert_eq!(summary.alive, 1);
+        assert_eq!(summary.filtered, 1);
+        assert_eq!(summary.down, 0);
+        assert!(!ret.is_alive(&dropped, None));
+        let hpr = ret.pings.get(&dropped).unwrap();
+        assert_eq!(status_counts_str(hpr), "filtered (2 filtered)");
+    }
+    #[test]
+    fn test_ping_results_display_stable() {
+        let addr: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let statuses = [
+            PingStatus::Up,
+            PingStatus::Down,
+            PingStatus::Up,
+            PingStatus::Up,
+        ];
+        let mut ret_1 = PingResults::new();
+        let mut ret_2 = PingResults::new();
+        for s in &statuses {
+            ret_1.insert(addr, s.clone(), Duration::from_millis(10));
+        }
+        for s in statuses.iter().rev() {
+            ret_2.insert(addr, s.clone(), Duration::from_millis(10));
+        }
+        ret_1.tests = statuses.len();
+        ret_2.tests = statuses.len();
+        let output = ret_1.to_string();
+        assert_eq!(output, ret_2.to_string());
+        assert!(output.contains("up (3 up, 1 down)"));
+        // the raw data keeps the insert order
+        assert_eq!(
+            ret_2.get_ping_status(&addr).unwrap(),
+            statuses.iter().rev().cloned().collect::<Vec<PingStatus>>()
+        );
+    }
+    #[test]
+    fn test_ping_methods_icmp_for() {
+        let ipv4: IpAddr = TEST_IPV4_LOCAL.into();
+        let ipv6: IpAddr = TEST_IPV6_LOCAL.into();
+        assert_eq!(PingMethods::icmp_for(ipv4), PingMethods::Icmp);
+        assert_eq!(PingMethods::icmp_for(ipv6), PingMethods::Icmpv6);
+        assert!(PingMethods::icmp_for(ipv4).supports(&ipv4));
+        assert!(PingMethods::icmp_for(ipv6).supports(&ipv6));
+        assert!(!PingMethods::Icmp.supports(&ipv6));
+        assert!(!PingMethods::Icmpv6.supports(&ipv4));
+        assert!(PingMethods::Syn.supports(&ipv4) && PingMethods::Syn.supports(&ipv6));
+    }
+    #[test]
     fn test_tcp_syn_ping() {
         // use crate::Logger;
         // Logger::init_debug_logging().unwrap();
@@ -762,4 +1857,49 @@ mod tests {
             // std::thread::sleep(Duration::new(1, 0));
         }
     }
+    #[test]
+    fn test_icmp_accept_set() {
+        let accept = IcmpAcceptSet::default();
+        assert!(accept.accepts(0, 0));
+        assert!(!accept.accepts(0, 1));
+        assert!(!accept.accepts(11, 0));
+        // the time exceeded in transit and the host unreachable of the last router
+        let mut accept = IcmpAcceptSet::new(&[(0, 0), (11, 0)]);
+        accept.insert(3, 1);
+        assert!(accept.accepts(11, 0));
+        assert!(accept.accepts(3, 1));
+        assert!(!accept.accepts(11, 1));
+        assert!(!accept.accepts(3, 3));
+    }
+    #[test]
+    fn test_loopback_ping() {
+        // no raw sockets needed for the loopback address
+        for dst_addr in [
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ] {
+            let target = Target::new(vec![Host::new(dst_addr, None)]);
+            let timeout = Some(Duration::new(1, 0));
+            let ret = tcp_syn_ping(target.clone(), None, None, timeout, 1).unwrap();
+            assert_eq!(ret.get_ping_status(&dst_addr), Some(vec![PingStatus::Up]));
+            let ret = icmp_ping(target, None, None, timeout, 1).unwrap();
+            assert_eq!(ret.get_ping_status(&dst_addr), Some(vec![PingStatus::Up]));
+            let (ping_status, _) = tcp_ack_ping_raw(dst_addr, 80, None, None, timeout).unwrap();
+            assert_eq!(ping_status, PingStatus::Up);
+        }
+    }
+    #[test]
+    fn test_ping_clock() {
+        // the probes run on the pool threads, the clock is given to them by the options
+        let dst_addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let target = Target::new(vec![Host::new(dst_addr, None)]);
+        let clock = Arc::new(crate::MockClock::new(Duration::from_millis(25)));
+        let options = PingOptions::default()
+            .with_timeout(Duration::new(1, 0))
+            .with_clock(clock);
+        let ret = ping_with_options(target, PingMethods::Syn, &options).unwrap();
+        let hpr = &ret.pings[&dst_addr][0];
+        assert_eq!(hpr.ping_status, PingStatus::Up);
+        assert_eq!(hpr.ping_time_cost, Duration::from_millis(25));
+    }
 }
@@ -1,7 +1,8 @@
 use chrono::Utc;
+#[cfg(target_os = "linux")]
+use log::warn;
 use pnet::packet::icmp;
 use pnet::packet::icmp::destination_unreachable;
-use pnet::packet::icmp::echo_reply;
 use pnet::packet::icmp::echo_request::MutableEchoRequestPacket;
 use pnet::packet::icmp::IcmpCode;
 use pnet::packet::icmp::IcmpPacket;
@@ -15,9 +16,29 @@ use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::ipv4::MutableIpv4Packet;
 use pnet::packet::Packet;
 use rand::Rng;
+#[cfg(target_os = "linux")]
+use socket2::Domain;
+#[cfg(target_os = "linux")]
+use socket2::Protocol;
+#[cfg(target_os = "linux")]
+use socket2::SockAddr;
+#[cfg(target_os = "linux")]
+use socket2::Socket;
+#[cfg(target_os = "linux")]
+use socket2::Type;
 
+#[cfg(target_os = "linux")]
+use pnet::packet::icmp::echo_reply::EchoReplyPacket;
+#[cfg(target_os = "linux")]
+use std::io::ErrorKind;
+#[cfg(target_os = "linux")]
+use std::io::Read;
 use std::net::Ipv4Addr;
+#[cfg(target_os = "linux")]
+use std::net::SocketAddr;
 use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::time::Instant;
 
 use crate::errors::PistolErrors;
 use crate::layers::layer3_ipv4_send;
@@ -26,16 +47,107 @@ use crate::layers::Layer4MatchIcmp;
 use crate::layers::LayersMatch;
 use crate::layers::ICMP_HEADER_SIZE;
 use crate::layers::IPV4_HEADER_SIZE;
+use crate::ping::IcmpAcceptSet;
 use crate::ping::PingStatus;
 
 const TTL: u8 = 64;
+const ICMP_DATA_SIZE: usize = 16;
 
-pub fn send_icmp_ping_packet(
+fn icmp_timestamp() -> Vec<u8> {
+    let mut tv_sec = Utc::now().timestamp().to_be_bytes();
+    tv_sec.reverse(); // Big-Endian
+    let mut tv_usec = Utc::now().timestamp_subsec_millis().to_be_bytes();
+    tv_usec.reverse(); // Big-Endian
+    let mut timestamp = Vec::new();
+    timestamp.extend(tv_sec);
+    timestamp.extend(tv_usec);
+    timestamp
+}
+
+/// Whether the raw icmp socket can be created (root or CAP_NET_RAW).
+#[cfg(target_os = "linux")]
+fn raw_socket_available() -> bool {
+    Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).is_ok()
+}
+
+/// The unprivileged icmp socket, same as the `ping` binary without CAP_NET_RAW,
+/// it only works when the gid of the process is in `net.ipv4.ping_group_range`.
+#[cfg(target_os = "linux")]
+pub fn dgram_icmp_socket() -> Result<Socket, PistolErrors> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?;
+    Ok(socket)
+}
+
+/// ICMP ping over the datagram icmp socket.
+/// The kernel sets the identifier and checksum of the echo request and
+/// only delivers the echo replies which belong to this socket.
+#[cfg(target_os = "linux")]
+pub fn send_icmp_ping_packet_dgram(
+    socket: Socket,
     src_ipv4: Ipv4Addr,
     dst_ipv4: Ipv4Addr,
     timeout: Duration,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
-    const ICMP_DATA_SIZE: usize = 16;
+    let mut rng = rand::thread_rng();
+    let mut icmp_buff = [0u8; ICMP_HEADER_SIZE + ICMP_DATA_SIZE];
+    let mut icmp_header = MutableEchoRequestPacket::new(&mut icmp_buff).unwrap();
+    icmp_header.set_icmp_type(IcmpTypes::EchoRequest);
+    icmp_header.set_icmp_code(IcmpCode(0));
+    let sequence_number = rng.gen();
+    icmp_header.set_sequence_number(sequence_number);
+    icmp_header.set_payload(&icmp_timestamp());
+
+    let src_addr = SocketAddr::new(src_ipv4.into(), 0);
+    if !src_ipv4.is_unspecified() {
+        socket.bind(&SockAddr::from(src_addr))?;
+    }
+    socket.set_ttl_v4(TTL as u32)?;
+    let dst_addr = SocketAddr::new(dst_ipv4.into(), 0);
+    let start_time = Instant::now();
+    socket.send_to(&icmp_buff, &SockAddr::from(dst_addr))?;
+
+    let mut recv_buff = [0u8; 1024];
+    loop {
+        let remaining = timeout.saturating_sub(start_time.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        // the datagram icmp socket returns the icmp packet without ip header
+        let n = match (&socket).read(&mut recv_buff) {
+            Ok(n) => n,
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => break,
+                _ => return Err(e.into()),
+            },
+        };
+        match EchoReplyPacket::new(&recv_buff[..n]) {
+            Some(reply)
+                if reply.get_icmp_type() == IcmpTypes::EchoReply
+                    && reply.get_sequence_number() == sequence_number =>
+            {
+                return Ok((PingStatus::Up, start_time.elapsed()));
+            }
+            _ => (),
+        }
+    }
+    // no response received
+    Ok((PingStatus::Down, start_time.elapsed()))
+}
+
+/// The error message is about the probe to `dst_ipv4`, the original ip header follows the 8 bytes icmp header.
+fn about_probe(icmp_packet: &IcmpPacket, dst_ipv4: Ipv4Addr) -> bool {
+    match icmp_packet.payload().get(4..).and_then(Ipv4Packet::new) {
+        Some(original) => original.get_destination() == dst_ipv4,
+        None => false,
+    }
+}
+
+pub(crate) fn build_echo_request(
+    src_ipv4: Ipv4Addr,
+    dst_ipv4: Ipv4Addr,
+    dont_fragment: bool,
+) -> [u8; IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + ICMP_DATA_SIZE] {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + ICMP_DATA_SIZE];
@@ -47,7 +159,11 @@ pub fn send_icmp_ping_packet(
     ip_header.set_total_length((IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + ICMP_DATA_SIZE) as u16);
     let id = rng.gen();
     ip_header.set_identification(id);
-    ip_header.set_flags(Ipv4Flags::DontFragment);
+    if dont_fragment {
+        ip_header.set_flags(Ipv4Flags::DontFragment);
+    } else {
+        ip_header.set_flags(0);
+    }
     ip_header.set_ttl(TTL);
     ip_header.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
     let c = ipv4::checksum(&ip_header.to_immutable());
@@ -58,19 +174,32 @@ pub fn send_icmp_ping_packet(
     icmp_header.set_icmp_code(IcmpCode(0));
     icmp_header.set_sequence_number(1);
     icmp_header.set_identifier(rng.gen());
-    let mut tv_sec = Utc::now().timestamp().to_be_bytes();
-    tv_sec.reverse(); // Big-Endian
-    let mut tv_usec = Utc::now().timestamp_subsec_millis().to_be_bytes();
-    tv_usec.reverse(); // Big-Endian
-    let mut timestamp = Vec::new();
-    timestamp.extend(tv_sec);
-    timestamp.extend(tv_usec);
-    // println!("{:?}", timestamp);
-    icmp_header.set_payload(&timestamp);
+    icmp_header.set_payload(&icmp_timestamp());
 
     let mut icmp_header = MutableIcmpPacket::new(&mut ip_buff[IPV4_HEADER_SIZE..]).unwrap();
     let checksum = icmp::checksum(&icmp_header.to_immutable());
     icmp_header.set_checksum(checksum);
+    ip_buff
+}
+
+/// The datagram icmp socket only returns the echo reply, the `icmp_accept` and the `dont_fragment` work with the raw socket.
+pub fn send_icmp_ping_packet(
+    src_ipv4: Ipv4Addr,
+    dst_ipv4: Ipv4Addr,
+    dont_fragment: bool,
+    icmp_accept: &IcmpAcceptSet,
+    timeout: Duration,
+) -> Result<(PingStatus, Duration), PistolErrors> {
+    #[cfg(target_os = "linux")]
+    if !raw_socket_available() {
+        match dgram_icmp_socket() {
+            Ok(socket) => {
+                return send_icmp_ping_packet_dgram(socket, src_ipv4, dst_ipv4, timeout);
+            }
+            Err(e) => warn!("create datagram icmp socket failed, use raw socket: {}", e),
+        }
+    }
+    let ip_buff = build_echo_request(src_ipv4, dst_ipv4, dont_fragment);
 
     let codes_1 = vec![
         destination_unreachable::IcmpCodes::DestinationProtocolUnreachable, // 2
@@ -91,9 +220,25 @@ pub fn send_icmp_ping_packet(
         types: None,
         codes: None,
     };
-    let layers_match = LayersMatch::Layer4MatchIcmp(layer4_icmp);
+    let mut layers_match = vec![LayersMatch::Layer4MatchIcmp(layer4_icmp)];
+    // the accepted time exceeded and unreachable messages may come from the devices on the path
+    for (icmp_type, icmp_code) in &icmp_accept.pairs {
+        if *icmp_type != IcmpTypes::EchoReply.0 {
+            let layer3 = Layer3Match {
+                layer2: None,
+                src_addr: None,
+                dst_addr: Some(src_ipv4.into()),
+            };
+            let layer4_icmp = Layer4MatchIcmp {
+                layer3: Some(layer3),
+                types: Some(IcmpType(*icmp_type)),
+                codes: Some(IcmpCode(*icmp_code)),
+            };
+            layers_match.push(LayersMatch::Layer4MatchIcmp(layer4_icmp));
+        }
+    }
 
-    let (ret, rtt) = layer3_ipv4_send(src_ipv4, dst_ipv4, &ip_buff, vec![layers_match], timeout)?;
+    let (ret, rtt) = layer3_ipv4_send(src_ipv4, dst_ipv4, &ip_buff, layers_match, timeout)?;
     match Ipv4Packet::new(&ret) {
         Some(ipv4_packet) => {
             match ipv4_packet.get_next_level_protocol() {
@@ -102,19 +247,18 @@ pub fn send_icmp_ping_packet(
                         Some(icmp_packet) => {
                             let icmp_type = icmp_packet.get_icmp_type();
                             let icmp_code = icmp_packet.get_icmp_code();
+                            let from_host = ipv4_packet.get_source() == dst_ipv4;
+                            if icmp_accept.accepts(icmp_type.0, icmp_code.0)
+                                && (from_host || about_probe(&icmp_packet, dst_ipv4))
+                            {
+                                return Ok((PingStatus::Up, rtt));
+                            }
 
-                            let codes_2 = vec![
-                                echo_reply::IcmpCodes::NoCode, // 0
-                            ];
                             if icmp_type == IcmpTypes::DestinationUnreachable {
                                 if codes_1.contains(&icmp_code) {
                                     // icmp protocol unreachable error (type 3, code 2)
                                     return Ok((PingStatus::Down, rtt));
                                 }
-                            } else if icmp_type == IcmpTypes::EchoReply {
-                                if codes_2.contains(&icmp_code) {
-                                    return Ok((PingStatus::Up, rtt));
-                                }
                             }
                         }
                         None => (),
@@ -1,6 +1,10 @@
 use chrono::Utc;
+#[cfg(target_os = "linux")]
+use log::warn;
 use pnet::packet::icmpv6;
 use pnet::packet::icmpv6::echo_reply;
+#[cfg(target_os = "linux")]
+use pnet::packet::icmpv6::echo_reply::EchoReplyPacket;
 use pnet::packet::icmpv6::echo_request::MutableEchoRequestPacket;
 use pnet::packet::icmpv6::Icmpv6Code;
 use pnet::packet::icmpv6::Icmpv6Packet;
@@ -12,8 +16,26 @@ use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::ipv6::MutableIpv6Packet;
 use pnet::packet::Packet;
 use rand::Rng;
+#[cfg(target_os = "linux")]
+use socket2::Domain;
+#[cfg(target_os = "linux")]
+use socket2::Protocol;
+#[cfg(target_os = "linux")]
+use socket2::SockAddr;
+#[cfg(target_os = "linux")]
+use socket2::Socket;
+#[cfg(target_os = "linux")]
+use socket2::Type;
+#[cfg(target_os = "linux")]
+use std::io::ErrorKind;
+#[cfg(target_os = "linux")]
+use std::io::Read;
 use std::net::Ipv6Addr;
+#[cfg(target_os = "linux")]
+use std::net::SocketAddrV6;
 use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::time::Instant;
 
 use crate::errors::PistolErrors;
 use crate::layers::layer3_ipv6_send;
@@ -23,15 +45,126 @@ use crate::layers::LayersMatch;
 use crate::layers::ICMPV6_ER_HEADER_SIZE;
 use crate::layers::IPV6_HEADER_SIZE;
 use crate::ping::PingStatus;
+#[cfg(target_os = "linux")]
+use crate::utils::find_interface_by_ip;
 
 const TTL: u8 = 255;
+const ICMPV6_DATA_SIZE: usize = 16;
+
+fn icmpv6_timestamp() -> Vec<u8> {
+    let mut tv_sec = Utc::now().timestamp().to_be_bytes();
+    tv_sec.reverse(); // Big-Endian
+    let mut tv_usec = Utc::now().timestamp_subsec_millis().to_be_bytes();
+    tv_usec.reverse(); // Big-Endian
+    let mut timestamp = Vec::new();
+    timestamp.extend(tv_sec);
+    timestamp.extend(tv_usec);
+    timestamp
+}
+
+/// Whether the raw icmpv6 socket can be created (root or CAP_NET_RAW).
+#[cfg(target_os = "linux")]
+pub(crate) fn raw_socket_available() -> bool {
+    Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6)).is_ok()
+}
+
+/// The unprivileged icmpv6 socket, it shares `net.ipv4.ping_group_range` with ipv4.
+#[cfg(target_os = "linux")]
+pub fn dgram_icmpv6_socket() -> Result<Socket, PistolErrors> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6))?;
+    Ok(socket)
+}
+
+/// The link-local address is only meaningful together with the zone,
+/// which is the index of the interface that owns the source address.
+#[cfg(target_os = "linux")]
+fn icmpv6_scope_id(src_ipv6: Ipv6Addr, dst_ipv6: Ipv6Addr) -> Result<u32, PistolErrors> {
+    if dst_ipv6.is_unicast_link_local() {
+        match find_interface_by_ip(src_ipv6.into()) {
+            Some(i) => Ok(i.index),
+            None => Err(PistolErrors::CanNotFoundInterface),
+        }
+    } else {
+        Ok(0)
+    }
+}
+
+/// ICMPv6 ping over the datagram icmpv6 socket.
+/// The kernel sets the identifier and checksum of the echo request and
+/// only delivers the echo replies which belong to this socket.
+#[cfg(target_os = "linux")]
+pub fn send_icmpv6_ping_packet_dgram(
+    socket: Socket,
+    src_ipv6: Ipv6Addr,
+    dst_ipv6: Ipv6Addr,
+    hop_limit: u8,
+    timeout: Duration,
+) -> Result<(PingStatus, Duration), PistolErrors> {
+    let mut rng = rand::thread_rng();
+    let mut icmpv6_buff = [0u8; ICMPV6_ER_HEADER_SIZE + ICMPV6_DATA_SIZE];
+    let mut icmpv6_header = MutableEchoRequestPacket::new(&mut icmpv6_buff).unwrap();
+    icmpv6_header.set_icmpv6_type(Icmpv6Types::EchoRequest);
+    icmpv6_header.set_icmpv6_code(Icmpv6Code(0));
+    let sequence_number = rng.gen();
+    icmpv6_header.set_sequence_number(sequence_number);
+    icmpv6_header.set_payload(&icmpv6_timestamp());
+
+    let scope_id = icmpv6_scope_id(src_ipv6, dst_ipv6)?;
+    if !src_ipv6.is_unspecified() {
+        let src_addr = SocketAddrV6::new(src_ipv6, 0, 0, scope_id);
+        socket.bind(&SockAddr::from(src_addr))?;
+    }
+    socket.set_unicast_hops_v6(hop_limit as u32)?;
+    let dst_addr = SocketAddrV6::new(dst_ipv6, 0, 0, scope_id);
+    let start_time = Instant::now();
+    socket.send_to(&icmpv6_buff, &SockAddr::from(dst_addr))?;
+
+    let mut recv_buff = [0u8; 1024];
+    loop {
+        let remaining = timeout.saturating_sub(start_time.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        // the datagram icmpv6 socket returns the icmpv6 packet without ipv6 header
+        let n = match (&socket).read(&mut recv_buff) {
+            Ok(n) => n,
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => break,
+                _ => return Err(e.into()),
+            },
+        };
+        match EchoReplyPacket::new(&recv_buff[..n]) {
+            Some(reply)
+                if reply.get_icmpv6_type() == Icmpv6Types::EchoReply
+                    && reply.get_sequence_number() == sequence_number =>
+            {
+                return Ok((PingStatus::Up, start_time.elapsed()));
+            }
+            _ => (),
+        }
+    }
+    // no response received
+    Ok((PingStatus::Down, start_time.elapsed()))
+}
 
 pub fn send_icmpv6_ping_packet(
     src_ipv6: Ipv6Addr,
     dst_ipv6: Ipv6Addr,
     timeout: Duration,
 ) -> Result<(PingStatus, Duration), PistolErrors> {
-    const ICMPV6_DATA_SIZE: usize = 16;
+    #[cfg(target_os = "linux")]
+    if !raw_socket_available() {
+        match dgram_icmpv6_socket() {
+            Ok(socket) => {
+                return send_icmpv6_ping_packet_dgram(socket, src_ipv6, dst_ipv6, TTL, timeout);
+            }
+            Err(e) => warn!(
+                "create datagram icmpv6 socket failed, use raw socket: {}",
+                e
+            ),
+        }
+    }
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + ICMPV6_ER_HEADER_SIZE + ICMPV6_DATA_SIZE];
@@ -53,15 +186,7 @@ pub fn send_icmpv6_ping_packet(
     icmpv6_header.set_icmpv6_code(Icmpv6Code(0));
     icmpv6_header.set_sequence_number(1);
     icmpv6_header.set_identifier(rng.gen());
-    let mut tv_sec = Utc::now().timestamp().to_be_bytes();
-    tv_sec.reverse(); // Big-Endian
-    let mut tv_usec = Utc::now().timestamp_subsec_millis().to_be_bytes();
-    tv_usec.reverse(); // Big-Endian
-    let mut timestamp = Vec::new();
-    timestamp.extend(tv_sec);
-    timestamp.extend(tv_usec);
-    // println!("{:?}", timestamp);
-    icmpv6_header.set_payload(&timestamp);
+    icmpv6_header.set_payload(&icmpv6_timestamp());
 
     let mut icmp_header = MutableIcmpv6Packet::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
     let checksum = icmpv6::checksum(&icmp_header.to_immutable(), &src_ipv6, &dst_ipv6);
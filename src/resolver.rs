@@ -0,0 +1,388 @@
+use log::debug;
+use rand::Rng;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::errors::PistolErrors;
+use crate::layers::dns_query_timeout;
+use crate::layers::dns_reverse_query_timeout;
+use crate::utils::get_default_timeout;
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_RCODE_NXDOMAIN: u8 = 3;
+const DNS_BUFF_SIZE: usize = 4096;
+/// The compression pointers followed in one name at most, guards against the pointer loops.
+const DNS_MAX_POINTERS: usize = 16;
+
+/// Where the dns queries of the target resolution and the reverse dns go.
+pub trait Resolver {
+    /// The addresses of the hostname.
+    fn lookup(
+        &self,
+        hostname: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<IpAddr>, PistolErrors>;
+    /// The name of the address, None if it has no name.
+    fn reverse(
+        &self,
+        addr: IpAddr,
+        timeout: Option<Duration>,
+    ) -> Result<Option<String>, PistolErrors>;
+}
+
+/// The resolver of the operating system, the same one `dns_query` uses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup(
+        &self,
+        hostname: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<IpAddr>, PistolErrors> {
+        dns_query_timeout(hostname, timeout)
+    }
+    fn reverse(
+        &self,
+        addr: IpAddr,
+        timeout: Option<Duration>,
+    ) -> Result<Option<String>, PistolErrors> {
+        dns_reverse_query_timeout(addr, timeout)
+    }
+}
+
+/// Sends the dns queries to the given server over udp, nothing goes to the system resolver.
+/// ```rust
+/// use pistol::DnsServerResolver;
+/// use pistol::Resolver;
+/// use std::time::Duration;
+///
+/// fn test() {
+///     let resolver = DnsServerResolver::new("9.9.9.9:53".parse().unwrap());
+///     let addrs = resolver.lookup("scanme.nmap.org", Some(Duration::from_secs(3))).unwrap();
+///     println!("{:?}", addrs);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct DnsServerResolver {
+    server: SocketAddr,
+}
+
+impl DnsServerResolver {
+    pub fn new(server: SocketAddr) -> DnsServerResolver {
+        DnsServerResolver { server }
+    }
+    /// Sends one query and returns the rdata of the answers of the type.
+    fn query(
+        &self,
+        name: &str,
+        qtype: u16,
+        timeout: Duration,
+    ) -> Result<Vec<Vec<u8>>, PistolErrors> {
+        let bind_addr: IpAddr = match self.server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0))?;
+        let id: u16 = rand::thread_rng().gen();
+        let request = dns_request(id, name, qtype)?;
+        socket.send_to(&request, self.server)?;
+
+        let start_time = Instant::now();
+        let mut buff = [0u8; DNS_BUFF_SIZE];
+        loop {
+            let remaining = timeout.saturating_sub(start_time.elapsed());
+            if remaining.is_zero() {
+                return Err(PistolErrors::DnsQueryTimeout {
+                    hostname: name.to_string(),
+                });
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let (n, src_addr) = match socket.recv_from(&mut buff) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            // the late response of an earlier query has another id
+            if src_addr != self.server || n < 12 || u16_at(&buff, 0) != id {
+                debug!("drop the dns response from {}", src_addr);
+                continue;
+            }
+            let response = &buff[..n];
+            let rcode = response[3] & 0x0f;
+            return match rcode {
+                0 => dns_answers(response, qtype).ok_or(PistolErrors::InvalidDnsResponse {
+                    server: self.server,
+                }),
+                DNS_RCODE_NXDOMAIN => Ok(Vec::new()),
+                _ => Err(PistolErrors::DnsQueryFailed {
+                    server: self.server,
+                    name: name.to_string(),
+                    rcode,
+                }),
+            };
+        }
+    }
+}
+
+impl Resolver for DnsServerResolver {
+    fn lookup(
+        &self,
+        hostname: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<IpAddr>, PistolErrors> {
+        // like the system resolver, the address needs no query
+        if let Ok(addr) = hostname.parse::<IpAddr>() {
+            return Ok(vec![addr]);
+        }
+        let timeout = timeout.unwrap_or(get_default_timeout());
+        let mut addrs: Vec<IpAddr> = Vec::new();
+        for rdata in self.query(hostname, DNS_TYPE_A, timeout)? {
+            if let Ok(octets) = <[u8; 4]>::try_from(rdata.as_slice()) {
+                addrs.push(Ipv4Addr::from(octets).into());
+            }
+        }
+        for rdata in self.query(hostname, DNS_TYPE_AAAA, timeout)? {
+            if let Ok(octets) = <[u8; 16]>::try_from(rdata.as_slice()) {
+                addrs.push(Ipv6Addr::from(octets).into());
+            }
+        }
+        Ok(addrs)
+    }
+    fn reverse(
+        &self,
+        addr: IpAddr,
+        timeout: Option<Duration>,
+    ) -> Result<Option<String>, PistolErrors> {
+        let timeout = timeout.unwrap_or(get_default_timeout());
+        let name = reverse_name(addr);
+        // the rdata of the PTR record is a name which may point back into the message,
+        // so the names are read in `dns_answers`
+        match self.query(&name, DNS_TYPE_PTR, timeout)?.into_iter().next() {
+            Some(rdata) => match String::from_utf8(rdata) {
+                Ok(name) => Ok(Some(name)),
+                Err(_) => Err(PistolErrors::InvalidDnsResponse {
+                    server: self.server,
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+fn u16_at(buff: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buff[offset], buff[offset + 1]])
+}
+
+/// The name of the PTR record of the address, e.g. `4.3.2.1.in-addr.arpa` for 1.2.3.4.
+fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(a) => {
+            let o = a.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(a) => {
+            let mut labels = Vec::new();
+            for b in a.octets().iter().rev() {
+                labels.push(format!("{:x}", b & 0x0f));
+                labels.push(format!("{:x}", b >> 4));
+            }
+            format!("{}.ip6.arpa", labels.join("."))
+        }
+    }
+}
+
+/// The standard query with recursion desired.
+fn dns_request(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, PistolErrors> {
+    let mut request = Vec::new();
+    request.extend(id.to_be_bytes());
+    request.extend([0x01, 0x00]); // flags: recursion desired
+    request.extend([0x00, 0x01]); // questions
+    request.extend([0x00; 6]); // answer, authority and additional rrs
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(PistolErrors::InvalidDnsName {
+                name: name.to_string(),
+            });
+        }
+        request.push(label.len() as u8);
+        request.extend(label.as_bytes());
+    }
+    request.push(0);
+    request.extend(qtype.to_be_bytes());
+    request.extend(DNS_CLASS_IN.to_be_bytes());
+    Ok(request)
+}
+
+/// Reads the (maybe compressed) name at the offset, returns the name and the offset after it.
+fn dns_read_name(buff: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    // the offset after the name, where the first pointer is
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *buff.get(offset)? as usize;
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > DNS_MAX_POINTERS {
+                return None;
+            }
+            let pointer = (len & 0x3f) << 8 | *buff.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = pointer;
+        } else if len == 0 {
+            let end = end.unwrap_or(offset + 1);
+            return Some((labels.join("."), end));
+        } else {
+            let label = buff.get(offset + 1..offset + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_string());
+            offset += 1 + len;
+        }
+    }
+}
+
+/// The rdata of the answers of the type, the names of the PTR records are decompressed.
+/// The CNAME records in front of the addresses are skipped.
+fn dns_answers(response: &[u8], qtype: u16) -> Option<Vec<Vec<u8>>> {
+    let questions = u16_at(response, 4);
+    let answers = u16_at(response, 6);
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (_, o) = dns_read_name(response, offset)?;
+        offset = o + 4;
+    }
+    let mut ret = Vec::new();
+    for _ in 0..answers {
+        let (_, o) = dns_read_name(response, offset)?;
+        let header = response.get(o..o + 10)?;
+        let rtype = u16_at(header, 0);
+        let rdlength = u16_at(header, 8) as usize;
+        let rdata_offset = o + 10;
+        let rdata = response.get(rdata_offset..rdata_offset + rdlength)?;
+        if rtype == qtype {
+            match rtype {
+                DNS_TYPE_PTR => {
+                    let (name, _) = dns_read_name(response, rdata_offset)?;
+                    ret.push(name.into_bytes());
+                }
+                _ => ret.push(rdata.to_vec()),
+            }
+        }
+        offset = rdata_offset + rdlength;
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Host;
+    use crate::Target;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+    /// Answers pistol.test and the PTR of 10.1.2.3, any other name does not exist.
+    fn mock_dns_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        thread::spawn(move || {
+            let mut buff = [0u8; DNS_BUFF_SIZE];
+            loop {
+                let (n, src_addr) = socket.recv_from(&mut buff).unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let request = &buff[..n];
+                let (name, offset) = dns_read_name(request, 12).unwrap();
+                let qtype = u16_at(request, offset);
+                let rdata: Option<Vec<u8>> = match (name.as_str(), qtype) {
+                    ("pistol.test", DNS_TYPE_A) => Some(vec![10, 1, 2, 3]),
+                    ("pistol.test", DNS_TYPE_AAAA) => {
+                        Some("fd00::1".parse::<Ipv6Addr>().unwrap().octets().to_vec())
+                    }
+                    ("3.2.1.10.in-addr.arpa", DNS_TYPE_PTR) => None,
+                    _ => {
+                        let mut response = request[..offset + 4].to_vec();
+                        response[2] = 0x81;
+                        response[3] = 0x80 | DNS_RCODE_NXDOMAIN;
+                        socket.send_to(&response, src_addr).unwrap();
+                        continue;
+                    }
+                };
+                let mut response = request[..offset + 4].to_vec();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[7] = 1; // answers
+                                 // a CNAME in front of the address, the resolver skips it
+                if qtype == DNS_TYPE_A {
+                    response[7] = 2;
+                    response.extend([0xc0, 0x0c]);
+                    response.extend([0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x02]);
+                    response.extend([0xc0, 0x0c]);
+                }
+                response.extend([0xc0, 0x0c]);
+                response.extend(qtype.to_be_bytes());
+                response.extend([0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+                let rdata = match rdata {
+                    Some(r) => r,
+                    None => b"\x04host\x06pistol\x04test\x00".to_vec(),
+                };
+                response.extend((rdata.len() as u16).to_be_bytes());
+                response.extend(rdata);
+                socket.send_to(&response, src_addr).unwrap();
+            }
+        });
+        (server, queries)
+    }
+    #[test]
+    fn test_dns_server_resolver() {
+        let (server, queries) = mock_dns_server();
+        let resolver = DnsServerResolver::new(server);
+        let timeout = Some(Duration::from_secs(1));
+        let addrs = resolver.lookup("pistol.test", timeout).unwrap();
+        let expect: Vec<IpAddr> = vec![
+            Ipv4Addr::new(10, 1, 2, 3).into(),
+            "fd00::1".parse::<Ipv6Addr>().unwrap().into(),
+        ];
+        assert_eq!(addrs, expect);
+        assert!(resolver.lookup("missing.test", timeout).unwrap().is_empty());
+        let name = resolver.reverse(Ipv4Addr::new(10, 1, 2, 3).into(), timeout);
+        assert_eq!(name.unwrap(), Some(String::from("host.pistol.test")));
+        let name = resolver.reverse(Ipv4Addr::new(10, 1, 2, 4).into(), timeout);
+        assert_eq!(name.unwrap(), None);
+        // both the A and the AAAA records of the missing name are queried
+        assert_eq!(queries.load(Ordering::Relaxed), 6);
+
+        // the target resolution and the reverse dns go to the server
+        let mut target = Target::new(vec![Host::new(Ipv4Addr::new(10, 1, 2, 4).into(), None)]);
+        target.push_hostname("pistol.test", Some(vec![80]));
+        target.resolve_all_with(&resolver, timeout).unwrap();
+        assert_eq!(target.resolved("pistol.test").unwrap(), expect.as_slice());
+        assert_eq!(target.len(), 3);
+        let names = target.reverse_all(&resolver, timeout).unwrap();
+        let mut expect_names = HashMap::new();
+        expect_names.insert(expect[0], String::from("host.pistol.test"));
+        assert_eq!(names, expect_names);
+    }
+    #[test]
+    fn test_reverse_name() {
+        assert_eq!(
+            reverse_name(Ipv4Addr::new(1, 2, 3, 4).into()),
+            "4.3.2.1.in-addr.arpa"
+        );
+        let name = reverse_name("2001:db8::567:89ab".parse().unwrap());
+        assert_eq!(
+            name,
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+}
@@ -1,17 +1,24 @@
 use log::debug;
 use log::warn;
-#[cfg(target_os = "windows")]
-use pnet::datalink::interfaces;
 use pnet::datalink::MacAddr;
 use pnet::datalink::NetworkInterface;
 use pnet::ipnetwork::IpNetwork;
 use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::marker::PhantomData;
 use std::net::IpAddr;
 use std::process::Command;
+use std::process::Stdio;
 use std::str::FromStr;
+use std::thread;
 
 // use crate::errors::InvalidRouteFormat;
 use crate::errors::PistolErrors;
@@ -23,12 +30,86 @@ use crate::errors::PistolErrors;
     target_os = "linux"
 ))]
 use crate::utils::find_interface_by_name;
+#[cfg(target_os = "windows")]
+use crate::utils::interfaces;
+
+/// Runs the system commands which the route table and the neighbor cache are parsed from.
+pub trait CommandRunner {
+    fn run(&self, cmd: &str) -> Result<String, PistolErrors>;
+    /// Passes every line of the output to `f` as soon as it is read,
+    /// the default one splits the whole output of `run`.
+    fn run_lines(&self, cmd: &str, f: &mut dyn FnMut(&str)) -> Result<(), PistolErrors> {
+        for line in self.run(cmd)?.lines() {
+            f(line);
+        }
+        Ok(())
+    }
+}
+
+/// Default runner, uses `sh -c` on unix and `powershell` on windows.
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run(&self, cmd: &str) -> Result<String, PistolErrors> {
+        #[cfg(target_os = "windows")]
+        let c = Command::new("powershell").args([cmd]).output()?;
+        #[cfg(not(target_os = "windows"))]
+        let c = Command::new("sh").args(["-c", cmd]).output()?;
+        if !c.status.success() {
+            return Err(PistolErrors::SystemCommandFailed {
+                cmd: cmd.to_string(),
+                stderr: String::from_utf8_lossy(&c.stderr).trim().to_string(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&c.stdout).to_string())
+    }
+    fn run_lines(&self, cmd: &str, f: &mut dyn FnMut(&str)) -> Result<(), PistolErrors> {
+        #[cfg(target_os = "windows")]
+        let mut command = Command::new("powershell");
+        #[cfg(target_os = "windows")]
+        command.args([cmd]);
+        #[cfg(not(target_os = "windows"))]
+        let mut command = Command::new("sh");
+        #[cfg(not(target_os = "windows"))]
+        command.args(["-c", cmd]);
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // the command blocks when the stderr pipe is full, so it is drained while the stdout is read
+        let stderr_reader = child.stderr.take().map(|mut e| {
+            thread::spawn(move || {
+                let mut stderr = Vec::new();
+                let _ = e.read_to_end(&mut stderr);
+                stderr
+            })
+        });
+        if let Some(stdout) = child.stdout.take() {
+            for line in BufReader::new(stdout).split(b'\n') {
+                f(&String::from_utf8_lossy(&line?));
+            }
+        }
+        let status = child.wait()?;
+        let stderr = match stderr_reader {
+            Some(r) => r.join().unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if !status.success() {
+            return Err(PistolErrors::SystemCommandFailed {
+                cmd: cmd.to_string(),
+                stderr: String::from_utf8_lossy(&stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+}
 
 #[cfg(any(
     target_os = "macos",
     target_os = "freebsd",
     target_os = "openbsd",
     target_os = "netbsd",
+    test
 ))]
 fn ipv6_addr_bsd_fix(dst_str: &str) -> Result<String, PistolErrors> {
     // Remove the %em0 .etc
@@ -42,9 +123,9 @@ fn ipv6_addr_bsd_fix(dst_str: &str) -> Result<String, PistolErrors> {
                 let mask = caps.name("mask").map_or("", |m| m.as_str());
                 if dst_str.contains("/") {
                     let output = addr.to_string() + "/" + mask;
-                    return Ok(output);
+                    Ok(output)
                 } else {
-                    return Ok(addr.to_string());
+                    Ok(addr.to_string())
                 }
             }
             None => {
@@ -63,12 +144,69 @@ pub struct DefaultRoute {
     pub dev: NetworkInterface, // Device interface name
 }
 
+impl DefaultRoute {
+    /// The gateway and the name and index of the interface, without the rest of the interface.
+    pub fn summary(&self) -> DefaultRouteSummary {
+        DefaultRouteSummary {
+            via: self.via,
+            dev_name: self.dev.name.clone(),
+            dev_index: self.dev.index,
+        }
+    }
+}
+
+/// The light form of the `DefaultRoute` for logging and serialization.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct DefaultRouteSummary {
+    pub via: IpAddr,
+    pub dev_name: String,
+    pub dev_index: u32,
+}
+
+impl fmt::Display for DefaultRouteSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "default via {} dev {}", self.via, self.dev_name)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum RouteAddr {
     IpNetwork(IpNetwork),
     IpAddr(IpAddr),
 }
 
+impl RouteAddr {
+    /// The single address is the network with the full prefix (/32 or /128).
+    fn ip_network(&self) -> IpNetwork {
+        match self {
+            RouteAddr::IpNetwork(n) => *n,
+            RouteAddr::IpAddr(a) => IpNetwork::from(*a),
+        }
+    }
+    /// The network address, the address itself for the single address.
+    pub fn network(&self) -> IpAddr {
+        self.ip_network().network()
+    }
+    /// The broadcast address of the ipv4 network,
+    /// the /31 (point-to-point link, RFC 3021), the /32 and the ipv6 network have no broadcast.
+    pub fn broadcast(&self) -> Option<IpAddr> {
+        match self.ip_network() {
+            IpNetwork::V4(n) if n.prefix() < 31 => Some(n.broadcast().into()),
+            _ => None,
+        }
+    }
+    /// The host addresses of the network, the network and broadcast addresses of the ipv4 network are skipped.
+    pub fn hosts_iter(&self) -> impl Iterator<Item = IpAddr> {
+        let network = self.ip_network();
+        // the /31 and /32 have no network address to skip either
+        let skipped = self.broadcast().map(|b| (network.network(), b));
+        network.iter().filter(move |a| match skipped {
+            Some((n, b)) => *a != n && *a != b,
+            None => true,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RouteTable {
     pub default_route: Option<DefaultRoute>,
@@ -76,125 +214,135 @@ pub struct RouteTable {
     pub routes: HashMap<RouteAddr, NetworkInterface>,
 }
 
-impl RouteTable {
-    #[cfg(target_os = "linux")]
-    pub fn init() -> Result<RouteTable, PistolErrors> {
-        let system_route_lines = || -> Result<Vec<String>, PistolErrors> {
-            // Linux
-            // ubuntu22.04 output:
-            // default via 192.168.72.2 dev ens33
-            // 192.168.1.0/24 dev ens36 proto kernel scope link src 192.168.1.132
-            // 192.168.72.0/24 dev ens33 proto kernel scope link src 192.168.72.128
-            // centos7 output:
-            // default via 192.168.72.2 dev ens33 proto dhcp metric 100
-            // 192.168.72.0/24 dev ens33 proto kernel scope link src 192.168.72.138 metric 100
-            let c = Command::new("sh").args(["-c", "ip -4 route"]).output()?;
-            let ipv4_output = String::from_utf8_lossy(&c.stdout);
-            let c = Command::new("sh").args(["-c", "ip -6 route"]).output()?;
-            let ipv6_output = String::from_utf8_lossy(&c.stdout);
-            let output = ipv4_output.to_string() + &ipv6_output;
-            let lines: Vec<String> = output
-                .lines()
-                .map(|x| x.trim().to_string())
-                .filter(|v| v.len() > 0)
-                .collect();
-            Ok(lines)
-        };
-
-        let mut default_ipv4_route = None;
-        let mut default_ipv6_route = None;
-        let mut routes = HashMap::new();
-
-        // regex
-        let default_route_re =
-            Regex::new(r"default\s+via\s+(?P<via>.+)\s+dev\s+(?P<dev>\w+)\s+.+")?;
-        let route_re = Regex::new(r"(?P<subnet>.+/\d{1,2})\s+dev\s+(?P<dev>\w+)\s+.+")?;
-
-        for line in system_route_lines()? {
-            let default_route_judge = |line: &str| -> bool { line.contains("default") };
-            if default_route_judge(&line) {
-                match default_route_re.captures(&line) {
-                    Some(caps) => {
-                        let via_str = caps.name("via").map_or("", |m| m.as_str());
-                        let via: IpAddr = match via_str.parse() {
-                            Ok(v) => v,
-                            Err(e) => {
-                                warn!("parse route table 'via' error:  {e}");
-                                continue;
-                            }
-                        };
-                        let dev_str = caps.name("dev").map_or("", |m| m.as_str());
-                        let dev = match find_interface_by_name(dev_str) {
-                            Some(i) => i,
-                            None => {
-                                // return Err(InvalidRouteFormat::new(line.to_string()).into());
-                                warn!("invaild default route string: [{}]", line);
-                                continue; // not raise error here
-                            }
-                        };
-
-                        let mut is_ipv4 = true;
-                        if via_str.contains(":") {
-                            is_ipv4 = false;
-                        }
+/// One line of the system route table.
+/// The device is kept as the system prints it (name on unix, interface index on windows),
+/// it is resolved to the interface when the route table is built.
+#[derive(Debug, Clone, PartialEq)]
+enum RouteLine<D> {
+    Default { via: IpAddr, dev: D },
+    Route { dst: RouteAddr, dev: D },
+}
 
-                        let default_route = DefaultRoute { via, dev };
+/// Builds the route table line by line, only the routes overlapping the `prefixes` are kept
+/// (all of them without the prefixes) and the interface of every device is looked up once.
+struct RouteTableBuilder<'a, D, F> {
+    find_interface: F,
+    prefixes: Option<&'a [IpNetwork]>,
+    devs: HashMap<String, Option<NetworkInterface>>,
+    table: RouteTable,
+    _dev: PhantomData<D>,
+}
 
-                        if is_ipv4 {
-                            default_ipv4_route = Some(default_route);
-                        } else {
-                            default_ipv6_route = Some(default_route);
-                        }
+impl<'a, D: fmt::Display, F: Fn(&D) -> Option<NetworkInterface>> RouteTableBuilder<'a, D, F> {
+    fn new(find_interface: F, prefixes: Option<&'a [IpNetwork]>) -> Self {
+        RouteTableBuilder {
+            find_interface,
+            prefixes,
+            devs: HashMap::new(),
+            table: RouteTable {
+                default_route: None,
+                default_route6: None,
+                routes: HashMap::new(),
+            },
+            _dev: PhantomData,
+        }
+    }
+    fn find_dev(&mut self, dev: &D) -> Option<NetworkInterface> {
+        let find_interface = &self.find_interface;
+        self.devs
+            .entry(dev.to_string())
+            .or_insert_with(|| find_interface(dev))
+            .clone()
+    }
+    fn wanted(&self, dst: &RouteAddr) -> bool {
+        let prefixes = match self.prefixes {
+            Some(p) => p,
+            None => return true,
+        };
+        prefixes.iter().any(|prefix| match dst {
+            RouteAddr::IpAddr(addr) => prefix.contains(*addr),
+            RouteAddr::IpNetwork(network) => {
+                prefix.contains(network.network()) || network.contains(prefix.network())
+            }
+        })
+    }
+    fn push(&mut self, route_line: RouteLine<D>) {
+        match route_line {
+            RouteLine::Default { via, dev } => {
+                let dev = match self.find_dev(&dev) {
+                    Some(i) => i,
+                    None => {
+                        warn!("invaild default route dev: [{}]", dev);
+                        return; // not raise error here
                     }
-                    None => warn!("line: [{}] default_route_re no match", line),
+                };
+                let default_route = DefaultRoute { via, dev };
+                match via {
+                    IpAddr::V4(_) => self.table.default_route = Some(default_route),
+                    IpAddr::V6(_) => self.table.default_route6 = Some(default_route),
                 }
-            } else {
-                match route_re.captures(&line) {
-                    Some(caps) => {
-                        let dst_str = caps.name("subnet").map_or("", |m| m.as_str());
-                        let dst = if dst_str.contains("/") {
-                            let dst = match IpNetwork::from_str(dst_str) {
-                                Ok(d) => d,
-                                Err(e) => {
-                                    warn!("parse route table 'dst' error:  {e}");
-                                    continue;
-                                }
-                            };
-                            let dst = RouteAddr::IpNetwork(dst);
-                            dst
-                        } else {
-                            let dst: IpAddr = match dst_str.parse() {
-                                Ok(d) => d,
-                                Err(e) => {
-                                    warn!("parse route table 'dst' error:  {e}");
-                                    continue;
-                                }
-                            };
-                            let dst = RouteAddr::IpAddr(dst);
-                            dst
-                        };
-                        let dev_str = caps.name("dev").map_or("", |m| m.as_str());
-                        let dev = match find_interface_by_name(dev_str) {
-                            Some(i) => i,
-                            None => {
-                                // return Err(InvalidRouteFormat::new(line.to_string()).into());
-                                warn!("invaild route string: [{}]", line);
-                                continue; // not raise error here
-                            }
-                        };
-                        routes.insert(dst, dev);
-                    }
-                    None => warn!("line: [{}] route_re no match", line),
+            }
+            RouteLine::Route { dst, dev } => {
+                if !self.wanted(&dst) {
+                    return;
                 }
+                let dev = match self.find_dev(&dev) {
+                    Some(i) => i,
+                    None => {
+                        warn!("invaild route dev: [{}]", dev);
+                        return; // not raise error here
+                    }
+                };
+                self.table.routes.insert(dst, dev);
             }
         }
+    }
+    fn finish(self) -> RouteTable {
+        self.table
+    }
+}
 
-        let rt = RouteTable {
-            default_route: default_ipv4_route,
-            default_route6: default_ipv6_route,
-            routes,
+impl RouteTable {
+    #[cfg(any(not(target_os = "linux"), test))]
+    fn from_lines<D: fmt::Display>(
+        route_lines: Vec<RouteLine<D>>,
+        prefixes: Option<&[IpNetwork]>,
+        find_interface: impl Fn(&D) -> Option<NetworkInterface>,
+    ) -> RouteTable {
+        let mut builder = RouteTableBuilder::new(find_interface, prefixes);
+        for route_line in route_lines {
+            builder.push(route_line);
+        }
+        builder.finish()
+    }
+    /// Only the routes overlapping the `prefixes` are kept, so the memory stays bounded
+    /// even with the full bgp table imported into the kernel, the default routes are always kept.
+    #[cfg(target_os = "linux")]
+    pub fn init_filtered(
+        runner: &dyn CommandRunner,
+        prefixes: Option<&[IpNetwork]>,
+    ) -> Result<RouteTable, PistolErrors> {
+        RouteTable::from_linux_stream(runner, prefixes, |dev: &String| find_interface_by_name(dev))
+    }
+    /// The output of `ip route` is parsed as it is read, it is never buffered as a whole.
+    #[cfg(any(target_os = "linux", test))]
+    fn from_linux_stream(
+        runner: &dyn CommandRunner,
+        prefixes: Option<&[IpNetwork]>,
+        find_interface: impl Fn(&String) -> Option<NetworkInterface>,
+    ) -> Result<RouteTable, PistolErrors> {
+        let mut builder = RouteTableBuilder::new(find_interface, prefixes);
+        let mut push_line = |line: &str| {
+            if let Some(route_line) = parse_linux_route_line(line) {
+                builder.push(route_line);
+            }
         };
-        Ok(rt)
+        runner.run_lines("ip -4 route", &mut push_line)?;
+        // the ipv6 stack may be disabled, keep the ipv4 routes in this case
+        if let Err(e) = runner.run_lines("ip -6 route", &mut push_line) {
+            warn!("get ipv6 route table failed: {e}");
+        }
+        Ok(builder.finish())
     }
     #[cfg(any(
         target_os = "macos",
@@ -202,308 +350,313 @@ impl RouteTable {
         target_os = "openbsd",
         target_os = "netbsd"
     ))]
-    pub fn init() -> Result<RouteTable, PistolErrors> {
-        let system_route_lines = || -> Result<Vec<String>, PistolErrors> {
-            // default 192.168.72.2 UGS em0
-            // default fe80::4a5f:8ff:fee0:1394%em1 UG em1
-            // 127.0.0.1          link#2             UH          lo0
-            let c = Command::new("sh").args(["-c", "netstat -rn"]).output()?;
-            let output = String::from_utf8_lossy(&c.stdout);
-            let lines: Vec<String> = output
-                .lines()
-                .map(|x| x.trim().to_string())
-                .filter(|v| {
-                    v.len() > 0
-                        && !v.contains("Destination")
-                        && !v.contains("Routing tables")
-                        && !v.contains("Internet")
-                })
-                .collect();
-            Ok(lines)
-        };
-
-        let mut default_ipv4_route = None;
-        let mut default_ipv6_route = None;
-        let mut routes = HashMap::new();
-
-        // regex
-        let default_route_re =
-            Regex::new(r"default\s+(?P<via>[^\s]+)\s+\w+\s+(?P<dev>[^\s]+)([\s\w]+)?")?;
-        let route_re = Regex::new(r"(?P<subnet>[^\s]+)\s+link#\d+\s+\w+\s+(?P<dev>\w+)")?;
-
-        for line in system_route_lines()? {
-            let default_route_judge = |line: &str| -> bool { line.contains("default") };
-            if default_route_judge(&line) {
-                match default_route_re.captures(&line) {
-                    Some(caps) => {
-                        let via_str = caps.name("via").map_or("", |m| m.as_str());
-                        let via_str = ipv6_addr_bsd_fix(via_str)?;
-                        let via: IpAddr = match via_str.parse() {
-                            Ok(v) => v,
-                            Err(e) => {
-                                warn!("parse route table 'via' error:  {e}");
-                                continue;
-                            }
-                        };
-                        let dev_str = caps.name("dev").map_or("", |m| m.as_str());
-                        let dev = match find_interface_by_name(dev_str) {
-                            Some(i) => i,
-                            None => {
-                                // return Err(InvalidRouteFormat::new(line.to_string()).into());
-                                warn!("invaild default route string: [{}]", line);
-                                continue; // not raise error here
-                            }
-                        };
-
-                        let mut is_ipv4 = true;
-                        if via_str.contains(":") {
-                            is_ipv4 = false;
-                        }
-
-                        let default_route = DefaultRoute { via, dev };
-
-                        if is_ipv4 {
-                            default_ipv4_route = Some(default_route);
-                        } else {
-                            default_ipv6_route = Some(default_route);
-                        }
-                    }
-                    None => warn!("line: [{}] default_route_re no match", line),
-                }
-            } else {
-                match route_re.captures(&line) {
-                    Some(caps) => {
-                        let dst_str = caps.name("subnet").map_or("", |m| m.as_str());
-
-                        let dst_str = ipv6_addr_bsd_fix(dst_str)?;
-                        let dst = if dst_str.contains("/") {
-                            let dst = match IpNetwork::from_str(&dst_str) {
-                                Ok(d) => d,
-                                Err(e) => {
-                                    warn!("parse route table 'dst' error:  {e}");
-                                    continue;
-                                }
-                            };
-                            let dst = RouteAddr::IpNetwork(dst);
-                            dst
-                        } else {
-                            let dst: IpAddr = match dst_str.parse() {
-                                Ok(d) => d,
-                                Err(e) => {
-                                    warn!("parse route table 'dst' error:  {e}");
-                                    continue;
-                                }
-                            };
-                            let dst = RouteAddr::IpAddr(dst);
-                            dst
-                        };
-                        let dev_str = caps.name("dev").map_or("", |m| m.as_str());
-                        let dev = match find_interface_by_name(dev_str) {
-                            Some(i) => i,
-                            None => {
-                                // return Err(InvalidRouteFormat::new(line.to_string()).into());
-                                warn!("invaild route string: [{}]", line);
-                                continue; // not raise error here
-                            }
-                        };
-                        routes.insert(dst, dev);
+    pub fn init_filtered(
+        runner: &dyn CommandRunner,
+        prefixes: Option<&[IpNetwork]>,
+    ) -> Result<RouteTable, PistolErrors> {
+        let output = runner.run("netstat -rn")?;
+        let route_lines = parse_bsd_route(&output)?;
+        Ok(RouteTable::from_lines(
+            route_lines,
+            prefixes,
+            |dev: &String| find_interface_by_name(dev),
+        ))
+    }
+    #[cfg(target_os = "windows")]
+    pub fn init_filtered(
+        runner: &dyn CommandRunner,
+        prefixes: Option<&[IpNetwork]>,
+    ) -> Result<RouteTable, PistolErrors> {
+        let output = runner.run("Get-NetRoute")?;
+        let route_lines = parse_windows_route(&output)?;
+        Ok(RouteTable::from_lines(
+            route_lines,
+            prefixes,
+            |if_index: &u32| {
+                for interface in interfaces() {
+                    if *if_index == interface.index {
+                        return Some(interface);
                     }
-                    None => warn!("line: [{}] route_re no match", line),
                 }
-            }
+                None
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+fn parse_linux_route(output: &str) -> Result<Vec<RouteLine<String>>, PistolErrors> {
+    Ok(output.lines().filter_map(parse_linux_route_line).collect())
+}
+
+/// One line of `ip route`, the words are matched without regex since the table may have many thousand lines.
+#[cfg(any(target_os = "linux", test))]
+fn parse_linux_route_line(line: &str) -> Option<RouteLine<String>> {
+    // Linux
+    // ubuntu22.04 output:
+    // default via 192.168.72.2 dev ens33
+    // 192.168.1.0/24 dev ens36 proto kernel scope link src 192.168.1.132
+    // 192.168.72.0/24 dev ens33 proto kernel scope link src 192.168.72.128
+    // centos7 output:
+    // default via 192.168.72.2 dev ens33 proto dhcp metric 100
+    // 192.168.72.0/24 dev ens33 proto kernel scope link src 192.168.72.138 metric 100
+    // the peer of the point-to-point interface has no prefix length, e.g.
+    // 10.8.0.1 dev tun0 proto kernel scope link src 10.8.0.2
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return None;
+    }
+    // dst [via gateway] dev name ...
+    let (via, dev_pos) = match words.get(1) {
+        Some(&"via") => (words.get(2).copied(), 3),
+        _ => (None, 1),
+    };
+    let dev = match (words.get(dev_pos), words.get(dev_pos + 1)) {
+        (Some(&"dev"), Some(dev)) => dev.to_string(),
+        _ => {
+            warn!("line: [{}] no route match", line);
+            return None;
         }
-        let rt = RouteTable {
-            default_route: default_ipv4_route,
-            default_route6: default_ipv6_route,
-            routes,
+    };
+    let dst_str = words[0];
+    if dst_str == "default" {
+        let via_str = match via {
+            Some(v) => v,
+            None => {
+                warn!("line: [{}] default route without via", line);
+                return None;
+            }
         };
-        Ok(rt)
-    }
-    #[cfg(target_os = "windows")]
-    pub fn init() -> Result<RouteTable, PistolErrors> {
-        let system_route_lines = || -> Result<Vec<String>, PistolErrors> {
-            // 1 ::1/128 :: 256 75 ActiveStore
-            // 15 ::/0 fe80::ecb5:83ff:fec3:6a6 16 45 ActiveStore
-            let c = Command::new("powershell").args(["Get-NetRoute"]).output()?;
-            let output = String::from_utf8_lossy(&c.stdout);
-            let route_lines: Vec<String> = output
-                .lines()
-                .map(|x| x.trim().to_string())
-                .filter(|v| v.len() > 0 && !v.contains("ifIndex") && !v.contains("--"))
-                .collect();
-            Ok(route_lines)
+        return match via_str.parse() {
+            Ok(via) => Some(RouteLine::Default { via, dev }),
+            Err(e) => {
+                warn!("parse route table 'via' error:  {e}");
+                None
+            }
         };
+    }
+    let dst = if dst_str.contains("/") {
+        match IpNetwork::from_str(dst_str) {
+            Ok(d) => RouteAddr::IpNetwork(d),
+            Err(e) => {
+                warn!("parse route table 'dst' error:  {e}");
+                return None;
+            }
+        }
+    } else {
+        match dst_str.parse() {
+            Ok(d) => RouteAddr::IpAddr(d),
+            Err(e) => {
+                warn!("parse route table 'dst' error:  {e}");
+                return None;
+            }
+        }
+    };
+    Some(RouteLine::Route { dst, dev })
+}
 
-        let mut default_ipv4_route = None;
-        let mut default_ipv6_route = None;
-        let mut routes = HashMap::new();
-
-        // regex
-        let default_route_re =
-            Regex::new(r"(?P<index>\d+)\s+(?P<dst>[\d\w\./:]+)\s+(?P<via>[\d\./:]+)\s+.+")?;
-        let route_re =
-            Regex::new(r"(?P<index>\d+)\s+(?P<dst>[\d\w\./:]+)\s+(?P<via>[\d\./:]+)\s+.+")?;
-
-        for line in system_route_lines()? {
-            let default_route_judge =
-                |line: &str| -> bool { line.contains("0.0.0.0/0") || line.contains("::/0") };
-            if default_route_judge(&line) {
-                match default_route_re.captures(&line) {
-                    Some(caps) => {
-                        let if_index = caps.name("index").map_or("", |m| m.as_str());
-                        let if_index: u32 = match if_index.parse() {
-                            Ok(i) => i,
-                            Err(e) => {
-                                warn!("parse route table 'if_index' error:  {e}");
-                                continue;
-                            }
-                        };
-                        let find_interface = |if_index: u32| -> Option<NetworkInterface> {
-                            for interface in interfaces() {
-                                if if_index == interface.index {
-                                    return Some(interface);
-                                }
-                            }
-                            None
-                        };
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    test
+))]
+fn parse_bsd_route(output: &str) -> Result<Vec<RouteLine<String>>, PistolErrors> {
+    // default 192.168.72.2 UGS em0
+    // default fe80::4a5f:8ff:fee0:1394%em1 UG em1
+    // 127.0.0.1          link#2             UH          lo0
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|x| x.trim())
+        .filter(|v| {
+            !v.is_empty()
+                && !v.contains("Destination")
+                && !v.contains("Routing tables")
+                && !v.contains("Internet")
+        })
+        .collect();
 
-                        let via_str = caps.name("via").map_or("", |m| m.as_str());
-                        let via: IpAddr = match via_str.parse() {
-                            Ok(v) => v,
-                            Err(e) => {
-                                warn!("parse route table 'via' error:  {e}");
-                                continue;
-                            }
-                        };
-                        let dev = find_interface(if_index);
-                        match dev {
-                            Some(dev) => {
-                                let mut is_ipv4 = true;
-                                if via_str.contains(":") {
-                                    is_ipv4 = false;
-                                }
-
-                                let default_route = DefaultRoute { via, dev };
-
-                                if is_ipv4 {
-                                    default_ipv4_route = Some(default_route);
-                                } else {
-                                    default_ipv6_route = Some(default_route);
-                                }
-                            }
-                            None => {
-                                // return Err(InvalidRouteFormat::new(line.to_string()).into());
-                                warn!("invaild default route string: [{}]", line);
-                                continue; // not raise error here
-                            }
+    // regex
+    let default_route_re =
+        Regex::new(r"default\s+(?P<via>[^\s]+)\s+\w+\s+(?P<dev>[^\s]+)([\s\w]+)?")?;
+    let route_re = Regex::new(r"(?P<subnet>[^\s]+)\s+link#\d+\s+\w+\s+(?P<dev>\w+)")?;
+
+    let mut ret = Vec::new();
+    for line in lines {
+        let default_route_judge = |line: &str| -> bool { line.contains("default") };
+        if default_route_judge(line) {
+            match default_route_re.captures(line) {
+                Some(caps) => {
+                    let via_str = caps.name("via").map_or("", |m| m.as_str());
+                    let via_str = ipv6_addr_bsd_fix(via_str)?;
+                    let via: IpAddr = match via_str.parse() {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("parse route table 'via' error:  {e}");
+                            continue;
                         }
-                    }
-                    None => warn!("line: [{}] default_route_re no match", line),
+                    };
+                    let dev = caps.name("dev").map_or("", |m| m.as_str()).to_string();
+                    ret.push(RouteLine::Default { via, dev });
                 }
-            } else {
-                match route_re.captures(&line) {
-                    Some(caps) => {
-                        let if_index = caps.name("index").map_or("", |m| m.as_str());
-                        let if_index: u32 = match if_index.parse() {
-                            Ok(i) => i,
+                None => warn!("line: [{}] default_route_re no match", line),
+            }
+        } else {
+            match route_re.captures(line) {
+                Some(caps) => {
+                    let dst_str = caps.name("subnet").map_or("", |m| m.as_str());
+                    let dst_str = ipv6_addr_bsd_fix(dst_str)?;
+                    let dst = if dst_str.contains("/") {
+                        match IpNetwork::from_str(&dst_str) {
+                            Ok(d) => RouteAddr::IpNetwork(d),
                             Err(e) => {
-                                warn!("parse route table 'if_index' error:  {e}");
+                                warn!("parse route table 'dst' error:  {e}");
                                 continue;
                             }
-                        };
-                        let find_interface = |if_index: u32| -> Option<NetworkInterface> {
-                            for interface in interfaces() {
-                                if if_index == interface.index {
-                                    return Some(interface);
-                                }
-                            }
-                            None
-                        };
-
-                        let dst = caps.name("dst").map_or("", |m| m.as_str());
-                        let dst = match IpNetwork::from_str(dst) {
-                            Ok(d) => d,
+                        }
+                    } else {
+                        match dst_str.parse() {
+                            Ok(d) => RouteAddr::IpAddr(d),
                             Err(e) => {
                                 warn!("parse route table 'dst' error:  {e}");
                                 continue;
                             }
-                        };
-                        let dst = RouteAddr::IpNetwork(dst);
-                        let dev = find_interface(if_index);
-                        let dev = match dev {
-                            Some(i) => i,
-                            None => {
-                                // return Err(InvalidRouteFormat::new(line.to_string()).into());
-                                warn!("invaild default route string: [{}]", line);
-                                continue; // not raise error here
-                            }
-                        };
-                        routes.insert(dst, dev);
-                    }
-                    None => warn!("line: [{}] default_route_re no match", line),
+                        }
+                    };
+                    let dev = caps.name("dev").map_or("", |m| m.as_str()).to_string();
+                    ret.push(RouteLine::Route { dst, dev });
                 }
+                None => warn!("line: [{}] route_re no match", line),
             }
         }
-
-        let rt = RouteTable {
-            default_route: default_ipv4_route,
-            default_route6: default_ipv6_route,
-            routes,
-        };
-        Ok(rt)
     }
+    Ok(ret)
 }
 
-#[derive(Debug, Clone)]
-pub struct NeighborCache {}
-
-impl NeighborCache {
-    #[cfg(target_os = "linux")]
-    pub fn init() -> Result<HashMap<IpAddr, MacAddr>, PistolErrors> {
-        // 192.168.72.2 dev ens33 lladdr 00:50:56:fb:1d:74 STALE
-        // 192.168.1.107 dev ens36 lladdr 74:05:a5:53:69:bb STALE
-        // 192.168.1.1 dev ens36 lladdr 48:5f:08:e0:13:94 STALE
-        // 192.168.1.128 dev ens36 lladdr a8:9c:ed:d5:00:4c STALE
-        // 192.168.72.1 dev ens33 lladdr 00:50:56:c0:00:08 REACHABLE
-        // fe80::4a5f:8ff:fee0:1394 dev ens36 lladdr 48:5f:08:e0:13:94 router STALE
-        let c = Command::new("sh").args(["-c", "ip neigh show"]).output()?;
-        let output = String::from_utf8_lossy(&c.stdout);
-        let lines: Vec<&str> = output
-            .lines()
-            .map(|x| x.trim())
-            .filter(|v| v.len() > 0)
-            .collect();
+#[cfg(any(target_os = "windows", test))]
+fn parse_windows_route(output: &str) -> Result<Vec<RouteLine<u32>>, PistolErrors> {
+    // 1 ::1/128 :: 256 75 ActiveStore
+    // 15 ::/0 fe80::ecb5:83ff:fec3:6a6 16 45 ActiveStore
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|x| x.trim())
+        .filter(|v| !v.is_empty() && !v.contains("ifIndex") && !v.contains("--"))
+        .collect();
 
-        // regex
-        let neighbor_re =
-            Regex::new(r"(?P<addr>[\d\w\.:]+)\s+dev[\w\s]+lladdr\s+(?P<mac>[\d\w:]+).+")?;
+    // regex
+    let route_re =
+        Regex::new(r"^(?P<index>\d+)\s+(?P<dst>[\d\w\./:]+)\s+(?P<via>[\da-fA-F\./:]+)\s+.+")?;
 
-        let mut ret = HashMap::new();
-        for line in lines {
-            match neighbor_re.captures(line) {
-                Some(caps) => {
-                    let addr = caps.name("addr").map_or("", |m| m.as_str());
-                    let addr: IpAddr = match addr.parse() {
-                        Ok(a) => a,
+    let mut ret = Vec::new();
+    for line in lines {
+        let default_route_judge =
+            |line: &str| -> bool { line.contains("0.0.0.0/0") || line.contains("::/0") };
+        match route_re.captures(line) {
+            Some(caps) => {
+                let if_index = caps.name("index").map_or("", |m| m.as_str());
+                let if_index: u32 = match if_index.parse() {
+                    Ok(i) => i,
+                    Err(e) => {
+                        warn!("parse route table 'if_index' error:  {e}");
+                        continue;
+                    }
+                };
+                if default_route_judge(line) {
+                    let via_str = caps.name("via").map_or("", |m| m.as_str());
+                    let via: IpAddr = match via_str.parse() {
+                        Ok(v) => v,
                         Err(e) => {
-                            warn!("parse neighbor 'addr' error:  {e}");
+                            warn!("parse route table 'via' error:  {e}");
                             continue;
                         }
                     };
-                    let mac = caps.name("mac").map_or("", |m| m.as_str());
-                    let mac: MacAddr = match mac.parse() {
-                        Ok(m) => m,
+                    ret.push(RouteLine::Default { via, dev: if_index });
+                } else {
+                    let dst = caps.name("dst").map_or("", |m| m.as_str());
+                    let dst = match IpNetwork::from_str(dst) {
+                        Ok(d) => d,
                         Err(e) => {
-                            warn!("parse neighbor 'mac' error:  {e}");
+                            warn!("parse route table 'dst' error:  {e}");
                             continue;
                         }
                     };
-                    ret.insert(addr, mac);
+                    let dst = RouteAddr::IpNetwork(dst);
+                    ret.push(RouteLine::Route { dst, dev: if_index });
                 }
-                None => warn!("line: [{}] neighbor_re no match", line),
             }
+            None => warn!("line: [{}] route_re no match", line),
         }
-        Ok(ret)
+    }
+    Ok(ret)
+}
+
+/// The key of the neighbor cache.
+/// The link-local ipv6 address is only unique on one link,
+/// so it is kept with the zone (interface index) of the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NeighborKey {
+    pub addr: IpAddr,
+    pub zone: Option<u32>,
+}
+
+impl NeighborKey {
+    /// The zone is ignored if the addr is not a link-local ipv6 address.
+    pub fn new(addr: IpAddr, zone: Option<u32>) -> NeighborKey {
+        let zone = if is_link_local_ipv6(addr) { zone } else { None };
+        NeighborKey { addr, zone }
+    }
+}
+
+fn is_link_local_ipv6(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(_) => false,
+        // fe80::/10
+        IpAddr::V6(ipv6) => (ipv6.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// One entry of the system neighbor cache.
+/// The device is kept as the system prints it (name on unix, interface index on windows).
+#[derive(Debug, Clone, PartialEq)]
+struct NeighborLine<D> {
+    addr: IpAddr,
+    mac: MacAddr,
+    dev: Option<D>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NeighborCache {}
+
+impl NeighborCache {
+    fn from_lines<D: fmt::Display>(
+        neighbor_lines: Vec<NeighborLine<D>>,
+        find_interface_index: impl Fn(&D) -> Option<u32>,
+    ) -> HashMap<NeighborKey, MacAddr> {
+        let mut ret = HashMap::new();
+        for neighbor_line in neighbor_lines {
+            let zone = match &neighbor_line.dev {
+                Some(dev) => match find_interface_index(dev) {
+                    Some(i) => Some(i),
+                    None => {
+                        warn!("invaild neighbor dev: [{}]", dev);
+                        None
+                    }
+                },
+                None => None,
+            };
+            let key = NeighborKey::new(neighbor_line.addr, zone);
+            ret.insert(key, neighbor_line.mac);
+        }
+        ret
+    }
+    #[cfg(target_os = "linux")]
+    pub fn init(runner: &dyn CommandRunner) -> Result<HashMap<NeighborKey, MacAddr>, PistolErrors> {
+        let output = runner.run("ip neigh show")?;
+        let neighbor_lines = parse_linux_neighbor(&output)?;
+        Ok(NeighborCache::from_lines(neighbor_lines, |dev: &String| {
+            find_interface_by_name(dev).map(|i| i.index)
+        }))
     }
     #[cfg(any(
         target_os = "macos",
@@ -511,102 +664,333 @@ impl NeighborCache {
         target_os = "openbsd",
         target_os = "netbsd"
     ))]
-    pub fn init() -> Result<HashMap<IpAddr, MacAddr>, PistolErrors> {
-        // # arp -a
-        // ? (192.168.72.1) at 00:50:56:c0:00:08 on em0 expires in 1139 seconds [ethernet]
-        // ? (192.168.72.129) at 00:0c:29:88:20:d2 on em0 permanent [ethernet]
-        // ? (192.168.72.2) at 00:50:56:fb:1d:74 on em0 expires in 1168 seconds [ethernet]
-        // MacOS
-        // ? (192.168.50.2) at (incomplete) on en0 ifscope [ethernet]
-        // # ndp -a
-        // Neighbor                             Linklayer Address  Netif Expire    1s 5s
-        // fe80::20c:29ff:fe88:20d2%em0         00:0c:29:88:20:d2    em0 permanent R
-        let c = Command::new("sh").args(["-c", "arp -a"]).output()?;
-        let arp_output = String::from_utf8_lossy(&c.stdout);
-        let c = Command::new("sh").args(["-c", "ndp -a"]).output()?;
-        let ndp_output = String::from_utf8_lossy(&c.stdout);
-        let output = arp_output.to_string() + &ndp_output;
-        let lines: Vec<&str> = output
-            .lines()
-            .map(|x| x.trim())
-            .filter(|v| v.len() > 0 && !v.contains("Neighbor"))
-            .collect();
+    pub fn init(runner: &dyn CommandRunner) -> Result<HashMap<NeighborKey, MacAddr>, PistolErrors> {
+        let arp_output = runner.run("arp -a")?;
+        // ndp is missing on some minimal systems, keep the arp result in this case
+        let ndp_output = match runner.run("ndp -a") {
+            Ok(o) => o,
+            Err(e) => {
+                warn!("get ndp neighbor cache failed: {e}");
+                String::new()
+            }
+        };
+        let neighbor_lines = parse_bsd_neighbor(&(arp_output + &ndp_output))?;
+        Ok(NeighborCache::from_lines(neighbor_lines, |dev: &String| {
+            find_interface_by_name(dev).map(|i| i.index)
+        }))
+    }
+    #[cfg(target_os = "windows")]
+    pub fn init(runner: &dyn CommandRunner) -> Result<HashMap<NeighborKey, MacAddr>, PistolErrors> {
+        let output = runner.run("Get-NetNeighbor")?;
+        let neighbor_lines = parse_windows_neighbor(&output)?;
+        Ok(NeighborCache::from_lines(
+            neighbor_lines,
+            |if_index: &u32| Some(*if_index),
+        ))
+    }
+}
 
-        // regex
-        let neighbor_re = Regex::new(r"\?\s+\((?P<addr>[^\s]+)\)\s+at\s+(?P<mac>[\w\d:]+).+")?;
+#[cfg(any(target_os = "linux", test))]
+fn parse_linux_neighbor(output: &str) -> Result<Vec<NeighborLine<String>>, PistolErrors> {
+    // 192.168.72.2 dev ens33 lladdr 00:50:56:fb:1d:74 STALE
+    // 192.168.1.107 dev ens36 lladdr 74:05:a5:53:69:bb STALE
+    // 192.168.1.1 dev ens36 lladdr 48:5f:08:e0:13:94 STALE
+    // 192.168.1.128 dev ens36 lladdr a8:9c:ed:d5:00:4c STALE
+    // 192.168.72.1 dev ens33 lladdr 00:50:56:c0:00:08 REACHABLE
+    // fe80::4a5f:8ff:fee0:1394 dev ens36 lladdr 48:5f:08:e0:13:94 router STALE
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|x| x.trim())
+        .filter(|v| !v.is_empty())
+        .collect();
 
-        let mut ret = HashMap::new();
-        for line in lines {
-            match neighbor_re.captures(line) {
-                Some(caps) => {
-                    let addr_str = caps.name("addr").map_or("", |m| m.as_str());
-                    let addr_str = ipv6_addr_bsd_fix(addr_str)?;
-                    let addr: IpAddr = match addr_str.parse() {
-                        Ok(a) => a,
-                        Err(e) => {
-                            warn!("parse neighbor 'addr' error:  {e}");
-                            continue;
-                        }
-                    };
-                    let mac = caps.name("mac").map_or("", |m| m.as_str());
-                    let mac: MacAddr = match mac.parse() {
-                        Ok(m) => m,
-                        Err(e) => {
-                            warn!("parse neighbor 'mac' error:  {e}");
-                            continue;
-                        }
-                    };
-                    ret.insert(addr, mac);
-                }
-                None => warn!("line: [{}] neighbor_re no match", line),
+    // regex
+    let neighbor_re = Regex::new(
+        r"(?P<addr>[\d\w\.:]+)\s+dev\s+(?P<dev>[^\s]+)[\w\s]*lladdr\s+(?P<mac>[\d\w:]+).+",
+    )?;
+
+    let mut ret = Vec::new();
+    for line in lines {
+        match neighbor_re.captures(line) {
+            Some(caps) => {
+                let addr = caps.name("addr").map_or("", |m| m.as_str());
+                let addr: IpAddr = match addr.parse() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!("parse neighbor 'addr' error:  {e}");
+                        continue;
+                    }
+                };
+                let mac = caps.name("mac").map_or("", |m| m.as_str());
+                let mac: MacAddr = match mac.parse() {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!("parse neighbor 'mac' error:  {e}");
+                        continue;
+                    }
+                };
+                let dev = caps.name("dev").map(|m| m.as_str().to_string());
+                ret.push(NeighborLine { addr, mac, dev });
             }
+            None => warn!("line: [{}] neighbor_re no match", line),
         }
-        Ok(ret)
     }
-    #[cfg(target_os = "windows")]
-    pub fn init() -> Result<HashMap<IpAddr, MacAddr>, PistolErrors> {
-        // 58 ff02::1:ff73:3ff4 33-33-FF-73-3F-F4 Permanent ActiveStore
-        // 58 ff02::1:2  33-33-00-01-00-02 Permanent ActiveStore
-        let c = Command::new("powershell")
-            .args(["Get-NetNeighbor"])
-            .output()?;
-        let output = String::from_utf8_lossy(&c.stdout);
-        let lines: Vec<&str> = output
-            .lines()
-            .map(|x| x.trim())
-            .filter(|v| v.len() > 0 && !v.contains("ifIndex") && !v.contains("--"))
-            .collect();
+    Ok(ret)
+}
 
-        // regex
-        let neighbor_re =
-            Regex::new(r"\d+\s+(?P<addr>[\w\d\.:]+)\s+(?P<mac>[\w\d-]+)\s+\w+\s+\w+")?;
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    test
+))]
+fn parse_bsd_neighbor(output: &str) -> Result<Vec<NeighborLine<String>>, PistolErrors> {
+    // # arp -a
+    // ? (192.168.72.1) at 00:50:56:c0:00:08 on em0 expires in 1139 seconds [ethernet]
+    // ? (192.168.72.129) at 00:0c:29:88:20:d2 on em0 permanent [ethernet]
+    // ? (192.168.72.2) at 00:50:56:fb:1d:74 on em0 expires in 1168 seconds [ethernet]
+    // MacOS
+    // ? (192.168.50.2) at (incomplete) on en0 ifscope [ethernet]
+    // # ndp -a
+    // Neighbor                             Linklayer Address  Netif Expire    1s 5s
+    // fe80::20c:29ff:fe88:20d2%em0         00:0c:29:88:20:d2    em0 permanent R
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|x| x.trim())
+        .filter(|v| !v.is_empty() && !v.contains("Neighbor"))
+        .collect();
 
-        let mut ret = HashMap::new();
-        for line in lines {
-            match neighbor_re.captures(line) {
+    // regex
+    let neighbor_re =
+        Regex::new(r"\?\s+\((?P<addr>[^\s]+)\)\s+at\s+(?P<mac>[\w\d:]+)(\s+on\s+(?P<dev>\w+))?.+")?;
+    let ndp_re = Regex::new(
+        r"^(?P<addr>[\da-fA-F:]+)(%(?P<zone>\w+))?\s+(?P<mac>[\da-fA-F:]+)\s+(?P<dev>\w+)",
+    )?;
+
+    let mut ret = Vec::new();
+    for line in lines {
+        // the zone is more accurate than the netif
+        let (caps, dev) = match neighbor_re.captures(line) {
+            Some(caps) => {
+                let dev = caps.name("dev").map(|m| m.as_str().to_string());
+                (caps, dev)
+            }
+            None => match ndp_re.captures(line) {
                 Some(caps) => {
-                    let addr = caps.name("addr").map_or("", |m| m.as_str());
-                    let addr: IpAddr = match addr.parse() {
-                        Ok(a) => a,
-                        Err(e) => {
-                            warn!("parse neighbor 'addr' error:  {e}");
-                            continue;
-                        }
-                    };
-                    let mac = caps.name("mac").map_or("", |m| m.as_str());
-                    let mac: MacAddr = match mac.parse() {
-                        Ok(m) => m,
-                        Err(e) => {
-                            warn!("parse neighbor 'mac' error:  {e}");
-                            continue;
-                        }
+                    let dev = match caps.name("zone") {
+                        Some(z) => Some(z.as_str().to_string()),
+                        None => caps.name("dev").map(|m| m.as_str().to_string()),
                     };
-                    ret.insert(addr, mac);
+                    (caps, dev)
+                }
+                None => {
+                    warn!("line: [{}] neighbor_re no match", line);
+                    continue;
                 }
-                None => warn!("line: [{}] neighbor_re no match", line),
+            },
+        };
+        let addr_str = caps.name("addr").map_or("", |m| m.as_str());
+        let addr_str = ipv6_addr_bsd_fix(addr_str)?;
+        let addr: IpAddr = match addr_str.parse() {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("parse neighbor 'addr' error:  {e}");
+                continue;
+            }
+        };
+        let mac = caps.name("mac").map_or("", |m| m.as_str());
+        let mac: MacAddr = match mac.parse() {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("parse neighbor 'mac' error:  {e}");
+                continue;
+            }
+        };
+        ret.push(NeighborLine { addr, mac, dev });
+    }
+    Ok(ret)
+}
+
+#[cfg(any(target_os = "windows", test))]
+fn parse_windows_neighbor(output: &str) -> Result<Vec<NeighborLine<u32>>, PistolErrors> {
+    // 58 ff02::1:ff73:3ff4 33-33-FF-73-3F-F4 Permanent ActiveStore
+    // 58 ff02::1:2  33-33-00-01-00-02 Permanent ActiveStore
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|x| x.trim())
+        .filter(|v| !v.is_empty() && !v.contains("ifIndex") && !v.contains("--"))
+        .collect();
+
+    // regex
+    let neighbor_re =
+        Regex::new(r"(?P<index>\d+)\s+(?P<addr>[\w\d\.:]+)\s+(?P<mac>[\w\d-]+)\s+\w+\s+\w+")?;
+
+    let mut ret = Vec::new();
+    for line in lines {
+        match neighbor_re.captures(line) {
+            Some(caps) => {
+                let addr = caps.name("addr").map_or("", |m| m.as_str());
+                let addr: IpAddr = match addr.parse() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!("parse neighbor 'addr' error:  {e}");
+                        continue;
+                    }
+                };
+                // windows use '-' as the separator
+                let mac = caps
+                    .name("mac")
+                    .map_or("", |m| m.as_str())
+                    .replace("-", ":");
+                let mac: MacAddr = match mac.parse() {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!("parse neighbor 'mac' error:  {e}");
+                        continue;
+                    }
+                };
+                let dev = match caps.name("index").map_or("", |m| m.as_str()).parse() {
+                    Ok(i) => Some(i),
+                    Err(e) => {
+                        warn!("parse neighbor 'if_index' error:  {e}");
+                        None
+                    }
+                };
+                ret.push(NeighborLine { addr, mac, dev });
+            }
+            None => warn!("line: [{}] neighbor_re no match", line),
+        }
+    }
+    Ok(ret)
+}
+
+/// The same neighbor address with a different mac address in the new snapshot,
+/// which is a possible sign of ARP (or NDP) spoofing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NeighborChange {
+    pub key: NeighborKey,
+    pub old_mac: MacAddr,
+    pub new_mac: MacAddr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetCacheDiff {
+    pub added_neighbors: Vec<(NeighborKey, MacAddr)>,
+    pub removed_neighbors: Vec<(NeighborKey, MacAddr)>,
+    pub changed_neighbors: Vec<NeighborChange>,
+    pub default_route_changed: bool,
+    pub default_route6_changed: bool,
+}
+
+impl NetCacheDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_neighbors.is_empty()
+            && self.removed_neighbors.is_empty()
+            && self.changed_neighbors.is_empty()
+            && !self.default_route_changed
+            && !self.default_route6_changed
+    }
+}
+
+fn default_route_changed(old: &Option<DefaultRoute>, new: &Option<DefaultRoute>) -> bool {
+    match (old, new) {
+        (Some(o), Some(n)) => o.via != n.via || o.dev.name != n.dev.name,
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacConflict {
+    /// The mac address of the neighbor changed across the snapshots (in the order of appearance).
+    MacFlap {
+        key: NeighborKey,
+        macs: Vec<MacAddr>,
+    },
+    /// One mac address claims more addresses (of the same family) than the threshold.
+    MultipleAddrs {
+        mac: MacAddr,
+        addrs: Vec<NeighborKey>,
+    },
+}
+
+/// Find the classic ARP (or NDP) spoofing indicators in the neighbor cache history.
+/// The gateway which owns many addresses legitimately can be put into `allowlist`,
+/// it is ignored by the multiple addresses check.
+pub fn detect_mac_conflicts(
+    history: &[SystemNetCache],
+    max_addrs_per_mac: usize,
+    allowlist: &[MacAddr],
+) -> Vec<MacConflict> {
+    let mut key_macs: BTreeMap<NeighborKey, Vec<MacAddr>> = BTreeMap::new();
+    let mut mac_addrs: HashMap<MacAddr, BTreeSet<NeighborKey>> = HashMap::new();
+    for snc in history {
+        for (key, mac) in &snc.neighbor {
+            let macs = key_macs.entry(*key).or_default();
+            if !macs.contains(mac) {
+                macs.push(*mac);
             }
+            mac_addrs.entry(*mac).or_default().insert(*key);
+        }
+    }
+
+    let mut ret = Vec::new();
+    for (key, macs) in key_macs {
+        if macs.len() > 1 {
+            ret.push(MacConflict::MacFlap { key, macs });
+        }
+    }
+    let mut multiple_addrs = Vec::new();
+    for (mac, addrs) in mac_addrs {
+        if allowlist.contains(&mac) {
+            continue;
+        }
+        // a host has both ipv4 and ipv6 addresses, count them separately
+        let ipv4_num = addrs.iter().filter(|k| k.addr.is_ipv4()).count();
+        let ipv6_num = addrs.len() - ipv4_num;
+        if ipv4_num > max_addrs_per_mac || ipv6_num > max_addrs_per_mac {
+            multiple_addrs.push(MacConflict::MultipleAddrs {
+                mac,
+                addrs: addrs.into_iter().collect(),
+            });
         }
-        Ok(ret)
+    }
+    // keep the output stable
+    multiple_addrs.sort_by_key(|c| match c {
+        MacConflict::MultipleAddrs { mac, .. } => mac.octets(),
+        MacConflict::MacFlap { .. } => [0; 6],
+    });
+    ret.extend(multiple_addrs);
+    ret
+}
+
+/// The json object keys must be strings,
+/// so the maps keyed by the structs and the tuples are serialized as the lists of the key-value pairs.
+mod map_as_pairs {
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serialize;
+    use serde::Serializer;
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let pairs: Vec<(K, V)> = Vec::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
     }
 }
 
@@ -614,50 +998,174 @@ impl NeighborCache {
 pub struct SystemNetCache {
     pub default_route: Option<DefaultRoute>,
     pub default_route6: Option<DefaultRoute>,
+    #[serde(with = "map_as_pairs")]
     pub routes: HashMap<RouteAddr, NetworkInterface>,
-    pub neighbor: HashMap<IpAddr, MacAddr>,
+    #[serde(with = "map_as_pairs")]
+    pub neighbor: HashMap<NeighborKey, MacAddr>,
+    /// The selected source address of the matched route and the temporary ipv6 preference of the scan,
+    /// the destinations without a route share the one of the default route (`0.0.0.0/0` or `::/0`).
+    #[serde(with = "map_as_pairs")]
+    pub source_addrs: HashMap<(RouteAddr, bool), IpAddr>,
 }
 
 impl SystemNetCache {
     pub fn init() -> Result<SystemNetCache, PistolErrors> {
-        let route_table = RouteTable::init()?;
+        SystemNetCache::init_with(&SystemCommandRunner)
+    }
+    /// The route table and the neighbor cache are independent,
+    /// if one of them fails, the other one is still returned and the failed one is left empty.
+    pub fn init_with(runner: &dyn CommandRunner) -> Result<SystemNetCache, PistolErrors> {
+        SystemNetCache::init_with_route_filter(runner, None)
+    }
+    /// Only the routes overlapping the `prefixes` (e.g. the networks to scan) are kept,
+    /// for the hosts with a very large route table, see `RouteTable::init_filtered`.
+    pub fn init_with_route_filter(
+        runner: &dyn CommandRunner,
+        prefixes: Option<&[IpNetwork]>,
+    ) -> Result<SystemNetCache, PistolErrors> {
+        let route_table = match RouteTable::init_filtered(runner, prefixes) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("init route table failed, use empty route table instead: {e}");
+                RouteTable {
+                    default_route: None,
+                    default_route6: None,
+                    routes: HashMap::new(),
+                }
+            }
+        };
         debug!("route table [{}] done", route_table.routes.len());
-        let neighbor_cache = NeighborCache::init()?;
+        let neighbor_cache = match NeighborCache::init(runner) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("init neighbor cache failed, use empty neighbor cache instead: {e}");
+                HashMap::new()
+            }
+        };
         debug!("neighbor cache [{}] done", neighbor_cache.len());
         let snc = SystemNetCache {
             default_route: route_table.default_route,
             default_route6: route_table.default_route6,
             routes: route_table.routes,
             neighbor: neighbor_cache,
+            source_addrs: HashMap::new(),
         };
         Ok(snc)
     }
-    pub fn search_mac(&self, ipaddr: IpAddr) -> Option<MacAddr> {
-        let mac = match self.neighbor.get(&ipaddr) {
-            Some(m) => Some(*m),
-            None => None,
-        };
-        mac
+    /// The interface (index) is needed to search the link-local ipv6 address,
+    /// without it, the link-local neighbor is only returned if it is unique.
+    pub fn search_mac(&self, ipaddr: IpAddr, interface: Option<u32>) -> Option<MacAddr> {
+        if is_link_local_ipv6(ipaddr) && interface.is_none() {
+            let macs: Vec<&MacAddr> = self
+                .neighbor
+                .iter()
+                .filter(|(k, _)| k.addr == ipaddr)
+                .map(|(_, m)| m)
+                .collect();
+            if macs.len() > 1 {
+                warn!(
+                    "link-local neighbor [{}] is ambiguous without the interface",
+                    ipaddr
+                );
+                return None;
+            }
+            return macs.first().map(|m| **m);
+        }
+        let key = NeighborKey::new(ipaddr, interface);
+        self.neighbor.get(&key).copied()
     }
-    pub fn update_neighbor_cache(&mut self, ipaddr: IpAddr, mac: MacAddr) {
-        self.neighbor.insert(ipaddr, mac);
+    pub fn update_neighbor_cache(&mut self, ipaddr: IpAddr, mac: MacAddr, interface: Option<u32>) {
+        let key = NeighborKey::new(ipaddr, interface);
+        self.neighbor.insert(key, mac);
     }
-    pub fn search_route(&self, ipaddr: IpAddr) -> Option<NetworkInterface> {
-        for (dst, dev) in &self.routes {
-            match dst {
-                RouteAddr::IpAddr(dst) => {
-                    if *dst == ipaddr {
-                        return Some(dev.clone());
-                    }
-                }
-                RouteAddr::IpNetwork(dst) => {
-                    if dst.contains(ipaddr) {
-                        return Some(dev.clone());
+    fn source_key(&self, dst_addr: IpAddr) -> RouteAddr {
+        match self.matched_route(dst_addr) {
+            Some((dst, _)) => dst.clone(),
+            None => {
+                let default_network = match dst_addr {
+                    IpAddr::V4(_) => IpNetwork::from_str("0.0.0.0/0"),
+                    IpAddr::V6(_) => IpNetwork::from_str("::/0"),
+                };
+                RouteAddr::IpNetwork(default_network.expect("invalid default network"))
+            }
+        }
+    }
+    pub fn search_source_addr(&self, dst_addr: IpAddr, prefer_temporary: bool) -> Option<IpAddr> {
+        let key = (self.source_key(dst_addr), prefer_temporary);
+        self.source_addrs.get(&key).copied()
+    }
+    pub fn update_source_addr(
+        &mut self,
+        dst_addr: IpAddr,
+        prefer_temporary: bool,
+        src_addr: IpAddr,
+    ) {
+        let key = (self.source_key(dst_addr), prefer_temporary);
+        self.source_addrs.insert(key, src_addr);
+    }
+    /// Replace the routes with the new route table,
+    /// the selected source addresses are dropped since they belong to the old routes.
+    pub fn update_routes(&mut self, route_table: RouteTable) {
+        self.default_route = route_table.default_route;
+        self.default_route6 = route_table.default_route6;
+        self.routes = route_table.routes;
+        self.source_addrs.clear();
+    }
+    /// Compare with the `other` snapshot which is taken later.
+    pub fn diff(&self, other: &SystemNetCache) -> NetCacheDiff {
+        let mut added_neighbors = Vec::new();
+        let mut removed_neighbors = Vec::new();
+        let mut changed_neighbors = Vec::new();
+        for (key, new_mac) in &other.neighbor {
+            match self.neighbor.get(key) {
+                Some(old_mac) => {
+                    if old_mac != new_mac {
+                        changed_neighbors.push(NeighborChange {
+                            key: *key,
+                            old_mac: *old_mac,
+                            new_mac: *new_mac,
+                        });
                     }
                 }
+                None => added_neighbors.push((*key, *new_mac)),
+            }
+        }
+        for (key, old_mac) in &self.neighbor {
+            if !other.neighbor.contains_key(key) {
+                removed_neighbors.push((*key, *old_mac));
             }
         }
-        None
+        added_neighbors.sort_by_key(|(k, _)| *k);
+        removed_neighbors.sort_by_key(|(k, _)| *k);
+        changed_neighbors.sort_by_key(|c| c.key);
+        NetCacheDiff {
+            added_neighbors,
+            removed_neighbors,
+            changed_neighbors,
+            default_route_changed: default_route_changed(&self.default_route, &other.default_route),
+            default_route6_changed: default_route_changed(
+                &self.default_route6,
+                &other.default_route6,
+            ),
+        }
+    }
+    /// The longest prefix match, the peer route of the point-to-point interface (e.g. `10.8.0.1 dev tun0`)
+    /// wins over the subnet route which also contains the peer.
+    pub fn search_route(&self, ipaddr: IpAddr) -> Option<NetworkInterface> {
+        self.matched_route(ipaddr).map(|(_, dev)| dev.clone())
+    }
+    fn matched_route(&self, ipaddr: IpAddr) -> Option<(&RouteAddr, &NetworkInterface)> {
+        self.routes
+            .iter()
+            .filter_map(|(dst, dev)| match dst {
+                RouteAddr::IpAddr(addr) if *addr == ipaddr => Some((u8::MAX, dst, dev)),
+                RouteAddr::IpNetwork(network) if network.contains(ipaddr) => {
+                    Some((network.prefix(), dst, dev))
+                }
+                _ => None,
+            })
+            .max_by_key(|(prefix, _, _)| *prefix)
+            .map(|(_, dst, dev)| (dst, dev))
     }
 }
 
@@ -665,7 +1173,618 @@ impl SystemNetCache {
 mod tests {
     use super::*;
     // use std::time::Instant;
-    use pnet::datalink::interfaces;
+    use crate::utils::interfaces;
+    use std::net::Ipv4Addr;
+    struct FakeCommandRunner {
+        outputs: HashMap<String, String>,
+    }
+    impl CommandRunner for FakeCommandRunner {
+        fn run(&self, cmd: &str) -> Result<String, PistolErrors> {
+            match self.outputs.get(cmd) {
+                Some(o) => Ok(o.clone()),
+                None => Err(PistolErrors::SystemCommandFailed {
+                    cmd: cmd.to_string(),
+                    stderr: String::from("command not found"),
+                }),
+            }
+        }
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_network_cache_partial() {
+        let mut outputs = HashMap::new();
+        outputs.insert(
+            String::from("ip -4 route"),
+            String::from("127.0.0.0/8 dev lo proto kernel scope link src 127.0.0.1"),
+        );
+        // the neighbor command is missing
+        let runner = FakeCommandRunner { outputs };
+        let snc = SystemNetCache::init_with(&runner).unwrap();
+        assert_eq!(snc.routes.len(), 1);
+        assert!(snc.neighbor.is_empty());
+        let dev = snc.search_route("127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(dev.name, "lo");
+
+        // and the other way around
+        let mut outputs = HashMap::new();
+        outputs.insert(
+            String::from("ip neigh show"),
+            String::from("192.168.72.2 dev ens33 lladdr 00:50:56:fb:1d:74 STALE"),
+        );
+        let runner = FakeCommandRunner { outputs };
+        let snc = SystemNetCache::init_with(&runner).unwrap();
+        assert!(snc.routes.is_empty());
+        assert_eq!(snc.neighbor.len(), 1);
+    }
+    #[test]
+    fn test_parse_linux_route() {
+        // ubuntu22.04
+        let output = "default via 192.168.72.2 dev ens33
+192.168.1.0/24 dev ens36 proto kernel scope link src 192.168.1.132
+192.168.72.0/24 dev ens33 proto kernel scope link src 192.168.72.128";
+        let route_lines = parse_linux_route(output).unwrap();
+        assert_eq!(route_lines.len(), 3);
+        assert_eq!(
+            route_lines[0],
+            RouteLine::Default {
+                via: "192.168.72.2".parse().unwrap(),
+                dev: String::from("ens33"),
+            }
+        );
+        assert_eq!(
+            route_lines[1],
+            RouteLine::Route {
+                dst: RouteAddr::IpNetwork(IpNetwork::from_str("192.168.1.0/24").unwrap()),
+                dev: String::from("ens36"),
+            }
+        );
+        // centos7
+        let output = "default via 192.168.72.2 dev ens33 proto dhcp metric 100
+192.168.72.0/24 dev ens33 proto kernel scope link src 192.168.72.138 metric 100";
+        let route_lines = parse_linux_route(output).unwrap();
+        assert_eq!(route_lines.len(), 2);
+        assert_eq!(
+            route_lines[0],
+            RouteLine::Default {
+                via: "192.168.72.2".parse().unwrap(),
+                dev: String::from("ens33"),
+            }
+        );
+    }
+    #[test]
+    fn test_point_to_point_route() {
+        // openvpn tun0 with the peer 10.8.0.1, the lan 10.8.0.0/24 is also reachable by eth0
+        let output = "default via 192.168.1.1 dev eth0 proto dhcp metric 100
+10.8.0.0/24 dev eth0 proto kernel scope link src 10.8.0.50
+10.8.0.1 dev tun0 proto kernel scope link src 10.8.0.2
+10.9.0.0/16 via 10.8.0.1 dev tun0
+fd00:8::1/128 dev wg-vpn proto kernel metric 256 pref medium";
+        let route_lines = parse_linux_route(output).unwrap();
+        assert_eq!(route_lines.len(), 5);
+        assert_eq!(
+            route_lines[2],
+            RouteLine::Route {
+                dst: RouteAddr::IpAddr("10.8.0.1".parse().unwrap()),
+                dev: String::from("tun0"),
+            }
+        );
+        let fake_interface = |name: &str, index: u32| NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index,
+            mac: None,
+            ips: vec![],
+            flags: 0,
+        };
+        let route_table =
+            RouteTable::from_lines(route_lines, None, |dev: &String| match dev.as_str() {
+                "eth0" => Some(fake_interface("eth0", 2)),
+                "tun0" => Some(fake_interface("tun0", 3)),
+                "wg-vpn" => Some(fake_interface("wg-vpn", 4)),
+                _ => None,
+            });
+        let snc = SystemNetCache {
+            default_route: route_table.default_route,
+            default_route6: route_table.default_route6,
+            routes: route_table.routes,
+            neighbor: HashMap::new(),
+            source_addrs: HashMap::new(),
+        };
+        let dev = snc.search_route("10.8.0.1".parse().unwrap()).unwrap();
+        assert_eq!(dev.name, "tun0");
+        let dev = snc.search_route("10.8.0.5".parse().unwrap()).unwrap();
+        assert_eq!(dev.name, "eth0");
+        let dev = snc.search_route("10.9.1.1".parse().unwrap()).unwrap();
+        assert_eq!(dev.name, "tun0");
+        let dev = snc.search_route("fd00:8::1".parse().unwrap()).unwrap();
+        assert_eq!(dev.name, "wg-vpn");
+    }
+    #[test]
+    fn test_system_net_cache_json() {
+        let output = "default via 192.168.1.1 dev eth0 proto dhcp metric 100
+10.8.0.0/24 dev eth0 proto kernel scope link src 10.8.0.50
+10.8.0.1 dev eth0 proto kernel scope link src 10.8.0.50";
+        let find_interface = |dev: &String| {
+            Some(NetworkInterface {
+                name: dev.to_string(),
+                description: String::new(),
+                index: 2,
+                mac: None,
+                ips: vec![],
+                flags: 0,
+            })
+        };
+        let route_lines = parse_linux_route(output).unwrap();
+        let route_table = RouteTable::from_lines(route_lines, None, find_interface);
+        let mut snc = SystemNetCache {
+            default_route: None,
+            default_route6: None,
+            routes: HashMap::new(),
+            neighbor: HashMap::new(),
+            source_addrs: HashMap::new(),
+        };
+        snc.update_routes(route_table);
+        let mac = MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+        snc.update_neighbor_cache("fe80::1".parse().unwrap(), mac, Some(2));
+        snc.update_neighbor_cache("10.8.0.1".parse().unwrap(), mac, None);
+        snc.update_source_addr(
+            "10.8.0.5".parse().unwrap(),
+            false,
+            "10.8.0.50".parse().unwrap(),
+        );
+
+        let json = serde_json::to_string(&snc).unwrap();
+        let restored: SystemNetCache = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.routes, snc.routes);
+        assert_eq!(restored.neighbor, snc.neighbor);
+        assert_eq!(restored.source_addrs, snc.source_addrs);
+        assert_eq!(restored.routes.len(), 2);
+        assert_eq!(restored.neighbor.len(), 2);
+        assert_eq!(restored.source_addrs.len(), 1);
+    }
+    #[test]
+    fn test_source_addr_cache() {
+        let output = "default via 192.168.1.1 dev eth0 proto dhcp metric 100
+10.8.0.0/24 dev eth0 proto kernel scope link src 10.8.0.50
+10.8.0.1 dev tun0 proto kernel scope link src 10.8.0.2";
+        let fake_interface = |name: &str, index: u32| NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index,
+            mac: None,
+            ips: vec![],
+            flags: 0,
+        };
+        let find_interface = |dev: &String| match dev.as_str() {
+            "eth0" => Some(fake_interface("eth0", 2)),
+            "tun0" => Some(fake_interface("tun0", 3)),
+            _ => None,
+        };
+        let route_lines = parse_linux_route(output).unwrap();
+        let route_table = RouteTable::from_lines(route_lines, None, find_interface);
+        let mut snc = SystemNetCache {
+            default_route: None,
+            default_route6: None,
+            routes: HashMap::new(),
+            neighbor: HashMap::new(),
+            source_addrs: HashMap::new(),
+        };
+        snc.update_routes(route_table);
+        let lan_src: IpAddr = "10.8.0.50".parse().unwrap();
+        let tun_src: IpAddr = "10.8.0.2".parse().unwrap();
+        let wan_src: IpAddr = "192.168.1.10".parse().unwrap();
+        snc.update_source_addr("10.8.0.5".parse().unwrap(), false, lan_src);
+        snc.update_source_addr("10.8.0.1".parse().unwrap(), false, tun_src);
+        snc.update_source_addr("198.51.100.10".parse().unwrap(), false, wan_src);
+        // the same route
+        assert_eq!(
+            snc.search_source_addr("10.8.0.6".parse().unwrap(), false),
+            Some(lan_src)
+        );
+        // the peer route wins over the subnet route
+        assert_eq!(
+            snc.search_source_addr("10.8.0.1".parse().unwrap(), false),
+            Some(tun_src)
+        );
+        // the destinations without a route share the default route
+        assert_eq!(
+            snc.search_source_addr("203.0.113.1".parse().unwrap(), false),
+            Some(wan_src)
+        );
+        assert_eq!(
+            snc.search_source_addr("2001:db8::1".parse().unwrap(), false),
+            None
+        );
+        // the scan which prefers the temporary ipv6 address has its own entry
+        let dst_ipv6: IpAddr = "2001:db8::1".parse().unwrap();
+        let stable_src: IpAddr = "2001:db8::10".parse().unwrap();
+        let temporary_src: IpAddr = "2001:db8::20".parse().unwrap();
+        snc.update_source_addr(dst_ipv6, false, stable_src);
+        snc.update_source_addr(dst_ipv6, true, temporary_src);
+        assert_eq!(snc.search_source_addr(dst_ipv6, false), Some(stable_src));
+        assert_eq!(snc.search_source_addr(dst_ipv6, true), Some(temporary_src));
+
+        // the peer is gone, the source address is selected again
+        let output = "default via 192.168.1.1 dev eth0 proto dhcp metric 100
+10.8.0.0/24 dev eth0 proto kernel scope link src 10.8.0.50";
+        let route_lines = parse_linux_route(output).unwrap();
+        let route_table = RouteTable::from_lines(route_lines, None, find_interface);
+        snc.update_routes(route_table);
+        assert_eq!(
+            snc.search_source_addr("10.8.0.1".parse().unwrap(), false),
+            None
+        );
+        assert_eq!(
+            snc.search_source_addr("10.8.0.6".parse().unwrap(), false),
+            None
+        );
+    }
+    /// Generates the `ip route` output of a full table line by line, `run` is not supported
+    /// so the output can not be buffered as a whole.
+    struct LargeRouteRunner {
+        routes: usize,
+        lines: std::cell::Cell<usize>,
+    }
+    impl CommandRunner for LargeRouteRunner {
+        fn run(&self, cmd: &str) -> Result<String, PistolErrors> {
+            Err(PistolErrors::SystemCommandFailed {
+                cmd: cmd.to_string(),
+                stderr: String::from("buffered output"),
+            })
+        }
+        fn run_lines(&self, cmd: &str, f: &mut dyn FnMut(&str)) -> Result<(), PistolErrors> {
+            if cmd != "ip -4 route" {
+                return self.run(cmd).map(|_| ());
+            }
+            f("default via 192.168.1.1 dev eth0 proto dhcp metric 100");
+            for i in 0..self.routes {
+                let line = format!(
+                    "10.{}.{}.0/24 via 192.168.1.254 dev eth1 proto bgp metric 20",
+                    i >> 8,
+                    i & 0xff
+                );
+                f(&line);
+                self.lines.set(self.lines.get() + 1);
+            }
+            f("192.168.1.0/24 dev eth0 proto kernel scope link src 192.168.1.10");
+            Ok(())
+        }
+    }
+    #[test]
+    fn test_route_table_stream() {
+        let fake_interface = |name: &str, index: u32| NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index,
+            mac: None,
+            ips: vec![],
+            flags: 0,
+        };
+        let lookups = std::cell::Cell::new(0);
+        let find_interface = |dev: &String| {
+            lookups.set(lookups.get() + 1);
+            match dev.as_str() {
+                "eth0" => Some(fake_interface("eth0", 2)),
+                "eth1" => Some(fake_interface("eth1", 3)),
+                _ => None,
+            }
+        };
+        let runner = LargeRouteRunner {
+            routes: 50000,
+            lines: std::cell::Cell::new(0),
+        };
+        let prefixes = vec![
+            IpNetwork::from_str("10.7.0.0/16").unwrap(),
+            IpNetwork::from_str("192.168.1.5/32").unwrap(),
+        ];
+        let route_table =
+            RouteTable::from_linux_stream(&runner, Some(&prefixes), find_interface).unwrap();
+        assert_eq!(runner.lines.get(), 50000);
+        // 10.7.0.0/24 to 10.7.255.0/24 and the lan
+        assert_eq!(route_table.routes.len(), 257);
+        let route = RouteAddr::IpNetwork(IpNetwork::from_str("10.7.42.0/24").unwrap());
+        assert_eq!(route_table.routes[&route].name, "eth1");
+        let route = RouteAddr::IpNetwork(IpNetwork::from_str("10.8.0.0/24").unwrap());
+        assert!(!route_table.routes.contains_key(&route));
+        assert_eq!(route_table.default_route.unwrap().dev.name, "eth0");
+        // every device is looked up once
+        assert_eq!(lookups.get(), 2);
+
+        // without the prefixes all the routes are kept
+        let route_table =
+            RouteTable::from_linux_stream(&runner, None, |dev: &String| match dev.as_str() {
+                "eth0" => Some(fake_interface("eth0", 2)),
+                _ => Some(fake_interface("eth1", 3)),
+            })
+            .unwrap();
+        assert_eq!(route_table.routes.len(), 50001);
+    }
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn test_run_lines_stderr() {
+        // more stderr than the pipe holds, written before the stdout
+        let cmd = "head -c 200000 /dev/zero | tr '\\0' x >&2; echo done; exit 1";
+        let mut lines = Vec::new();
+        let ret = SystemCommandRunner.run_lines(cmd, &mut |l| lines.push(l.to_string()));
+        assert_eq!(lines, vec!["done"]);
+        match ret {
+            Err(PistolErrors::SystemCommandFailed { stderr, .. }) => {
+                assert_eq!(stderr.len(), 200000)
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+    #[test]
+    fn test_parse_bsd_route() {
+        let output = "default 192.168.72.2 UGS em0
+default fe80::4a5f:8ff:fee0:1394%em1 UG em1
+127.0.0.1          link#2             UH          lo0";
+        let route_lines = parse_bsd_route(output).unwrap();
+        assert_eq!(route_lines.len(), 3);
+        assert_eq!(
+            route_lines[1],
+            RouteLine::Default {
+                via: "fe80::4a5f:8ff:fee0:1394".parse().unwrap(),
+                dev: String::from("em1"),
+            }
+        );
+        assert_eq!(
+            route_lines[2],
+            RouteLine::Route {
+                dst: RouteAddr::IpAddr("127.0.0.1".parse().unwrap()),
+                dev: String::from("lo0"),
+            }
+        );
+        // real macos output
+        let route_lines = parse_bsd_route(include_str!("./test/macos_routetable.txt")).unwrap();
+        assert!(route_lines.contains(&RouteLine::Default {
+            via: "192.168.50.1".parse().unwrap(),
+            dev: String::from("en0"),
+        }));
+        assert!(route_lines.contains(&RouteLine::Route {
+            dst: RouteAddr::IpNetwork(IpNetwork::from_str("192.168.50.1/32").unwrap()),
+            dev: String::from("en0"),
+        }));
+    }
+    #[test]
+    fn test_parse_windows_route() {
+        let output = "ifIndex DestinationPrefix NextHop RouteMetric ifMetric PolicyStore
+------- ----------------- ------- ----------- -------- -----------
+1 ::1/128 :: 256 75 ActiveStore
+15 ::/0 fe80::ecb5:83ff:fec3:6a6 16 45 ActiveStore
+15 0.0.0.0/0 192.168.1.1 0 45 ActiveStore";
+        let route_lines = parse_windows_route(output).unwrap();
+        assert_eq!(
+            route_lines,
+            vec![
+                RouteLine::Route {
+                    dst: RouteAddr::IpNetwork(IpNetwork::from_str("::1/128").unwrap()),
+                    dev: 1,
+                },
+                RouteLine::Default {
+                    via: "fe80::ecb5:83ff:fec3:6a6".parse().unwrap(),
+                    dev: 15,
+                },
+                RouteLine::Default {
+                    via: "192.168.1.1".parse().unwrap(),
+                    dev: 15,
+                },
+            ]
+        );
+    }
+    #[test]
+    fn test_parse_neighbor() {
+        let output = "192.168.72.2 dev ens33 lladdr 00:50:56:fb:1d:74 STALE
+192.168.1.1 dev ens36 lladdr 48:5f:08:e0:13:94 STALE
+fe80::4a5f:8ff:fee0:1394 dev ens36 lladdr 48:5f:08:e0:13:94 router STALE";
+        let neighbor = parse_linux_neighbor(output).unwrap();
+        assert_eq!(neighbor.len(), 3);
+        assert_eq!(
+            neighbor[2],
+            NeighborLine {
+                addr: "fe80::4a5f:8ff:fee0:1394".parse().unwrap(),
+                mac: MacAddr::new(0x48, 0x5f, 0x08, 0xe0, 0x13, 0x94),
+                dev: Some(String::from("ens36")),
+            }
+        );
+
+        let output =
+            "? (192.168.72.1) at 00:50:56:c0:00:08 on em0 expires in 1139 seconds [ethernet]
+? (192.168.72.129) at 00:0c:29:88:20:d2 on em0 permanent [ethernet]
+? (192.168.50.2) at (incomplete) on en0 ifscope [ethernet]
+Neighbor                             Linklayer Address  Netif Expire    1s 5s
+fe80::20c:29ff:fe88:20d2%em1         00:0c:29:88:20:d2    em1 permanent R";
+        let neighbor = parse_bsd_neighbor(output).unwrap();
+        assert_eq!(neighbor.len(), 3);
+        assert_eq!(
+            neighbor[1],
+            NeighborLine {
+                addr: "192.168.72.129".parse().unwrap(),
+                mac: MacAddr::new(0x00, 0x0c, 0x29, 0x88, 0x20, 0xd2),
+                dev: Some(String::from("em0")),
+            }
+        );
+        assert_eq!(
+            neighbor[2],
+            NeighborLine {
+                addr: "fe80::20c:29ff:fe88:20d2".parse().unwrap(),
+                mac: MacAddr::new(0x00, 0x0c, 0x29, 0x88, 0x20, 0xd2),
+                dev: Some(String::from("em1")),
+            }
+        );
+
+        let output = "58 ff02::1:ff73:3ff4 33-33-FF-73-3F-F4 Permanent ActiveStore
+58 ff02::1:2  33-33-00-01-00-02 Permanent ActiveStore";
+        let neighbor = parse_windows_neighbor(output).unwrap();
+        assert_eq!(neighbor.len(), 2);
+        assert_eq!(
+            neighbor[0],
+            NeighborLine {
+                addr: "ff02::1:ff73:3ff4".parse().unwrap(),
+                mac: MacAddr::new(0x33, 0x33, 0xff, 0x73, 0x3f, 0xf4),
+                dev: Some(58),
+            }
+        );
+    }
+    #[test]
+    fn test_neighbor_link_local_zone() {
+        let output = "fe80::1 dev eth0 lladdr 00:50:56:fb:1d:74 router STALE
+fe80::1 dev eth1 lladdr 48:5f:08:e0:13:94 router STALE
+192.168.1.1 dev eth1 lladdr 48:5f:08:e0:13:94 STALE";
+        let neighbor_lines = parse_linux_neighbor(output).unwrap();
+        let neighbor =
+            NeighborCache::from_lines(neighbor_lines, |dev: &String| match dev.as_str() {
+                "eth0" => Some(2),
+                "eth1" => Some(3),
+                _ => None,
+            });
+        assert_eq!(neighbor.len(), 3);
+        let mut snc = SystemNetCache {
+            default_route: None,
+            default_route6: None,
+            routes: HashMap::new(),
+            neighbor,
+            source_addrs: HashMap::new(),
+        };
+        let link_local: IpAddr = "fe80::1".parse().unwrap();
+        let mac_eth0 = MacAddr::new(0x00, 0x50, 0x56, 0xfb, 0x1d, 0x74);
+        let mac_eth1 = MacAddr::new(0x48, 0x5f, 0x08, 0xe0, 0x13, 0x94);
+        assert_eq!(snc.search_mac(link_local, Some(2)), Some(mac_eth0));
+        assert_eq!(snc.search_mac(link_local, Some(3)), Some(mac_eth1));
+        // ambiguous without the interface
+        assert_eq!(snc.search_mac(link_local, None), None);
+        // the zone only matters for the link-local address
+        let ipv4: IpAddr = "192.168.1.1".parse().unwrap();
+        assert_eq!(snc.search_mac(ipv4, None), Some(mac_eth1));
+        assert_eq!(snc.search_mac(ipv4, Some(2)), Some(mac_eth1));
+
+        let other_link_local: IpAddr = "fe80::2".parse().unwrap();
+        snc.update_neighbor_cache(other_link_local, mac_eth0, Some(2));
+        assert_eq!(snc.search_mac(other_link_local, None), Some(mac_eth0));
+        assert_eq!(snc.search_mac(other_link_local, Some(3)), None);
+    }
+    #[test]
+    fn test_network_cache_diff() {
+        let gateway: IpAddr = "192.168.1.1".parse().unwrap();
+        let host_1: IpAddr = "192.168.1.10".parse().unwrap();
+        let host_2: IpAddr = "192.168.1.11".parse().unwrap();
+        let mac_1 = MacAddr::new(0x48, 0x5f, 0x08, 0xe0, 0x13, 0x94);
+        let mac_2 = MacAddr::new(0x00, 0x50, 0x56, 0xfb, 0x1d, 0x74);
+        let mut old = SystemNetCache {
+            default_route: None,
+            default_route6: None,
+            routes: HashMap::new(),
+            neighbor: HashMap::new(),
+            source_addrs: HashMap::new(),
+        };
+        old.update_neighbor_cache(gateway, mac_1, None);
+        old.update_neighbor_cache(host_1, mac_2, None);
+        let mut new = old.clone();
+        assert!(old.diff(&new).is_empty());
+
+        // the gateway now answers with another mac
+        new.update_neighbor_cache(gateway, mac_2, None);
+        new.neighbor.remove(&NeighborKey::new(host_1, None));
+        new.update_neighbor_cache(host_2, mac_1, None);
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.changed_neighbors,
+            vec![NeighborChange {
+                key: NeighborKey::new(gateway, None),
+                old_mac: mac_1,
+                new_mac: mac_2,
+            }]
+        );
+        assert_eq!(
+            diff.added_neighbors,
+            vec![(NeighborKey::new(host_2, None), mac_1)]
+        );
+        assert_eq!(
+            diff.removed_neighbors,
+            vec![(NeighborKey::new(host_1, None), mac_2)]
+        );
+        assert!(!diff.default_route_changed);
+
+        let interface = interfaces().into_iter().next().unwrap();
+        new.default_route = Some(DefaultRoute {
+            via: gateway,
+            dev: interface,
+        });
+        assert!(old.diff(&new).default_route_changed);
+    }
+    #[test]
+    fn test_default_route_summary() {
+        let dev = NetworkInterface {
+            name: String::from("eth0"),
+            description: String::new(),
+            index: 2,
+            mac: Some(MacAddr::new(0x00, 0x0c, 0x29, 0x1f, 0x6f, 0x71)),
+            ips: vec!["192.168.1.10/24".parse().unwrap()],
+            flags: 0,
+        };
+        let default_route = DefaultRoute {
+            via: "192.168.1.1".parse().unwrap(),
+            dev,
+        };
+        let summary = default_route.summary();
+        assert_eq!(summary.via, default_route.via);
+        assert_eq!(summary.dev_name, default_route.dev.name);
+        assert_eq!(summary.dev_index, default_route.dev.index);
+        assert_eq!(summary.to_string(), "default via 192.168.1.1 dev eth0");
+    }
+    #[test]
+    fn test_detect_mac_conflicts() {
+        let gateway: IpAddr = "192.168.1.1".parse().unwrap();
+        let host: IpAddr = "192.168.1.10".parse().unwrap();
+        let gateway_mac = MacAddr::new(0x48, 0x5f, 0x08, 0xe0, 0x13, 0x94);
+        let attacker_mac = MacAddr::new(0x00, 0x50, 0x56, 0xfb, 0x1d, 0x74);
+        let router_mac = MacAddr::new(0x00, 0x0c, 0x29, 0x88, 0x20, 0xd2);
+        let empty = SystemNetCache {
+            default_route: None,
+            default_route6: None,
+            routes: HashMap::new(),
+            neighbor: HashMap::new(),
+            source_addrs: HashMap::new(),
+        };
+        let mut snc_1 = empty.clone();
+        snc_1.update_neighbor_cache(gateway, gateway_mac, None);
+        snc_1.update_neighbor_cache(host, attacker_mac, None);
+        // the attacker claims to be the gateway, then the gateway is back
+        let mut snc_2 = empty.clone();
+        snc_2.update_neighbor_cache(gateway, attacker_mac, None);
+        snc_2.update_neighbor_cache(host, attacker_mac, None);
+        let snc_3 = snc_1.clone();
+        // the router owns many addresses legitimately
+        let mut snc_4 = empty.clone();
+        for i in 100..110 {
+            let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, i));
+            snc_4.update_neighbor_cache(addr, router_mac, None);
+        }
+        let history = vec![snc_1, snc_2, snc_3, snc_4];
+
+        let conflicts = detect_mac_conflicts(&history, 4, &[router_mac]);
+        assert_eq!(
+            conflicts,
+            vec![MacConflict::MacFlap {
+                key: NeighborKey::new(gateway, None),
+                macs: vec![gateway_mac, attacker_mac],
+            }]
+        );
+        let conflicts = detect_mac_conflicts(&history, 1, &[router_mac]);
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(
+            conflicts[1],
+            MacConflict::MultipleAddrs {
+                mac: attacker_mac,
+                addrs: vec![
+                    NeighborKey::new(gateway, None),
+                    NeighborKey::new(host, None)
+                ],
+            }
+        );
+        let conflicts = detect_mac_conflicts(&history, 4, &[]);
+        assert_eq!(conflicts.len(), 2);
+    }
     #[test]
     fn test_network_cache() {
         // use crate::Logger;
@@ -680,7 +1799,6 @@ mod tests {
     fn test_windows_interface() {
         println!("TEST!!!!");
         for interface in interfaces() {
-            // can not found ipv6 address in windows
             println!("{}", interface);
             println!("{}", interface.index);
         }
@@ -704,4 +1822,35 @@ mod tests {
         let test_ipv6: IpAddr = "fe80::20c:29ff:feb6:8d99".parse().unwrap();
         println!("{}", ipnetwork.contains(test_ipv6));
     }
+    #[test]
+    fn test_route_addr_hosts() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let net24 = RouteAddr::IpNetwork("192.168.1.77/24".parse().unwrap());
+        assert_eq!(net24.network(), ip("192.168.1.0"));
+        assert_eq!(net24.broadcast(), Some(ip("192.168.1.255")));
+        let hosts: Vec<IpAddr> = net24.hosts_iter().collect();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], ip("192.168.1.1"));
+        assert_eq!(hosts[253], ip("192.168.1.254"));
+
+        // the point-to-point link uses both addresses
+        let net31 = RouteAddr::IpNetwork("10.0.0.1/31".parse().unwrap());
+        assert_eq!(net31.network(), ip("10.0.0.0"));
+        assert_eq!(net31.broadcast(), None);
+        let hosts: Vec<IpAddr> = net31.hosts_iter().collect();
+        assert_eq!(hosts, vec![ip("10.0.0.0"), ip("10.0.0.1")]);
+
+        let net32 = RouteAddr::IpNetwork("10.0.0.9/32".parse().unwrap());
+        assert_eq!(net32.network(), ip("10.0.0.9"));
+        assert_eq!(net32.broadcast(), None);
+        assert_eq!(net32.hosts_iter().collect::<Vec<_>>(), vec![ip("10.0.0.9")]);
+        let addr = RouteAddr::IpAddr(ip("10.0.0.9"));
+        assert_eq!(addr.network(), ip("10.0.0.9"));
+        assert_eq!(addr.hosts_iter().collect::<Vec<_>>(), vec![ip("10.0.0.9")]);
+
+        // no broadcast in ipv6
+        let net6 = RouteAddr::IpNetwork("fe80::/126".parse().unwrap());
+        assert_eq!(net6.broadcast(), None);
+        assert_eq!(net6.hosts_iter().count(), 4);
+    }
 }
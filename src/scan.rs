@@ -1,37 +1,76 @@
 /* Scan */
 use log::warn;
 use pnet::datalink::MacAddr;
+use pnet::ipnetwork::IpNetwork;
 use prettytable::row;
 use prettytable::Cell;
 use prettytable::Row;
 use prettytable::Table;
 use serde::Deserialize;
 use serde::Serialize;
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
+use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 
 pub mod arp;
+pub mod checkpoint;
+pub mod socks5;
 pub mod tcp;
 pub mod tcp6;
 pub mod udp;
 pub mod udp6;
 
 use crate::errors::PistolErrors;
+use crate::layers::capture_reply;
+use crate::layers::record_reply;
+use crate::layers::with_probe_options;
+use crate::layers::ProbeOptions;
+use crate::payloads;
+use crate::utils::clock_elapsed;
+use crate::utils::clock_now;
+use crate::utils::dispatch_probes;
 use crate::utils::find_interface_by_ip;
 use crate::utils::find_source_addr;
 use crate::utils::find_source_addr6;
+use crate::utils::find_source_addr6_with;
 use crate::utils::get_default_timeout;
+use crate::utils::get_default_timeout_by_src;
 use crate::utils::get_threads_pool;
 use crate::utils::random_port;
+use crate::utils::random_port_in;
+use crate::utils::Clock;
+use crate::utils::HostConnections;
+use crate::utils::ProbeJob;
+use crate::utils::TimingTemplate;
+use crate::vs::VsScanResults;
+use crate::Host;
 use crate::Target;
 
+use checkpoint::CheckpointWriter;
+use socks5::Socks5Proxy;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ScanMethod {
     Connect,
@@ -46,7 +85,7 @@ pub enum ScanMethod {
     Udp,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum PortStatus {
     Open,
     Closed,
@@ -60,40 +99,400 @@ pub enum PortStatus {
     Offline,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl PortStatus {
+    /// How much the status tells about the port, the merge of the results keeps the larger one.
+    fn informativeness(&self) -> u8 {
+        match self {
+            PortStatus::Open => 8,
+            PortStatus::Closed => 7,
+            PortStatus::Unfiltered => 6,
+            PortStatus::OpenOrFiltered => 5,
+            PortStatus::ClosedOrFiltered => 4,
+            PortStatus::Filtered => 3,
+            PortStatus::Unreachable => 2,
+            PortStatus::Offline => 1,
+            PortStatus::Error => 0,
+        }
+    }
+}
+
+/// The evidence which the port status is classified by, like the `--reason` of nmap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PortReason {
+    /// The tcp syn/ack response, or the connection accepted by the connect scan.
+    SynAck,
+    /// The tcp rst response, or the connection refused in the connect scan.
+    Reset,
+    UdpResponse,
+    /// The icmp (icmpv6 for the ipv6 target) destination unreachable error.
+    IcmpUnreachable {
+        icmp_type: u8,
+        icmp_code: u8,
+    },
+    /// The reply code of the SOCKS5 proxy.
+    ProxyReply(u8),
+    /// The ip id increment of the zombie host in the idle scan.
+    ZombieIpId,
+    #[default]
+    NoResponse,
+    /// The mac address of the host can not be found.
+    HostDown,
+    /// The port is given as open by `Host::known_open`, no probe is sent.
+    KnownOpen,
+    Error,
+}
+
+/// The connect() refused by the rst, or timed out without response.
+pub(crate) fn connect_error_reason(e: &std::io::Error) -> PortReason {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => PortReason::Reset,
+        _ => PortReason::NoResponse,
+    }
+}
+
+/// The coarse os family guessed from a single syn/ack.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OsHint {
+    Linux,
+    Bsd, // FreeBSD and macOS
+    Windows,
+    Solaris,
+    NetworkDevice, // Cisco IOS and the like
+}
+
+impl OsHint {
+    /// Guess the os family from the ttl and the tcp window size of the syn/ack.
+    pub fn classify(ttl: u8, window: u16) -> OsHint {
+        // the initial ttl is the nearest common default not below the observed one
+        match ttl {
+            0..=64 => match window {
+                65535 => OsHint::Bsd,
+                _ => OsHint::Linux,
+            },
+            65..=128 => OsHint::Windows,
+            _ => match window {
+                4128 => OsHint::NetworkDevice,
+                _ => OsHint::Solaris,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PortScanResults {
     pub port_status: PortStatus,
+    pub reason: PortReason,
     pub port_time_cost: Duration,
+    /// The raw reply packet of the probe, only captured with the `capture_raw` option.
+    pub raw_reply: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScanResults {
     pub scans: HashMap<IpAddr, HashMap<u16, Vec<PortScanResults>>>,
+    /// The source address used to scan each host.
+    pub source_addrs: HashMap<IpAddr, IpAddr>,
     pub avg_time_cost: f64,
     pub total_time_cost: f64,
     pub open_ports: usize,
+    /// The retries taken from the scan-wide retry budget.
+    pub retries: usize,
+    /// The hosts abandoned by the host timeout, their received results are kept.
+    pub timed_out_hosts: Vec<IpAddr>,
+    /// The scan deadline was reached before all the probes were answered.
+    pub incomplete: bool,
+    #[serde(skip, default = "Instant::now")]
     start_time: Instant,
     tests: usize,
 }
 
+/// The retries shared by all the probes of a scan.
+/// A probe without response (filtered or open|filtered) is sent again at most `per_probe` times,
+/// and once `total` retries are used up the remaining probes are sent only once.
+#[derive(Debug, Clone)]
+pub struct RetryBudget {
+    total: usize,
+    per_probe: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl RetryBudget {
+    pub fn new(total: usize, per_probe: usize) -> RetryBudget {
+        RetryBudget {
+            total,
+            per_probe,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    /// The retries used so far.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+    fn take(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| {
+                if u < self.total {
+                    Some(u + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+fn probe_with_retry<F>(
+    retry_budget: Option<&RetryBudget>,
+    mut probe: F,
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors>
+where
+    F: FnMut() -> Result<(PortStatus, PortReason, Duration), PistolErrors>,
+{
+    let mut ret = probe();
+    if let Some(budget) = retry_budget {
+        for _ in 0..budget.per_probe {
+            match ret {
+                Ok((PortStatus::Filtered | PortStatus::OpenOrFiltered, _, _)) if budget.take() => {
+                    ret = probe()
+                }
+                _ => break,
+            }
+        }
+    }
+    ret
+}
+
+/// The changes between two scans of the same targets.
+/// A host which only appears in one scan has all of its ports unknown in the other one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanDiff {
+    /// Hosts in the new scan but not in the previous one.
+    pub new_hosts: Vec<IpAddr>,
+    /// Hosts in the previous scan but not in the new one.
+    pub missing_hosts: Vec<IpAddr>,
+    /// Ports which are open now but were not open (or unknown) before.
+    pub newly_open: Vec<(IpAddr, u16)>,
+    /// Ports which were open before but are not open now.
+    pub newly_closed: Vec<(IpAddr, u16)>,
+    /// Ports which are scanned now but were unknown before.
+    pub new_ports: Vec<(IpAddr, u16)>,
+}
+
+impl ScanDiff {
+    pub fn is_empty(&self) -> bool {
+        self.new_hosts.is_empty()
+            && self.missing_hosts.is_empty()
+            && self.newly_open.is_empty()
+            && self.newly_closed.is_empty()
+            && self.new_ports.is_empty()
+    }
+}
+
+/// The hosts of one subnet in the scan results.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SubnetSummary {
+    /// The scanned hosts of the subnet.
+    pub hosts: usize,
+    /// The hosts which answered, with any port open, closed or unfiltered.
+    pub hosts_up: usize,
+    /// The open ports and the number of hosts they are open on.
+    pub open_ports: BTreeMap<u16, usize>,
+}
+
 impl ScanResults {
     pub fn new() -> ScanResults {
         ScanResults {
             scans: HashMap::new(),
+            source_addrs: HashMap::new(),
             avg_time_cost: 0.0,
             total_time_cost: 0.0,
             open_ports: 0,
+            retries: 0,
+            timed_out_hosts: Vec::new(),
+            incomplete: false,
             start_time: Instant::now(),
             tests: 0,
         }
     }
+    /// Serialize to the compact binary format, e.g. to send the results of a worker process.
+    /// It is much smaller than json for the large scans.
+    #[cfg(feature = "bincode")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PistolErrors> {
+        Ok(bincode::serialize(self)?)
+    }
+    /// Deserialize the results from `ScanResults::to_bytes`.
+    #[cfg(feature = "bincode")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+    pub fn from_bytes(bytes: &[u8]) -> Result<ScanResults, PistolErrors> {
+        Ok(bincode::deserialize(bytes)?)
+    }
     pub fn get(&self, k: &IpAddr) -> Option<HashMap<u16, Vec<PortScanResults>>> {
         match self.scans.get(k) {
             Some(ph) => Some(ph.clone()),
             None => None,
         }
     }
+    /// The status of the port, open if any of the tests found it open.
+    pub fn port_status(&self, k: &IpAddr, port: u16) -> Option<PortStatus> {
+        let psr = self.scans.get(k)?.get(&port)?;
+        match psr.iter().find(|p| p.port_status == PortStatus::Open) {
+            Some(p) => Some(p.port_status),
+            None => psr.first().map(|p| p.port_status),
+        }
+    }
+    /// Compare with the `previous` scan results, the results are sorted.
+    pub fn diff(&self, previous: &ScanResults) -> ScanDiff {
+        let mut diff = ScanDiff::default();
+        for (ip, ports) in &self.scans {
+            if !previous.scans.contains_key(ip) {
+                diff.new_hosts.push(*ip);
+            }
+            for port in ports.keys() {
+                let now = self.port_status(ip, *port);
+                let before = previous.port_status(ip, *port);
+                if before.is_none() {
+                    diff.new_ports.push((*ip, *port));
+                }
+                match (before, now) {
+                    (Some(PortStatus::Open), Some(PortStatus::Open)) => (),
+                    (_, Some(PortStatus::Open)) => diff.newly_open.push((*ip, *port)),
+                    (Some(PortStatus::Open), Some(_)) => diff.newly_closed.push((*ip, *port)),
+                    _ => (),
+                }
+            }
+        }
+        for ip in previous.scans.keys() {
+            if !self.scans.contains_key(ip) {
+                diff.missing_hosts.push(*ip);
+            }
+        }
+        diff.new_hosts.sort();
+        diff.missing_hosts.sort();
+        diff.newly_open.sort();
+        diff.newly_closed.sort();
+        diff.new_ports.sort();
+        diff
+    }
+    /// Group the hosts by the subnet they are in, `/ipv4_prefix` for ipv4 and `/ipv6_prefix` for ipv6,
+    /// e.g. 24 and 64 for the usual LANs.
+    pub fn group_by_network(
+        &self,
+        ipv4_prefix: u8,
+        ipv6_prefix: u8,
+    ) -> Result<BTreeMap<IpNetwork, SubnetSummary>, PistolErrors> {
+        let mut ret: BTreeMap<IpNetwork, SubnetSummary> = BTreeMap::new();
+        for (ip, ports) in &self.scans {
+            let prefix = match ip {
+                IpAddr::V4(_) => ipv4_prefix,
+                IpAddr::V6(_) => ipv6_prefix,
+            };
+            let network = IpNetwork::new(*ip, prefix)?;
+            let network = IpNetwork::new(network.network(), prefix)?;
+            let summary = ret.entry(network).or_default();
+            summary.hosts += 1;
+            let mut up = false;
+            for port in ports.keys() {
+                match self.port_status(ip, *port) {
+                    Some(PortStatus::Open) => {
+                        up = true;
+                        *summary.open_ports.entry(*port).or_default() += 1;
+                    }
+                    Some(PortStatus::Closed) | Some(PortStatus::Unfiltered) => up = true,
+                    _ => (),
+                }
+            }
+            if up {
+                summary.hosts_up += 1;
+            }
+        }
+        Ok(ret)
+    }
+    /// Change the open|filtered ports to open if the service detection reached them,
+    /// by the accepted tcp connection, or by the udp response for the udp scan.
+    /// Returns the promoted ports, sorted.
+    pub fn promote_open(&mut self, method: ScanMethod, vs: &VsScanResults) -> Vec<(IpAddr, u16)> {
+        let mut promoted = Vec::new();
+        for (ip, ports) in &vs.vss {
+            for (port, services) in ports {
+                let (open, reason) = match method {
+                    ScanMethod::Udp => (services.udp_open, PortReason::UdpResponse),
+                    _ => (services.tcp_open, PortReason::SynAck),
+                };
+                if !open {
+                    continue;
+                }
+                let psr = match self.scans.get_mut(ip).and_then(|h| h.get_mut(port)) {
+                    Some(psr) => psr,
+                    None => continue,
+                };
+                let was_open = psr.iter().any(|p| p.port_status == PortStatus::Open);
+                let mut changed = false;
+                for p in psr.iter_mut() {
+                    if p.port_status == PortStatus::OpenOrFiltered {
+                        p.port_status = PortStatus::Open;
+                        p.reason = reason;
+                        changed = true;
+                    }
+                }
+                if changed {
+                    if !was_open {
+                        self.open_ports += 1;
+                    }
+                    promoted.push((*ip, *port));
+                }
+            }
+        }
+        promoted.sort();
+        promoted
+    }
+    /// Merge the results of another worker, e.g. which scanned another port range of the same hosts.
+    /// If both have a port, the results with the most informative status are kept
+    /// (open > closed > unfiltered > open|filtered > closed|filtered > filtered > unreachable > offline > error),
+    /// and on a tie the results of `self` are kept.
+    /// The retries are added up and the time cost is the longer one, since the workers run at the same time.
+    pub fn merge(&mut self, other: ScanResults) {
+        let best = |psr: &[PortScanResults]| {
+            psr.iter()
+                .map(|p| p.port_status.informativeness())
+                .max()
+                .unwrap_or(0)
+        };
+        for (ip, ports) in other.scans {
+            let host = self.scans.entry(ip).or_default();
+            for (port, psr) in ports {
+                match host.get(&port) {
+                    Some(current) if best(current) >= best(&psr) => (),
+                    _ => {
+                        host.insert(port, psr);
+                    }
+                }
+            }
+        }
+        for (ip, src_addr) in other.source_addrs {
+            self.source_addrs.entry(ip).or_insert(src_addr);
+        }
+        for ip in other.timed_out_hosts {
+            if !self.timed_out_hosts.contains(&ip) {
+                self.timed_out_hosts.push(ip);
+            }
+        }
+        self.retries += other.retries;
+        self.incomplete |= other.incomplete;
+        self.tests = self.tests.max(other.tests);
+        self.total_time_cost = self.total_time_cost.max(other.total_time_cost);
+        self.count_ports();
+    }
+    /// The source address which was actually used to scan the host.
+    pub fn source_for(&self, k: &IpAddr) -> Option<IpAddr> {
+        self.source_addrs.get(k).copied()
+    }
     pub fn enrichment(&mut self) {
+        self.count_ports();
+        self.total_time_cost = self.start_time.elapsed().as_secs_f64();
+    }
+    /// The avg time cost and the open ports.
+    fn count_ports(&mut self) {
         // avg rtt
         let mut total_cost = 0.0;
         let mut total_num = 0;
@@ -119,20 +518,36 @@ impl ScanResults {
         }
         self.avg_time_cost = total_cost / total_num as f64;
         self.open_ports = open_ports;
-        self.total_time_cost = self.start_time.elapsed().as_secs_f64();
     }
+    #[cfg(test)]
     fn insert(
         &mut self,
         dst_addr: IpAddr,
         dst_port: u16,
         port_status: PortStatus,
+        reason: PortReason,
         port_time_cost: Duration,
     ) {
         let psr = PortScanResults {
             port_status,
+            reason,
             port_time_cost,
+            raw_reply: None,
         };
-
+        self.insert_result(dst_addr, dst_port, psr)
+    }
+    /// The results of one host, with its source address.
+    fn host_results(&self, dst_addr: &IpAddr) -> ScanResults {
+        let mut ret = ScanResults::new();
+        if let Some(s) = self.scans.get(dst_addr) {
+            ret.scans.insert(*dst_addr, s.clone());
+        }
+        if let Some(s) = self.source_addrs.get(dst_addr) {
+            ret.source_addrs.insert(*dst_addr, *s);
+        }
+        ret
+    }
+    fn insert_result(&mut self, dst_addr: IpAddr, dst_port: u16, psr: PortScanResults) {
         match self.scans.get_mut(&dst_addr) {
             Some(s) => match s.get_mut(&dst_port) {
                 Some(d) => {
@@ -453,6 +868,61 @@ pub fn arp_scan(
     Ok(ret)
 }
 
+/// Scan the loopback address through the system sockets,
+/// the raw packets and the layer 2 resolution do not work well on the loopback interface.
+/// The tcp methods are replaced by the connect scan, the udp one sends the `udp_payload`.
+pub(crate) fn loopback_scan(
+    method: ScanMethod,
+    dst_addr: IpAddr,
+    dst_port: u16,
+    udp_payload: &[u8],
+    timeout: Duration,
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
+    let addr = SocketAddr::new(dst_addr, dst_port);
+    let start_time = clock_now();
+    match method {
+        ScanMethod::Udp => {
+            let bind_addr: IpAddr = match dst_addr {
+                IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            let socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0))?;
+            socket.connect(addr)?;
+            socket.set_read_timeout(Some(timeout))?;
+            socket.send(udp_payload)?;
+            let mut buff = [0u8; 65535];
+            let (port_status, reason) = match socket.recv(&mut buff) {
+                Ok(n) => {
+                    record_reply(&buff[..n]);
+                    (PortStatus::Open, PortReason::UdpResponse)
+                }
+                // the port unreachable message is reported as the connection refused error
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => (
+                    PortStatus::Closed,
+                    PortReason::IcmpUnreachable {
+                        icmp_type: 3,
+                        icmp_code: 3,
+                    },
+                ),
+                Err(_) => (PortStatus::OpenOrFiltered, PortReason::NoResponse),
+            };
+            Ok((port_status, reason, clock_elapsed(start_time)))
+        }
+        _ => match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => Ok((
+                PortStatus::Open,
+                PortReason::SynAck,
+                clock_elapsed(start_time),
+            )),
+            Err(e) => Ok((
+                PortStatus::Closed,
+                connect_error_reason(&e),
+                clock_elapsed(start_time),
+            )),
+        },
+    }
+}
+
 fn threads_scan(
     method: ScanMethod,
     dst_ipv4: Ipv4Addr,
@@ -462,8 +932,12 @@ fn threads_scan(
     zombie_ipv4: Option<Ipv4Addr>,
     zombie_port: Option<u16>,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
-    let (scan_ret, rtt) = match method {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
+    if dst_ipv4.is_loopback() {
+        let udp_payload = payloads::for_port(dst_port).unwrap_or_default();
+        return loopback_scan(method, dst_ipv4.into(), dst_port, udp_payload, timeout);
+    }
+    let (scan_ret, reason, rtt) = match method {
         ScanMethod::Connect => {
             tcp::send_connect_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?
         }
@@ -500,16 +974,17 @@ fn threads_scan(
                 zombie_port,
                 timeout,
             ) {
-                Ok((status, _idel_rets, rtt)) => (status, rtt),
+                Ok((status, reason, _idel_rets, rtt)) => (status, reason, rtt),
                 Err(e) => return Err(e.into()),
             }
         }
         ScanMethod::Udp => {
-            udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?
+            let udp_payload = payloads::for_port(dst_port).unwrap_or_default();
+            udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, udp_payload, timeout)?
         }
     };
 
-    Ok((scan_ret, rtt))
+    Ok((scan_ret, reason, rtt))
 }
 
 fn threads_scan6(
@@ -519,8 +994,12 @@ fn threads_scan6(
     src_ipv6: Ipv6Addr,
     src_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
-    let (scan_ret, rtt) = match method {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
+    if dst_ipv6.is_loopback() {
+        let udp_payload = payloads::for_port(dst_port).unwrap_or_default();
+        return loopback_scan(method, dst_ipv6.into(), dst_port, udp_payload, timeout);
+    }
+    let (scan_ret, reason, rtt) = match method {
         ScanMethod::Connect => {
             tcp6::send_connect_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?
         }
@@ -546,7 +1025,15 @@ fn threads_scan6(
             tcp6::send_maimon_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?
         }
         ScanMethod::Udp => {
-            udp6::send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?
+            let udp_payload = payloads::for_port(dst_port).unwrap_or_default();
+            udp6::send_udp_scan_packet(
+                src_ipv6,
+                src_port,
+                dst_ipv6,
+                dst_port,
+                udp_payload,
+                timeout,
+            )?
         }
         ScanMethod::Idle => {
             warn!("idel scan not supported the ipv6 address, use connect scan instead now");
@@ -554,7 +1041,160 @@ fn threads_scan6(
         }
     };
 
-    Ok((scan_ret, rtt))
+    Ok((scan_ret, reason, rtt))
+}
+
+/// The settings of `scan_with_options`, the default one is the plain scan of `scan` with all its arguments None.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    pub src_addr: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    /// The zombie host of the idle scan.
+    pub zombie_ipv4: Option<Ipv4Addr>,
+    pub zombie_port: Option<u16>,
+    pub timeout: Option<Duration>,
+    /// The number of probes sent to every port, 1 by default.
+    pub tests: usize,
+    /// The source port is a random one in this range if the `src_port` is not given.
+    pub src_port_range: Option<RangeInclusive<u16>>,
+    /// Select the temporary (privacy extensions) ipv6 address as the source instead of the stable one
+    /// when `src_addr` is not given, see `find_source_addr6_with`.
+    pub prefer_temporary_ipv6: bool,
+    /// Route the connect scan through the SOCKS5 proxy, the other methods can not be proxied.
+    pub proxy: Option<Socks5Proxy>,
+    /// The connect scan opens at most this number of connections to a host at the same time,
+    /// so the connection limits of the host are not tripped, the other methods ignore it.
+    pub max_host_connections: Option<usize>,
+    /// The retries shared by all the probes of the scan.
+    pub retry_budget: Option<RetryBudget>,
+    /// The probes of a host still unanswered this long after its first probe is sent are abandoned
+    /// (like the `--host-timeout` of nmap), the host is reported in `timed_out_hosts` and its received results are kept.
+    pub host_timeout: Option<Duration>,
+    /// After it the whole scan stops and returns the received results with `incomplete` set.
+    pub scan_deadline: Option<Instant>,
+    /// Keep the raw reply packet of every probe in the results for debugging.
+    pub capture_raw: bool,
+    /// Craft the probes, e.g. the ip options inserted into the ipv4 header,
+    /// or the wrong checksum which only a middlebox (not the real host) responds to.
+    pub probe_options: Option<ProbeOptions>,
+    /// Every completed probe is appended to this file, which `resume_scan` continues from.
+    pub checkpoint: Option<PathBuf>,
+    /// The timing template sets the timeout, retries, parallelism and scan delay not given explicitly.
+    pub timing: Option<TimingTemplate>,
+    /// The probes of a host are sent at least this apart, the other hosts are not held up by it.
+    pub scan_delay: Option<Duration>,
+    /// The time source of the rtt measurement of every probe, the system clock by default, see `with_clock`.
+    pub clock: Option<Arc<dyn Clock + Send + Sync>>,
+    /// The max number of probes sent at the same time, instead of the parallelism of the `timing`,
+    /// every probe has its own thread by default.
+    pub threads_num: Option<usize>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            src_addr: None,
+            src_port: None,
+            zombie_ipv4: None,
+            zombie_port: None,
+            timeout: None,
+            tests: 1,
+            src_port_range: None,
+            prefer_temporary_ipv6: false,
+            proxy: None,
+            max_host_connections: None,
+            retry_budget: None,
+            host_timeout: None,
+            scan_deadline: None,
+            capture_raw: false,
+            probe_options: None,
+            checkpoint: None,
+            timing: None,
+            scan_delay: None,
+            clock: None,
+            threads_num: None,
+        }
+    }
+}
+
+impl ScanOptions {
+    pub fn with_src_addr(mut self, src_addr: IpAddr) -> ScanOptions {
+        self.src_addr = Some(src_addr);
+        self
+    }
+    pub fn with_src_port(mut self, src_port: u16) -> ScanOptions {
+        self.src_port = Some(src_port);
+        self
+    }
+    pub fn with_zombie(mut self, zombie_ipv4: Ipv4Addr, zombie_port: u16) -> ScanOptions {
+        self.zombie_ipv4 = Some(zombie_ipv4);
+        self.zombie_port = Some(zombie_port);
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> ScanOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+    pub fn with_tests(mut self, tests: usize) -> ScanOptions {
+        self.tests = tests;
+        self
+    }
+    pub fn with_src_port_range(mut self, src_port_range: RangeInclusive<u16>) -> ScanOptions {
+        self.src_port_range = Some(src_port_range);
+        self
+    }
+    pub fn with_prefer_temporary_ipv6(mut self, prefer_temporary_ipv6: bool) -> ScanOptions {
+        self.prefer_temporary_ipv6 = prefer_temporary_ipv6;
+        self
+    }
+    pub fn with_proxy(mut self, proxy: Socks5Proxy) -> ScanOptions {
+        self.proxy = Some(proxy);
+        self
+    }
+    pub fn with_max_host_connections(mut self, max_host_connections: usize) -> ScanOptions {
+        self.max_host_connections = Some(max_host_connections);
+        self
+    }
+    pub fn with_retry_budget(mut self, retry_budget: RetryBudget) -> ScanOptions {
+        self.retry_budget = Some(retry_budget);
+        self
+    }
+    pub fn with_host_timeout(mut self, host_timeout: Duration) -> ScanOptions {
+        self.host_timeout = Some(host_timeout);
+        self
+    }
+    pub fn with_scan_deadline(mut self, scan_deadline: Instant) -> ScanOptions {
+        self.scan_deadline = Some(scan_deadline);
+        self
+    }
+    pub fn with_capture_raw(mut self, capture_raw: bool) -> ScanOptions {
+        self.capture_raw = capture_raw;
+        self
+    }
+    pub fn with_probe_options(mut self, probe_options: ProbeOptions) -> ScanOptions {
+        self.probe_options = Some(probe_options);
+        self
+    }
+    pub fn with_checkpoint(mut self, checkpoint: &Path) -> ScanOptions {
+        self.checkpoint = Some(checkpoint.to_path_buf());
+        self
+    }
+    pub fn with_timing(mut self, timing: TimingTemplate) -> ScanOptions {
+        self.timing = Some(timing);
+        self
+    }
+    pub fn with_scan_delay(mut self, scan_delay: Duration) -> ScanOptions {
+        self.scan_delay = Some(scan_delay);
+        self
+    }
+    pub fn with_clock(mut self, clock: Arc<dyn Clock + Send + Sync>) -> ScanOptions {
+        self.clock = Some(clock);
+        self
+    }
+    pub fn with_threads_num(mut self, threads_num: usize) -> ScanOptions {
+        self.threads_num = Some(threads_num);
+        self
+    }
 }
 
 /// General scan function.
@@ -568,23 +1208,105 @@ pub fn scan(
     timeout: Option<Duration>,
     tests: usize,
 ) -> Result<ScanResults, PistolErrors> {
+    let options = ScanOptions {
+        src_addr,
+        src_port,
+        zombie_ipv4,
+        zombie_port,
+        timeout,
+        tests,
+        ..Default::default()
+    };
+    scan_with_options(target, method, &options)
+}
+
+/// The `scan` with the `options`, see `ScanOptions`.
+pub fn scan_with_options(
+    target: Target,
+    method: ScanMethod,
+    options: &ScanOptions,
+) -> Result<ScanResults, PistolErrors> {
+    scan_with(target, method, options, &mut |_, _| ())
+}
+
+/// The `scan_with_options` which calls `on_host_done` with the results of every host as soon as all its probes are done,
+/// e.g. to start the service detection of the host while the other hosts are still scanned.
+/// The hosts abandoned by the `host_timeout` or the `scan_deadline` are not reported.
+pub fn scan_with(
+    mut target: Target,
+    method: ScanMethod,
+    options: &ScanOptions,
+    on_host_done: &mut dyn FnMut(IpAddr, ScanResults),
+) -> Result<ScanResults, PistolErrors> {
+    let ScanOptions {
+        src_addr,
+        src_port,
+        zombie_ipv4,
+        zombie_port,
+        timeout,
+        tests,
+        src_port_range,
+        prefer_temporary_ipv6,
+        proxy,
+        max_host_connections,
+        retry_budget,
+        host_timeout,
+        scan_deadline,
+        capture_raw,
+        probe_options,
+        checkpoint,
+        timing,
+        scan_delay,
+        clock,
+        threads_num: max_threads_num,
+    } = options.clone();
+    if proxy.is_some() && method != ScanMethod::Connect {
+        return Err(PistolErrors::ProxyUnsupportedMethod);
+    }
+    if let Some(options) = &probe_options {
+        options.validate()?;
+    }
+    target.resolve_all(timeout)?;
+    let mut checkpoint = match checkpoint {
+        Some(path) => Some(CheckpointWriter::open(&path)?),
+        None => None,
+    };
+    let timeout = timeout.or(timing.and_then(|t| t.timeout()));
+    let retry_budget = match (retry_budget, timing) {
+        (Some(budget), _) => Some(budget),
+        (None, Some(t)) if t.max_retries() > 0 => {
+            Some(RetryBudget::new(usize::MAX, t.max_retries()))
+        }
+        (None, _) => None,
+    };
+    let scan_delay = scan_delay.or(timing.and_then(|t| t.scan_delay()));
+    let host_connections = match (method, max_host_connections) {
+        (ScanMethod::Connect, Some(max)) => Some(HostConnections::new(max)),
+        _ => None,
+    };
     let mut port_scan_ret = ScanResults::new();
+    let mut pending: HashMap<IpAddr, usize> = HashMap::new();
 
     let mut threads_num = 0;
     for host in &target.hosts {
-        threads_num += host.ports.len() * tests;
+        let known_open = host.ports.iter().filter(|p| host.known_open.contains(p));
+        threads_num += (host.ports.len() - known_open.count()) * tests;
+    }
+    match (max_threads_num, timing) {
+        (Some(m), _) => threads_num = threads_num.min(m.max(1)),
+        (None, Some(t)) => threads_num = t.threads_num(threads_num),
+        (None, None) => (),
     }
 
     let pool = get_threads_pool(threads_num);
     let (tx, rx) = channel();
-    let mut recv_size = 0;
-    let timeout = match timeout {
-        Some(t) => t,
-        None => get_default_timeout(),
-    };
-    let src_port = match src_port {
-        Some(s) => s,
-        None => {
+    let mut probes = Vec::new();
+    // without the timeout, the default one of the source interface is used
+    let mut src_timeouts: HashMap<IpAddr, Duration> = HashMap::new();
+    let src_port = match (src_port, src_port_range) {
+        (Some(s), _) => s,
+        (None, Some(range)) => random_port_in(&range)?,
+        (None, None) => {
             warn!("can not found src port, use random port instead");
             random_port()
         }
@@ -592,12 +1314,27 @@ pub fn scan(
 
     for host in target.hosts {
         let dst_addr = host.addr;
+        // the known open ports skip the probes
+        for &dst_port in &host.known_open {
+            let psr = PortScanResults {
+                port_status: PortStatus::Open,
+                reason: PortReason::KnownOpen,
+                port_time_cost: Duration::ZERO,
+                raw_reply: None,
+            };
+            port_scan_ret.insert_result(dst_addr, dst_port, psr);
+        }
+        let ports: Vec<u16> = host
+            .ports
+            .into_iter()
+            .filter(|p| !host.known_open.contains(p))
+            .collect();
         match dst_addr {
             IpAddr::V4(dst_ipv4) => {
-                for dst_port in host.ports {
+                for dst_port in ports {
                     for _ in 0..tests {
                         let tx = tx.clone();
-                        recv_size += 1;
+                        *pending.entry(dst_addr).or_insert(0) += 1;
                         let src_ipv4 = match find_source_addr(src_addr, dst_ipv4)? {
                             Some(s) => s,
                             None => {
@@ -605,72 +1342,481 @@ pub fn scan(
                                 return Err(PistolErrors::CanNotFoundSourceAddress);
                             }
                         };
+                        port_scan_ret.source_addrs.insert(dst_addr, src_ipv4.into());
+                        let timeout = match timeout {
+                            Some(t) => t,
+                            None => *src_timeouts
+                                .entry(src_ipv4.into())
+                                .or_insert_with(|| get_default_timeout_by_src(src_ipv4.into())),
+                        };
 
-                        pool.execute(move || {
+                        let retry_budget = retry_budget.clone();
+                        let proxy = proxy.clone();
+                        let probe_options = probe_options.clone();
+                        let host_connections = host_connections.clone();
+                        let job: ProbeJob = Box::new(move || {
                             let cost = Instant::now();
-                            let scan_ret = threads_scan(
-                                method,
-                                dst_ipv4,
-                                dst_port,
-                                src_ipv4,
-                                src_port,
-                                zombie_ipv4,
-                                zombie_port,
-                                timeout,
-                            );
-                            match tx.send((dst_addr, dst_port, scan_ret, cost)) {
-                                _ => (),
-                            }
+                            let scan_ret = with_probe_options(probe_options.as_ref(), || {
+                                capture_reply(capture_raw, || {
+                                    let probe = || {
+                                        probe_with_retry(retry_budget.as_ref(), || match &proxy {
+                                            Some(proxy) => socks5::send_connect_scan_packet(
+                                                proxy, dst_addr, dst_port, timeout,
+                                            ),
+                                            None => threads_scan(
+                                                method,
+                                                dst_ipv4,
+                                                dst_port,
+                                                src_ipv4,
+                                                src_port,
+                                                zombie_ipv4,
+                                                zombie_port,
+                                                timeout,
+                                            ),
+                                        })
+                                    };
+                                    match &host_connections {
+                                        Some(h) => h.run(dst_addr, probe),
+                                        None => probe(),
+                                    }
+                                })
+                            });
+                            let (ret, raw_reply) = scan_ret;
+                            let psr = port_scan_result(ret, raw_reply, cost.elapsed());
+                            let _ = tx.send((dst_addr, dst_port, psr, cost));
                         });
+                        probes.push((dst_addr, job));
                     }
                 }
             }
             IpAddr::V6(dst_ipv6) => {
-                for dst_port in host.ports {
+                for dst_port in ports {
                     for _ in 0..tests {
                         let tx = tx.clone();
-                        recv_size += 1;
-                        let src_ipv6 = match find_source_addr6(src_addr, dst_ipv6)? {
+                        *pending.entry(dst_addr).or_insert(0) += 1;
+                        let src_ipv6 = match find_source_addr6_with(
+                            src_addr,
+                            dst_ipv6,
+                            prefer_temporary_ipv6,
+                        )? {
                             Some(s) => s,
                             None => return Err(PistolErrors::CanNotFoundSourceAddress),
                         };
-                        pool.execute(move || {
+                        port_scan_ret.source_addrs.insert(dst_addr, src_ipv6.into());
+                        let timeout = match timeout {
+                            Some(t) => t,
+                            None => *src_timeouts
+                                .entry(src_ipv6.into())
+                                .or_insert_with(|| get_default_timeout_by_src(src_ipv6.into())),
+                        };
+                        let retry_budget = retry_budget.clone();
+                        let proxy = proxy.clone();
+                        let probe_options = probe_options.clone();
+                        let host_connections = host_connections.clone();
+                        let job: ProbeJob = Box::new(move || {
                             let cost = Instant::now();
-                            let scan_ret = threads_scan6(
-                                method, dst_ipv6, dst_port, src_ipv6, src_port, timeout,
-                            );
-                            match tx.send((dst_addr, dst_port, scan_ret, cost)) {
-                                _ => (),
-                            }
+                            let scan_ret = with_probe_options(probe_options.as_ref(), || {
+                                capture_reply(capture_raw, || {
+                                    let probe = || {
+                                        probe_with_retry(retry_budget.as_ref(), || match &proxy {
+                                            Some(proxy) => socks5::send_connect_scan_packet(
+                                                proxy, dst_addr, dst_port, timeout,
+                                            ),
+                                            None => threads_scan6(
+                                                method, dst_ipv6, dst_port, src_ipv6, src_port,
+                                                timeout,
+                                            ),
+                                        })
+                                    };
+                                    match &host_connections {
+                                        Some(h) => h.run(dst_addr, probe),
+                                        None => probe(),
+                                    }
+                                })
+                            });
+                            let (ret, raw_reply) = scan_ret;
+                            let psr = port_scan_result(ret, raw_reply, cost.elapsed());
+                            let _ = tx.send((dst_addr, dst_port, psr, cost));
                         });
+                        probes.push((dst_addr, job));
                     }
                 }
             }
         }
     }
 
-    let iter = rx.into_iter().take(recv_size);
+    let host_starts = HostStarts::default();
+    let probes = match host_timeout {
+        Some(_) => probes
+            .into_iter()
+            .map(|(addr, job)| (addr, host_starts.track(addr, job)))
+            .collect(),
+        None => probes,
+    };
+    dispatch_probes(&pool, probes, scan_delay, clock);
 
-    for (dst_ipv4, dst_port, v, cost) in iter {
-        let tc = cost.elapsed();
-        match v {
-            Ok((port_status, rtt)) => {
-                // println!("rtt: {:.2}", rtt.as_secs_f32());
-                port_scan_ret.insert(dst_ipv4.into(), dst_port, port_status, rtt);
+    // the results of every host, starting from the known open ports
+    let mut host_scans: HashMap<IpAddr, ScanResults> = HashMap::new();
+    let mut host_pending = pending.clone();
+    for dst_addr in port_scan_ret.scans.keys() {
+        host_scans.insert(*dst_addr, port_scan_ret.host_results(dst_addr));
+    }
+    // the hosts with only the known open ports are done already
+    let done: Vec<IpAddr> = host_scans
+        .keys()
+        .filter(|h| !host_pending.contains_key(h))
+        .copied()
+        .collect();
+    for dst_addr in done {
+        if let Some(mut host_ret) = host_scans.remove(&dst_addr) {
+            host_ret.enrichment();
+            on_host_done(dst_addr, host_ret);
+        }
+    }
+
+    let host_rets = recv_host_results(
+        &rx,
+        pending,
+        host_timeout,
+        &host_starts,
+        scan_deadline,
+        |ret| {
+            let (dst_addr, dst_port, psr, _) = ret;
+            if let Some(writer) = checkpoint.as_mut() {
+                if let Err(e) = writer.append(*dst_addr, *dst_port, psr) {
+                    warn!("write checkpoint failed: {}", e);
+                }
             }
-            Err(e) => match e {
-                PistolErrors::CanNotFoundMacAddress => {
-                    port_scan_ret.insert(dst_ipv4.into(), dst_port, PortStatus::Offline, tc);
+            let host_ret = host_scans
+                .entry(*dst_addr)
+                .or_insert_with(|| port_scan_ret.host_results(dst_addr));
+            host_ret.insert_result(*dst_addr, *dst_port, psr.clone());
+            if let Some(p) = host_pending.get_mut(dst_addr) {
+                *p -= 1;
+                if *p == 0 {
+                    if let Some(mut host_ret) = host_scans.remove(dst_addr) {
+                        host_ret.enrichment();
+                        on_host_done(*dst_addr, host_ret);
+                    }
                 }
-                _ => {
-                    warn!("scan error: {}", e);
-                    port_scan_ret.insert(dst_ipv4.into(), dst_port, PortStatus::Error, tc);
+            }
+        },
+    );
+    for dst_addr in &host_rets.timed_out_hosts {
+        warn!(
+            "host {} timed out, the remaining probes are abandoned",
+            dst_addr
+        );
+    }
+    if host_rets.incomplete {
+        warn!("scan deadline reached, return the partial results");
+    }
+    port_scan_ret.timed_out_hosts = host_rets.timed_out_hosts;
+    port_scan_ret.incomplete = host_rets.incomplete;
+
+    for (dst_addr, dst_port, psr, _) in host_rets.rets {
+        port_scan_ret.insert_result(dst_addr, dst_port, psr);
+    }
+    if let Some(budget) = retry_budget {
+        port_scan_ret.retries = budget.used();
+    }
+    port_scan_ret.enrichment();
+    Ok(port_scan_ret)
+}
+
+/// The port scan results of one probe, the offline host and the error are kept as the port status.
+fn port_scan_result(
+    ret: Result<(PortStatus, PortReason, Duration), PistolErrors>,
+    raw_reply: Option<Vec<u8>>,
+    tc: Duration,
+) -> PortScanResults {
+    match ret {
+        Ok((port_status, reason, rtt)) => PortScanResults {
+            port_status,
+            reason,
+            port_time_cost: rtt,
+            raw_reply,
+        },
+        Err(PistolErrors::CanNotFoundMacAddress) => PortScanResults {
+            port_status: PortStatus::Offline,
+            reason: PortReason::HostDown,
+            port_time_cost: tc,
+            raw_reply: None,
+        },
+        Err(e) => {
+            warn!("scan error: {}", e);
+            PortScanResults {
+                port_status: PortStatus::Error,
+                reason: PortReason::Error,
+                port_time_cost: tc,
+                raw_reply: None,
+            }
+        }
+    }
+}
+
+/// The host, port, result and start time of one probe.
+type ProbeResult<T> = (IpAddr, u16, T, Instant);
+
+/// The results received by `recv_host_results`.
+struct HostResults<T> {
+    rets: Vec<ProbeResult<T>>,
+    timed_out_hosts: Vec<IpAddr>,
+    incomplete: bool,
+}
+
+#[derive(Debug, Default)]
+struct HostStartsState {
+    started: HashSet<IpAddr>,
+    /// The hosts started since the last `take_started`.
+    new: Vec<(IpAddr, Instant)>,
+    abandoned: HashSet<IpAddr>,
+}
+
+/// The time the first probe of every host is sent, the `host_timeout` of a host counts from it,
+/// so the hosts queued behind the pool or the scan delay are not timed out before they are probed.
+#[derive(Debug, Clone, Default)]
+struct HostStarts {
+    state: Arc<Mutex<HostStartsState>>,
+}
+
+impl HostStarts {
+    /// The `job` which records the start of its host, it is skipped once the host is abandoned.
+    fn track(&self, addr: IpAddr, job: ProbeJob) -> ProbeJob {
+        let host_starts = self.clone();
+        Box::new(move || {
+            if host_starts.start(addr) {
+                job();
+            }
+        })
+    }
+    fn start(&self, addr: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.abandoned.contains(&addr) {
+            return false;
+        }
+        if state.started.insert(addr) {
+            state.new.push((addr, Instant::now()));
+        }
+        true
+    }
+    fn take_started(&self) -> Vec<(IpAddr, Instant)> {
+        std::mem::take(&mut self.state.lock().unwrap().new)
+    }
+    fn abandon(&self, addr: IpAddr) {
+        self.state.lock().unwrap().abandoned.insert(addr);
+    }
+}
+
+/// Receive the results of the `pending` probes of each host.
+/// A host still waiting for probes `host_timeout` after its first probe started (see `HostStarts`)
+/// is abandoned alone and returned with its received results, the other hosts are still received,
+/// and at `scan_deadline` the receiving stops and the results are marked as incomplete.
+fn recv_host_results<T>(
+    rx: &Receiver<ProbeResult<T>>,
+    mut pending: HashMap<IpAddr, usize>,
+    host_timeout: Option<Duration>,
+    host_starts: &HostStarts,
+    scan_deadline: Option<Instant>,
+    mut on_recv: impl FnMut(&ProbeResult<T>),
+) -> HostResults<T> {
+    // the deadlines of the started hosts, the earliest first
+    let mut host_deadlines: BinaryHeap<Reverse<(Instant, IpAddr)>> = BinaryHeap::new();
+    let mut timed_out_hosts = Vec::new();
+    let mut incomplete = false;
+    let mut rets = Vec::new();
+    let mut recv_size: usize = pending.values().sum();
+    while recv_size > 0 {
+        let now = Instant::now();
+        let mut deadline = scan_deadline;
+        if let Some(t) = host_timeout {
+            for (host, start) in host_starts.take_started() {
+                host_deadlines.push(Reverse((start + t, host)));
+            }
+            while let Some(&Reverse((d, host))) = host_deadlines.peek() {
+                if d > now {
+                    break;
+                }
+                host_deadlines.pop();
+                if let Some(p) = pending.remove(&host) {
+                    if p > 0 {
+                        recv_size -= p;
+                        host_starts.abandon(host);
+                        timed_out_hosts.push(host);
+                    }
                 }
+            }
+            // the hosts not started yet can not time out before `now + t`
+            let next = match host_deadlines.peek() {
+                Some(&Reverse((d, _))) => d.min(now + t),
+                None => now + t,
+            };
+            deadline = Some(deadline.map_or(next, |d| d.min(next)));
+        }
+        if recv_size == 0 {
+            break;
+        }
+        if let Some(d) = scan_deadline {
+            if d <= now {
+                incomplete = true;
+                break;
+            }
+        }
+        let ret = match deadline {
+            Some(d) => match rx.recv_timeout(d.saturating_duration_since(now)) {
+                Ok(ret) => ret,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
             },
+            None => match rx.recv() {
+                Ok(ret) => ret,
+                Err(_) => break,
+            },
+        };
+        match pending.get_mut(&ret.0) {
+            Some(p) if *p > 0 => *p -= 1,
+            // the late result of the timed out host
+            _ => continue,
         }
+        recv_size -= 1;
+        on_recv(&ret);
+        rets.push(ret);
     }
-    port_scan_ret.enrichment();
-    Ok(port_scan_ret)
+    timed_out_hosts.sort();
+    HostResults {
+        rets,
+        timed_out_hosts,
+        incomplete,
+    }
+}
+
+/// Continue the scan interrupted before, like the `--resume` of nmap.
+/// The (host, port) pairs with all the `tests` done in the `state_file` are not scanned again,
+/// the others are scanned with their results appended to the `state_file`.
+/// The results contain both, the same as the scan not interrupted.
+pub fn resume_scan(
+    state_file: &Path,
+    target: Target,
+    method: ScanMethod,
+    src_addr: Option<IpAddr>,
+    src_port: Option<u16>,
+    timeout: Option<Duration>,
+    tests: usize,
+) -> Result<ScanResults, PistolErrors> {
+    let mut done: HashMap<(IpAddr, u16), Vec<PortScanResults>> = HashMap::new();
+    for entry in checkpoint::load_checkpoint(state_file)? {
+        done.entry((entry.addr, entry.port))
+            .or_default()
+            .push(entry.result);
+    }
+    // the pairs with only some of the tests done are scanned again
+    done.retain(|_, psr| psr.len() >= tests);
+
+    let mut hosts = Vec::new();
+    let mut restored = Vec::new();
+    for host in target.hosts {
+        let mut ports = Vec::new();
+        for port in host.ports {
+            match done.remove(&(host.addr, port)) {
+                Some(mut psr) => {
+                    // the latest results, the earlier ones are left by the partial scan
+                    psr.drain(..psr.len() - tests);
+                    restored.push((host.addr, port, psr));
+                }
+                None => ports.push(port),
+            }
+        }
+        if !ports.is_empty() || !host.known_open.is_empty() {
+            hosts.push(Host::new(host.addr, Some(ports)).with_known_open(host.known_open));
+        }
+    }
+
+    let mut ret = if hosts.is_empty() {
+        ScanResults::new()
+    } else {
+        let options = ScanOptions {
+            src_addr,
+            src_port,
+            timeout,
+            tests,
+            ..Default::default()
+        };
+        scan_with_options(
+            Target::new(hosts),
+            method,
+            &options.with_checkpoint(state_file),
+        )?
+    };
+    for (addr, port, psr) in restored {
+        for p in psr {
+            ret.insert_result(addr, port, p);
+        }
+    }
+    ret.enrichment();
+    Ok(ret)
+}
+
+/// The smallest ipv4 prefix `port_sweep` accepts (65536 addresses).
+const PORT_SWEEP_MIN_PREFIX: u8 = 16;
+/// The smallest ipv6 prefix `port_sweep` accepts (65536 addresses).
+const PORT_SWEEP_MIN_PREFIX6: u8 = 112;
+
+/// Find the hosts in `network` with `dst_port` open, one probe per host.
+/// At most `threads_num` hosts are probed at the same time, all by one scan, so a slow host never holds up the others.
+pub fn port_sweep(
+    network: IpNetwork,
+    dst_port: u16,
+    method: ScanMethod,
+    threads_num: usize,
+    timeout: Option<Duration>,
+) -> Result<Vec<IpAddr>, PistolErrors> {
+    let hosts: Vec<IpAddr> = match network {
+        IpNetwork::V4(ipv4_network) => {
+            let prefix = ipv4_network.prefix();
+            if prefix < PORT_SWEEP_MIN_PREFIX {
+                return Err(PistolErrors::NetworkTooLarge {
+                    network: network.to_string(),
+                    min_prefix: PORT_SWEEP_MIN_PREFIX,
+                });
+            }
+            ipv4_network
+                .iter()
+                .filter(|ip| {
+                    // skip the network and broadcast address
+                    prefix > 30
+                        || (*ip != ipv4_network.network() && *ip != ipv4_network.broadcast())
+                })
+                .map(|ip| ip.into())
+                .collect()
+        }
+        IpNetwork::V6(ipv6_network) => {
+            if ipv6_network.prefix() < PORT_SWEEP_MIN_PREFIX6 {
+                return Err(PistolErrors::NetworkTooLarge {
+                    network: network.to_string(),
+                    min_prefix: PORT_SWEEP_MIN_PREFIX6,
+                });
+            }
+            ipv6_network.iter().map(|ip| ip.into()).collect()
+        }
+    };
+
+    let target = Target::new(
+        hosts
+            .iter()
+            .map(|ip| Host::new(*ip, Some(vec![dst_port])))
+            .collect(),
+    );
+    let options = ScanOptions {
+        timeout,
+        threads_num: Some(threads_num),
+        ..Default::default()
+    };
+    let ret = scan_with_options(target, method, &options)?;
+    let open_hosts = hosts
+        .into_iter()
+        .filter(|ip| ret.port_status(ip, dst_port) == Some(PortStatus::Open))
+        .collect();
+    Ok(open_hosts)
 }
 
 /// TCP Connect() Scan.
@@ -686,6 +1832,7 @@ pub fn scan(
 /// This is the fastest scanning method supported by nmap, and is available with the -t (TCP) option.
 /// The big downside is that this sort of scan is easily detectable and filterable.
 /// The target hosts logs will show a bunch of connection and error messages for the services which take the connection and then have it immediately shutdown.
+/// The SOCKS5 proxy and the connections limit of a host are set by the `ScanOptions` of `scan_with_options`.
 pub fn tcp_connect_scan(
     target: Target,
     src_addr: Option<IpAddr>,
@@ -725,6 +1872,23 @@ pub fn tcp_connect_scan_raw(
     )
 }
 
+/// TCP connect() Scan through the SOCKS5 `proxy` (e.g. a pivot host), raw version.
+/// The refused connection is closed and the connection timed out is filtered.
+pub fn proxy_connect_scan_raw(
+    proxy: &Socks5Proxy,
+    dst_addr: IpAddr,
+    dst_port: u16,
+    timeout: Option<Duration>,
+) -> Result<(PortStatus, Duration), PistolErrors> {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => get_default_timeout(),
+    };
+    let (port_status, _, rtt) =
+        socks5::send_connect_scan_packet(proxy, dst_addr, dst_port, timeout)?;
+    Ok((port_status, rtt))
+}
+
 /// TCP SYN Scan.
 /// This technique is often referred to as "half-open" scanning, because you don't open a full TCP connection.
 /// You send a SYN packet, as if you are going to open a real connection and wait for a response.
@@ -1167,7 +2331,7 @@ pub fn scan_raw(
         Some(t) => t,
         None => get_default_timeout(),
     };
-    match dst_addr {
+    let (port_status, _, rtt) = match dst_addr {
         IpAddr::V4(dst_ipv4) => {
             let src_ipv4 = match find_source_addr(src_addr, dst_ipv4)? {
                 Some(s) => s,
@@ -1182,27 +2346,925 @@ pub fn scan_raw(
                 zombie_ipv4,
                 zombie_port,
                 timeout,
-            )
+            )?
         }
         IpAddr::V6(dst_ipv6) => {
             let src_ipv6 = match find_source_addr6(src_addr, dst_ipv6)? {
                 Some(s) => s,
                 None => return Err(PistolErrors::CanNotFoundSourceAddress),
             };
-            threads_scan6(method, dst_ipv6, dst_port, src_ipv6, src_port, timeout)
+            threads_scan6(method, dst_ipv6, dst_port, src_ipv6, src_port, timeout)?
         }
-    }
+    };
+    Ok((port_status, rtt))
+}
+
+/// Cheap os family guess from the ttl and window size of one syn/ack,
+/// returns `None` if the port does not answer with syn/ack.
+/// Use the `os` module for the full fingerprint.
+pub fn os_hint(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    timeout: Option<Duration>,
+) -> Result<Option<OsHint>, PistolErrors> {
+    let src_port = random_port();
+    let timeout = match timeout {
+        Some(t) => t,
+        None => get_default_timeout(),
+    };
+    let ret = match dst_addr {
+        IpAddr::V4(dst_ipv4) => {
+            let src_ipv4 = match find_source_addr(None, dst_ipv4)? {
+                Some(s) => s,
+                None => return Err(PistolErrors::CanNotFoundSourceAddress),
+            };
+            tcp::send_os_hint_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?
+        }
+        IpAddr::V6(dst_ipv6) => {
+            let src_ipv6 = match find_source_addr6(None, dst_ipv6)? {
+                Some(s) => s,
+                None => return Err(PistolErrors::CanNotFoundSourceAddress),
+            };
+            tcp6::send_os_hint_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?
+        }
+    };
+    Ok(ret.map(|(ttl, window)| OsHint::classify(ttl, window)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Host;
+    use crate::utils::with_clock;
+    use crate::utils::MockClock;
     use crate::Target;
     use crate::TEST_IPV4_LOCAL;
+    use std::io::Read;
+    use std::io::Write;
+    use std::str::FromStr;
     use subnetwork::CrossIpv4Pool;
     use subnetwork::Ipv4Pool;
     #[test]
+    fn test_port_sweep() {
+        // only 127.0.0.1 listens on the port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_port = listener.local_addr().unwrap().port();
+        let network = IpNetwork::from_str("127.0.0.0/29").unwrap();
+        let timeout = Some(Duration::new(1, 0));
+        let ret = port_sweep(network, dst_port, ScanMethod::Connect, 4, timeout).unwrap();
+        assert_eq!(ret, vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]);
+
+        for network in ["fd00::/64", "10.0.0.0/8"] {
+            let network = IpNetwork::from_str(network).unwrap();
+            let ret = port_sweep(network, dst_port, ScanMethod::Connect, 4, timeout);
+            assert!(matches!(ret, Err(PistolErrors::NetworkTooLarge { .. })));
+        }
+    }
+    #[test]
+    fn test_retry_budget() {
+        // every probe is lost
+        let sends = AtomicUsize::new(0);
+        let lossy_probe = || {
+            sends.fetch_add(1, Ordering::Relaxed);
+            Ok((
+                PortStatus::Filtered,
+                PortReason::NoResponse,
+                Duration::from_millis(10),
+            ))
+        };
+        let budget = RetryBudget::new(5, 3);
+        for _ in 0..10 {
+            let (port_status, _, _) = probe_with_retry(Some(&budget), lossy_probe).unwrap();
+            assert_eq!(port_status, PortStatus::Filtered);
+        }
+        // 10 first sends and 5 retries
+        assert_eq!(sends.load(Ordering::Relaxed), 15);
+        assert_eq!(budget.used(), 5);
+
+        // answered probes are not retried
+        let budget = RetryBudget::new(5, 3);
+        probe_with_retry(Some(&budget), || {
+            Ok((PortStatus::Open, PortReason::SynAck, Duration::ZERO))
+        })
+        .unwrap();
+        assert_eq!(budget.used(), 0);
+    }
+    #[test]
+    fn test_host_timeout() {
+        // the proxy holds the probes of the slow host and answers the fast one at once
+        let slow_host: IpAddr = Ipv4Addr::new(127, 0, 0, 2).into();
+        let fast_host: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).unwrap();
+                    stream.write_all(&[0x05, 0x00]).unwrap();
+                    let mut request = [0u8; 10];
+                    stream.read_exact(&mut request).unwrap();
+                    let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+                    if IpAddr::V4(ip) == slow_host {
+                        std::thread::sleep(Duration::from_secs(2));
+                    }
+                    let mut reply = vec![0x05, 0x00, 0x00, 0x01];
+                    reply.extend_from_slice(&request[4..10]);
+                    let _ = stream.write_all(&reply);
+                });
+            }
+        });
+        // one thread, so the fast host waits behind the slow one
+        let target = Target::new(vec![
+            Host::new(slow_host, Some(vec![22])),
+            Host::new(fast_host, Some(vec![22])),
+        ]);
+        let options = ScanOptions::default()
+            .with_proxy(Socks5Proxy::new(proxy_addr))
+            .with_timing(TimingTemplate::T2)
+            .with_scan_delay(Duration::ZERO)
+            .with_retry_budget(RetryBudget::new(0, 0))
+            .with_timeout(Duration::from_secs(5))
+            .with_host_timeout(Duration::from_secs(1));
+        let ret = scan_with_options(target, ScanMethod::Connect, &options).unwrap();
+        assert_eq!(ret.timed_out_hosts, vec![slow_host]);
+        assert_eq!(ret.port_status(&slow_host, 22), None);
+        assert_eq!(ret.port_status(&fast_host, 22), Some(PortStatus::Open));
+    }
+    #[test]
+    fn test_scan_deadline() {
+        // a large target, only the first hosts answer before the deadline
+        let (tx, rx) = channel();
+        let mut pending = HashMap::new();
+        for i in 0..=255 {
+            let host: IpAddr = Ipv4Addr::new(192, 168, 1, i).into();
+            pending.insert(host, 1);
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let cost = Instant::now();
+                let delay = if i < 8 { 10 } else { 5000 };
+                std::thread::sleep(Duration::from_millis(delay));
+                let _ = tx.send((host, 80, PortStatus::Open, cost));
+            });
+        }
+        let scan_deadline = Some(Instant::now() + Duration::from_millis(500));
+        let start = Instant::now();
+        let host_rets = recv_host_results(
+            &rx,
+            pending,
+            None,
+            &HostStarts::default(),
+            scan_deadline,
+            |_| (),
+        );
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(host_rets.incomplete);
+        assert!(host_rets.timed_out_hosts.is_empty());
+        assert_eq!(host_rets.rets.len(), 8);
+
+        // the deadline of a finished scan does not matter
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_port = listener.local_addr().unwrap().port();
+        let host = Host::new(Ipv4Addr::LOCALHOST.into(), Some(vec![dst_port]));
+        let scan_deadline = Instant::now() + Duration::from_secs(10);
+        let ret = scan_with_options(
+            Target::new(vec![host]),
+            ScanMethod::Connect,
+            &ScanOptions::default()
+                .with_scan_deadline(scan_deadline)
+                .with_timeout(Duration::new(1, 0)),
+        )
+        .unwrap();
+        assert!(!ret.incomplete);
+        assert_eq!(ret.open_ports, 1);
+    }
+    #[test]
+    fn test_loopback_scan() {
+        // the syn scan and the udp scan work on the loopback address without raw sockets
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let host = Host::new(dst_addr, Some(vec![open_port, closed_port]));
+        let target = Target::new(vec![host]);
+        let timeout = Some(Duration::new(1, 0));
+        let ret = tcp_syn_scan(target, None, None, timeout, 1).unwrap();
+        assert_eq!(
+            ret.port_status(&dst_addr, open_port),
+            Some(PortStatus::Open)
+        );
+        assert_eq!(
+            ret.port_status(&dst_addr, closed_port),
+            Some(PortStatus::Closed)
+        );
+
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_port = responder.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut buff = [0u8; 64];
+            let (_, src) = responder.recv_from(&mut buff).unwrap();
+            responder.send_to(b"pong", src).unwrap();
+        });
+        let (port_status, reason, _) = loopback_scan(
+            ScanMethod::Udp,
+            dst_addr,
+            udp_port,
+            &[],
+            Duration::new(1, 0),
+        )
+        .unwrap();
+        assert_eq!(port_status, PortStatus::Open);
+        assert_eq!(reason, PortReason::UdpResponse);
+        let (port_status, reason, _) = loopback_scan(
+            ScanMethod::Udp,
+            dst_addr,
+            closed_port,
+            &[],
+            Duration::new(1, 0),
+        )
+        .unwrap();
+        assert_eq!(port_status, PortStatus::Closed);
+        assert_eq!(
+            reason,
+            PortReason::IcmpUnreachable {
+                icmp_type: 3,
+                icmp_code: 3
+            }
+        );
+    }
+    /// A minimal SOCKS5 proxy, `silent` never answers the connect request.
+    fn socks5_server(silent: bool) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut greeting = [0u8; 3];
+                stream.read_exact(&mut greeting).unwrap();
+                stream.write_all(&[0x05, 0x00]).unwrap();
+                // ipv4 connect request
+                let mut request = [0u8; 10];
+                stream.read_exact(&mut request).unwrap();
+                if silent {
+                    std::thread::sleep(Duration::from_secs(2));
+                    continue;
+                }
+                let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+                let port = u16::from_be_bytes([request[8], request[9]]);
+                let rep = match TcpStream::connect((ip, port)) {
+                    Ok(_) => 0x00,
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => 0x05,
+                    Err(_) => 0x01,
+                };
+                let mut reply = vec![0x05, rep, 0x00, 0x01];
+                reply.extend_from_slice(&request[4..10]);
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        addr
+    }
+    #[test]
+    fn test_max_host_connections() {
+        // the proxy holds every connection for a while and counts the ones open at the same time
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let open = Arc::new(AtomicUsize::new(0));
+        let max_open = Arc::new(AtomicUsize::new(0));
+        let (open_c, max_open_c) = (open.clone(), max_open.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let n = open_c.fetch_add(1, Ordering::SeqCst) + 1;
+                max_open_c.fetch_max(n, Ordering::SeqCst);
+                let open_c = open_c.clone();
+                std::thread::spawn(move || {
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).unwrap();
+                    stream.write_all(&[0x05, 0x00]).unwrap();
+                    let mut request = [0u8; 10];
+                    stream.read_exact(&mut request).unwrap();
+                    std::thread::sleep(Duration::from_millis(100));
+                    // closed before the reply, which ends the probe
+                    open_c.fetch_sub(1, Ordering::SeqCst);
+                    let mut reply = vec![0x05, 0x05, 0x00, 0x01];
+                    reply.extend_from_slice(&request[4..10]);
+                    stream.write_all(&reply).unwrap();
+                });
+            }
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let ports: Vec<u16> = (40001..40009).collect();
+        let target = Target::new(vec![Host::new(dst_addr, Some(ports.clone()))]);
+        let proxy = Socks5Proxy::new(proxy_addr);
+        let options = ScanOptions::default()
+            .with_proxy(proxy)
+            .with_max_host_connections(2)
+            .with_timeout(Duration::from_secs(2));
+        let ret = scan_with_options(target, ScanMethod::Connect, &options).unwrap();
+        for port in ports {
+            assert_eq!(ret.port_status(&dst_addr, port), Some(PortStatus::Closed));
+        }
+        assert_eq!(max_open.load(Ordering::SeqCst), 2);
+    }
+    #[test]
+    fn test_socks5_connect_scan() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let timeout = Duration::from_millis(500);
+
+        let proxy = Socks5Proxy::new(socks5_server(false));
+        let host = Host::new(dst_addr, Some(vec![open_port, closed_port]));
+        let target = Target::new(vec![host]);
+        let options = ScanOptions::default()
+            .with_proxy(proxy)
+            .with_timeout(timeout);
+        let ret = scan_with_options(target, ScanMethod::Connect, &options).unwrap();
+        assert_eq!(
+            ret.port_status(&dst_addr, open_port),
+            Some(PortStatus::Open)
+        );
+        assert_eq!(
+            ret.port_status(&dst_addr, closed_port),
+            Some(PortStatus::Closed)
+        );
+
+        let proxy = Socks5Proxy::new(socks5_server(true));
+        let (port_status, _) =
+            proxy_connect_scan_raw(&proxy, dst_addr, open_port, Some(timeout)).unwrap();
+        assert_eq!(port_status, PortStatus::Filtered);
+
+        // the proxy can not be used by the raw socket scans
+        let proxy = Socks5Proxy::new(socks5_server(false));
+        let target = Target::new(vec![Host::new(dst_addr, Some(vec![open_port]))]);
+        let options = ScanOptions::default()
+            .with_proxy(proxy)
+            .with_timeout(timeout);
+        let ret = scan_with_options(target, ScanMethod::Syn, &options);
+        assert!(matches!(ret, Err(PistolErrors::ProxyUnsupportedMethod)));
+    }
+    #[test]
+    fn test_mock_clock_rtt() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_port = responder.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut buff = [0u8; 64];
+            let (_, src) = responder.recv_from(&mut buff).unwrap();
+            responder.send_to(b"pong", src).unwrap();
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        // the probes run on the pool threads, the clock is given to them by the options
+        let clock = Arc::new(MockClock::new(Duration::from_millis(25)));
+        let target = Target::new(vec![Host::new(dst_addr, Some(vec![udp_port]))]);
+        let options = ScanOptions::default()
+            .with_timeout(Duration::new(1, 0))
+            .with_clock(clock);
+        let ret = scan_with_options(target, ScanMethod::Udp, &options).unwrap();
+        let psr = &ret.scans[&dst_addr][&udp_port][0];
+        assert_eq!(psr.port_status, PortStatus::Open);
+        assert_eq!(psr.port_time_cost, Duration::from_millis(25));
+    }
+    #[test]
+    fn test_with_clock_panic() {
+        let clock = Arc::new(MockClock::new(Duration::from_millis(25)));
+        let ret = std::panic::catch_unwind(|| with_clock(clock, || panic!("probe failed")));
+        assert!(ret.is_err());
+        // the system clock is back after the panic
+        let start = clock_now();
+        assert!(clock_elapsed(start) < Duration::from_millis(25));
+    }
+    #[test]
+    fn test_port_reason() {
+        // the closed port answers the syn with a rst
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let host = Host::new(dst_addr, Some(vec![closed_port, open_port]));
+        let target = Target::new(vec![host]);
+        let ret = tcp_syn_scan(target, None, None, Some(Duration::new(1, 0)), 1).unwrap();
+        let psr = &ret.scans[&dst_addr][&closed_port][0];
+        assert_eq!(psr.port_status, PortStatus::Closed);
+        assert_eq!(psr.reason, PortReason::Reset);
+        let psr = &ret.scans[&dst_addr][&open_port][0];
+        assert_eq!(psr.port_status, PortStatus::Open);
+        assert_eq!(psr.reason, PortReason::SynAck);
+
+        let json = serde_json::to_string(&ret).unwrap();
+        assert!(json.contains("\"reason\":\"Reset\""));
+    }
+    #[test]
+    fn test_resume_scan() {
+        use std::io::Write;
+        let listener_1 = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port_1 = listener_1.local_addr().unwrap().port();
+        let listener_2 = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port_2 = listener_2.local_addr().unwrap().port();
+        let listener_3 = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port_3 = listener_3.local_addr().unwrap().port();
+        drop(listener_3);
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let ports = vec![port_1, port_2, port_3];
+        let target = |ports: Vec<u16>| Target::new(vec![Host::new(dst_addr, Some(ports))]);
+        let timeout = Duration::new(1, 0);
+        let state_file =
+            std::env::temp_dir().join(format!("pistol_resume_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&state_file);
+
+        let full = tcp_syn_scan(target(ports.clone()), None, None, Some(timeout), 1).unwrap();
+
+        // interrupted after the first port, in the middle of writing the second one
+        scan_with_options(
+            target(vec![port_1]),
+            ScanMethod::Syn,
+            &ScanOptions::default()
+                .with_checkpoint(&state_file)
+                .with_timeout(timeout),
+        )
+        .unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&state_file)
+            .unwrap();
+        file.write_all(b"{\"addr\":\"127.0.0.1\",\"po").unwrap();
+        drop(file);
+
+        // the completed port is not scanned again
+        drop(listener_1);
+        let ret = resume_scan(
+            &state_file,
+            target(ports.clone()),
+            ScanMethod::Syn,
+            None,
+            None,
+            Some(timeout),
+            1,
+        )
+        .unwrap();
+        for port in &ports {
+            assert_eq!(
+                ret.port_status(&dst_addr, *port),
+                full.port_status(&dst_addr, *port)
+            );
+            assert_eq!(ret.scans[&dst_addr][port].len(), 1);
+        }
+        assert_eq!(ret.open_ports, full.open_ports);
+        let entries = checkpoint::load_checkpoint(&state_file).unwrap();
+        assert_eq!(entries.len(), 3);
+
+        // nothing left to scan
+        let ret = resume_scan(
+            &state_file,
+            target(ports),
+            ScanMethod::Syn,
+            None,
+            None,
+            Some(timeout),
+            1,
+        )
+        .unwrap();
+        assert_eq!(ret.open_ports, full.open_ports);
+        std::fs::remove_file(&state_file).unwrap();
+        drop(listener_2);
+    }
+    #[test]
+    fn test_scan_timing() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let host = Host::new(dst_addr, Some(vec![port]));
+        let target = Target::new(vec![host]);
+        let start = Instant::now();
+        // polite, the probes of the host are sent one by one with the scan delay
+        let ret = scan_with_options(
+            target,
+            ScanMethod::Syn,
+            &ScanOptions::default()
+                .with_timing(TimingTemplate::T2)
+                .with_tests(2),
+        )
+        .unwrap();
+        assert!(start.elapsed() >= TimingTemplate::T2.scan_delay().unwrap());
+        assert_eq!(ret.port_status(&dst_addr, port), Some(PortStatus::Open));
+    }
+    #[test]
+    fn test_capture_raw() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_port = responder.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut buff = [0u8; 64];
+            for _ in 0..2 {
+                let (_, src) = responder.recv_from(&mut buff).unwrap();
+                responder.send_to(b"pong", src).unwrap();
+            }
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        for capture_raw in [true, false] {
+            let host = Host::new(dst_addr, Some(vec![udp_port]));
+            let target = Target::new(vec![host]);
+            let ret = scan_with_options(
+                target,
+                ScanMethod::Udp,
+                &ScanOptions::default()
+                    .with_capture_raw(capture_raw)
+                    .with_timeout(Duration::new(1, 0)),
+            )
+            .unwrap();
+            let psr = &ret.scans[&dst_addr][&udp_port][0];
+            assert_eq!(psr.port_status, PortStatus::Open);
+            if capture_raw {
+                assert_eq!(psr.raw_reply, Some(b"pong".to_vec()));
+            } else {
+                assert_eq!(psr.raw_reply, None);
+            }
+        }
+    }
+    #[test]
+    fn test_scan_diff() {
+        let host_1: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let host_2: IpAddr = Ipv4Addr::new(192, 168, 1, 11).into();
+        let host_3: IpAddr = Ipv4Addr::new(192, 168, 1, 12).into();
+        let rtt = Duration::from_millis(10);
+        let mut baseline = ScanResults::new();
+        baseline.insert(host_1, 22, PortStatus::Open, PortReason::SynAck, rtt);
+        baseline.insert(host_1, 80, PortStatus::Closed, PortReason::Reset, rtt);
+        baseline.insert(host_2, 22, PortStatus::Open, PortReason::SynAck, rtt);
+        baseline.enrichment();
+        // store the baseline and load it back
+        let baseline = serde_json::to_string(&baseline).unwrap();
+        let baseline: ScanResults = serde_json::from_str(&baseline).unwrap();
+
+        let mut ret = ScanResults::new();
+        ret.insert(host_1, 22, PortStatus::Open, PortReason::SynAck, rtt);
+        ret.insert(
+            host_1,
+            80,
+            PortStatus::Filtered,
+            PortReason::NoResponse,
+            rtt,
+        );
+        ret.insert(host_1, 80, PortStatus::Open, PortReason::SynAck, rtt);
+        ret.insert(host_2, 22, PortStatus::Open, PortReason::SynAck, rtt);
+        ret.enrichment();
+        let diff = ret.diff(&baseline);
+        assert_eq!(
+            diff,
+            ScanDiff {
+                newly_open: vec![(host_1, 80)],
+                ..Default::default()
+            }
+        );
+        assert!(ret.diff(&ret).is_empty());
+
+        // host absent from one side
+        let mut ret = ScanResults::new();
+        ret.insert(host_1, 22, PortStatus::Closed, PortReason::Reset, rtt);
+        ret.insert(host_3, 443, PortStatus::Open, PortReason::SynAck, rtt);
+        let diff = ret.diff(&baseline);
+        assert_eq!(diff.new_hosts, vec![host_3]);
+        assert_eq!(diff.missing_hosts, vec![host_2]);
+        assert_eq!(diff.newly_open, vec![(host_3, 443)]);
+        assert_eq!(diff.newly_closed, vec![(host_1, 22)]);
+        assert_eq!(diff.new_ports, vec![(host_3, 443)]);
+    }
+    #[test]
+    fn test_group_by_network() {
+        let rtt = Duration::from_millis(10);
+        let mut ret = ScanResults::new();
+        ret.insert(
+            Ipv4Addr::new(192, 168, 1, 10).into(),
+            22,
+            PortStatus::Open,
+            PortReason::SynAck,
+            rtt,
+        );
+        ret.insert(
+            Ipv4Addr::new(192, 168, 1, 10).into(),
+            80,
+            PortStatus::Open,
+            PortReason::SynAck,
+            rtt,
+        );
+        ret.insert(
+            Ipv4Addr::new(192, 168, 1, 11).into(),
+            22,
+            PortStatus::Open,
+            PortReason::SynAck,
+            rtt,
+        );
+        ret.insert(
+            Ipv4Addr::new(192, 168, 1, 12).into(),
+            22,
+            PortStatus::Filtered,
+            PortReason::NoResponse,
+            rtt,
+        );
+        ret.insert(
+            Ipv4Addr::new(192, 168, 2, 10).into(),
+            22,
+            PortStatus::Closed,
+            PortReason::Reset,
+            rtt,
+        );
+        ret.insert(
+            "fd00::10".parse().unwrap(),
+            22,
+            PortStatus::Open,
+            PortReason::SynAck,
+            rtt,
+        );
+        let groups = ret.group_by_network(24, 64).unwrap();
+        let networks: Vec<String> = groups.keys().map(|n| n.to_string()).collect();
+        assert_eq!(
+            networks,
+            vec!["192.168.1.0/24", "192.168.2.0/24", "fd00::/64"]
+        );
+        let subnet_1 = &groups[&"192.168.1.0/24".parse().unwrap()];
+        assert_eq!(subnet_1.hosts, 3);
+        assert_eq!(subnet_1.hosts_up, 2);
+        assert_eq!(subnet_1.open_ports, BTreeMap::from([(22, 2), (80, 1)]));
+        let subnet_2 = &groups[&"192.168.2.0/24".parse().unwrap()];
+        assert_eq!(subnet_2.hosts, 1);
+        assert_eq!(subnet_2.hosts_up, 1);
+        assert!(subnet_2.open_ports.is_empty());
+        let subnet_6 = &groups[&"fd00::/64".parse().unwrap()];
+        assert_eq!(subnet_6.hosts_up, 1);
+
+        assert!(ret.group_by_network(33, 64).is_err());
+    }
+    #[test]
+    fn test_scan_with_host_done() {
+        let closed_port = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let host1: IpAddr = Ipv4Addr::new(127, 0, 0, 1).into();
+        let host2: IpAddr = Ipv4Addr::new(127, 0, 0, 2).into();
+        let host3: IpAddr = Ipv4Addr::new(127, 0, 0, 3).into();
+        let target = Target::new(vec![
+            Host::new(host1, Some(vec![open_port, closed_port])),
+            Host::new(host2, Some(vec![closed_port])),
+            // nothing to probe
+            Host::new(host3, None).with_known_open(vec![22]),
+        ]);
+        let mut done = Vec::new();
+        let ret = scan_with(
+            target,
+            ScanMethod::Connect,
+            &ScanOptions::default()
+                .with_timeout(Duration::new(1, 0))
+                .with_tests(2),
+            &mut |dst_addr, host_ret| done.push((dst_addr, host_ret)),
+        )
+        .unwrap();
+        // the host without the probes is done before any probe returns
+        assert_eq!(done.len(), 3);
+        assert_eq!(done[0].0, host3);
+        assert_eq!(done[0].1.open_ports, 1);
+        for (dst_addr, host_ret) in &done {
+            // only the results of the host, with all the tests done
+            assert_eq!(host_ret.scans.len(), 1);
+            for psr in host_ret.get(dst_addr).unwrap().values() {
+                if *dst_addr != host3 {
+                    assert_eq!(psr.len(), 2);
+                }
+            }
+        }
+        let host1_ret = &done.iter().find(|(h, _)| *h == host1).unwrap().1;
+        assert_eq!(
+            host1_ret.port_status(&host1, open_port),
+            Some(PortStatus::Open)
+        );
+        assert_eq!(host1_ret.open_ports, 1);
+        assert_eq!(ret.open_ports, 2);
+    }
+    #[test]
+    fn test_scan_known_open() {
+        // nothing listens on the ports, a probe gets the reset
+        let closed_ports: Vec<u16> = (0..2)
+            .map(|_| {
+                let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                l.local_addr().unwrap().port()
+            })
+            .collect();
+        let (probed_port, known_port) = (closed_ports[0], closed_ports[1]);
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let host =
+            Host::new(dst_addr, Some(closed_ports.clone())).with_known_open(vec![known_port]);
+        let ret = scan(
+            Target::new(vec![host]),
+            ScanMethod::Connect,
+            None,
+            None,
+            None,
+            None,
+            Some(Duration::new(1, 0)),
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            ret.port_status(&dst_addr, probed_port),
+            Some(PortStatus::Closed)
+        );
+        let known = &ret.get(&dst_addr).unwrap()[&known_port];
+        assert_eq!(known.len(), 1);
+        assert_eq!(known[0].port_status, PortStatus::Open);
+        assert_eq!(known[0].reason, PortReason::KnownOpen);
+        assert_eq!(ret.open_ports, 1);
+    }
+    #[test]
+    fn test_promote_open() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(b"SSH-2.0-OpenSSH_8.9p1\r\n").unwrap();
+            }
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let rtt = Duration::from_millis(10);
+        // the fin scan can not tell open from filtered
+        let mut ret = ScanResults::new();
+        ret.insert(
+            dst_addr,
+            dst_port,
+            PortStatus::OpenOrFiltered,
+            PortReason::NoResponse,
+            rtt,
+        );
+        ret.enrichment();
+        assert_eq!(ret.open_ports, 0);
+
+        let host = Host::new(dst_addr, Some(vec![dst_port]));
+        let vs = crate::vs_scan_with_options(
+            Target::new(vec![host]),
+            &crate::VsOptions {
+                only_null_probe: true,
+                only_tcp_recommended: true,
+                only_udp_recommended: true,
+                exclude_ports: Some(crate::vs::dbparser::ExcludePorts::new(vec![])),
+                connect_timeout: Some(Duration::from_secs(1)),
+                read_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // the connection does not prove the udp port is open
+        let mut udp_ret = ret.clone();
+        assert!(udp_ret.promote_open(ScanMethod::Udp, &vs).is_empty());
+        assert_eq!(
+            udp_ret.port_status(&dst_addr, dst_port),
+            Some(PortStatus::OpenOrFiltered)
+        );
+
+        let promoted = ret.promote_open(ScanMethod::Fin, &vs);
+        assert_eq!(promoted, vec![(dst_addr, dst_port)]);
+        assert_eq!(ret.port_status(&dst_addr, dst_port), Some(PortStatus::Open));
+        assert_eq!(
+            ret.scans[&dst_addr][&dst_port][0].reason,
+            PortReason::SynAck
+        );
+        assert_eq!(ret.open_ports, 1);
+    }
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_scan_results_bytes() {
+        let mut ret = ScanResults::new();
+        let rtt = Duration::from_millis(3);
+        for i in 0..50 {
+            let dst_addr: IpAddr = Ipv4Addr::new(10, 0, 0, i).into();
+            for dst_port in 1..=200 {
+                let (port_status, reason) = match dst_port % 3 {
+                    0 => (PortStatus::Open, PortReason::SynAck),
+                    1 => (PortStatus::Closed, PortReason::Reset),
+                    _ => (
+                        PortStatus::Filtered,
+                        PortReason::IcmpUnreachable {
+                            icmp_type: 3,
+                            icmp_code: 13,
+                        },
+                    ),
+                };
+                ret.insert(dst_addr, dst_port, port_status, reason, rtt);
+            }
+        }
+        ret.scans
+            .get_mut(&Ipv4Addr::new(10, 0, 0, 1).into())
+            .unwrap()
+            .get_mut(&80)
+            .unwrap()[0]
+            .raw_reply = Some(vec![0x45, 0x00, 0x00, 0x28]);
+        ret.enrichment();
+        let bytes = ret.to_bytes().unwrap();
+        let json = serde_json::to_vec(&ret).unwrap();
+        assert!(bytes.len() < json.len() / 2);
+        let back = ScanResults::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            serde_json::to_value(&back).unwrap(),
+            serde_json::to_value(&ret).unwrap()
+        );
+        assert_eq!(back.open_ports, ret.open_ports);
+        assert!(ScanResults::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+    }
+    #[test]
+    fn test_scan_results_merge() {
+        let host: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let other_host: IpAddr = Ipv4Addr::new(192, 168, 1, 11).into();
+        let rtt = Duration::from_millis(10);
+        // the workers scanned the overlapping port ranges of the same host
+        let mut a = ScanResults::new();
+        a.insert(host, 22, PortStatus::Open, PortReason::SynAck, rtt);
+        a.insert(host, 80, PortStatus::Filtered, PortReason::NoResponse, rtt);
+        a.insert(host, 443, PortStatus::Error, PortReason::Error, rtt);
+        a.insert(host, 8443, PortStatus::Open, PortReason::SynAck, rtt);
+        a.retries = 2;
+        a.enrichment();
+        let mut b = ScanResults::new();
+        b.insert(host, 80, PortStatus::Closed, PortReason::Reset, rtt);
+        b.insert(host, 443, PortStatus::Filtered, PortReason::NoResponse, rtt);
+        b.insert(host, 8080, PortStatus::Open, PortReason::SynAck, rtt);
+        b.insert(host, 8443, PortStatus::Open, PortReason::ProxyReply(0), rtt);
+        b.insert(other_host, 22, PortStatus::Closed, PortReason::Reset, rtt);
+        b.retries = 3;
+        b.incomplete = true;
+        b.enrichment();
+
+        let mut ab = a.clone();
+        ab.merge(b.clone());
+        let status = |r: &ScanResults, ip, port| r.port_status(&ip, port);
+        assert_eq!(status(&ab, host, 22), Some(PortStatus::Open));
+        assert_eq!(status(&ab, host, 80), Some(PortStatus::Closed));
+        assert_eq!(status(&ab, host, 443), Some(PortStatus::Filtered));
+        assert_eq!(status(&ab, host, 8080), Some(PortStatus::Open));
+        assert_eq!(status(&ab, other_host, 22), Some(PortStatus::Closed));
+        // the tie keeps the own results
+        assert_eq!(ab.scans[&host][&8443][0].reason, PortReason::SynAck);
+        assert_eq!(ab.open_ports, 3);
+        assert_eq!(ab.retries, 5);
+        assert!(ab.incomplete);
+
+        // the statuses do not depend on the merge order
+        let mut ba = b.clone();
+        ba.merge(a.clone());
+        for (ip, ports) in &ab.scans {
+            for port in ports.keys() {
+                assert_eq!(status(&ab, *ip, *port), status(&ba, *ip, *port));
+            }
+        }
+        assert_eq!(ba.scans[&host][&8443][0].reason, PortReason::ProxyReply(0));
+        assert_eq!(ba.open_ports, ab.open_ports);
+    }
+    #[test]
+    fn test_os_hint_classify() {
+        let table = [
+            // (observed ttl, window, os)
+            (64, 29200, OsHint::Linux),
+            (57, 64240, OsHint::Linux),
+            (64, 65535, OsHint::Bsd),
+            (50, 65535, OsHint::Bsd),
+            (128, 8192, OsHint::Windows),
+            (113, 64240, OsHint::Windows),
+            (65, 65535, OsHint::Windows),
+            (255, 4128, OsHint::NetworkDevice),
+            (240, 4128, OsHint::NetworkDevice),
+            (254, 49640, OsHint::Solaris),
+        ];
+        for (ttl, window, os) in table {
+            assert_eq!(
+                OsHint::classify(ttl, window),
+                os,
+                "ttl {} window {}",
+                ttl,
+                window
+            );
+        }
+    }
+    #[test]
+    fn test_scan_source_for() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_port = listener.local_addr().unwrap().port();
+        let dst_ipv4 = Ipv4Addr::new(127, 0, 0, 1);
+        let host = Host::new(dst_ipv4.into(), Some(vec![dst_port]));
+        let target = Target::new(vec![host]);
+        let timeout = Some(Duration::new(1, 0));
+        let ret = tcp_connect_scan(target, None, None, timeout, 1).unwrap();
+        let src_ipv4 = find_source_addr(None, dst_ipv4).unwrap().unwrap();
+        assert_eq!(ret.source_for(&dst_ipv4.into()), Some(src_ipv4.into()));
+        assert_eq!(ret.source_for(&TEST_IPV4_LOCAL.into()), None);
+    }
+    #[test]
     fn test_arp_scan_subnet() {
         let subnet: Ipv4Pool = Ipv4Pool::from("192.168.1.0/24").unwrap();
         let mut hosts: Vec<Host> = vec![];
@@ -1240,7 +3302,7 @@ mod tests {
         println!("{}", ret);
 
         // let target: Target = Target::from_subnet("192.168.1.1/24", Some(vec![22]))?;
-        // let ret = tcp_connect_scan(target, src_ipv4, src_port, threads_num, timeout).unwrap();
+        // let ret = tcp_connect_scan(target, src_ipv4, src_port, threads_num, None, timeout).unwrap();
         // println!("{}", ret);
     }
     #[test]
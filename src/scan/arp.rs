@@ -10,6 +10,7 @@ use std::time::Duration;
 use crate::errors::PistolErrors;
 use crate::layers::get_mac_from_arp;
 use crate::layers::layer2_send;
+use crate::layers::Layer2Frame;
 use crate::layers::Layer2Match;
 use crate::layers::Layer3Match;
 use crate::layers::LayersMatch;
@@ -49,10 +50,8 @@ pub fn send_arp_scan_packet(
     let layers_match = LayersMatch::Layer3Match(layer3);
 
     let (ret, rtt) = layer2_send(
-        dst_mac,
         interface,
-        &arp_buffer,
-        ethernet_type,
+        Layer2Frame::new(dst_mac, ethernet_type, &arp_buffer),
         vec![layers_match],
         timeout,
     )?;
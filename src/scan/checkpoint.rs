@@ -0,0 +1,87 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::errors::PistolErrors;
+
+use super::PortScanResults;
+
+/// One completed probe in the checkpoint file, a line of json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointEntry {
+    pub addr: IpAddr,
+    pub port: u16,
+    pub result: PortScanResults,
+}
+
+/// Append the completed probes to the checkpoint file, every probe is flushed as soon as it is done.
+pub(crate) struct CheckpointWriter {
+    file: File,
+}
+
+impl CheckpointWriter {
+    pub(crate) fn open(path: &Path) -> Result<CheckpointWriter, PistolErrors> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        // finish the line partially written by the interrupted scan,
+        // so the appended entries are not glued to it
+        if file.metadata()?.len() > 0 {
+            let mut last = [0u8; 1];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+        Ok(CheckpointWriter { file })
+    }
+    pub(crate) fn append(
+        &mut self,
+        addr: IpAddr,
+        port: u16,
+        result: &PortScanResults,
+    ) -> Result<(), PistolErrors> {
+        let entry = CheckpointEntry {
+            addr,
+            port,
+            result: result.clone(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Read the completed probes of the checkpoint file, the broken lines (partial writes) are skipped.
+/// The file which does not exist has no completed probes.
+pub fn load_checkpoint(path: &Path) -> Result<Vec<CheckpointEntry>, PistolErrors> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        match serde_json::from_slice::<CheckpointEntry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => continue,
+        }
+    }
+    Ok(entries)
+}
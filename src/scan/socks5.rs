@@ -0,0 +1,175 @@
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::errors::PistolErrors;
+use crate::utils::clock_elapsed;
+use crate::utils::clock_now;
+
+use super::PortReason;
+use super::PortStatus;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+// reply codes of RFC 1928
+const SOCKS5_REP_SUCCEEDED: u8 = 0x00;
+const SOCKS5_REP_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_REP_NOT_ALLOWED: u8 = 0x02;
+const SOCKS5_REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const SOCKS5_REP_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS5_REP_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS5_REP_TTL_EXPIRED: u8 = 0x06;
+
+/// The SOCKS5 proxy which the connect scan goes through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Socks5Proxy {
+    pub addr: SocketAddr,
+    /// The username and password (RFC 1929), None for the proxy without authentication.
+    pub auth: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    pub fn new(addr: SocketAddr) -> Socks5Proxy {
+        Socks5Proxy { addr, auth: None }
+    }
+    pub fn with_auth(addr: SocketAddr, username: &str, password: &str) -> Socks5Proxy {
+        Socks5Proxy {
+            addr,
+            auth: Some((username.to_string(), password.to_string())),
+        }
+    }
+    fn error(&self, reason: &str) -> PistolErrors {
+        PistolErrors::Socks5ProxyError {
+            proxy: self.addr,
+            reason: reason.to_string(),
+        }
+    }
+    fn handshake(&self, stream: &mut TcpStream) -> Result<(), PistolErrors> {
+        let method = match self.auth {
+            Some(_) => SOCKS5_AUTH_PASSWORD,
+            None => SOCKS5_AUTH_NONE,
+        };
+        stream.write_all(&[SOCKS5_VERSION, 1, method])?;
+        let mut buff = [0u8; 2];
+        stream.read_exact(&mut buff)?;
+        if buff[0] != SOCKS5_VERSION {
+            return Err(self.error("invalid version"));
+        }
+        if buff[1] == SOCKS5_AUTH_NO_ACCEPTABLE || buff[1] != method {
+            return Err(self.error("no acceptable authentication method"));
+        }
+        if let Some((username, password)) = &self.auth {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(self.error("username or password too long"));
+            }
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request)?;
+            stream.read_exact(&mut buff)?;
+            if buff[1] != 0x00 {
+                return Err(self.error("authentication failed"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn connect_request(dst_addr: IpAddr, dst_port: u16) -> Vec<u8> {
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00];
+    match dst_addr {
+        IpAddr::V4(ipv4) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&ipv4.octets());
+        }
+        IpAddr::V6(ipv6) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&ipv6.octets());
+        }
+    }
+    request.extend_from_slice(&dst_port.to_be_bytes());
+    request
+}
+
+/// Read the rest of the reply after the reply code, the bound address is not used.
+fn read_bound_addr(stream: &mut TcpStream) -> Result<(), PistolErrors> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    let addr_len = match header[1] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => 0,
+    };
+    let mut addr = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut addr)?;
+    Ok(())
+}
+
+/// The connect scan through the SOCKS5 proxy, the proxy does the connect() to the target.
+/// The connection refused reply of the proxy means closed,
+/// the unreachable replies and no reply before the timeout mean filtered.
+pub fn send_connect_scan_packet(
+    proxy: &Socks5Proxy,
+    dst_addr: IpAddr,
+    dst_port: u16,
+    timeout: Duration,
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
+    let start_time = clock_now();
+    let mut stream = TcpStream::connect_timeout(&proxy.addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    proxy.handshake(&mut stream)?;
+
+    stream.write_all(&connect_request(dst_addr, dst_port))?;
+    let mut reply = [0u8; 2];
+    match stream.read_exact(&mut reply) {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            return Ok((
+                PortStatus::Filtered,
+                PortReason::NoResponse,
+                clock_elapsed(start_time),
+            ));
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if reply[0] != SOCKS5_VERSION {
+        return Err(proxy.error("invalid version"));
+    }
+    let port_status = match reply[1] {
+        SOCKS5_REP_SUCCEEDED => {
+            read_bound_addr(&mut stream)?;
+            PortStatus::Open
+        }
+        SOCKS5_REP_CONNECTION_REFUSED => PortStatus::Closed,
+        // many proxies report the connect timeout as the general failure
+        SOCKS5_REP_GENERAL_FAILURE
+        | SOCKS5_REP_NOT_ALLOWED
+        | SOCKS5_REP_NETWORK_UNREACHABLE
+        | SOCKS5_REP_HOST_UNREACHABLE
+        | SOCKS5_REP_TTL_EXPIRED => PortStatus::Filtered,
+        _ => return Err(proxy.error("unsupported command or address type")),
+    };
+    Ok((
+        port_status,
+        PortReason::ProxyReply(reply[1]),
+        clock_elapsed(start_time),
+    ))
+}
@@ -29,7 +29,9 @@ use crate::layers::LayersMatch;
 use crate::layers::IPV4_HEADER_SIZE;
 use crate::layers::TCP_HEADER_SIZE;
 
+use super::connect_error_reason;
 use super::IdleScanResults;
+use super::PortReason;
 use super::PortStatus;
 
 const TCP_DATA_SIZE: usize = 0;
@@ -44,13 +46,76 @@ const TCP_FLAGS_RST_MASK: u8 = 0b00000100;
 // const TCP_FLAGS_SYN_MASK: u8 = 0b00000010;
 // const TCP_FLAGS_FIN_MASK: u8 = 0b00000001;
 
+/// Send one syn packet and return the ttl and window size of the syn/ack response.
+pub fn send_os_hint_packet(
+    src_ipv4: Ipv4Addr,
+    src_port: u16,
+    dst_ipv4: Ipv4Addr,
+    dst_port: u16,
+    timeout: Duration,
+) -> Result<Option<(u8, u16)>, PistolErrors> {
+    let mut rng = rand::thread_rng();
+    // ip header
+    let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
+    let mut ip_header = MutableIpv4Packet::new(&mut ip_buff).unwrap();
+    ip_header.set_version(4);
+    ip_header.set_header_length(5);
+    ip_header.set_source(src_ipv4);
+    ip_header.set_destination(dst_ipv4);
+    ip_header.set_total_length((IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE) as u16);
+    let id = rng.gen();
+    ip_header.set_identification(id);
+    ip_header.set_flags(Ipv4Flags::DontFragment);
+    ip_header.set_ttl(TTL);
+    ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    let c = ipv4::checksum(&ip_header.to_immutable());
+    ip_header.set_checksum(c);
+
+    // tcp header
+    let mut tcp_header = MutableTcpPacket::new(&mut ip_buff[IPV4_HEADER_SIZE..]).unwrap();
+    tcp_header.set_source(src_port);
+    tcp_header.set_destination(dst_port);
+    tcp_header.set_sequence(rng.gen());
+    tcp_header.set_acknowledgement(rng.gen());
+    tcp_header.set_reserved(0);
+    tcp_header.set_flags(TcpFlags::SYN);
+    tcp_header.set_urgent_ptr(0);
+    tcp_header.set_window(1024);
+    tcp_header.set_data_offset(5);
+    let checksum = tcp::ipv4_checksum(&tcp_header.to_immutable(), &src_ipv4, &dst_ipv4);
+    tcp_header.set_checksum(checksum);
+
+    let layer3 = Layer3Match {
+        layer2: None,
+        src_addr: Some(dst_ipv4.into()),
+        dst_addr: Some(src_ipv4.into()),
+    };
+    let layer4_tcp_udp = Layer4MatchTcpUdp {
+        layer3: Some(layer3),
+        src_port: Some(dst_port),
+        dst_port: Some(src_port),
+    };
+    let layers_match = LayersMatch::Layer4MatchTcpUdp(layer4_tcp_udp);
+
+    let (ret, _rtt) = layer3_ipv4_send(src_ipv4, dst_ipv4, &ip_buff, vec![layers_match], timeout)?;
+    match Ipv4Packet::new(&ret) {
+        Some(ipv4_packet) => match TcpPacket::new(ipv4_packet.payload()) {
+            Some(tcp_packet) if tcp_packet.get_flags() == (TcpFlags::SYN | TcpFlags::ACK) => {
+                Ok(Some((ipv4_packet.get_ttl(), tcp_packet.get_window())))
+            }
+            _ => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
 pub fn send_syn_scan_packet(
     src_ipv4: Ipv4Addr,
     src_port: u16,
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -107,54 +172,56 @@ pub fn send_syn_scan_packet(
         vec![layers_match_1, layers_match_2],
         timeout,
     )?;
-    match Ipv4Packet::new(&ret) {
-        Some(ipv4_packet) => {
-            match ipv4_packet.get_next_level_protocol() {
-                IpNextHeaderProtocols::Tcp => {
-                    match TcpPacket::new(ipv4_packet.payload()) {
-                        Some(tcp_packet) => {
-                            let tcp_flags = tcp_packet.get_flags();
-                            if tcp_flags == (TcpFlags::SYN | TcpFlags::ACK) {
-                                // tcp syn/ack response
-                                return Ok((PortStatus::Open, rtt));
-                            } else if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
-                                // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
-                            }
-                        }
-                        None => (),
+    let (port_status, reason) = syn_scan_reply(&ret);
+    Ok((port_status, reason, rtt))
+}
+
+/// The port status of the reply to the syn probe, the empty reply means no response.
+fn syn_scan_reply(reply: &[u8]) -> (PortStatus, PortReason) {
+    if let Some(ipv4_packet) = Ipv4Packet::new(reply) {
+        match ipv4_packet.get_next_level_protocol() {
+            IpNextHeaderProtocols::Tcp => {
+                if let Some(tcp_packet) = TcpPacket::new(ipv4_packet.payload()) {
+                    let tcp_flags = tcp_packet.get_flags();
+                    if tcp_flags == (TcpFlags::SYN | TcpFlags::ACK) {
+                        // tcp syn/ack response
+                        return (PortStatus::Open, PortReason::SynAck);
+                    } else if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
+                        // tcp rst response
+                        return (PortStatus::Closed, PortReason::Reset);
                     }
                 }
-                IpNextHeaderProtocols::Icmp => {
-                    match IcmpPacket::new(ipv4_packet.payload()) {
-                        Some(icmp_packet) => {
-                            let icmp_type = icmp_packet.get_icmp_type();
-                            let icmp_code = icmp_packet.get_icmp_code();
-                            let codes = vec![
-                                        destination_unreachable::IcmpCodes::DestinationHostUnreachable, // 1
-                                        destination_unreachable::IcmpCodes::DestinationProtocolUnreachable, // 2
-                                        destination_unreachable::IcmpCodes::DestinationPortUnreachable, // 3
-                                        destination_unreachable::IcmpCodes::NetworkAdministrativelyProhibited, // 9
-                                        destination_unreachable::IcmpCodes::HostAdministrativelyProhibited, // 10
-                                        destination_unreachable::IcmpCodes::CommunicationAdministrativelyProhibited, // 13
-                                    ];
-                            if icmp_type == IcmpTypes::DestinationUnreachable
-                                && codes.contains(&icmp_code)
-                            {
-                                // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
-                            }
-                        }
-                        None => (),
+            }
+            IpNextHeaderProtocols::Icmp => {
+                if let Some(icmp_packet) = IcmpPacket::new(ipv4_packet.payload()) {
+                    let icmp_type = icmp_packet.get_icmp_type();
+                    let icmp_code = icmp_packet.get_icmp_code();
+                    let codes = [
+                        destination_unreachable::IcmpCodes::DestinationHostUnreachable, // 1
+                        destination_unreachable::IcmpCodes::DestinationProtocolUnreachable, // 2
+                        destination_unreachable::IcmpCodes::DestinationPortUnreachable, // 3
+                        destination_unreachable::IcmpCodes::NetworkAdministrativelyProhibited, // 9
+                        destination_unreachable::IcmpCodes::HostAdministrativelyProhibited, // 10
+                        destination_unreachable::IcmpCodes::CommunicationAdministrativelyProhibited, // 13
+                    ];
+                    if icmp_type == IcmpTypes::DestinationUnreachable && codes.contains(&icmp_code)
+                    {
+                        // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
+                        return (
+                            PortStatus::Filtered,
+                            PortReason::IcmpUnreachable {
+                                icmp_type: icmp_type.0,
+                                icmp_code: icmp_code.0,
+                            },
+                        );
                     }
                 }
-                _ => (),
             }
+            _ => (),
         }
-        None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::Filtered, rtt))
+    (PortStatus::Filtered, PortReason::NoResponse)
 }
 
 pub fn send_fin_scan_packet(
@@ -163,7 +230,7 @@ pub fn send_fin_scan_packet(
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -229,10 +296,10 @@ pub fn send_fin_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags == (TcpFlags::SYN | TcpFlags::ACK) {
                                 // tcp syn/ack response
-                                return Ok((PortStatus::Open, rtt));
+                                return Ok((PortStatus::Open, PortReason::SynAck, rtt));
                             } else if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst packet
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -255,7 +322,14 @@ pub fn send_fin_scan_packet(
                                 && codes.contains(&icmp_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -267,7 +341,7 @@ pub fn send_fin_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_ack_scan_packet(
@@ -276,7 +350,7 @@ pub fn send_ack_scan_packet(
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -342,7 +416,7 @@ pub fn send_ack_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Unfiltered, rtt));
+                                return Ok((PortStatus::Unfiltered, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -365,7 +439,14 @@ pub fn send_ack_scan_packet(
                                 && codes.contains(&icmp_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -377,7 +458,7 @@ pub fn send_ack_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::Filtered, rtt))
+    Ok((PortStatus::Filtered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_null_scan_packet(
@@ -386,7 +467,7 @@ pub fn send_null_scan_packet(
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -452,7 +533,7 @@ pub fn send_null_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -475,7 +556,14 @@ pub fn send_null_scan_packet(
                                 && codes.contains(&icmp_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -487,7 +575,7 @@ pub fn send_null_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_xmas_scan_packet(
@@ -496,7 +584,7 @@ pub fn send_xmas_scan_packet(
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -563,7 +651,7 @@ pub fn send_xmas_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -586,7 +674,14 @@ pub fn send_xmas_scan_packet(
                                 && codes.contains(&icmp_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -598,7 +693,7 @@ pub fn send_xmas_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_window_scan_packet(
@@ -607,7 +702,7 @@ pub fn send_window_scan_packet(
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -674,10 +769,10 @@ pub fn send_window_scan_packet(
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 if tcp_packet.get_window() > 0 {
                                     // tcp rst response with non-zero window field
-                                    return Ok((PortStatus::Open, rtt));
+                                    return Ok((PortStatus::Open, PortReason::Reset, rtt));
                                 } else {
                                     // tcp rst response with zero window field
-                                    return Ok((PortStatus::Closed, rtt));
+                                    return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                                 }
                             }
                         }
@@ -701,7 +796,14 @@ pub fn send_window_scan_packet(
                                 && codes.contains(&icmp_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -713,7 +815,7 @@ pub fn send_window_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::Filtered, rtt))
+    Ok((PortStatus::Filtered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_maimon_scan_packet(
@@ -722,7 +824,7 @@ pub fn send_maimon_scan_packet(
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
     let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -788,7 +890,7 @@ pub fn send_maimon_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -811,7 +913,14 @@ pub fn send_maimon_scan_packet(
                                 && codes.contains(&icmp_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -823,7 +932,7 @@ pub fn send_maimon_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_idle_scan_packet(
@@ -834,7 +943,7 @@ pub fn send_idle_scan_packet(
     zombie_ipv4: Ipv4Addr,
     zombie_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Option<IdleScanResults>, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Option<IdleScanResults>, Duration), PistolErrors> {
     fn _forge_syn_packet(
         src_ipv4: Ipv4Addr,
         dst_ipv4: Ipv4Addr,
@@ -938,7 +1047,15 @@ pub fn send_idle_scan_packet(
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
                                 // dst is unreachable ignore this port
-                                return Ok((PortStatus::Unreachable, None, rtt_1));
+                                return Ok((
+                                    PortStatus::Unreachable,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    None,
+                                    rtt_1,
+                                ));
                             }
                         }
                         None => (),
@@ -1019,7 +1136,15 @@ pub fn send_idle_scan_packet(
                             {
                                 // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
                                 // dst is unreachable ignore this port
-                                return Ok((PortStatus::Unreachable, None, rtt));
+                                return Ok((
+                                    PortStatus::Unreachable,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    None,
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -1038,6 +1163,7 @@ pub fn send_idle_scan_packet(
     } else if zombie_ip_id_2 - zombie_ip_id_1 >= 2 {
         Ok((
             PortStatus::Open,
+            PortReason::ZombieIpId,
             Some(IdleScanResults {
                 zombie_ip_id_1,
                 zombie_ip_id_2,
@@ -1047,6 +1173,7 @@ pub fn send_idle_scan_packet(
     } else {
         Ok((
             PortStatus::ClosedOrFiltered,
+            PortReason::ZombieIpId,
             Some(IdleScanResults {
                 zombie_ip_id_1,
                 zombie_ip_id_2,
@@ -1062,11 +1189,47 @@ pub fn send_connect_scan_packet(
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let addr = SocketAddr::V4(SocketAddrV4::new(dst_ipv4, dst_port));
     let start_time = Instant::now();
     match TcpStream::connect_timeout(&addr, timeout) {
-        Ok(_) => Ok((PortStatus::Open, start_time.elapsed())),
-        Err(_) => Ok((PortStatus::Closed, start_time.elapsed())),
+        Ok(_) => Ok((PortStatus::Open, PortReason::SynAck, start_time.elapsed())),
+        Err(e) => Ok((
+            PortStatus::Closed,
+            connect_error_reason(&e),
+            start_time.elapsed(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_syn_scan_reply_rst() {
+        let mut buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE];
+        let mut ip_header = MutableIpv4Packet::new(&mut buff).unwrap();
+        ip_header.set_version(4);
+        ip_header.set_header_length(5);
+        ip_header.set_total_length((IPV4_HEADER_SIZE + TCP_HEADER_SIZE) as u16);
+        ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        let mut tcp_header = MutableTcpPacket::new(&mut buff[IPV4_HEADER_SIZE..]).unwrap();
+        tcp_header.set_data_offset(5);
+        tcp_header.set_flags(TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(
+            syn_scan_reply(&buff),
+            (PortStatus::Closed, PortReason::Reset)
+        );
+        let mut tcp_header = MutableTcpPacket::new(&mut buff[IPV4_HEADER_SIZE..]).unwrap();
+        tcp_header.set_flags(TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(
+            syn_scan_reply(&buff),
+            (PortStatus::Open, PortReason::SynAck)
+        );
+        // no reply before the timeout
+        assert_eq!(
+            syn_scan_reply(&[]),
+            (PortStatus::Filtered, PortReason::NoResponse)
+        );
     }
 }
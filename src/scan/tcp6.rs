@@ -26,6 +26,8 @@ use crate::layers::LayersMatch;
 use crate::layers::IPV6_HEADER_SIZE;
 use crate::layers::TCP_HEADER_SIZE;
 
+use super::connect_error_reason;
+use super::PortReason;
 use super::PortStatus;
 
 // const TCP_FLAGS_CWR_MASK: u8 = 0b10000000;
@@ -40,13 +42,73 @@ const TCP_FLAGS_RST_MASK: u8 = 0b00000100;
 const TCP_DATA_SIZE: usize = 0;
 const TTL: u8 = 255;
 
+/// Send one syn packet and return the hop limit and window size of the syn/ack response.
+pub fn send_os_hint_packet(
+    src_ipv6: Ipv6Addr,
+    src_port: u16,
+    dst_ipv6: Ipv6Addr,
+    dst_port: u16,
+    timeout: Duration,
+) -> Result<Option<(u8, u16)>, PistolErrors> {
+    let mut rng = rand::thread_rng();
+    // ipv6 header
+    let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
+    let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
+    ipv6_header.set_version(6);
+    ipv6_header.set_flow_label(0x12345);
+    let payload_length = TCP_HEADER_SIZE + TCP_DATA_SIZE;
+    ipv6_header.set_payload_length(payload_length as u16);
+    ipv6_header.set_next_header(IpNextHeaderProtocols::Tcp);
+    ipv6_header.set_hop_limit(TTL);
+    ipv6_header.set_source(src_ipv6);
+    ipv6_header.set_destination(dst_ipv6);
+
+    // tcp header
+    let mut tcp_header = MutableTcpPacket::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+    tcp_header.set_source(src_port);
+    tcp_header.set_destination(dst_port);
+    tcp_header.set_sequence(rng.gen());
+    tcp_header.set_acknowledgement(rng.gen());
+    tcp_header.set_reserved(0);
+    tcp_header.set_flags(TcpFlags::SYN);
+    tcp_header.set_urgent_ptr(0);
+    tcp_header.set_window(1024);
+    tcp_header.set_data_offset(5);
+    let checksum = ipv6_checksum(&tcp_header.to_immutable(), &src_ipv6, &dst_ipv6);
+    tcp_header.set_checksum(checksum);
+
+    let layer3 = Layer3Match {
+        layer2: None,
+        src_addr: Some(dst_ipv6.into()),
+        dst_addr: Some(src_ipv6.into()),
+    };
+    let layer4_tcp_udp = Layer4MatchTcpUdp {
+        layer3: Some(layer3),
+        src_port: Some(dst_port),
+        dst_port: Some(src_port),
+    };
+    let layers_match = LayersMatch::Layer4MatchTcpUdp(layer4_tcp_udp);
+
+    let (ret, _rtt) =
+        layer3_ipv6_send(src_ipv6, dst_ipv6, &ipv6_buff, vec![layers_match], timeout)?;
+    match Ipv6Packet::new(&ret) {
+        Some(ipv6_packet) => match TcpPacket::new(ipv6_packet.payload()) {
+            Some(tcp_packet) if tcp_packet.get_flags() == (TcpFlags::SYN | TcpFlags::ACK) => {
+                Ok(Some((ipv6_packet.get_hop_limit(), tcp_packet.get_window())))
+            }
+            _ => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
 pub fn send_syn_scan_packet(
     src_ipv6: Ipv6Addr,
     src_port: u16,
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -111,10 +173,10 @@ pub fn send_syn_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags == (TcpFlags::SYN | TcpFlags::ACK) {
                                 // tcp syn/ack response
-                                return Ok((PortStatus::Open, rtt));
+                                return Ok((PortStatus::Open, PortReason::SynAck, rtt));
                             } else if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -134,7 +196,14 @@ pub fn send_syn_scan_packet(
                                 && codes.contains(&icmpv6_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 3, or 4)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -146,7 +215,7 @@ pub fn send_syn_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::Filtered, rtt))
+    Ok((PortStatus::Filtered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_fin_scan_packet(
@@ -155,7 +224,7 @@ pub fn send_fin_scan_packet(
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -220,10 +289,10 @@ pub fn send_fin_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags == (TcpFlags::SYN | TcpFlags::ACK) {
                                 // tcp syn/ack response
-                                return Ok((PortStatus::Open, rtt));
+                                return Ok((PortStatus::Open, PortReason::SynAck, rtt));
                             } else if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst packet
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -243,7 +312,14 @@ pub fn send_fin_scan_packet(
                                 && codes.contains(&icmpv6_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 3, or 4)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -255,7 +331,7 @@ pub fn send_fin_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_ack_scan_packet(
@@ -264,7 +340,7 @@ pub fn send_ack_scan_packet(
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -329,7 +405,7 @@ pub fn send_ack_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Unfiltered, rtt));
+                                return Ok((PortStatus::Unfiltered, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -349,7 +425,14 @@ pub fn send_ack_scan_packet(
                                 && codes.contains(&icmpv6_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 3, or 4)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -361,7 +444,7 @@ pub fn send_ack_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::Filtered, rtt))
+    Ok((PortStatus::Filtered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_null_scan_packet(
@@ -370,7 +453,7 @@ pub fn send_null_scan_packet(
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -435,7 +518,7 @@ pub fn send_null_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -455,7 +538,14 @@ pub fn send_null_scan_packet(
                                 && codes.contains(&icmpv6_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 3, or 4)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -467,7 +557,7 @@ pub fn send_null_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_xmas_scan_packet(
@@ -476,7 +566,7 @@ pub fn send_xmas_scan_packet(
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -541,7 +631,7 @@ pub fn send_xmas_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -561,7 +651,14 @@ pub fn send_xmas_scan_packet(
                                 && codes.contains(&icmpv6_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 3, or 4)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -573,7 +670,7 @@ pub fn send_xmas_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_window_scan_packet(
@@ -582,7 +679,7 @@ pub fn send_window_scan_packet(
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -648,10 +745,10 @@ pub fn send_window_scan_packet(
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 if tcp_packet.get_window() > 0 {
                                     // tcp rst response with non-zero window field
-                                    return Ok((PortStatus::Open, rtt));
+                                    return Ok((PortStatus::Open, PortReason::Reset, rtt));
                                 } else {
                                     // tcp rst response with zero window field
-                                    return Ok((PortStatus::Closed, rtt));
+                                    return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                                 }
                             }
                         }
@@ -672,7 +769,14 @@ pub fn send_window_scan_packet(
                                 && codes.contains(&icmpv6_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 3, or 4)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -684,7 +788,7 @@ pub fn send_window_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::Filtered, rtt))
+    Ok((PortStatus::Filtered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_maimon_scan_packet(
@@ -693,7 +797,7 @@ pub fn send_maimon_scan_packet(
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ipv6 header
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
@@ -758,7 +862,7 @@ pub fn send_maimon_scan_packet(
                             let tcp_flags = tcp_packet.get_flags();
                             if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
                                 // tcp rst response
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((PortStatus::Closed, PortReason::Reset, rtt));
                             }
                         }
                         None => (),
@@ -778,7 +882,14 @@ pub fn send_maimon_scan_packet(
                                 && codes.contains(&icmpv6_code)
                             {
                                 // icmp unreachable error (type 3, code 1, 3, or 4)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -790,7 +901,7 @@ pub fn send_maimon_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
 
 pub fn send_connect_scan_packet(
@@ -799,11 +910,15 @@ pub fn send_connect_scan_packet(
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let addr = SocketAddr::V6(SocketAddrV6::new(dst_ipv6, dst_port, 0, 0));
     let start_time = Instant::now();
     match TcpStream::connect_timeout(&addr, timeout) {
-        Ok(_) => Ok((PortStatus::Open, start_time.elapsed())),
-        Err(_) => Ok((PortStatus::Closed, start_time.elapsed())),
+        Ok(_) => Ok((PortStatus::Open, PortReason::SynAck, start_time.elapsed())),
+        Err(e) => Ok((
+            PortStatus::Closed,
+            connect_error_reason(&e),
+            start_time.elapsed(),
+        )),
     }
 }
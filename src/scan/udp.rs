@@ -21,9 +21,9 @@ use crate::layers::LayersMatch;
 use crate::layers::IPV4_HEADER_SIZE;
 use crate::layers::UDP_HEADER_SIZE;
 
+use super::PortReason;
 use super::PortStatus;
 
-const UDP_DATA_SIZE: usize = 0;
 const TTL: u8 = 64;
 
 pub fn send_udp_scan_packet(
@@ -31,15 +31,17 @@ pub fn send_udp_scan_packet(
     src_port: u16,
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
+    payload: &[u8],
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     let mut rng = rand::thread_rng();
     // ip header
-    let mut ip_buff = [0u8; IPV4_HEADER_SIZE + UDP_HEADER_SIZE + UDP_DATA_SIZE];
+    let udp_data_size = payload.len();
+    let mut ip_buff = vec![0u8; IPV4_HEADER_SIZE + UDP_HEADER_SIZE + udp_data_size];
     let mut ip_header = MutableIpv4Packet::new(&mut ip_buff).unwrap();
     ip_header.set_version(4);
     ip_header.set_header_length(5);
-    ip_header.set_total_length((IPV4_HEADER_SIZE + UDP_HEADER_SIZE + UDP_DATA_SIZE) as u16);
+    ip_header.set_total_length((IPV4_HEADER_SIZE + UDP_HEADER_SIZE + udp_data_size) as u16);
     let id = rng.gen();
     ip_header.set_identification(id);
     ip_header.set_flags(Ipv4Flags::DontFragment);
@@ -54,8 +56,8 @@ pub fn send_udp_scan_packet(
     let mut udp_header = MutableUdpPacket::new(&mut ip_buff[IPV4_HEADER_SIZE..]).unwrap();
     udp_header.set_source(src_port);
     udp_header.set_destination(dst_port);
-    udp_header.set_length((UDP_HEADER_SIZE + UDP_DATA_SIZE) as u16);
-    // udp_header.set_payload(&vec![b'a'; 10]); // test
+    udp_header.set_length((UDP_HEADER_SIZE + udp_data_size) as u16);
+    udp_header.set_payload(payload);
     let checksum = ipv4_checksum(&udp_header.to_immutable(), &src_ipv4, &dst_ipv4);
     udp_header.set_checksum(checksum);
 
@@ -100,7 +102,7 @@ pub fn send_udp_scan_packet(
             match ipv4_packet.get_next_level_protocol() {
                 IpNextHeaderProtocols::Udp => {
                     // any udp response from target port (unusual)
-                    return Ok((PortStatus::Open, rtt));
+                    return Ok((PortStatus::Open, PortReason::UdpResponse, rtt));
                 }
                 IpNextHeaderProtocols::Icmp => {
                     match IcmpPacket::new(ipv4_packet.payload()) {
@@ -109,10 +111,24 @@ pub fn send_udp_scan_packet(
                             let icmp_code = icmp_packet.get_icmp_code();
                             if codes_1.contains(&icmp_code) {
                                 // icmp port unreachable error (type 3, code 3)
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((
+                                    PortStatus::Closed,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             } else if codes_2.contains(&icmp_code) {
                                 // other icmp unreachable errors (type 3, code 1, 2, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmp_packet.get_icmp_type().0,
+                                        icmp_code: icmp_packet.get_icmp_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -124,5 +140,5 @@ pub fn send_udp_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
@@ -18,9 +18,9 @@ use crate::layers::LayersMatch;
 use crate::layers::IPV6_HEADER_SIZE;
 use crate::layers::UDP_HEADER_SIZE;
 
+use super::PortReason;
 use super::PortStatus;
 
-const UDP_DATA_SIZE: usize = 0;
 const TTL: u8 = 255;
 
 pub fn send_udp_scan_packet(
@@ -28,16 +28,18 @@ pub fn send_udp_scan_packet(
     src_port: u16,
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
+    payload: &[u8],
     timeout: Duration,
-) -> Result<(PortStatus, Duration), PistolErrors> {
+) -> Result<(PortStatus, PortReason, Duration), PistolErrors> {
     // ipv6 header
-    let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + UDP_HEADER_SIZE + UDP_DATA_SIZE];
+    let udp_data_size = payload.len();
+    let mut ipv6_buff = vec![0u8; IPV6_HEADER_SIZE + UDP_HEADER_SIZE + udp_data_size];
     let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
     ipv6_header.set_version(6);
     // In all cases, the IPv6 flow label is 0x12345, on platforms that allow us to set it.
     // On platforms that do not (which includes non-Linux Unix platforms when not using Ethernet to send), the flow label will be 0.
     ipv6_header.set_flow_label(0x12345);
-    let payload_length = UDP_HEADER_SIZE + UDP_DATA_SIZE;
+    let payload_length = UDP_HEADER_SIZE + udp_data_size;
     ipv6_header.set_payload_length(payload_length as u16);
     ipv6_header.set_next_header(IpNextHeaderProtocols::Udp);
     ipv6_header.set_hop_limit(TTL);
@@ -48,7 +50,8 @@ pub fn send_udp_scan_packet(
     let mut udp_header = MutableUdpPacket::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
     udp_header.set_source(src_port);
     udp_header.set_destination(dst_port);
-    udp_header.set_length((UDP_HEADER_SIZE + UDP_DATA_SIZE) as u16);
+    udp_header.set_length((UDP_HEADER_SIZE + udp_data_size) as u16);
+    udp_header.set_payload(payload);
     let checksum = ipv6_checksum(&udp_header.to_immutable(), &src_ipv6, &dst_ipv6);
     udp_header.set_checksum(checksum);
 
@@ -90,7 +93,7 @@ pub fn send_udp_scan_packet(
             match ipv6_packet.get_next_header() {
                 IpNextHeaderProtocols::Udp => {
                     // any udp response from target port (unusual)
-                    return Ok((PortStatus::Open, rtt));
+                    return Ok((PortStatus::Open, PortReason::UdpResponse, rtt));
                 }
                 IpNextHeaderProtocols::Icmpv6 => {
                     match Icmpv6Packet::new(ipv6_packet.payload()) {
@@ -99,10 +102,24 @@ pub fn send_udp_scan_packet(
                             let icmpv6_code = icmpv6_packet.get_icmpv6_code();
                             if codes_1.contains(&icmpv6_code) {
                                 // icmp port unreachable error (type 3, code 3)
-                                return Ok((PortStatus::Closed, rtt));
+                                return Ok((
+                                    PortStatus::Closed,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             } else if codes_2.contains(&icmpv6_code) {
                                 // other icmp unreachable errors (type 3, code 1, 2, 9, 10, or 13)
-                                return Ok((PortStatus::Filtered, rtt));
+                                return Ok((
+                                    PortStatus::Filtered,
+                                    PortReason::IcmpUnreachable {
+                                        icmp_type: icmpv6_packet.get_icmpv6_type().0,
+                                        icmp_code: icmpv6_packet.get_icmpv6_code().0,
+                                    },
+                                    rtt,
+                                ));
                             }
                         }
                         None => (),
@@ -114,5 +131,5 @@ pub fn send_udp_scan_packet(
         None => (),
     }
     // no response received (even after retransmissions)
-    Ok((PortStatus::OpenOrFiltered, rtt))
+    Ok((PortStatus::OpenOrFiltered, PortReason::NoResponse, rtt))
 }
@@ -1,19 +1,51 @@
 use log::warn;
 use num_cpus;
-use pnet::datalink::interfaces;
+#[cfg(target_os = "windows")]
+use once_cell::sync::Lazy;
+use pnet::datalink;
 use pnet::datalink::MacAddr;
 use pnet::datalink::NetworkInterface;
+#[cfg(any(target_os = "windows", test))]
+use pnet::ipnetwork::IpNetwork;
 use rand::Rng;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::fs;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::ops::RangeInclusive;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 use threadpool::ThreadPool;
 
 use crate::errors::PistolErrors;
+use crate::layers::raw_socket_available;
+use crate::layers::system_route;
+use crate::layers::system_route6;
+use crate::ping::icmp_ping_raw;
+use crate::ping::tcp_syn_ping_raw;
+use crate::ping::PingStatus;
+use crate::ping::SYN_PING_DEFAULT_PORT;
+#[cfg(not(target_os = "linux"))]
+use crate::route::CommandRunner;
 use crate::route::DefaultRoute;
+#[cfg(not(target_os = "linux"))]
+use crate::route::SystemCommandRunner;
 use crate::Ipv6CheckMethods;
+use crate::DEFAULT_CELLULAR_TIMEOUT;
 use crate::DEFAULT_TIMEOUT;
+use crate::DEFAULT_WIRELESS_TIMEOUT;
 use crate::SYSTEM_NET_CACHE;
 
 pub fn system_cache_search_route(dst_addr: IpAddr) -> Option<NetworkInterface> {
@@ -24,12 +56,12 @@ pub fn system_cache_search_route(dst_addr: IpAddr) -> Option<NetworkInterface> {
     snc.search_route(dst_addr)
 }
 
-pub fn system_cache_search_mac(dst_addr: IpAddr) -> Option<MacAddr> {
+pub fn system_cache_search_mac(dst_addr: IpAddr, interface: Option<u32>) -> Option<MacAddr> {
     // release the lock when leaving the function
     let snc = SYSTEM_NET_CACHE
         .lock()
         .expect("can not lock SYSTEM_NET_CACHE");
-    snc.search_mac(dst_addr)
+    snc.search_mac(dst_addr, interface)
 }
 
 pub fn system_cache_default_route() -> Option<DefaultRoute> {
@@ -48,12 +80,28 @@ pub fn system_cache_default_route6() -> Option<DefaultRoute> {
     snc.default_route6.clone()
 }
 
-pub fn system_cache_update(addr: IpAddr, mac: MacAddr) {
+pub fn system_cache_update(addr: IpAddr, mac: MacAddr, interface: Option<u32>) {
     // release the lock when leaving the function
     let mut snc = SYSTEM_NET_CACHE
         .lock()
         .expect("can not lock SYSTEM_NET_CACHE");
-    snc.update_neighbor_cache(addr, mac)
+    snc.update_neighbor_cache(addr, mac, interface)
+}
+
+pub fn system_cache_search_source_addr(dst_addr: IpAddr, prefer_temporary: bool) -> Option<IpAddr> {
+    // release the lock when leaving the function
+    let snc = SYSTEM_NET_CACHE
+        .lock()
+        .expect("can not lock SYSTEM_NET_CACHE");
+    snc.search_source_addr(dst_addr, prefer_temporary)
+}
+
+pub fn system_cache_update_source_addr(dst_addr: IpAddr, prefer_temporary: bool, src_addr: IpAddr) {
+    // release the lock when leaving the function
+    let mut snc = SYSTEM_NET_CACHE
+        .lock()
+        .expect("can not lock SYSTEM_NET_CACHE");
+    snc.update_source_addr(dst_addr, prefer_temporary, src_addr)
 }
 
 pub fn dst_ipv4_in_local(dst_ipv4: Ipv4Addr) -> bool {
@@ -80,6 +128,39 @@ pub fn dst_ipv6_in_local(dst_ipv6: Ipv6Addr) -> bool {
     false
 }
 
+/// The addresses of the local interfaces, the loopback ones are left out.
+pub fn local_addrs() -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    for interface in interfaces() {
+        for ipnetwork in interface.ips {
+            let addr = ipnetwork.ip();
+            if !addr.is_loopback() && !addr.is_unspecified() {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+/// The hosts behind the same route share the source address,
+/// so the result of `lookup` is cached per matched route, see `SystemNetCache::source_addrs`.
+fn cached_source_addr(
+    dst_addr: IpAddr,
+    prefer_temporary: bool,
+    lookup: impl FnOnce() -> Result<Option<IpAddr>, PistolErrors>,
+) -> Result<Option<IpAddr>, PistolErrors> {
+    match system_cache_search_source_addr(dst_addr, prefer_temporary) {
+        Some(s) => Ok(Some(s)),
+        None => {
+            let src_addr = lookup()?;
+            if let Some(s) = src_addr {
+                system_cache_update_source_addr(dst_addr, prefer_temporary, s);
+            }
+            Ok(src_addr)
+        }
+    }
+}
+
 pub fn find_source_addr(
     src_addr: Option<IpAddr>,
     dst_ipv4: Ipv4Addr,
@@ -90,46 +171,72 @@ pub fn find_source_addr(
             IpAddr::V4(s) => return Ok(Some(s)),
         },
         None => {
-            match system_cache_search_route(dst_ipv4.into()) {
-                Some(i) => {
-                    for ipnetwork in i.ips {
-                        match ipnetwork.ip() {
-                            IpAddr::V4(src_ipv4) => {
-                                if !src_ipv4.is_loopback() {
-                                    return Ok(Some(src_ipv4));
-                                }
-                            }
-                            _ => (),
-                        }
+            let src_addr = cached_source_addr(dst_ipv4.into(), false, || {
+                Ok(search_source_addr(dst_ipv4)?.map(|s| s.into()))
+            })?;
+            if let Some(IpAddr::V4(s)) = src_addr {
+                return Ok(Some(s));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn search_source_addr(dst_ipv4: Ipv4Addr) -> Result<Option<Ipv4Addr>, PistolErrors> {
+    match system_cache_search_route(dst_ipv4.into()) {
+        Some(i) => {
+            for ipnetwork in i.ips {
+                if let IpAddr::V4(src_ipv4) = ipnetwork.ip() {
+                    if !src_ipv4.is_loopback() {
+                        return Ok(Some(src_ipv4));
                     }
                 }
-                None => {
-                    // return the route ip
-                    let route = match system_cache_default_route() {
-                        Some(d) => d,
-                        None => return Err(PistolErrors::CanNotFoundRouterAddress),
-                    };
-                    if let IpAddr::V4(route_ipv4) = route.via {
-                        for interface in interfaces() {
-                            for ipnetwork in interface.ips {
-                                if ipnetwork.contains(route_ipv4.into()) {
-                                    if let IpAddr::V4(src_ipv4) = ipnetwork.ip() {
-                                        return Ok(Some(src_ipv4));
-                                    }
-                                }
+            }
+        }
+        None => {
+            // return the route ip
+            let route = match system_cache_default_route() {
+                Some(d) => d,
+                None => return Err(PistolErrors::CanNotFoundRouterAddress),
+            };
+            if let IpAddr::V4(route_ipv4) = route.via {
+                for interface in interfaces() {
+                    for ipnetwork in interface.ips {
+                        if ipnetwork.contains(route_ipv4.into()) {
+                            if let IpAddr::V4(src_ipv4) = ipnetwork.ip() {
+                                return Ok(Some(src_ipv4));
                             }
                         }
                     }
                 }
-            };
+            }
+            // the gateway is the peer of the point-to-point interface (e.g. tun0 of openvpn),
+            // it is not in the subnet of any local address, use the address of the route device
+            for ipnetwork in route.dev.ips {
+                if let IpAddr::V4(src_ipv4) = ipnetwork.ip() {
+                    if !src_ipv4.is_loopback() {
+                        return Ok(Some(src_ipv4));
+                    }
+                }
+            }
         }
-    }
+    };
     Ok(None)
 }
 
 pub fn find_source_addr6(
     src_addr: Option<IpAddr>,
     dst_ipv6: Ipv6Addr,
+) -> Result<Option<Ipv6Addr>, PistolErrors> {
+    find_source_addr6_with(src_addr, dst_ipv6, false)
+}
+
+/// The `find_source_addr6` which selects the temporary (privacy extensions) address
+/// instead of the stable one when `prefer_temporary` is set, see `select_source_addr6`.
+pub fn find_source_addr6_with(
+    src_addr: Option<IpAddr>,
+    dst_ipv6: Ipv6Addr,
+    prefer_temporary: bool,
 ) -> Result<Option<Ipv6Addr>, PistolErrors> {
     match src_addr {
         Some(s) => match s {
@@ -137,45 +244,312 @@ pub fn find_source_addr6(
             IpAddr::V6(s) => return Ok(Some(s)),
         },
         None => {
-            match system_cache_search_route(dst_ipv6.into()) {
-                Some(i) => {
-                    for ipnetwork in i.ips {
-                        match ipnetwork.ip() {
-                            IpAddr::V6(src_ipv6) => {
-                                if !src_ipv6.is_loopback() {
-                                    if (dst_ipv6.is_global_x() && src_ipv6.is_global_x())
-                                        || (!dst_ipv6.is_global_x() && !src_ipv6.is_global_x())
-                                    {
-                                        return Ok(Some(src_ipv6));
-                                    }
-                                }
+            let src_addr = cached_source_addr(dst_ipv6.into(), prefer_temporary, || {
+                Ok(search_source_addr6(dst_ipv6, prefer_temporary)?.map(|s| s.into()))
+            })?;
+            if let Some(IpAddr::V6(s)) = src_addr {
+                return Ok(Some(s));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn search_source_addr6(
+    dst_ipv6: Ipv6Addr,
+    prefer_temporary: bool,
+) -> Result<Option<Ipv6Addr>, PistolErrors> {
+    let mut candidates = Vec::new();
+    match system_cache_search_route(dst_ipv6.into()) {
+        Some(i) => {
+            for ipnetwork in i.ips {
+                if let IpAddr::V6(src_ipv6) = ipnetwork.ip() {
+                    if !src_ipv6.is_loopback() && dst_ipv6.is_global_x() == src_ipv6.is_global_x() {
+                        candidates.push(src_ipv6);
+                    }
+                }
+            }
+        }
+        None => {
+            // return the route ip
+            let route = match system_cache_default_route6() {
+                Some(d) => d,
+                None => return Err(PistolErrors::CanNotFoundRouterAddress),
+            };
+            if let IpAddr::V6(route_ipv6) = route.via {
+                for interface in interfaces() {
+                    for ipnetwork in interface.ips {
+                        if ipnetwork.contains(route_ipv6.into()) {
+                            if let IpAddr::V6(src_ipv6) = ipnetwork.ip() {
+                                candidates.push(src_ipv6);
                             }
-                            _ => (),
                         }
                     }
                 }
-                None => {
-                    // return the route ip
-                    let route = match system_cache_default_route6() {
-                        Some(d) => d,
-                        None => return Err(PistolErrors::CanNotFoundRouterAddress),
-                    };
-                    if let IpAddr::V6(route_ipv6) = route.via {
-                        for interface in interfaces() {
-                            for ipnetwork in interface.ips {
-                                if ipnetwork.contains(route_ipv6.into()) {
-                                    if let IpAddr::V6(src_ipv6) = ipnetwork.ip() {
-                                        return Ok(Some(src_ipv6));
-                                    }
-                                }
-                            }
+            }
+            // the peer of the point-to-point interface, see `search_source_addr`
+            if candidates.is_empty() {
+                for ipnetwork in route.dev.ips {
+                    if let IpAddr::V6(src_ipv6) = ipnetwork.ip() {
+                        if !src_ipv6.is_loopback()
+                            && src_ipv6.is_global_x() == dst_ipv6.is_global_x()
+                        {
+                            candidates.push(src_ipv6);
                         }
                     }
                 }
+            }
+        }
+    };
+    let flags = ipv6_addr_flags();
+    Ok(select_source_addr6(
+        dst_ipv6,
+        &candidates,
+        &flags,
+        prefer_temporary,
+    ))
+}
+
+// The ifa_flags of the address, from linux/if_addr.h.
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+/// Parse the `/proc/net/if_inet6` of linux, returns the flags of every ipv6 address, e.g.
+/// `fe80000000000000020c29fffe2c09e4 02 40 20 80 eth0`,
+/// the fields are the address, interface index, prefix length, scope, flags and interface name.
+fn parse_if_inet6(content: &str) -> HashMap<Ipv6Addr, u32> {
+    let mut ret = HashMap::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            continue;
+        }
+        let addr = match u128::from_str_radix(fields[0], 16) {
+            Ok(a) => Ipv6Addr::from(a),
+            Err(_) => continue,
+        };
+        if let Ok(flags) = u32::from_str_radix(fields[4], 16) {
+            ret.insert(addr, flags);
+        }
+    }
+    ret
+}
+
+/// The flags of the local ipv6 addresses.
+/// Only linux tells which address is temporary here,
+/// on the other systems no address is known as temporary and the first candidate is used.
+#[cfg(target_os = "linux")]
+fn ipv6_addr_flags() -> HashMap<Ipv6Addr, u32> {
+    match fs::read_to_string("/proc/net/if_inet6") {
+        Ok(content) => parse_if_inet6(&content),
+        Err(_) => HashMap::new(),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ipv6_addr_flags() -> HashMap<Ipv6Addr, u32> {
+    HashMap::new()
+}
+
+// The scopes of RFC 6724 section 3.1.
+const IPV6_SCOPE_LINK_LOCAL: u8 = 0x2;
+const IPV6_SCOPE_SITE_LOCAL: u8 = 0x5;
+const IPV6_SCOPE_GLOBAL: u8 = 0xe;
+
+/// The scope of the address, the loopback address is link-local.
+fn ipv6_scope(addr: Ipv6Addr) -> u8 {
+    let segments = addr.segments();
+    if addr.is_multicast() {
+        (segments[0] & 0x000f) as u8
+    } else if addr.is_loopback() || segments[0] & 0xffc0 == 0xfe80 {
+        IPV6_SCOPE_LINK_LOCAL
+    } else if segments[0] & 0xffc0 == 0xfec0 {
+        IPV6_SCOPE_SITE_LOCAL
+    } else {
+        IPV6_SCOPE_GLOBAL
+    }
+}
+
+/// The leading bits the addresses share, at most the 64 bits of the subnet prefix.
+fn common_prefix_len(a: Ipv6Addr, b: Ipv6Addr) -> u32 {
+    let diff = u128::from(a) ^ u128::from(b);
+    diff.leading_zeros().min(64)
+}
+
+/// Select the source address from the candidates by the rules of RFC 6724 section 5, in order:
+/// - rule 1, prefer the destination address itself,
+/// - rule 2, prefer the smallest scope not smaller than the scope of the destination,
+/// - rule 3, avoid the deprecated addresses (the temporary address about to expire),
+/// - rule 7, prefer the stable addresses to the temporary ones unless `prefer_temporary` is set
+///   (the RFC prefers the temporary ones, most systems do not),
+/// - rule 8, prefer the longest prefix shared with the destination.
+///
+/// The candidates are already on the outgoing interface (rule 5),
+/// the mobile ip (rule 4), next-hop (rule 5.5) and policy table label (rule 6) rules are not implemented.
+/// The first candidate wins the tie.
+fn select_source_addr6(
+    dst_ipv6: Ipv6Addr,
+    candidates: &[Ipv6Addr],
+    flags: &HashMap<Ipv6Addr, u32>,
+    prefer_temporary: bool,
+) -> Option<Ipv6Addr> {
+    candidates
+        .iter()
+        .min_by_key(|addr| source_addr6_rank(dst_ipv6, **addr, flags, prefer_temporary))
+        .copied()
+}
+
+/// The rank of the source address by the rules of `select_source_addr6`, the smaller the better.
+fn source_addr6_rank(
+    dst_ipv6: Ipv6Addr,
+    src_ipv6: Ipv6Addr,
+    flags: &HashMap<Ipv6Addr, u32>,
+    prefer_temporary: bool,
+) -> (bool, (bool, i16), bool, bool, Reverse<u32>) {
+    let f = flags.get(&src_ipv6).copied().unwrap_or(0);
+    let deprecated = f & IFA_F_DEPRECATED != 0;
+    let temporary = f & IFA_F_TEMPORARY != 0;
+    let dst_scope = ipv6_scope(dst_ipv6);
+    let scope = ipv6_scope(src_ipv6);
+    // the smallest of the large enough scopes, or the largest of the too small ones
+    let scope_key = if scope >= dst_scope {
+        (false, scope as i16)
+    } else {
+        (true, -(scope as i16))
+    };
+    (
+        src_ipv6 != dst_ipv6,
+        scope_key,
+        deprecated,
+        temporary != prefer_temporary,
+        Reverse(common_prefix_len(src_ipv6, dst_ipv6)),
+    )
+}
+
+/// The local addresses which can be the source of the destination, the loopback destination only has itself.
+/// The address family has to match, and the ipv6 source has to be global for the global destination
+/// (and local for the local one) like `find_source_addr6`, the ipv6 sources are ordered by `select_source_addr6`
+/// with the stable addresses first.
+pub fn source_candidates(dst_addr: IpAddr, local_addrs: &[IpAddr]) -> Vec<IpAddr> {
+    if dst_addr.is_loopback() {
+        return vec![dst_addr];
+    }
+    let usable = |a: &&IpAddr| !a.is_loopback() && !a.is_unspecified();
+    match dst_addr {
+        IpAddr::V4(_) => local_addrs
+            .iter()
+            .filter(usable)
+            .filter(|a| a.is_ipv4())
+            .copied()
+            .collect(),
+        IpAddr::V6(dst_ipv6) => {
+            let mut candidates: Vec<Ipv6Addr> = local_addrs
+                .iter()
+                .filter(usable)
+                .filter_map(|a| match a {
+                    IpAddr::V6(s) if s.is_global_x() == dst_ipv6.is_global_x() => Some(*s),
+                    _ => None,
+                })
+                .collect();
+            let flags = ipv6_addr_flags();
+            candidates.sort_by_key(|s| source_addr6_rank(dst_ipv6, *s, &flags, false));
+            candidates.into_iter().map(|s| s.into()).collect()
+        }
+    }
+}
+
+/// The interfaces of the system.
+#[cfg(not(target_os = "windows"))]
+pub fn interfaces() -> Vec<NetworkInterface> {
+    datalink::interfaces()
+}
+
+/// The interfaces of the system.
+/// On windows pnet only reads the ipv4 addresses and leaves the loopback interface out,
+/// so the ipv6 addresses from `Get-NetIPAddress` are merged in.
+#[cfg(target_os = "windows")]
+pub fn interfaces() -> Vec<NetworkInterface> {
+    merge_windows_ipv6(datalink::interfaces(), &WINDOWS_IPV6_ADDRS)
+}
+
+/// The powershell command prints one `index|alias|address|prefix length` line per address.
+#[cfg(target_os = "windows")]
+const WINDOWS_IPV6_ADDRS_CMD: &str = "Get-NetIPAddress -AddressFamily IPv6 | ForEach-Object { \"$($_.InterfaceIndex)|$($_.InterfaceAlias)|$($_.IPAddress)|$($_.PrefixLength)\" }";
+
+/// Running powershell is slow, the addresses are read only once.
+#[cfg(target_os = "windows")]
+static WINDOWS_IPV6_ADDRS: Lazy<Vec<WindowsIpv6Addr>> =
+    Lazy::new(|| match SystemCommandRunner.run(WINDOWS_IPV6_ADDRS_CMD) {
+        Ok(output) => parse_windows_ipv6_addrs(&output),
+        Err(e) => {
+            warn!("get the ipv6 addresses failed: {}", e);
+            Vec::new()
+        }
+    });
+
+/// One ipv6 address of an interface from `Get-NetIPAddress`.
+#[cfg(any(target_os = "windows", test))]
+#[derive(Debug, Clone, PartialEq)]
+struct WindowsIpv6Addr {
+    if_index: u32,
+    alias: String,
+    ipnetwork: IpNetwork,
+}
+
+/// Parse the lines of `WINDOWS_IPV6_ADDRS_CMD`, e.g. `12|Ethernet|fe80::1c2d:3e4f:5a6b:7c8d%12|64`.
+#[cfg(any(target_os = "windows", test))]
+fn parse_windows_ipv6_addrs(output: &str) -> Vec<WindowsIpv6Addr> {
+    let mut ret = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.trim().split('|').collect();
+        if let [if_index, alias, addr, prefix] = fields.as_slice() {
+            // the link-local addresses end with the zone index
+            let addr = addr.split('%').next().unwrap_or_default();
+            let (if_index, addr, prefix) = match (
+                if_index.parse::<u32>(),
+                addr.parse::<Ipv6Addr>(),
+                prefix.parse::<u8>(),
+            ) {
+                (Ok(i), Ok(a), Ok(p)) => (i, a, p),
+                _ => continue,
             };
+            if let Ok(ipnetwork) = IpNetwork::new(addr.into(), prefix) {
+                ret.push(WindowsIpv6Addr {
+                    if_index,
+                    alias: alias.to_string(),
+                    ipnetwork,
+                });
+            }
         }
     }
-    Ok(None)
+    ret
+}
+
+/// Add the addresses to the interfaces of the same index,
+/// the interfaces which pnet does not know (e.g. the loopback) are added too.
+#[cfg(any(target_os = "windows", test))]
+fn merge_windows_ipv6(
+    mut interfaces: Vec<NetworkInterface>,
+    addrs: &[WindowsIpv6Addr],
+) -> Vec<NetworkInterface> {
+    for a in addrs {
+        match interfaces.iter_mut().find(|i| i.index == a.if_index) {
+            Some(i) => {
+                if !i.ips.contains(&a.ipnetwork) {
+                    i.ips.push(a.ipnetwork);
+                }
+            }
+            None => interfaces.push(NetworkInterface {
+                name: a.alias.clone(),
+                description: a.alias.clone(),
+                index: a.if_index,
+                mac: None,
+                ips: vec![a.ipnetwork],
+                flags: 0,
+            }),
+        }
+    }
+    interfaces
 }
 
 #[cfg(any(
@@ -194,6 +568,67 @@ pub fn find_interface_by_name(name: &str) -> Option<NetworkInterface> {
     None
 }
 
+/// Returns the mtu of the interface from the system, None if it can not be read.
+#[cfg(target_os = "linux")]
+pub fn interface_mtu(iface: &NetworkInterface) -> Option<u32> {
+    let path = format!("/sys/class/net/{}/mtu", iface.name);
+    match fs::read_to_string(&path) {
+        Ok(mtu) => match mtu.trim().parse() {
+            Ok(m) => Some(m),
+            Err(e) => {
+                warn!("parse mtu from {} failed: {}", path, e);
+                None
+            }
+        },
+        Err(e) => {
+            warn!("read mtu from {} failed: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Returns the mtu of the interface from the system, None if it can not be read.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+pub fn interface_mtu(iface: &NetworkInterface) -> Option<u32> {
+    // em0: flags=8843<UP,BROADCAST,RUNNING,SIMPLEX,MULTICAST> metric 0 mtu 1500
+    let cmd = format!("ifconfig {}", iface.name);
+    let output = match SystemCommandRunner.run(&cmd) {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("get mtu of {} failed: {}", iface.name, e);
+            return None;
+        }
+    };
+    let output_split: Vec<&str> = output.split_whitespace().collect();
+    for i in 1..output_split.len() {
+        if output_split[i - 1] == "mtu" {
+            return output_split[i].parse().ok();
+        }
+    }
+    None
+}
+
+/// Returns the mtu of the interface from the system, None if it can not be read.
+#[cfg(target_os = "windows")]
+pub fn interface_mtu(iface: &NetworkInterface) -> Option<u32> {
+    let cmd = format!(
+        "(Get-NetIPInterface -InterfaceIndex {} -AddressFamily IPv4).NlMtu",
+        iface.index
+    );
+    match SystemCommandRunner.run(&cmd) {
+        Ok(o) => o.trim().parse().ok(),
+        Err(e) => {
+            warn!("get mtu of {} failed: {}", iface.name, e);
+            None
+        }
+    }
+}
+
 pub fn find_interface_by_ip(ipaddr: IpAddr) -> Option<NetworkInterface> {
     for interface in interfaces() {
         for ip in &interface.ips {
@@ -212,6 +647,15 @@ pub fn random_port() -> u16 {
     rng.gen_range(10000..=65535)
 }
 
+/// Returns the random port in the `range`, the range should be non-empty and in 1..=65535.
+pub fn random_port_in(range: &RangeInclusive<u16>) -> Result<u16, PistolErrors> {
+    let (start, end) = (*range.start(), *range.end());
+    if start == 0 || range.is_empty() {
+        return Err(PistolErrors::InvalidPortRange { start, end });
+    }
+    Ok(random_port_sp(start, end))
+}
+
 pub fn random_port_sp(start: u16, end: u16) -> u16 {
     let mut rng = rand::thread_rng();
     rng.gen_range(start..=end)
@@ -232,10 +676,318 @@ pub fn get_threads_pool(threads_num: usize) -> ThreadPool {
     pool
 }
 
+/// The timing templates of nmap (`-T0` to `-T5`), which set the defaults of the timing knobs.
+///
+/// | template | timeout | retries | parallelism | scan delay |
+/// |----------|---------|---------|-------------|------------|
+/// | T0 (paranoid) | 10s | 5 | 1 | 5min |
+/// | T1 (sneaky) | 10s | 5 | 1 | 15s |
+/// | T2 (polite) | 10s | 3 | 1 | 400ms |
+/// | T3 (normal) | default of the interface | 2 | 256 | 0 |
+/// | T4 (aggressive) | 1250ms | 1 | 1024 | 0 |
+/// | T5 (insane) | 300ms | 0 | 4096 | 0 |
+///
+/// The timeout is the time to wait for the response of a probe,
+/// the retries are the times an unanswered probe is sent again (the ping does not retry),
+/// the parallelism is the max number of probes sent at the same time,
+/// and the scan delay is the pause between the probes of a host.
+/// The timeout and the retries given explicitly are used instead of the template ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingTemplate {
+    /// Paranoid, one probe at a time with a long pause, for the IDS evasion.
+    T0,
+    /// Sneaky.
+    T1,
+    /// Polite, uses less bandwidth and target resources.
+    T2,
+    /// Normal.
+    T3,
+    /// Aggressive, for the fast and reliable network.
+    T4,
+    /// Insane, sacrifices the accuracy for the speed.
+    T5,
+}
+
+impl TimingTemplate {
+    /// The timeout of each probe, None for the default timeout of the source interface.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            TimingTemplate::T0 | TimingTemplate::T1 | TimingTemplate::T2 => {
+                Some(Duration::from_secs(10))
+            }
+            TimingTemplate::T3 => None,
+            TimingTemplate::T4 => Some(Duration::from_millis(1250)),
+            TimingTemplate::T5 => Some(Duration::from_millis(300)),
+        }
+    }
+    /// The retries of each unanswered probe.
+    pub fn max_retries(&self) -> usize {
+        match self {
+            TimingTemplate::T0 | TimingTemplate::T1 => 5,
+            TimingTemplate::T2 => 3,
+            TimingTemplate::T3 => 2,
+            TimingTemplate::T4 => 1,
+            TimingTemplate::T5 => 0,
+        }
+    }
+    /// The max number of probes sent at the same time.
+    pub fn parallelism(&self) -> usize {
+        match self {
+            TimingTemplate::T0 | TimingTemplate::T1 | TimingTemplate::T2 => 1,
+            TimingTemplate::T3 => 256,
+            TimingTemplate::T4 => 1024,
+            TimingTemplate::T5 => 4096,
+        }
+    }
+    /// The pause between the probes of a host.
+    pub fn scan_delay(&self) -> Option<Duration> {
+        match self {
+            TimingTemplate::T0 => Some(Duration::from_secs(300)),
+            TimingTemplate::T1 => Some(Duration::from_secs(15)),
+            TimingTemplate::T2 => Some(Duration::from_millis(400)),
+            _ => None,
+        }
+    }
+    /// The threads to send `probes` probes, limited by the parallelism.
+    pub fn threads_num(&self, probes: usize) -> usize {
+        probes.min(self.parallelism())
+    }
+}
+
+/// The probe sent by the thread pool.
+pub type ProbeJob = Box<dyn FnOnce() + Send + 'static>;
+
+/// Submit the probes to the pool, the probes of the same host are sent `scan_delay` apart
+/// (like the `--scan-delay` of nmap) in the order they are given.
+/// One scheduler thread paces all the hosts (see `schedule_probes`), so the delay of a host never holds up the pool or the other hosts.
+/// With the `clock`, every probe runs with it as the time source, see `with_clock`.
+pub fn dispatch_probes(
+    pool: &ThreadPool,
+    probes: Vec<(IpAddr, ProbeJob)>,
+    scan_delay: Option<Duration>,
+    clock: Option<Arc<dyn Clock + Send + Sync>>,
+) {
+    let probes: Vec<(IpAddr, ProbeJob)> = match clock {
+        Some(clock) => probes
+            .into_iter()
+            .map(|(addr, job)| {
+                let clock = clock.clone();
+                let job: ProbeJob = Box::new(move || with_clock(clock, job));
+                (addr, job)
+            })
+            .collect(),
+        None => probes,
+    };
+    let scan_delay = match scan_delay {
+        Some(d) if !d.is_zero() => d,
+        _ => {
+            for (_, job) in probes {
+                pool.execute(job);
+            }
+            return;
+        }
+    };
+    let mut hosts: HashMap<IpAddr, VecDeque<ProbeJob>> = HashMap::new();
+    for (addr, job) in probes {
+        hosts.entry(addr).or_default().push_back(job);
+    }
+    let pool = pool.clone();
+    thread::spawn(move || schedule_probes(&pool, hosts, scan_delay));
+}
+
+/// Submit the next probe of a host `scan_delay` after its previous probe started to run,
+/// so the delay holds between the actual sends even when the probes wait in the queue of a busy pool.
+fn schedule_probes(
+    pool: &ThreadPool,
+    mut hosts: HashMap<IpAddr, VecDeque<ProbeJob>>,
+    scan_delay: Duration,
+) {
+    let (started_tx, started_rx) = channel();
+    // the next send time of every host, the earliest first
+    let now = Instant::now();
+    let mut next_sends: BinaryHeap<Reverse<(Instant, IpAddr)>> =
+        hosts.keys().map(|addr| Reverse((now, *addr))).collect();
+    // the probes submitted and not started yet
+    let mut queued = 0;
+    loop {
+        let now = Instant::now();
+        while let Some(&Reverse((t, addr))) = next_sends.peek() {
+            if t > now {
+                break;
+            }
+            next_sends.pop();
+            if let Some(job) = hosts.get_mut(&addr).and_then(|jobs| jobs.pop_front()) {
+                let started_tx = started_tx.clone();
+                queued += 1;
+                pool.execute(move || {
+                    let _ = started_tx.send((addr, Instant::now()));
+                    job();
+                });
+            }
+        }
+        let started = match next_sends.peek() {
+            Some(&Reverse((t, _))) => started_rx.recv_timeout(t - now).ok(),
+            None if queued > 0 => started_rx.recv().ok(),
+            None => break,
+        };
+        if let Some((addr, start)) = started {
+            queued -= 1;
+            if hosts.get(&addr).is_some_and(|jobs| !jobs.is_empty()) {
+                next_sends.push(Reverse((start + scan_delay, addr)));
+            }
+        }
+    }
+}
+
+/// Limits the connections open to each host at the same time, like the per-host parallelism of nmap,
+/// however many threads the scan has.
+#[derive(Debug, Clone)]
+pub struct HostConnections {
+    max: usize,
+    open: Arc<(Mutex<HashMap<IpAddr, usize>>, Condvar)>,
+}
+
+impl HostConnections {
+    /// At most `max` connections to a host, at least one.
+    pub fn new(max: usize) -> HostConnections {
+        HostConnections {
+            max: max.max(1),
+            open: Arc::new((Mutex::new(HashMap::new()), Condvar::new())),
+        }
+    }
+    /// Wait until a connection to `addr` is allowed, and run `f` which makes it.
+    pub fn run<T>(&self, addr: IpAddr, f: impl FnOnce() -> T) -> T {
+        let (open, closed) = &*self.open;
+        {
+            let mut open = open.lock().unwrap();
+            while open.get(&addr).copied().unwrap_or(0) >= self.max {
+                open = closed.wait(open).unwrap();
+            }
+            *open.entry(addr).or_insert(0) += 1;
+        }
+        let ret = f();
+        let mut open = open.lock().unwrap();
+        if let Some(n) = open.get_mut(&addr) {
+            *n -= 1;
+            if *n == 0 {
+                open.remove(&addr);
+            }
+        }
+        closed.notify_all();
+        ret
+    }
+}
+
 pub fn get_default_timeout() -> Duration {
     Duration::new(DEFAULT_TIMEOUT, 0)
 }
 
+/// Returns the default timeout of the interface, the wireless and cellular interfaces get a larger one.
+/// The link type is guessed from the interface name only, e.g. `wlan0`, `wlp2s0` and `Wi-Fi` are wireless,
+/// `wwan0`, `rmnet0` and `pdp_ip0` are cellular.
+/// Some systems (e.g. `en0` of macOS) do not tell the link type by the name, they get the wired default.
+pub fn get_default_timeout_for(iface: &str) -> Duration {
+    let name = iface.to_lowercase();
+    let cellular_prefixes = ["wwan", "rmnet", "ccmni", "pdp_ip"];
+    if cellular_prefixes.iter().any(|p| name.starts_with(p)) {
+        Duration::new(DEFAULT_CELLULAR_TIMEOUT, 0)
+    } else if name.starts_with("wl") || name.contains("wi-fi") || name.contains("wireless") {
+        Duration::new(DEFAULT_WIRELESS_TIMEOUT, 0)
+    } else {
+        Duration::new(DEFAULT_TIMEOUT, 0)
+    }
+}
+
+/// Returns the default timeout of the interface which `src_addr` belongs to.
+pub fn get_default_timeout_by_src(src_addr: IpAddr) -> Duration {
+    match find_interface_by_ip(src_addr) {
+        Some(i) => get_default_timeout_for(&i.name),
+        None => get_default_timeout(),
+    }
+}
+
+/// The time source of the rtt measurement.
+pub trait Clock: fmt::Debug {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The clock for the tests, every reading moves the time forward by `step`,
+/// so a probe answered between two readings gets the rtt of exactly `step`.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+    step: Duration,
+}
+
+impl MockClock {
+    pub fn new(step: Duration) -> MockClock {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+            step,
+        }
+    }
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += d;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        let ret = *now;
+        *now += self.step;
+        ret
+    }
+}
+
+thread_local! {
+    // The clock set by `with_clock` on this thread, the system clock is used if it is None.
+    static CLOCK: RefCell<Option<Arc<dyn Clock + Send + Sync>>> = const { RefCell::new(None) };
+}
+
+/// Puts the previous clock of the thread back when dropped, also when `f` of `with_clock` panics.
+struct ClockGuard {
+    prev: Option<Arc<dyn Clock + Send + Sync>>,
+}
+
+impl Drop for ClockGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CLOCK.with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Run `f` with the `clock` as the time source of the probes sent on this thread.
+/// The scans and pings run their probes on the pool threads, give them the clock with the `clock` of the options.
+pub fn with_clock<T>(clock: Arc<dyn Clock + Send + Sync>, f: impl FnOnce() -> T) -> T {
+    let _guard = ClockGuard {
+        prev: CLOCK.with(|c| c.replace(Some(clock))),
+    };
+    f()
+}
+
+pub fn clock_now() -> Instant {
+    CLOCK.with(|c| match c.borrow().as_ref() {
+        Some(clock) => clock.now(),
+        None => Instant::now(),
+    })
+}
+
+pub fn clock_elapsed(since: Instant) -> Duration {
+    clock_now().saturating_duration_since(since)
+}
+
 pub struct SpHex {
     pub hex: Option<String>, // hex => dec
 }
@@ -276,9 +1028,683 @@ impl SpHex {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ListenerProtocol {
+    Tcp,
+    Udp,
+}
+
+/// The socket listening on the local host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub proto: ListenerProtocol,
+    pub addr: IpAddr,
+    pub port: u16,
+    /// The owner process, None if it is not known (e.g. the socket of the other user).
+    pub pid: Option<u32>,
+}
+
+/// Parse the address of `/proc/net/tcp` of linux, e.g. `0100007F:0016` is 127.0.0.1:22,
+/// every 32 bits of the address is printed in the host byte order.
+fn parse_proc_net_addr(field: &str) -> Option<(IpAddr, u16)> {
+    let (addr, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut octets = Vec::new();
+    for i in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let addr: IpAddr = match octets.len() {
+        4 => Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into(),
+        16 => {
+            let octets: [u8; 16] = octets.try_into().ok()?;
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return None,
+    };
+    Some((addr, port))
+}
+
+/// Parse the `/proc/net/{tcp,tcp6,udp,udp6}` of linux, returns the listeners and their socket inodes.
+/// The tcp sockets in the LISTEN state (0A) and the unconnected udp sockets (07) are listening.
+#[cfg(any(target_os = "linux", test))]
+fn parse_proc_net(content: &str, proto: ListenerProtocol) -> Vec<(Listener, u64)> {
+    let listen_state = match proto {
+        ListenerProtocol::Tcp => "0A",
+        ListenerProtocol::Udp => "07",
+    };
+    let mut ret = Vec::new();
+    // skip the header line
+    for line in content.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || fields[3] != listen_state {
+            continue;
+        }
+        let (addr, port) = match parse_proc_net_addr(fields[1]) {
+            Some(a) => a,
+            None => continue,
+        };
+        let inode: u64 = match fields[9].parse() {
+            Ok(i) => i,
+            Err(_) => continue,
+        };
+        let listener = Listener {
+            proto,
+            addr,
+            port,
+            pid: None,
+        };
+        ret.push((listener, inode));
+    }
+    ret
+}
+
+/// The owner process of every socket inode, the processes of the other users are skipped without the permission.
+#[cfg(target_os = "linux")]
+fn socket_inode_pids() -> HashMap<u64, u32> {
+    let mut ret = HashMap::new();
+    let procs = match fs::read_dir("/proc") {
+        Ok(p) => p,
+        Err(_) => return ret,
+    };
+    for entry in procs.flatten() {
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            if let Ok(link) = fs::read_link(fd.path()) {
+                // socket:[12345]
+                let link = link.to_string_lossy();
+                if let Some(inode) = link
+                    .strip_prefix("socket:[")
+                    .and_then(|l| l.strip_suffix(']'))
+                    .and_then(|i| i.parse().ok())
+                {
+                    ret.insert(inode, pid);
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// Split the `addr:port` or `addr.port` of netstat, `*` is the unspecified address.
+#[cfg(any(not(target_os = "linux"), test))]
+fn parse_netstat_addr(field: &str, sep: char, ipv6: bool) -> Option<(IpAddr, u16)> {
+    let (addr, port) = field.rsplit_once(sep)?;
+    let port = port.parse().ok()?;
+    let addr = addr.trim_start_matches('[').trim_end_matches(']');
+    // the zone of the link local address, e.g. fe80::1%lo0
+    let addr = addr.split('%').next()?;
+    let addr: IpAddr = match addr {
+        "*" if ipv6 => Ipv6Addr::UNSPECIFIED.into(),
+        "*" => Ipv4Addr::UNSPECIFIED.into(),
+        _ => addr.parse().ok()?,
+    };
+    Some((addr, port))
+}
+
+/// Parse the `netstat -an` of bsd and macos, e.g.
+/// `tcp4       0      0  127.0.0.1.631          *.*                    LISTEN`,
+/// netstat does not tell the owner process here.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    test
+))]
+fn parse_bsd_netstat(output: &str) -> Vec<Listener> {
+    let mut ret = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 5 {
+            continue;
+        }
+        let proto = match fields[0] {
+            p if p.starts_with("tcp") && fields.get(5) == Some(&"LISTEN") => ListenerProtocol::Tcp,
+            p if p.starts_with("udp") && fields[4] == "*.*" => ListenerProtocol::Udp,
+            _ => continue,
+        };
+        let ipv6 = fields[0].ends_with('6');
+        if let Some((addr, port)) = parse_netstat_addr(fields[3], '.', ipv6) {
+            ret.push(Listener {
+                proto,
+                addr,
+                port,
+                pid: None,
+            });
+        }
+    }
+    ret
+}
+
+/// Parse the `netstat -ano` of windows, e.g.
+/// `  TCP    0.0.0.0:135            0.0.0.0:0              LISTENING       1016`,
+/// the udp lines have no state.
+#[cfg(any(target_os = "windows", test))]
+fn parse_windows_netstat(output: &str) -> Vec<Listener> {
+    let mut ret = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (proto, pid) = match fields.as_slice() {
+            ["TCP", _, _, "LISTENING", pid] => (ListenerProtocol::Tcp, pid),
+            ["UDP", _, "*:*", pid] => (ListenerProtocol::Udp, pid),
+            _ => continue,
+        };
+        let ipv6 = fields[1].starts_with('[');
+        if let Some((addr, port)) = parse_netstat_addr(fields[1], ':', ipv6) {
+            ret.push(Listener {
+                proto,
+                addr,
+                port,
+                pid: pid.parse().ok(),
+            });
+        }
+    }
+    ret
+}
+
+/// The listening tcp and udp sockets of the local host, no packet is sent.
+#[cfg(target_os = "linux")]
+pub fn local_listeners() -> Result<Vec<Listener>, PistolErrors> {
+    let files = [
+        ("/proc/net/tcp", ListenerProtocol::Tcp),
+        ("/proc/net/tcp6", ListenerProtocol::Tcp),
+        ("/proc/net/udp", ListenerProtocol::Udp),
+        ("/proc/net/udp6", ListenerProtocol::Udp),
+    ];
+    let mut listeners = Vec::new();
+    for (path, proto) in files {
+        match fs::read_to_string(path) {
+            Ok(content) => listeners.extend(parse_proc_net(&content, proto)),
+            // the ipv6 stack may be disabled
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let pids = socket_inode_pids();
+    Ok(listeners
+        .into_iter()
+        .map(|(mut listener, inode)| {
+            listener.pid = pids.get(&inode).copied();
+            listener
+        })
+        .collect())
+}
+
+/// The listening tcp and udp sockets of the local host, no packet is sent.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+pub fn local_listeners() -> Result<Vec<Listener>, PistolErrors> {
+    let output = SystemCommandRunner.run("netstat -an")?;
+    Ok(parse_bsd_netstat(&output))
+}
+
+/// The listening tcp and udp sockets of the local host, no packet is sent.
+#[cfg(target_os = "windows")]
+pub fn local_listeners() -> Result<Vec<Listener>, PistolErrors> {
+    let output = SystemCommandRunner.run("netstat -ano")?;
+    Ok(parse_windows_netstat(&output))
+}
+
+/// The result of `self_test`, the failed steps are kept in `errors`.
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub interface: String,
+    /// The datalink channel (raw socket) can be opened on the interface.
+    pub raw_socket: bool,
+    /// The probe target, the default gateway of the interface or the loopback address.
+    pub target: IpAddr,
+    pub source_addr: Option<IpAddr>,
+    /// The mac address of the target, None on the loopback interface.
+    pub neighbor_mac: Option<MacAddr>,
+    /// The target answered the probe.
+    pub reply: bool,
+    pub rtt: Option<Duration>,
+    pub errors: Vec<String>,
+}
+
+impl SelfTestReport {
+    /// Every step passed, the interface is ready to scan.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty() && self.reply
+    }
+}
+
+/// Check the scan capability of the interface before a big scan,
+/// a probe is sent to the default gateway of the interface (the loopback address on the loopback interface),
+/// the raw socket, the source address selection and the neighbor resolution are checked on the way.
+pub fn self_test(iface: &str, timeout: Duration) -> Result<SelfTestReport, PistolErrors> {
+    let interface = match find_interface_by_name(iface) {
+        Some(i) => i,
+        None => return Err(PistolErrors::CanNotFoundInterface),
+    };
+    let raw_socket = raw_socket_available(&interface);
+    let mut errors = Vec::new();
+    if !raw_socket {
+        errors.push(String::from("can not open the raw socket on the interface"));
+    }
+
+    if interface.is_loopback() {
+        let target: IpAddr = match interface.ips.iter().any(|i| i.is_ipv4()) {
+            true => Ipv4Addr::LOCALHOST.into(),
+            false => Ipv6Addr::LOCALHOST.into(),
+        };
+        // the loopback probes go through the system sockets, the closed port also answers
+        let (reply, rtt) =
+            match tcp_syn_ping_raw(target, SYN_PING_DEFAULT_PORT, None, None, Some(timeout)) {
+                Ok((status, rtt)) => (status == PingStatus::Up, Some(rtt)),
+                Err(e) => {
+                    errors.push(format!("probe failed: {e}"));
+                    (false, None)
+                }
+            };
+        return Ok(SelfTestReport {
+            interface: interface.name,
+            raw_socket,
+            target,
+            source_addr: Some(target),
+            neighbor_mac: None,
+            reply,
+            rtt,
+            errors,
+        });
+    }
+
+    let default_route = [system_cache_default_route(), system_cache_default_route6()]
+        .into_iter()
+        .flatten()
+        .find(|r| r.dev.name == interface.name);
+    let target = match default_route {
+        Some(r) => r.via,
+        None => return Err(PistolErrors::CanNotFoundRouterAddress),
+    };
+    let source_addr = match target {
+        IpAddr::V4(via) => find_source_addr(None, via).map(|s| s.map(IpAddr::V4)),
+        IpAddr::V6(via) => find_source_addr6(None, via).map(|s| s.map(IpAddr::V6)),
+    };
+    let source_addr = match source_addr {
+        Ok(Some(s)) => Some(s),
+        Ok(None) => {
+            errors.push(String::from("can not found the source address"));
+            None
+        }
+        Err(e) => {
+            errors.push(format!("source address selection failed: {e}"));
+            None
+        }
+    };
+    let neighbor = match (source_addr, target) {
+        (Some(IpAddr::V4(src)), IpAddr::V4(via)) => Some(system_route(src, via, timeout)),
+        (Some(IpAddr::V6(src)), IpAddr::V6(via)) => Some(system_route6(src, via, timeout)),
+        _ => None,
+    };
+    let neighbor_mac = match neighbor {
+        Some(Ok((mac, _))) => Some(mac),
+        Some(Err(e)) => {
+            errors.push(format!("neighbor resolution failed: {e}"));
+            None
+        }
+        None => None,
+    };
+    let (reply, rtt) = match icmp_ping_raw(target, source_addr, Some(timeout)) {
+        Ok((status, rtt)) => (status == PingStatus::Up, Some(rtt)),
+        Err(e) => {
+            errors.push(format!("probe failed: {e}"));
+            (false, None)
+        }
+    };
+    Ok(SelfTestReport {
+        interface: interface.name,
+        raw_socket,
+        target,
+        source_addr,
+        neighbor_mac,
+        reply,
+        rtt,
+        errors,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
+    use std::str::FromStr;
+    #[test]
+    fn test_dispatch_probes() {
+        let scan_delay = Duration::from_millis(100);
+        let host_1: IpAddr = Ipv4Addr::new(192, 168, 1, 1).into();
+        let host_2: IpAddr = Ipv4Addr::new(192, 168, 1, 2).into();
+        let pool = get_threads_pool(2);
+        let (tx, rx) = channel();
+        let mut probes: Vec<(IpAddr, ProbeJob)> = Vec::new();
+        for i in 0..3 {
+            for host in [host_1, host_2] {
+                let tx = tx.clone();
+                probes.push((
+                    host,
+                    Box::new(move || tx.send((host, i, Instant::now())).unwrap()),
+                ));
+            }
+        }
+        let start = Instant::now();
+        dispatch_probes(&pool, probes, Some(scan_delay), None);
+        let mut sent: HashMap<IpAddr, Vec<(usize, Instant)>> = HashMap::new();
+        for (host, i, t) in rx.iter().take(6) {
+            sent.entry(host).or_default().push((i, t));
+        }
+        for host in [host_1, host_2] {
+            let times = &sent[&host];
+            // in order, and the first probe of every host is not delayed by the other host
+            assert_eq!(
+                times.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+                vec![0, 1, 2]
+            );
+            assert!(times[0].1 - start < scan_delay);
+            for w in times.windows(2) {
+                assert!(w[1].1 - w[0].1 >= scan_delay - Duration::from_millis(5));
+            }
+        }
+
+        // the probes queued behind a busy pool are still sent apart
+        let blocker_host: IpAddr = Ipv4Addr::new(192, 168, 1, 0).into();
+        let pool = get_threads_pool(1);
+        let (tx, rx) = channel();
+        let mut probes: Vec<(IpAddr, ProbeJob)> = vec![(
+            blocker_host,
+            Box::new(|| std::thread::sleep(Duration::from_millis(300))),
+        )];
+        for _ in 0..3 {
+            let tx = tx.clone();
+            probes.push((host_1, Box::new(move || tx.send(Instant::now()).unwrap())));
+        }
+        dispatch_probes(&pool, probes, Some(scan_delay), None);
+        let times: Vec<Instant> = rx.iter().take(3).collect();
+        for w in times.windows(2) {
+            assert!(w[1] - w[0] >= scan_delay - Duration::from_millis(5));
+        }
+    }
+    #[test]
+    fn test_timing_template() {
+        let probes = 65535;
+        let paranoid = TimingTemplate::T0.threads_num(probes);
+        let insane = TimingTemplate::T5.threads_num(probes);
+        assert_eq!(paranoid, 1);
+        assert!(insane >= paranoid * 1000);
+        assert_eq!(TimingTemplate::T5.threads_num(10), 10);
+        assert!(TimingTemplate::T0.scan_delay() > TimingTemplate::T2.scan_delay());
+        assert_eq!(TimingTemplate::T3.scan_delay(), None);
+        assert_eq!(TimingTemplate::T3.timeout(), None);
+        assert!(TimingTemplate::T5.timeout() < TimingTemplate::T4.timeout());
+        assert!(TimingTemplate::T0.max_retries() > TimingTemplate::T5.max_retries());
+    }
+    #[test]
+    fn test_self_test() {
+        let lo = interfaces().into_iter().find(|i| i.is_loopback()).unwrap();
+        let report = self_test(&lo.name, Duration::new(1, 0)).unwrap();
+        assert!(report.target.is_loopback());
+        assert!(report.reply);
+        assert!(report.rtt.is_some());
+        assert_eq!(report.neighbor_mac, None);
+    }
+    #[test]
+    fn test_local_listeners() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_port = tcp.local_addr().unwrap().port();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_port = udp.local_addr().unwrap().port();
+        let listeners = local_listeners().unwrap();
+        let found = |proto, port| {
+            listeners
+                .iter()
+                .find(|l| l.proto == proto && l.port == port)
+                .cloned()
+        };
+        let l = found(ListenerProtocol::Tcp, tcp_port).unwrap();
+        assert_eq!(l.addr, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        #[cfg(any(target_os = "linux", target_os = "windows"))]
+        assert_eq!(l.pid, Some(std::process::id()));
+        assert!(found(ListenerProtocol::Udp, udp_port).is_some());
+    }
+    #[test]
+    fn test_merge_windows_ipv6() {
+        let output = "\
+12|Ethernet|fe80::1c2d:3e4f:5a6b:7c8d%12|64
+12|Ethernet|2001:db8::5|64
+1|Loopback Pseudo-Interface 1|::1|128
+";
+        let addrs = parse_windows_ipv6_addrs(output);
+        assert_eq!(addrs.len(), 3);
+        assert_eq!(addrs[0].if_index, 12);
+        assert_eq!(
+            addrs[0].ipnetwork,
+            IpNetwork::from_str("fe80::1c2d:3e4f:5a6b:7c8d/64").unwrap()
+        );
+        assert_eq!(addrs[2].alias, "Loopback Pseudo-Interface 1");
+
+        // pnet only knows the ipv4 address of the ethernet
+        let ethernet = NetworkInterface {
+            name: String::from("{5E2B4C1A-0000-0000-0000-000000000000}"),
+            description: String::from("Ethernet"),
+            index: 12,
+            mac: Some(MacAddr::new(0x00, 0x0c, 0x29, 0x5f, 0x4a, 0x6e)),
+            ips: vec![IpNetwork::from_str("192.168.1.5/24").unwrap()],
+            flags: 0,
+        };
+        let interfaces = merge_windows_ipv6(vec![ethernet], &addrs);
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(interfaces[0].ips.len(), 3);
+        assert_eq!(interfaces[1].index, 1);
+        assert_eq!(interfaces[1].ips[0].ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+    }
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn test_windows_ipv6_loopback() {
+        let found = interfaces().iter().any(|i| {
+            i.ips
+                .iter()
+                .any(|n| n.ip() == IpAddr::V6(Ipv6Addr::LOCALHOST))
+        });
+        assert!(found);
+    }
+    #[test]
+    fn test_parse_netstat() {
+        let output = "\
+Active Internet connections (including servers)
+Proto Recv-Q Send-Q  Local Address          Foreign Address        (state)
+tcp4       0      0  127.0.0.1.631          *.*                    LISTEN
+tcp6       0      0  *.22                   *.*                    LISTEN
+tcp4       0      0  192.168.1.5.50123      17.57.146.20.443       ESTABLISHED
+udp4       0      0  *.5353                 *.*
+udp6       0      0  fe80::1%lo0.123        *.*";
+        let listeners = parse_bsd_netstat(output);
+        assert_eq!(listeners.len(), 4);
+        assert_eq!(listeners[0].addr, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(listeners[0].port, 631);
+        assert_eq!(listeners[1].addr, IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(listeners[2].proto, ListenerProtocol::Udp);
+        assert_eq!(listeners[3].addr, "fe80::1".parse::<IpAddr>().unwrap());
+
+        let output = "\
+Active Connections
+
+  Proto  Local Address          Foreign Address        State           PID
+  TCP    0.0.0.0:135            0.0.0.0:0              LISTENING       1016
+  TCP    10.0.0.5:50123         20.42.65.92:443        ESTABLISHED     4312
+  TCP    [::]:445               [::]:0                 LISTENING       4
+  UDP    0.0.0.0:123            *:*                                    1580";
+        let listeners = parse_windows_netstat(output);
+        assert_eq!(listeners.len(), 3);
+        assert_eq!(listeners[0].port, 135);
+        assert_eq!(listeners[0].pid, Some(1016));
+        assert_eq!(listeners[1].addr, IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(listeners[2].proto, ListenerProtocol::Udp);
+
+        let content = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0277 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 24512 1 0000000000000000 100 0 0 10 0
+   1: 0500A8C0:C3CB 145C2A14:01BB 01 00000000:00000000 02:0000021C 00000000  1000        0 51234 2 0000000000000000 20 4 30 10 -1";
+        let listeners = parse_proc_net(content, ListenerProtocol::Tcp);
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].1, 24512);
+        #[cfg(target_endian = "little")]
+        assert_eq!(listeners[0].0.addr, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(listeners[0].0.port, 631);
+    }
+    #[test]
+    fn test_select_source_addr6() {
+        let content = "\
+20010db800000010c5a1b2c3d4e5f607 02 40 00 01     eth0
+20010db800000010021122fffe334455 02 40 00 00     eth0
+20010db800000010a1b2c3d4e5f60718 02 40 00 21     eth0
+fe80000000000000021122fffe334455 02 40 20 80     eth0";
+        let flags = parse_if_inet6(content);
+        assert_eq!(flags.len(), 4);
+        let temporary: Ipv6Addr = "2001:db8:0:10:c5a1:b2c3:d4e5:f607".parse().unwrap();
+        let stable: Ipv6Addr = "2001:db8:0:10:211:22ff:fe33:4455".parse().unwrap();
+        let expiring: Ipv6Addr = "2001:db8:0:10:a1b2:c3d4:e5f6:718".parse().unwrap();
+        assert_eq!(flags[&temporary], IFA_F_TEMPORARY);
+
+        let dst: Ipv6Addr = "2001:db8:0:10::1".parse().unwrap();
+        let candidates = vec![expiring, temporary, stable];
+        assert_eq!(
+            select_source_addr6(dst, &candidates, &flags, false),
+            Some(stable)
+        );
+        assert_eq!(
+            select_source_addr6(dst, &candidates, &flags, true),
+            Some(temporary)
+        );
+        // the expiring temporary address is used only if nothing else is left
+        assert_eq!(
+            select_source_addr6(dst, &[expiring, stable], &flags, true),
+            Some(stable)
+        );
+        assert_eq!(
+            select_source_addr6(dst, &[expiring], &flags, false),
+            Some(expiring)
+        );
+        // nothing is known about the flags, keep the order
+        assert_eq!(
+            select_source_addr6(dst, &candidates, &HashMap::new(), false),
+            Some(expiring)
+        );
+        assert_eq!(select_source_addr6(dst, &[], &flags, false), None);
+    }
+    #[test]
+    fn test_select_source_addr6_rfc6724() {
+        let flags = HashMap::new();
+        let link_local: Ipv6Addr = "fe80::211:22ff:fe33:4455".parse().unwrap();
+        let site_local: Ipv6Addr = "fec0::5".parse().unwrap();
+        let global: Ipv6Addr = "2001:db8:0:10::5".parse().unwrap();
+        let global_far: Ipv6Addr = "2001:db8:ff:20::5".parse().unwrap();
+        let ula: Ipv6Addr = "fd00:1:2:3::5".parse().unwrap();
+
+        // scope match: the link-local destination gets the link-local source
+        let dst: Ipv6Addr = "fe80::1".parse().unwrap();
+        let candidates = vec![global, site_local, link_local];
+        assert_eq!(
+            select_source_addr6(dst, &candidates, &flags, false),
+            Some(link_local)
+        );
+        // the global destination never gets a smaller scope if a global one exists
+        let dst: Ipv6Addr = "2001:db8:0:10::1".parse().unwrap();
+        assert_eq!(
+            select_source_addr6(dst, &[link_local, site_local, global], &flags, false),
+            Some(global)
+        );
+        // without a large enough scope, the largest one is the best
+        assert_eq!(
+            select_source_addr6(dst, &[link_local, site_local], &flags, false),
+            Some(site_local)
+        );
+
+        // longest prefix: the source in the subnet of the destination
+        let candidates = vec![ula, global_far, global];
+        assert_eq!(
+            select_source_addr6(dst, &candidates, &flags, false),
+            Some(global)
+        );
+        let dst: Ipv6Addr = "fd00:1:2:3::1".parse().unwrap();
+        assert_eq!(
+            select_source_addr6(dst, &candidates, &flags, false),
+            Some(ula)
+        );
+        // the bits after the subnet prefix do not count
+        let dst: Ipv6Addr = "2001:db8:0:10::4".parse().unwrap();
+        let near: Ipv6Addr = "2001:db8:0:10::1:5".parse().unwrap();
+        assert_eq!(
+            select_source_addr6(dst, &[near, global], &flags, false),
+            Some(near)
+        );
+        // the destination address itself goes first
+        assert_eq!(
+            select_source_addr6(global, &[near, global], &flags, false),
+            Some(global)
+        );
+    }
+    #[test]
+    fn test_default_timeout_for() {
+        let wired = get_default_timeout_for("eth0");
+        assert_eq!(wired, get_default_timeout());
+        assert_eq!(get_default_timeout_for("enp3s0"), wired);
+        for wireless in ["wlan0", "wlp2s0", "Wi-Fi"] {
+            assert!(get_default_timeout_for(wireless) > wired);
+        }
+        assert!(get_default_timeout_for("wwan0") > get_default_timeout_for("wlan0"));
+    }
+    #[test]
+    fn test_random_port_in() {
+        let range = 40000..=40010;
+        for _ in 0..100 {
+            let port = random_port_in(&range).unwrap();
+            assert!(range.contains(&port));
+        }
+        assert_eq!(random_port_in(&(5353..=5353)).unwrap(), 5353);
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty = 40010..=40000;
+        assert!(matches!(
+            random_port_in(&empty),
+            Err(PistolErrors::InvalidPortRange { .. })
+        ));
+        assert!(random_port_in(&(0..=100)).is_err());
+    }
+    #[test]
+    fn test_cached_source_addr() {
+        // TEST-NET-2, behind the same route (the default one if nothing else)
+        let dst_1: IpAddr = "198.51.100.10".parse().unwrap();
+        let dst_2: IpAddr = "198.51.100.20".parse().unwrap();
+        let src_addr: IpAddr = "198.51.100.1".parse().unwrap();
+        let lookups = Cell::new(0);
+        let lookup = || {
+            lookups.set(lookups.get() + 1);
+            Ok(Some(src_addr))
+        };
+        // the route may be looked up by the other tests already
+        let src_1 = cached_source_addr(dst_1, false, lookup).unwrap();
+        let src_2 = cached_source_addr(dst_2, false, lookup).unwrap();
+        assert!(src_1.is_some());
+        assert_eq!(src_1, src_2);
+        assert!(lookups.get() <= 1);
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_interface_mtu() {
+        let lo = find_interface_by_name("lo").unwrap();
+        let mtu = interface_mtu(&lo).unwrap();
+        assert!(mtu > 0);
+    }
     #[test]
     fn test_convert() {
         let v: Vec<u8> = vec![1, 1];
@@ -1,3 +1,6 @@
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Utc;
 use log::debug;
 use prettytable::row;
 use prettytable::Cell;
@@ -7,9 +10,12 @@ use serde::Deserialize;
 use serde::Serialize;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::net::IpAddr;
+use std::net::Ipv6Addr;
 use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -20,7 +26,15 @@ use crate::vs::dbparser::nsp_exclued_parser;
 use crate::vs::dbparser::nsp_parser;
 use crate::vs::dbparser::ExcludePorts;
 use crate::vs::dbparser::Match;
+use crate::vs::dbparser::MatchResult;
+use crate::vs::dbparser::ProbeDb;
+use crate::vs::dbparser::ProbesProtocol;
+use crate::vs::vscan::collect_responses;
+use crate::vs::vscan::host_vs_probe;
+use crate::vs::vscan::link_local_scope;
 use crate::vs::vscan::threads_vs_probe;
+use crate::vs::vscan::VsHost;
+use crate::Host;
 use crate::Target;
 
 pub mod dbparser;
@@ -30,6 +44,14 @@ pub mod vscan;
 pub struct Services {
     pub matchs: Vec<Match>,
     pub elapsed: Duration,
+    /// The detection stopped at the max probes limit, some probes were not tried.
+    pub truncated: bool,
+    /// The tcp connection to the port was accepted.
+    pub tcp_open: bool,
+    /// The port answered a udp probe.
+    pub udp_open: bool,
+    /// The tcp connection to the port was refused (reset), the port is closed.
+    pub tcp_closed: bool,
 }
 
 impl Services {
@@ -37,8 +59,28 @@ impl Services {
         Services {
             matchs: Vec::new(),
             elapsed: Duration::new(0, 0),
+            truncated: false,
+            tcp_open: false,
+            udp_open: false,
+            tcp_closed: false,
         }
     }
+    /// The best match of the port, see `MatchResult::best`.
+    pub fn best(&self) -> Option<MatchResult> {
+        MatchResult::best(&self.matchs)
+    }
+    /// All the cpe names of the matched services, without duplicates.
+    pub fn cpes(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+        for m in &self.matchs {
+            for c in &m.cpe {
+                if !ret.contains(c) {
+                    ret.push(c.clone());
+                }
+            }
+        }
+        ret
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -61,6 +103,19 @@ impl VsScanResults {
     pub fn get(&self, k: &IpAddr) -> Option<&HashMap<u16, Services>> {
         self.vss.get(k)
     }
+    /// Returns the detection time of each port, slowest first, at most `n` items.
+    /// Useful for tuning the intensity and timeout.
+    pub fn slowest(&self, n: usize) -> Vec<(IpAddr, u16, Duration)> {
+        let mut elapsed = Vec::new();
+        for (ip, h) in &self.vss {
+            for (port, s) in h {
+                elapsed.push((*ip, *port, s.elapsed));
+            }
+        }
+        elapsed.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)).then(a.1.cmp(&b.1)));
+        elapsed.truncate(n);
+        elapsed
+    }
     pub fn enrichment(&mut self) {
         self.total_time_cost = self.start_time.elapsed().as_secs_f64();
         let mut total_time = 0.0;
@@ -98,7 +153,9 @@ impl fmt::Display for VsScanResults {
                     }
                 }
                 let mut services_str = sv.join(",");
-                if services_str.trim().len() == 0 {
+                if services.tcp_closed {
+                    services_str = String::from("closed");
+                } else if services_str.trim().is_empty() {
                     services_str = String::from("closed|nomatch");
                 }
                 table.add_row(row![c -> i, c -> ip, c -> port, c -> services_str]);
@@ -115,6 +172,95 @@ impl fmt::Display for VsScanResults {
     }
 }
 
+/// How the NULL probe reads the banner of the service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NullProbeRead {
+    /// Return at the first data, most banners arrive in one packet.
+    FirstData,
+    /// Read until the service closes the connection or the read times out.
+    #[default]
+    Listen,
+}
+
+/// Like nmap, the banner and the responses of the probes are waited for five seconds by default.
+const VS_READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// The service which streams data forever (or a hostile target) can not use up the memory.
+const MAX_BANNER_SIZE: usize = 64 * 1024;
+/// The retransmissions of an unanswered udp probe.
+const UDP_RETRIES: usize = 1;
+
+/// The settings of `vs_scan_with_options` and `vs_scan_raw_with_options`.
+#[derive(Debug, Clone)]
+pub struct VsOptions {
+    /// Only try the NULL probe (for tcp), the banner the service sends on connect.
+    pub only_null_probe: bool,
+    /// Only try the tcp probes recommended for the port.
+    pub only_tcp_recommended: bool,
+    /// Only try the udp probes recommended for the port.
+    pub only_udp_recommended: bool,
+    /// Only send the recommended probe of the lowest rarity per protocol, the fastest first guess.
+    pub only_best_probe: bool,
+    /// Send every probe on a new connection (slower, for the services which only answer once).
+    pub reconnect_per_probe: bool,
+    /// The ports not probed, None for the `Exclude` directive of the database.
+    pub exclude_ports: Option<ExcludePorts>,
+    /// The version intensity, the probes rarer than it are not sent.
+    pub intensity: usize,
+    /// Skip the probes rarer than this even if the intensity allows them.
+    pub max_rarity: Option<usize>,
+    /// Stop the probes of one port after this number.
+    pub max_probes_per_port: Option<usize>,
+    /// Only match the first bytes of every response.
+    pub match_bytes: Option<usize>,
+    pub null_probe_read: NullProbeRead,
+    /// The timeout of the tcp connections, a short one skips the dead ports fast.
+    pub connect_timeout: Option<Duration>,
+    /// The wait for the banner and the responses, a long one catches the slow services.
+    /// None means five seconds like nmap.
+    pub read_timeout: Option<Duration>,
+    /// The max size of the banner and the response read from a tcp service,
+    /// the reading stops there and the matching goes on with the data read.
+    pub max_banner_size: usize,
+    /// The retransmissions of an unanswered udp probe, 0 disables them.
+    /// Every retransmission waits the read timeout again, so each probe which gets no response
+    /// (e.g. on an open|filtered port) costs (udp_retries + 1) read timeouts.
+    pub udp_retries: usize,
+}
+
+impl Default for VsOptions {
+    fn default() -> VsOptions {
+        VsOptions {
+            only_null_probe: false,
+            only_tcp_recommended: false,
+            only_udp_recommended: false,
+            only_best_probe: false,
+            reconnect_per_probe: false,
+            exclude_ports: None,
+            intensity: FINGERPRINT_INTENSITY,
+            max_rarity: None,
+            max_probes_per_port: None,
+            match_bytes: None,
+            null_probe_read: NullProbeRead::default(),
+            connect_timeout: None,
+            read_timeout: None,
+            max_banner_size: MAX_BANNER_SIZE,
+            udp_retries: UDP_RETRIES,
+        }
+    }
+}
+
+impl VsOptions {
+    /// The timeouts with the defaults filled in, (connect timeout, read timeout).
+    fn timeouts(&self) -> (Duration, Duration) {
+        let connect_timeout = match self.connect_timeout {
+            Some(t) => t,
+            None => get_default_timeout(),
+        };
+        let read_timeout = self.read_timeout.unwrap_or(VS_READ_TIMEOUT);
+        (connect_timeout, read_timeout)
+    }
+}
+
 /// Detect target port service.
 pub fn vs_scan(
     target: Target,
@@ -125,15 +271,73 @@ pub fn vs_scan(
     intensity: usize,
     timeout: Option<Duration>,
 ) -> Result<VsScanResults, PistolErrors> {
-    let mut threads_num = 0;
-    for h in &target.hosts {
-        threads_num += h.ports.len();
-    }
+    let options = VsOptions {
+        only_null_probe,
+        only_tcp_recommended,
+        only_udp_recommended,
+        exclude_ports,
+        intensity,
+        connect_timeout: timeout,
+        ..Default::default()
+    };
+    vs_scan_with_options(target, &options)
+}
 
-    let timeout = match timeout {
+/// The `vs_scan` with the `options`, see `VsOptions`.
+pub fn vs_scan_with_options(
+    target: Target,
+    options: &VsOptions,
+) -> Result<VsScanResults, PistolErrors> {
+    run_vs_scan(target, None, options)
+}
+
+/// The fast service detection which only grabs the banner the service sends on connect (the NULL probe),
+/// no probe is sent to the port. The services which wait for the client (e.g. http) get no match.
+/// The `threads_num` 0 means the number of cpus.
+/// ```rust
+/// use pistol::vs_scan_banner_only;
+/// use pistol::Host;
+/// use pistol::Target;
+/// use std::net::Ipv4Addr;
+/// use std::time::Duration;
+///
+/// fn test() {
+///     let host = Host::new(Ipv4Addr::new(192, 168, 5, 5).into(), Some(vec![21, 22, 25]));
+///     let target = Target::new(vec![host]);
+///     let ret = vs_scan_banner_only(target, 8, Some(Duration::new(1, 0))).unwrap();
+///     println!("{}", ret);
+/// }
+/// ```
+pub fn vs_scan_banner_only(
+    target: Target,
+    threads_num: usize,
+    timeout: Option<Duration>,
+) -> Result<VsScanResults, PistolErrors> {
+    let options = VsOptions {
+        only_null_probe: true,
+        null_probe_read: NullProbeRead::FirstData,
+        connect_timeout: timeout,
+        ..Default::default()
+    };
+    run_vs_scan(target, Some(threads_num), &options)
+}
+
+/// The `vs_scan` with the number of threads, None means one thread per port.
+fn run_vs_scan(
+    mut target: Target,
+    threads_num: Option<usize>,
+    options: &VsOptions,
+) -> Result<VsScanResults, PistolErrors> {
+    target.resolve_all(options.connect_timeout)?;
+    let threads_num = match threads_num {
         Some(t) => t,
-        None => get_default_timeout(),
+        None => target
+            .hosts
+            .iter()
+            .map(|h| h.ports.len() + h.known_open.len())
+            .sum(),
     };
+
     let nsp_str = include_str!("./db/nmap-service-probes");
     let mut nsp_lines = Vec::new();
     for l in nsp_str.lines() {
@@ -143,37 +347,28 @@ pub fn vs_scan(
 
     let pool = get_threads_pool(threads_num);
     let (tx, rx) = channel();
-    let mut vs_target = HashMap::new();
+    let vs_target = vs_hosts(target.hosts, link_local_scope)?;
 
-    for h in target.hosts {
-        vs_target.insert(h.addr, h.ports);
-    }
-
-    let exclude_ports = match exclude_ports {
-        Some(e) => e,
+    let exclude_ports = match &options.exclude_ports {
+        Some(e) => e.clone(),
         None => nsp_exclued_parser(&nsp_lines)?,
     };
-    let service_probes = nsp_parser(&nsp_lines)?;
+    // shared by all the ports, the patterns are compiled once
+    let service_probes = Arc::new(nsp_parser(&nsp_lines)?);
+    let options = Arc::new(options.clone());
     debug!("nmap service db parse finish");
 
     let mut recv_size = 0;
-    for (dst_addr, ports) in vs_target {
+    for (host, ports) in vs_target {
+        let dst_addr = host.addr();
         for dst_port in ports {
             // Nmap checks to see if the port is one of the ports to be excluded.
             if !exclude_ports.ports.contains(&dst_port) {
                 let tx = tx.clone();
                 let service_probes = service_probes.clone();
+                let options = options.clone();
                 pool.execute(move || {
-                    let ret = threads_vs_probe(
-                        dst_addr,
-                        dst_port,
-                        only_null_probe,
-                        only_tcp_recommended,
-                        only_udp_recommended,
-                        intensity,
-                        &service_probes,
-                        timeout,
-                    );
+                    let ret = host_vs_probe(&host, dst_port, &service_probes, &options);
                     match tx.send((dst_addr, dst_port, ret)) {
                         _ => (),
                     }
@@ -187,21 +382,16 @@ pub fn vs_scan(
     let rx = rx.into_iter().take(recv_size);
     for (addr, port, r) in rx {
         match r {
-            Ok((r, rtt)) => {
-                let mut service_status = Services::new();
-                service_status.matchs = r;
-                service_status.elapsed = rtt;
-                match ret.vss.get_mut(&addr) {
-                    Some(services) => {
-                        services.insert(port, service_status);
-                    }
-                    None => {
-                        let mut host = HashMap::new();
-                        host.insert(port, service_status);
-                        ret.vss.insert(addr, host);
-                    }
+            Ok(service_status) => match ret.vss.get_mut(&addr) {
+                Some(services) => {
+                    services.insert(port, service_status);
                 }
-            }
+                None => {
+                    let mut host = HashMap::new();
+                    host.insert(port, service_status);
+                    ret.vss.insert(addr, host);
+                }
+            },
             Err(e) => return Err(e),
         }
     }
@@ -209,6 +399,36 @@ pub fn vs_scan(
     Ok(ret)
 }
 
+/// Group the ports by host, the known open ports go straight to the service detection.
+/// The setup of a host (the `scope_lookup` of the link-local target) is done once and shared by its ports.
+fn vs_hosts(
+    hosts: Vec<Host>,
+    mut scope_lookup: impl FnMut(Ipv6Addr) -> Result<Option<u32>, PistolErrors>,
+) -> Result<Vec<(VsHost, Vec<u16>)>, PistolErrors> {
+    let mut ret: Vec<(VsHost, Vec<u16>)> = Vec::new();
+    // the index of the host in ret and the ports of it already pushed, the hosts keep their order
+    let mut index: HashMap<IpAddr, usize> = HashMap::new();
+    let mut seen: Vec<HashSet<u16>> = Vec::new();
+    for h in hosts {
+        let i = match index.get(&h.addr) {
+            Some(i) => *i,
+            None => {
+                let host = VsHost::new_with(h.addr, &mut scope_lookup)?;
+                ret.push((host, Vec::new()));
+                seen.push(HashSet::new());
+                index.insert(h.addr, ret.len() - 1);
+                ret.len() - 1
+            }
+        };
+        for p in h.ports.into_iter().chain(h.known_open) {
+            if seen[i].insert(p) {
+                ret[i].1.push(p);
+            }
+        }
+    }
+    Ok(ret)
+}
+
 pub fn vs_scan_raw(
     dst_addr: IpAddr,
     dst_port: u16,
@@ -217,6 +437,23 @@ pub fn vs_scan_raw(
     only_udp_recommended: bool,
     intensity: usize,
     timeout: Option<Duration>,
+) -> Result<Services, PistolErrors> {
+    let options = VsOptions {
+        only_null_probe,
+        only_tcp_recommended,
+        only_udp_recommended,
+        intensity,
+        connect_timeout: timeout,
+        ..Default::default()
+    };
+    vs_scan_raw_with_options(dst_addr, dst_port, &options)
+}
+
+/// The `vs_scan_raw` with the `options`, see `VsOptions`.
+pub fn vs_scan_raw_with_options(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    options: &VsOptions,
 ) -> Result<Services, PistolErrors> {
     let nsp_str = include_str!("./db/nmap-service-probes");
     let mut nsp_lines = Vec::new();
@@ -228,37 +465,433 @@ pub fn vs_scan_raw(
     let service_probes = nsp_parser(&nsp_lines)?;
     debug!("nmap service db parse finish");
 
+    threads_vs_probe(dst_addr, dst_port, &service_probes, options)
+}
+
+/// The default version intensity of nmap.
+const FINGERPRINT_INTENSITY: usize = 7;
+/// The lines of the fingerprint are wrapped like nmap does.
+const FINGERPRINT_LINE_WIDTH: usize = 80;
+
+/// Escape the response like nmap, the regex special characters are escaped with `\`,
+/// and the space and the unprintable bytes are written as `\xHH`.
+fn fingerprint_escape(response: &[u8]) -> String {
+    let mut ret = String::new();
+    for &b in response {
+        match b {
+            b'\r' => ret += "\\r",
+            b'\n' => ret += "\\n",
+            b'\t' => ret += "\\t",
+            0 => ret += "\\0",
+            b'\\' | b'"' | b'.' | b'*' | b'+' | b'?' | b'(' | b')' | b'[' | b']' | b'{' | b'}'
+            | b'^' | b'$' | b'|' => {
+                ret.push('\\');
+                ret.push(b as char);
+            }
+            0x21..=0x7e => ret.push(b as char),
+            _ => ret += &format!("\\x{:02x}", b),
+        }
+    }
+    ret
+}
+
+/// The nmap format fingerprint (the `SF-Port` block) of the responses for the submission of new services.
+pub fn service_fingerprint(
+    dst_port: u16,
+    protocol: ProbesProtocol,
+    responses: &[(String, Vec<u8>)],
+    time: DateTime<Utc>,
+) -> String {
+    let proto = match protocol {
+        ProbesProtocol::Tcp => "TCP",
+        ProbesProtocol::Udp => "UDP",
+    };
+    let mut fp = format!(
+        "SF-Port{}-{}:V={}%I={}%D={}/{}%Time={:X}%P={}-{}",
+        dst_port,
+        proto,
+        env!("CARGO_PKG_VERSION"),
+        FINGERPRINT_INTENSITY,
+        time.month(),
+        time.day(),
+        time.timestamp(),
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+    );
+    for (probename, response) in responses {
+        fp += &format!(
+            "%r({},{:X},\"{}\")",
+            probename,
+            response.len(),
+            fingerprint_escape(response)
+        );
+    }
+    fp.push(';');
+
+    // the first line keeps the "SF-Port" prefix, the others start with "SF:"
+    let chars: Vec<char> = fp.chars().collect();
+    let (first, rest) = chars.split_at(FINGERPRINT_LINE_WIDTH.min(chars.len()));
+    let mut lines = vec![first.iter().collect::<String>()];
+    for chunk in rest.chunks(FINGERPRINT_LINE_WIDTH - 3) {
+        lines.push(format!("SF:{}", chunk.iter().collect::<String>()));
+    }
+    lines.join("\n")
+}
+
+/// Probe the service and return its nmap format fingerprint when no probe matches it,
+/// which can be submitted to nmap to add the service to the database.
+pub fn fingerprint_unknown(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    protocol: ProbesProtocol,
+    timeout: Option<Duration>,
+) -> Result<String, PistolErrors> {
     let timeout = match timeout {
         Some(t) => t,
         None => get_default_timeout(),
     };
-
-    match threads_vs_probe(
+    let db = ProbeDb::builtin()?;
+    let (responses, matches) = collect_responses(
         dst_addr,
         dst_port,
-        only_null_probe,
-        only_tcp_recommended,
-        only_udp_recommended,
-        intensity,
-        &service_probes,
+        protocol,
+        FINGERPRINT_INTENSITY,
+        &db.service_probes,
         timeout,
-    ) {
-        Ok((r, rtt)) => {
-            let mut service_status = Services::new();
-            service_status.matchs = r;
-            service_status.elapsed = rtt;
-            Ok(service_status)
+        MAX_BANNER_SIZE,
+    )?;
+    if let Some(m) = matches.iter().find(|m| m.class == "match") {
+        return Err(PistolErrors::ServiceIdentified {
+            service: m.service.clone(),
+        });
+    }
+    if responses.is_empty() {
+        return Err(PistolErrors::NoServiceResponse);
+    }
+    Ok(service_fingerprint(
+        dst_port,
+        protocol,
+        &responses,
+        Utc::now(),
+    ))
+}
+
+/// The options of `identify_service`.
+#[derive(Debug, Clone)]
+pub struct IdentifyOptions {
+    /// The version intensity, the probes rarer than it are not sent.
+    pub intensity: usize,
+    /// The timeout of each probe, None for the default one.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for IdentifyOptions {
+    fn default() -> IdentifyOptions {
+        IdentifyOptions {
+            intensity: FINGERPRINT_INTENSITY,
+            timeout: None,
         }
-        Err(e) => Err(e),
     }
 }
 
+/// The service found on one port by `identify_service`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServiceIdentification {
+    /// The service name, None if no probe matched the responses.
+    pub service: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    /// The response the service was identified by, or the first response if no probe matched.
+    pub banner: Vec<u8>,
+    /// The probe which got the banner, `NULL` if the service sent it without any probe.
+    pub probe_name: String,
+    pub elapsed: Duration,
+}
+
+/// Identify the service of one port, every probe is sent on a new connection and the probes stop at the first hard match.
+/// ```rust
+/// use pistol::vs::identify_service;
+/// use pistol::vs::dbparser::ProbesProtocol;
+/// use std::io::Read;
+/// use std::io::Write;
+/// use std::net::TcpListener;
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// // a local http server which answers the GET requests
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let addr = listener.local_addr().unwrap();
+/// thread::spawn(move || {
+///     for stream in listener.incoming() {
+///         let mut stream = stream.unwrap();
+///         thread::spawn(move || {
+///             let mut buff = [0u8; 1024];
+///             if let Ok(n) = stream.read(&mut buff) {
+///                 if buff[..n].starts_with(b"GET ") {
+///                     let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nServer: Apache/2.4.41 (Ubuntu)\r\n\r\n");
+///                 }
+///             }
+///         });
+///     }
+/// });
+///
+/// let mut options = pistol::vs::IdentifyOptions::default();
+/// options.timeout = Some(Duration::from_millis(500));
+/// let ret = identify_service(addr.ip(), addr.port(), ProbesProtocol::Tcp, Some(options)).unwrap();
+/// assert_eq!(ret.service.as_deref(), Some("http"));
+/// assert_eq!(ret.product.as_deref(), Some("Apache httpd"));
+/// assert_eq!(ret.version.as_deref(), Some("2.4.41"));
+/// assert!(ret.banner.starts_with(b"HTTP/1.1 200 OK"));
+/// ```
+pub fn identify_service(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    protocol: ProbesProtocol,
+    options: Option<IdentifyOptions>,
+) -> Result<ServiceIdentification, PistolErrors> {
+    let start_time = Instant::now();
+    let options = options.unwrap_or_default();
+    let timeout = match options.timeout {
+        Some(t) => t,
+        None => get_default_timeout(),
+    };
+    let db = ProbeDb::builtin()?;
+    let (responses, matches) = collect_responses(
+        dst_addr,
+        dst_port,
+        protocol,
+        options.intensity,
+        &db.service_probes,
+        timeout,
+        MAX_BANNER_SIZE,
+    )?;
+    let best = MatchResult::best(&matches);
+    let response = match &best {
+        Some(m) => responses.iter().find(|(name, _)| *name == m.probe_name),
+        None => responses.first(),
+    };
+    let (probe_name, banner) = match response {
+        Some(r) => r.clone(),
+        None => return Err(PistolErrors::NoServiceResponse),
+    };
+    Ok(ServiceIdentification {
+        service: best.as_ref().map(|m| m.service.clone()),
+        product: best.as_ref().and_then(|m| m.product()),
+        version: best.as_ref().and_then(|m| m.version()),
+        banner,
+        probe_name,
+        elapsed: start_time.elapsed(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::Host;
     // use crate::Logger;
     use crate::TEST_IPV4_LOCAL;
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+    use std::net::Shutdown;
+    use std::net::SocketAddr;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+    #[test]
+    fn test_service_fingerprint() {
+        use chrono::TimeZone;
+        let responses = vec![
+            ("NULL".to_string(), b"HELLO 1.0\r\n".to_vec()),
+            ("GetRequest".to_string(), vec![0xff, 0x00, b'"', b'a']),
+            ("GenericLines".to_string(), vec![b'x'; 200]),
+        ];
+        let time = Utc.timestamp_opt(1700000000, 0).unwrap();
+        let fp = service_fingerprint(9999, ProbesProtocol::Tcp, &responses, time);
+        let lines: Vec<&str> = fp.lines().collect();
+        assert!(lines[0].starts_with("SF-Port9999-TCP:V="));
+        assert!(lines.len() > 3);
+        for line in &lines {
+            assert!(line.len() <= 80);
+        }
+        for line in &lines[1..] {
+            assert!(line.starts_with("SF:"));
+        }
+        let fp: String = lines
+            .iter()
+            .enumerate()
+            .map(|(i, l)| if i == 0 { *l } else { &l[3..] })
+            .collect();
+        assert!(fp.contains("%I=7%D=11/14%Time=6553F100%P="));
+        assert!(fp.contains(r#"%r(NULL,B,"HELLO\x201\.0\r\n")"#));
+        assert!(fp.contains(r#"%r(GetRequest,4,"\xff\0\"a")"#));
+        assert!(fp.contains(&format!("%r(GenericLines,C8,\"{}\")", "x".repeat(200))));
+        assert!(fp.ends_with(';'));
+    }
+    #[test]
+    fn test_fingerprint_unknown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.write_all(b"~~pistol unknown service~~\xfe\r\n");
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let fp = fingerprint_unknown(
+            dst_addr,
+            dst_port,
+            ProbesProtocol::Tcp,
+            Some(Duration::from_millis(200)),
+        )
+        .unwrap();
+        assert!(fp.starts_with(&format!("SF-Port{}-TCP:", dst_port)));
+        let fp = fp.replace("\nSF:", "");
+        assert!(fp.contains(r#"%r(NULL,1D,"~~pistol\x20unknown\x20service~~\xfe\r\n")"#));
+    }
+    #[test]
+    fn test_fingerprint_unknown_connect_refused() {
+        // only the NULL probe connects, the port is closed for the other probes
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"~~pistol unknown service~~\r\n");
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let fp = fingerprint_unknown(
+            dst_addr,
+            dst_port,
+            ProbesProtocol::Tcp,
+            Some(Duration::from_millis(200)),
+        )
+        .unwrap();
+        let fp = fp.replace("\nSF:", "");
+        assert!(fp.contains(r#"%r(NULL,1C,"~~pistol\x20unknown\x20service~~\r\n")"#));
+    }
+    #[test]
+    fn test_vs_scan_banner_only() {
+        let ssh = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ssh_port = ssh.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in ssh.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(b"SSH-2.0-OpenSSH_8.9p1\r\n").unwrap();
+            }
+        });
+        // the unknown service closes the connection after the banner, count the connections
+        let unknown = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unknown_port = unknown.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in unknown.incoming() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let _ = stream.write_all(b"~~pistol unknown service~~\r\n");
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let target = Target::new(vec![Host::new(
+            dst_addr,
+            Some(vec![ssh_port, unknown_port]),
+        )]);
+        let timeout = Some(Duration::from_secs(1));
+
+        let ret = vs_scan_banner_only(target.clone(), 2, timeout).unwrap();
+        let services = ret.get(&dst_addr).unwrap();
+        assert_eq!(services[&ssh_port].best().unwrap().service, "ssh");
+        assert!(services[&unknown_port].matchs.is_empty());
+        let banner_only = connections.swap(0, Ordering::Relaxed);
+        assert_eq!(banner_only, 1);
+
+        let ret = vs_scan_with_options(
+            target,
+            &VsOptions {
+                only_udp_recommended: true,
+                exclude_ports: Some(ExcludePorts::new(vec![])),
+                max_probes_per_port: Some(3),
+                connect_timeout: timeout,
+                read_timeout: timeout,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            ret.get(&dst_addr).unwrap()[&ssh_port]
+                .best()
+                .unwrap()
+                .service,
+            "ssh"
+        );
+        // the full scan sends the probes after the NULL probe gets no match
+        assert!(connections.load(Ordering::Relaxed) > banner_only);
+    }
+    #[test]
+    fn test_vs_hosts() {
+        let link_local_1: IpAddr = "fe80::1".parse().unwrap();
+        let link_local_2: IpAddr = "fe80::2".parse().unwrap();
+        let ipv4: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let hosts = vec![
+            Host::new(link_local_1, Some(vec![22, 80, 443])).with_known_open(vec![8080]),
+            Host::new(link_local_2, Some(vec![22, 80])),
+            Host::new(ipv4, Some(vec![22, 80])),
+            // the same host again
+            Host::new(link_local_1, Some(vec![80, 3306])),
+        ];
+        let mut lookups = 0;
+        let ret = vs_hosts(hosts, |_| {
+            lookups += 1;
+            Ok(Some(4))
+        })
+        .unwrap();
+        // one source lookup per link-local host, not per port
+        assert_eq!(lookups, 2);
+        assert_eq!(ret.len(), 3);
+        let (host, ports) = &ret[0];
+        assert_eq!(ports, &vec![22, 80, 443, 8080, 3306]);
+        assert_eq!(host.socket_addr(22), "[fe80::1%4]:22".parse().unwrap());
+        assert_eq!(ret[2].0.socket_addr(80), SocketAddr::new(ipv4, 80));
+    }
+    #[test]
+    fn test_vs_elapsed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(b"SSH-2.0-OpenSSH_8.9p1\r\n").unwrap();
+            }
+        });
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let host = Host::new(dst_addr, Some(vec![dst_port]));
+        let target = Target::new(vec![host]);
+        let ret = vs_scan_with_options(
+            target,
+            &VsOptions {
+                only_null_probe: true,
+                only_tcp_recommended: true,
+                only_udp_recommended: true,
+                exclude_ports: Some(ExcludePorts::new(vec![])),
+                connect_timeout: Some(Duration::from_secs(1)),
+                read_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let services = ret.get(&dst_addr).unwrap().get(&dst_port).unwrap();
+        let best = services.best().unwrap();
+        assert_eq!(best.service, "ssh");
+        let elapsed = services.elapsed;
+        assert!(elapsed > Duration::new(0, 0));
+        let slowest = ret.slowest(3);
+        assert_eq!(slowest, vec![(dst_addr, dst_port, elapsed)]);
+    }
     #[test]
     fn test_vs_detect() {
         // Logger::init_debug_logging()?;
@@ -1,7 +1,12 @@
 use fancy_regex::Regex;
+use once_cell::sync::OnceCell;
+use regex_syntax::hir::literal::Extractor;
+use regex_syntax::hir::Hir;
+use regex_syntax::hir::Look;
 use serde::Deserialize;
 use serde::Serialize;
 use std::fmt;
+use std::num::ParseIntError;
 
 use crate::errors::PistolErrors;
 
@@ -21,6 +26,13 @@ pub struct Match {
     pub pattern: String,
     // The <versioninfo> section actually contains several optional fields.
     pub versioninfo: String,
+    // The cpe:/ entries in the <versioninfo>, the $-backreferences are expanded after matching.
+    pub cpe: Vec<String>,
+    // The line number of the match in the database file, starts from 1.
+    pub line: usize,
+    // The name of the probe whose response matched, empty until the match is found.
+    #[serde(default)]
+    pub probe_name: String,
 }
 
 impl fmt::Display for Match {
@@ -29,6 +41,66 @@ impl fmt::Display for Match {
     }
 }
 
+/// The single service picked for a port from all its matches.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MatchResult {
+    pub service: String,
+    pub versioninfo: String,
+    pub cpe: Vec<String>,
+    /// Only a softmatch was found, the service is known but the version is not.
+    pub soft: bool,
+    /// The name of the probe which elicited the match, `NULL` for the banner.
+    pub probe_name: String,
+}
+
+impl MatchResult {
+    /// Like nmap, a hard match wins over a softmatch and the first hard match (the earliest probe) is taken.
+    /// Without any hard match, the first softmatch is taken.
+    pub fn best(matches: &[Match]) -> Option<MatchResult> {
+        let hard = matches.iter().find(|m| m.class == "match");
+        let (m, soft) = match hard {
+            Some(m) => (m, false),
+            None => (matches.iter().find(|m| m.class == "softmatch")?, true),
+        };
+        Some(MatchResult {
+            service: m.service.clone(),
+            versioninfo: m.versioninfo.clone(),
+            cpe: m.cpe.clone(),
+            soft,
+            probe_name: m.probe_name.clone(),
+        })
+    }
+    /// The product name, the `p/` field of the versioninfo.
+    pub fn product(&self) -> Option<String> {
+        versioninfo_field(&self.versioninfo, "p")
+    }
+    /// The version, the `v/` field of the versioninfo.
+    pub fn version(&self) -> Option<String> {
+        versioninfo_field(&self.versioninfo, "v")
+    }
+}
+
+/// The value of the `field` in the <versioninfo>, e.g. `Apache httpd` of `p` in `p/Apache httpd/ v/2.4.41/`.
+/// The value can be delimited by any character, like `p|a/b|`, the empty value is None.
+fn versioninfo_field(versioninfo: &str, field: &str) -> Option<String> {
+    let mut rest = versioninfo.trim_start();
+    while !rest.is_empty() {
+        let name_len = rest.find(|c: char| !c.is_ascii_alphabetic() && c != ':')?;
+        let (name, value) = rest.split_at(name_len);
+        let delimiter = value.chars().next()?;
+        let value = &value[delimiter.len_utf8()..];
+        let end = value.find(delimiter)?;
+        if name == field && end > 0 {
+            return Some(value[..end].to_string());
+        }
+        // the flags after the cpe, e.g. the `a` of `cpe:/a:apache:http_server/a`
+        rest = value[end + delimiter.len_utf8()..]
+            .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+            .trim_start();
+    }
+    None
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Probe {
     /// This must be either TCP or UDP. Nmap only uses probes that match the protocol of the service it is trying to scan.
@@ -58,64 +130,122 @@ pub struct ServiceProbe {
     pub rarity: Option<u64>,
     /// This optional directive specifies which probes should be used as fallbacks for if there are no matches in the current Probe section.
     pub fallback: Option<Vec<String>>,
+    /// The compiled patterns, built at the first check.
+    #[serde(skip)]
+    matcher: OnceCell<ProbeMatcher>,
+}
+
+/// The compiled match and softmatch patterns of one probe (the match lines first).
+/// Most patterns are anchored at the start of the response with a literal prefix (e.g. `^SSH-`),
+/// such a pattern only runs the slower `fancy_regex` if the response starts with the prefix, the others always run.
+#[derive(Debug, Clone)]
+struct ProbeMatcher {
+    /// The `fancy_regex` of the pattern, compiled when it is first a candidate.
+    /// None for the pattern which can not be compiled, it never matches.
+    regexes: Vec<OnceCell<Option<Regex>>>,
+    /// The literal prefixes of every pattern, one of them starts any match of the pattern.
+    /// None for the pattern which is not anchored or has no literal prefix.
+    prefixes: Vec<Option<Vec<Vec<u8>>>>,
+    /// The pattern which only matches at the end of the response (`$`),
+    /// it can not match the response cut at the match bytes.
+    end_anchored: Vec<bool>,
+}
+
+/// The literal prefixes of the pattern anchored at the start of the response.
+fn anchored_prefixes(hir: &Hir) -> Option<Vec<Vec<u8>>> {
+    if !hir.properties().look_set_prefix().contains(Look::Start) {
+        return None;
+    }
+    // None for the infinite sequence, the prefixes are unknown
+    let literals = Extractor::new().extract(hir).literals()?.to_vec();
+    if literals.is_empty() || literals.iter().any(|l| l.is_empty()) {
+        return None;
+    }
+    Some(literals.iter().map(|l| l.as_bytes().to_vec()).collect())
+}
+
+/// Every match of the pattern ends at the end of the response.
+fn end_anchored(hir: &Hir) -> bool {
+    hir.properties().look_set_suffix().contains(Look::End)
+}
+
+impl ProbeMatcher {
+    fn new<'a>(matchs: impl Iterator<Item = &'a Match>) -> ProbeMatcher {
+        let mut regexes = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut end_anchoreds = Vec::new();
+        for m in matchs {
+            regexes.push(OnceCell::new());
+            // the pattern only fancy_regex supports (lookaround, backreferences) fails to parse here
+            let hir = regex_syntax::parse(&m.pattern).ok();
+            prefixes.push(hir.as_ref().and_then(anchored_prefixes));
+            end_anchoreds.push(hir.as_ref().is_some_and(end_anchored));
+        }
+        ProbeMatcher {
+            regexes,
+            prefixes,
+            end_anchored: end_anchoreds,
+        }
+    }
+    /// The patterns which may match the response, in the pattern order.
+    /// The end anchored ones are left out for the `truncated` response.
+    fn candidates(&self, recv_str: &str, truncated: bool) -> Vec<usize> {
+        let recv = recv_str.as_bytes();
+        self.prefixes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(truncated && self.end_anchored[*i]))
+            .filter(|(_, p)| match p {
+                Some(p) => p.iter().any(|l| recv.starts_with(l)),
+                None => true,
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
 }
 
 impl ServiceProbe {
+    /// The matches and then the softmatches of the response, in the order of the database.
     pub fn check(&self, recv_str: &str) -> Vec<Match> {
-        let match_function = |m: &Match, re: &Regex, recv_str: &str| -> Option<Match> {
-            match re.is_match(&recv_str) {
-                Ok(b) => {
-                    if b {
-                        let captures_group = match re.captures(&recv_str) {
-                            Ok(v) => v,
-                            Err(_) => None,
-                        };
-                        match captures_group {
-                            Some(v) => {
-                                let mut versioninfo = m.versioninfo.to_string();
-                                for i in 0..v.len() {
-                                    let value = v.get(i).unwrap();
-                                    versioninfo =
-                                        versioninfo.replace(&format!("${}", i), value.as_str());
-                                }
-                                let new_match = Match {
-                                    class: m.class.clone(),
-                                    service: m.service.clone(),
-                                    pattern: m.pattern.clone(),
-                                    versioninfo,
-                                };
-                                return Some(new_match);
-                            }
-                            None => {
-                                let new_match = Match {
-                                    class: m.class.clone(),
-                                    service: m.service.clone(),
-                                    pattern: m.pattern.clone(),
-                                    versioninfo: String::from(""),
-                                };
-                                return Some(new_match);
-                            }
-                        }
-                    }
+        self.check_with(recv_str, false)
+    }
+    /// The `check` of the response cut at the match bytes,
+    /// the patterns anchored at the end of the response (`$`) are skipped since the cut is not the end.
+    pub fn check_truncated(&self, recv_str: &str) -> Vec<Match> {
+        self.check_with(recv_str, true)
+    }
+    fn check_with(&self, recv_str: &str, truncated: bool) -> Vec<Match> {
+        let matcher = self
+            .matcher
+            .get_or_init(|| ProbeMatcher::new(self.matchs.iter().chain(&self.softmatchs)));
+        let mut ret = Vec::new();
+        for i in matcher.candidates(recv_str, truncated) {
+            let m = match self.matchs.get(i) {
+                Some(m) => m,
+                None => &self.softmatchs[i - self.matchs.len()],
+            };
+            // rust regex is not support some format, and it will return error here
+            let re = matcher.regexes[i].get_or_init(|| Regex::new(&m.pattern).ok());
+            if let Some(re) = re {
+                if let Some(r) = match_pattern(m, re, recv_str) {
+                    ret.push(r);
                 }
-                Err(_) => (),
             }
-            None
-        };
-
+        }
+        ret
+    }
+    /// The old check which compiles and runs every pattern, to verify the prefiltered one.
+    #[cfg(test)]
+    fn check_linear(&self, recv_str: &str) -> Vec<Match> {
         let mut ret = Vec::new();
         // match
         for m in &self.matchs {
-            // println!("{}", m.pattern);
-            let recv_str = recv_str.to_string();
             let re = match Regex::new(&m.pattern) {
                 Ok(r) => r,
                 Err(_) => continue, // rust regex is not support some format, and it will return error here
             };
-            let r = match_function(m, &re, &recv_str);
-            match r {
-                Some(r) => ret.push(r),
-                None => (),
+            if let Some(r) = match_pattern(m, &re, recv_str) {
+                ret.push(r);
             }
         }
 
@@ -125,17 +255,65 @@ impl ServiceProbe {
                 Ok(r) => r,
                 Err(_) => continue, // rust regex is not support some format, and it will return error here
             };
-            let r = match_function(m, &re, &recv_str);
-            match r {
-                Some(r) => ret.push(r),
-                None => (),
+            if let Some(r) = match_pattern(m, &re, recv_str) {
+                ret.push(r);
             }
         }
         ret
     }
 }
 
-fn ports_parser(ports: &str) -> Result<Vec<u16>, PistolErrors> {
+/// The match with the $-backreferences of the versioninfo and the cpe expanded, None if the pattern does not match.
+fn match_pattern(m: &Match, re: &Regex, recv_str: &str) -> Option<Match> {
+    // the errors of fancy_regex (e.g. the backtrack limit) count as no match
+    if !re.is_match(recv_str).unwrap_or(false) {
+        return None;
+    }
+    let (versioninfo, cpe) = match re.captures(recv_str).unwrap_or_default() {
+        Some(v) => {
+            let mut versioninfo = m.versioninfo.to_string();
+            let mut cpe = m.cpe.clone();
+            for i in 0..v.len() {
+                let value = v.get(i).unwrap();
+                versioninfo = versioninfo.replace(&format!("${}", i), value.as_str());
+                for c in cpe.iter_mut() {
+                    *c = c.replace(&format!("${}", i), value.as_str());
+                }
+            }
+            (versioninfo, cpe)
+        }
+        None => (String::new(), m.cpe.clone()),
+    };
+    Some(Match {
+        class: m.class.clone(),
+        service: m.service.clone(),
+        pattern: m.pattern.clone(),
+        versioninfo,
+        cpe: cpe.iter().map(|c| cpe_normalize(c)).collect(),
+        line: m.line,
+        probe_name: m.probe_name.clone(),
+    })
+}
+
+/// Get the cpe:/ entries from the <versioninfo>,
+/// e.g. `p/Apache httpd/ v/$1/ cpe:/a:apache:http_server:$1/` => `cpe:/a:apache:http_server:$1`.
+fn cpe_parser(versioninfo: &str) -> Result<Vec<String>, PistolErrors> {
+    // the simple pattern does not need the fancy_regex
+    let cpe_re = regex::Regex::new(r"cpe:/(?P<cpe>[^/]+)/")?;
+    let mut ret = Vec::new();
+    for caps in cpe_re.captures_iter(versioninfo) {
+        let cpe = caps.name("cpe").map_or("", |m| m.as_str());
+        ret.push(format!("cpe:/{}", cpe));
+    }
+    Ok(ret)
+}
+
+/// CPE names are lowercase and use '_' instead of the space.
+fn cpe_normalize(cpe: &str) -> String {
+    cpe.trim().to_lowercase().replace(" ", "_")
+}
+
+fn ports_parser(ports: &str) -> Result<Vec<u16>, ParseIntError> {
     let mut ret = Vec::new();
     let ports_split: Vec<&str> = ports.split(",").map(|s| s.trim()).collect();
     for ps in ports_split {
@@ -154,6 +332,100 @@ fn ports_parser(ports: &str) -> Result<Vec<u16>, PistolErrors> {
     Ok(ret)
 }
 
+/// The summary of one probe in the service probes database.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProbeInfo {
+    pub name: String,
+    pub protocol: ProbesProtocol,
+    /// The probe is sent when the intensity is not lower than the rarity, None is sent at any intensity.
+    pub rarity: Option<u64>,
+    pub ports: Vec<u16>,
+    pub sslports: Vec<u16>,
+    /// The number of the match and softmatch lines of the probe.
+    pub match_count: usize,
+}
+
+/// The problem found by `ProbeDb::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeWarning {
+    /// The pattern of the match line can not be compiled, this match never works.
+    BadPattern {
+        probe: String,
+        line: usize,
+        reason: String,
+    },
+    /// The probe has no match or softmatch lines, its responses can not be recognized.
+    NoMatch { probe: String },
+}
+
+impl fmt::Display for ProbeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeWarning::BadPattern {
+                probe,
+                line,
+                reason,
+            } => write!(f, "probe {} line {}: bad pattern: {}", probe, line, reason),
+            ProbeWarning::NoMatch { probe } => write!(f, "probe {} has no match lines", probe),
+        }
+    }
+}
+
+/// The parsed service probes database.
+#[derive(Debug, Clone)]
+pub struct ProbeDb {
+    pub service_probes: Vec<ServiceProbe>,
+}
+
+impl ProbeDb {
+    pub fn new(service_probes: Vec<ServiceProbe>) -> ProbeDb {
+        ProbeDb { service_probes }
+    }
+    /// Parse the nmap-service-probes file shipped with pistol.
+    pub fn builtin() -> Result<ProbeDb, PistolErrors> {
+        let nsp_str = include_str!("../db/nmap-service-probes");
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines)?;
+        Ok(ProbeDb { service_probes })
+    }
+    /// Check the database before scanning, every pattern is compiled once here.
+    pub fn validate(&self) -> Vec<ProbeWarning> {
+        let mut ret = Vec::new();
+        for sp in &self.service_probes {
+            let probe = &sp.probe.probename;
+            if sp.matchs.is_empty() && sp.softmatchs.is_empty() {
+                ret.push(ProbeWarning::NoMatch {
+                    probe: probe.clone(),
+                });
+            }
+            for m in sp.matchs.iter().chain(&sp.softmatchs) {
+                if let Err(e) = Regex::new(&m.pattern) {
+                    ret.push(ProbeWarning::BadPattern {
+                        probe: probe.clone(),
+                        line: m.line,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+        ret
+    }
+    /// All the probes in the database order.
+    pub fn list(&self) -> Vec<ProbeInfo> {
+        self.service_probes
+            .iter()
+            .map(|sp| ProbeInfo {
+                name: sp.probe.probename.clone(),
+                protocol: sp.probe.protocol,
+                rarity: sp.rarity,
+                ports: sp.ports.clone().unwrap_or_default(),
+                sslports: sp.sslports.clone().unwrap_or_default(),
+                match_count: sp.matchs.len() + sp.softmatchs.len(),
+            })
+            .collect()
+    }
+}
+
 /// Instead of getting the `Exclude` port based on the `nmap-service-probes` file,
 /// we expect the user to provide a parameter to specify this value themselves.
 pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>, PistolErrors> {
@@ -167,7 +439,12 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>, PistolErrors> {
     let mut tcpwrappedms_global: Option<u64> = None;
     let mut rarity_global: Option<u64> = None;
     let mut fallback_gloabl: Option<Vec<String>> = None;
-    for line in lines {
+    for (i, line) in lines.iter().enumerate() {
+        // keep the line with the parse error
+        let parse_error = |source| PistolErrors::ServiceDBParseError {
+            line: line.clone(),
+            source,
+        };
         if line.contains("#") {
             continue;
         } else if line.contains("Exclude") {
@@ -187,6 +464,7 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>, PistolErrors> {
                         tcpwrappedms: tcpwrappedms_global,
                         rarity: rarity_global,
                         fallback: fallback_gloabl.clone(),
+                        matcher: OnceCell::new(),
                     };
                     ret.push(sp);
                     matchs_global = Vec::new();
@@ -243,11 +521,15 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>, PistolErrors> {
                 .trim()
                 .to_string();
 
+            let cpe = cpe_parser(&versioninfo)?;
             let m = Match {
                 class,
                 service,
                 pattern,
                 versioninfo,
+                cpe,
+                line: i + 1,
+                probe_name: String::new(),
             };
             matchs_global.push(m);
         } else if line.starts_with("softmatch") {
@@ -269,34 +551,38 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>, PistolErrors> {
                 .trim()
                 .to_string();
 
+            let cpe = cpe_parser(&versioninfo)?;
             let m = Match {
                 class,
                 service,
                 pattern,
                 versioninfo,
+                cpe,
+                line: i + 1,
+                probe_name: String::new(),
             };
             softmatchs_global.push(m);
         } else if line.starts_with("ports") {
             let line_split: Vec<&str> = line.split(" ").collect();
             let ports_line = line_split[1..].to_vec().join(" ");
-            let ports = ports_parser(&ports_line)?;
+            let ports = ports_parser(&ports_line).map_err(parse_error)?;
             ports_global = Some(ports);
         } else if line.starts_with("sslports") {
             let line_split: Vec<&str> = line.split(" ").collect();
             let sslports_line = line_split[1..].to_vec().join(" ");
-            let sslports = ports_parser(&sslports_line)?;
+            let sslports = ports_parser(&sslports_line).map_err(parse_error)?;
             sslports_global = Some(sslports);
         } else if line.starts_with("totalwaitms") {
             let line_split: Vec<&str> = line.split(" ").collect();
-            let totalwaitms: u64 = line_split[1].parse()?;
+            let totalwaitms: u64 = line_split[1].parse().map_err(parse_error)?;
             totalwaitms_global = Some(totalwaitms);
         } else if line.starts_with("tcpwrappedms") {
             let line_split: Vec<&str> = line.split(" ").collect();
-            let tcpwrappedms: u64 = line_split[1].parse()?;
+            let tcpwrappedms: u64 = line_split[1].parse().map_err(parse_error)?;
             tcpwrappedms_global = Some(tcpwrappedms);
         } else if line.starts_with("rarity") {
             let line_split: Vec<&str> = line.split(" ").collect();
-            let rarity: u64 = line_split[1].parse()?;
+            let rarity: u64 = line_split[1].parse().map_err(parse_error)?;
             rarity_global = Some(rarity);
         } else if line.starts_with("fallback") {
             let line_split: Vec<String> = line.split(" ").map(|s| s.to_string()).collect();
@@ -316,6 +602,7 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>, PistolErrors> {
                 tcpwrappedms: tcpwrappedms_global,
                 rarity: rarity_global,
                 fallback: fallback_gloabl,
+                matcher: OnceCell::new(),
             };
             ret.push(sp);
         }
@@ -399,3 +686,195 @@ pub fn nsp_exclued_parser(lines: &[String]) -> Result<ExcludePorts, PistolErrors
     };
     Ok(ep)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    #[test]
+    fn test_nsp_parser_error_source() {
+        let lines = vec![
+            String::from("Probe TCP NULL q||"),
+            String::from("totalwaitms 6000ms"),
+        ];
+        let e = nsp_parser(&lines).unwrap_err();
+        match &e {
+            PistolErrors::ServiceDBParseError { line, .. } => {
+                assert_eq!(line, "totalwaitms 6000ms")
+            }
+            _ => panic!("unexpected error: {}", e),
+        }
+        let source = e.source().unwrap();
+        assert!(source.downcast_ref::<ParseIntError>().is_some());
+    }
+    #[test]
+    fn test_check_prefilter() {
+        let probe_db = ProbeDb::builtin().unwrap();
+        let responses = [
+            "SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.6\r\n",
+            "220 (vsFTPd 3.0.5)\r\n",
+            "220 mail.example.com ESMTP Postfix (Ubuntu)\r\n",
+            "HTTP/1.1 200 OK\r\nServer: Apache/2.4.52 (Ubuntu)\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.0 404 Not Found\r\nServer: nginx/1.18.0\r\n\r\n",
+            "+OK Dovecot ready.\r\n",
+            "\x01\x02pistol unknown\r\n",
+        ];
+        // the linear check compiles every pattern, so only a part of the NULL probe is compared,
+        // with the pattern only fancy_regex supports and the one which never compiles
+        let null_probe = &probe_db.service_probes[0];
+        let mut sp = null_probe.clone();
+        sp.matchs.truncate(400);
+        sp.softmatchs.truncate(40);
+        // the full NULL probe only runs the candidates, so it is not slower than the linear check
+        let matches = null_probe.check(responses[0]);
+        assert!(matches.iter().any(|m| m.service == "ssh"));
+        let fancy = Match {
+            class: String::from("match"),
+            service: String::from("pistol"),
+            pattern: String::from(r"^\x01\x02(?=pistol)(\w+)"),
+            versioninfo: String::from("p/pistol/ v/$1/"),
+            cpe: vec![],
+            line: 0,
+            probe_name: String::new(),
+        };
+        let bad = Match {
+            pattern: String::from(r"^SSH-\i"),
+            ..fancy.clone()
+        };
+        sp.matchs.insert(0, bad);
+        sp.softmatchs.push(fancy);
+        let mut match_count = 0;
+        for response in responses {
+            let linear = sp.check_linear(response);
+            let matches = sp.check(response);
+            let key = |m: &Match| {
+                (
+                    m.class.clone(),
+                    m.line,
+                    m.versioninfo.clone(),
+                    m.cpe.clone(),
+                )
+            };
+            let linear: Vec<_> = linear.iter().map(key).collect();
+            let matches: Vec<_> = matches.iter().map(key).collect();
+            assert_eq!(
+                linear, matches,
+                "probe {} response {:?}",
+                sp.probe.probename, response
+            );
+            match_count += matches.len();
+        }
+        assert!(match_count > 1);
+        let matches = sp.check(responses[6]);
+        assert_eq!(matches.last().unwrap().versioninfo, "p/pistol/ v/pistol/");
+    }
+    #[test]
+    fn test_probe_db_list() {
+        let probe_db = ProbeDb::builtin().unwrap();
+        let probes = probe_db.list();
+        assert_eq!(probes.len(), probe_db.service_probes.len());
+        let null_probe = &probes[0];
+        assert_eq!(null_probe.name, "NULL");
+        assert_eq!(null_probe.protocol, ProbesProtocol::Tcp);
+        assert_eq!(null_probe.rarity, None);
+        assert!(null_probe.match_count > 0);
+        let get_request = probes.iter().find(|p| p.name == "GetRequest").unwrap();
+        assert_eq!(get_request.rarity, Some(1));
+        assert!(get_request.ports.contains(&80));
+        assert!(probes
+            .iter()
+            .any(|p| p.protocol == ProbesProtocol::Udp && p.rarity.is_some()));
+    }
+    #[test]
+    fn test_probe_db_validate() {
+        let nsp_str = "Probe TCP GetRequest q|GET / HTTP/1.0\\r\\n\\r\\n|
+rarity 1
+match http m|^HTTP/1\\.[01] \\d\\d\\d| p/http/
+match broken m|^HTTP/(1\\.[01]| p/broken/
+Probe UDP Silent q|hello|
+rarity 9";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let probe_db = ProbeDb::new(nsp_parser(&nsp_lines).unwrap());
+        let warnings = probe_db.validate();
+        assert_eq!(warnings.len(), 2);
+        match &warnings[0] {
+            ProbeWarning::BadPattern { probe, line, .. } => {
+                assert_eq!(probe, "GetRequest");
+                assert_eq!(*line, 4);
+            }
+            w => panic!("unexpected warning: {}", w),
+        }
+        assert_eq!(
+            warnings[1],
+            ProbeWarning::NoMatch {
+                probe: String::from("Silent")
+            }
+        );
+    }
+    #[test]
+    fn test_match_result_best() {
+        let new_match = |class: &str, service: &str| Match {
+            class: class.to_string(),
+            service: service.to_string(),
+            pattern: String::new(),
+            versioninfo: String::new(),
+            cpe: vec![],
+            line: 0,
+            probe_name: String::new(),
+        };
+        let matches = vec![
+            new_match("softmatch", "ftp"),
+            new_match("match", "vsftpd"),
+            new_match("softmatch", "smtp"),
+            new_match("match", "proftpd"),
+        ];
+        let best = MatchResult::best(&matches).unwrap();
+        assert_eq!(best.service, "vsftpd");
+        assert!(!best.soft);
+
+        let best = MatchResult::best(&matches[..1]).unwrap();
+        assert_eq!(best.service, "ftp");
+        assert!(best.soft);
+        assert_eq!(MatchResult::best(&[]), None);
+    }
+    #[test]
+    fn test_versioninfo_field() {
+        let versioninfo =
+            "p/Apache httpd/ v/2.4.41/ i/(Ubuntu)/ cpe:/a:apache:http_server:2.4.41/a o|Linux|";
+        assert_eq!(
+            versioninfo_field(versioninfo, "p"),
+            Some(String::from("Apache httpd"))
+        );
+        assert_eq!(
+            versioninfo_field(versioninfo, "v"),
+            Some(String::from("2.4.41"))
+        );
+        assert_eq!(
+            versioninfo_field(versioninfo, "o"),
+            Some(String::from("Linux"))
+        );
+        assert_eq!(versioninfo_field(versioninfo, "h"), None);
+        assert_eq!(versioninfo_field("p/OpenSSH/ v//", "v"), None);
+        assert_eq!(versioninfo_field("", "p"), None);
+    }
+    #[test]
+    fn test_match_cpe() {
+        let lines = vec![
+            String::from("Probe TCP GetRequest q|GET / HTTP/1.0\\r\\n\\r\\n|"),
+            String::from(
+                r"match http m|^HTTP/1\.[01] \d\d\d .*\r\nServer: Apache/([\d.]+)| p/Apache httpd/ v/$1/ cpe:/a:apache:http_server:$1/",
+            ),
+        ];
+        let service_probes = nsp_parser(&lines).unwrap();
+        let m = &service_probes[0].matchs[0];
+        assert_eq!(m.cpe, vec![String::from("cpe:/a:apache:http_server:$1")]);
+
+        let recv_str = "HTTP/1.1 200 OK\r\nServer: Apache/2.4.52 (Ubuntu)\r\n\r\n";
+        let r = service_probes[0].check(recv_str);
+        assert_eq!(r.len(), 1);
+        assert_eq!(
+            r[0].cpe,
+            vec![String::from("cpe:/a:apache:http_server:2.4.52")]
+        );
+    }
+}
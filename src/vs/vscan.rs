@@ -1,10 +1,14 @@
 use log::debug;
+use log::warn;
+use std::cell::RefCell;
+use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::net::SocketAddrV6;
 use std::net::TcpStream;
 use std::net::UdpSocket;
 use std::time::Duration;
@@ -14,21 +18,166 @@ use std::time::Instant;
 use super::dbparser::Match;
 use super::dbparser::ProbesProtocol;
 use super::dbparser::ServiceProbe;
+use super::NullProbeRead;
+use super::Services;
+use super::VsOptions;
 use crate::errors::PistolErrors;
-use crate::utils::random_port;
+use crate::utils::find_interface_by_ip;
+use crate::utils::find_source_addr6;
 
 const TCP_BUFF_SIZE: usize = 4096;
 const UDP_BUFF_SIZE: usize = 4096;
 
-fn format_send(data: &str) -> String {
-    let new_data = data.replace("\\n", "\n");
-    let new_data = new_data.replace("\\r", "\r");
-    let new_data = new_data.replace("\\t", "\t");
-    new_data
+/// Append the data up to the max banner size, returns true when it is reached.
+fn push_capped(recv_all_buff: &mut Vec<u8>, data: &[u8], max_banner_size: usize) -> bool {
+    let room = max_banner_size.saturating_sub(recv_all_buff.len());
+    recv_all_buff.extend(&data[..data.len().min(room)]);
+    recv_all_buff.len() >= max_banner_size
+}
+
+/// Decode the C-style escapes of the probestring, `\xHH` and `\0` included, into the bytes to send.
+/// An unknown escape is sent as it is.
+/// The `{host}` and `{port}` tokens are replaced with the target, e.g. for the HTTP Host header,
+/// the ipv6 host is in brackets like `[::1]`.
+fn format_send(data: &str, dst_addr: SocketAddr) -> Vec<u8> {
+    let host = match dst_addr.ip() {
+        IpAddr::V4(ipv4) => ipv4.to_string(),
+        IpAddr::V6(ipv6) => format!("[{}]", ipv6),
+    };
+    let data = data
+        .replace("{host}", &host)
+        .replace("{port}", &dst_addr.port().to_string());
+    let bytes = data.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let (b, len) = match bytes.get(i..i + 2) {
+            Some([b'\\', c]) => match c {
+                b'0' => (0x00, 2),
+                b'a' => (0x07, 2),
+                b'b' => (0x08, 2),
+                b'f' => (0x0c, 2),
+                b'n' => (b'\n', 2),
+                b'r' => (b'\r', 2),
+                b't' => (b'\t', 2),
+                b'v' => (0x0b, 2),
+                b'\\' => (b'\\', 2),
+                b'x' => match data.get(i + 2..i + 4).map(|h| u8::from_str_radix(h, 16)) {
+                    Some(Ok(b)) => (b, 4),
+                    _ => (bytes[i], 1),
+                },
+                _ => (bytes[i], 1),
+            },
+            _ => (bytes[i], 1),
+        };
+        ret.push(b);
+        i += len;
+    }
+    ret
+}
+
+/// The zone of the link-local target, the index of the interface that owns the source address.
+pub fn link_local_scope(dst_ipv6: Ipv6Addr) -> Result<Option<u32>, PistolErrors> {
+    match find_source_addr6(None, dst_ipv6)? {
+        Some(src_ipv6) => Ok(find_interface_by_ip(src_ipv6.into()).map(|i| i.index)),
+        None => Ok(None),
+    }
+}
+
+/// The setup shared by all the ports of a host, the source lookup of the link-local target is done once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsHost {
+    addr: IpAddr,
+    scope_id: u32,
+}
+
+impl VsHost {
+    pub fn new(addr: IpAddr) -> Result<VsHost, PistolErrors> {
+        VsHost::new_with(addr, link_local_scope)
+    }
+    /// The link-local address is only reachable together with the zone, which `scope_lookup` finds.
+    pub fn new_with(
+        addr: IpAddr,
+        scope_lookup: impl FnOnce(Ipv6Addr) -> Result<Option<u32>, PistolErrors>,
+    ) -> Result<VsHost, PistolErrors> {
+        let scope_id = match addr {
+            IpAddr::V6(ipv6) if ipv6.is_unicast_link_local() => match scope_lookup(ipv6)? {
+                Some(scope_id) => scope_id,
+                None => return Err(PistolErrors::CanNotFoundInterface),
+            },
+            _ => 0,
+        };
+        Ok(VsHost { addr, scope_id })
+    }
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+    /// The address of the port to connect or send to.
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        match self.addr {
+            IpAddr::V6(ipv6) => SocketAddr::V6(SocketAddrV6::new(ipv6, port, 0, self.scope_id)),
+            IpAddr::V4(_) => SocketAddr::new(self.addr, port),
+        }
+    }
+}
+
+fn vs_dst_addr(dst_addr: IpAddr, dst_port: u16) -> Result<SocketAddr, PistolErrors> {
+    Ok(VsHost::new(dst_addr)?.socket_addr(dst_port))
+}
+
+/// Match the response with the probe, only the first `match_bytes` bytes if set.
+/// The patterns anchored at the end (`$`) are not matched at the cut, see `ServiceProbe::check_truncated`.
+/// The matches record the name of the probe.
+fn check_response(sp: &ServiceProbe, response: &[u8], match_bytes: Option<usize>) -> Vec<Match> {
+    let mut matches = match match_bytes {
+        Some(m) if response.len() > m => {
+            // the utf-8 char split by the cut is left out instead of decoded as a replacement char
+            let mut cut = m;
+            while cut > 0 && m - cut < 3 && response[cut] & 0xc0 == 0x80 {
+                cut -= 1;
+            }
+            sp.check_truncated(&String::from_utf8_lossy(&response[..cut]))
+        }
+        _ => sp.check(&String::from_utf8_lossy(response)),
+    };
+    for m in matches.iter_mut() {
+        m.probe_name = sp.probe.probename.clone();
+    }
+    matches
+}
+
+/// The service of the ports which answer with a tls record, the detection should be retried in a tls tunnel.
+pub const TLS_SERVICE: &str = "ssl/tls";
+
+/// The response starts with a tls record header, an alert (0x15) or a handshake (0x16) of version 3.x.
+fn is_tls_record(response: &[u8]) -> bool {
+    response.len() >= 5
+        && matches!(response[0], 0x15 | 0x16)
+        && response[1] == 0x03
+        && response[2] <= 0x04
+}
+
+/// The softmatch of a tls service, for the responses which match no pattern.
+fn tls_match(sp: &ServiceProbe, response: &[u8]) -> Option<Match> {
+    if !is_tls_record(response) {
+        return None;
+    }
+    Some(Match {
+        class: String::from("softmatch"),
+        service: TLS_SERVICE.to_string(),
+        pattern: String::new(),
+        versioninfo: String::new(),
+        cpe: Vec::new(),
+        line: 0,
+        probe_name: sp.probe.probename.clone(),
+    })
 }
 
 fn tcp_null_probe(
     stream: &mut TcpStream,
+    match_bytes: Option<usize>,
+    null_probe_read: NullProbeRead,
+    max_banner_size: usize,
     service_probes: &[ServiceProbe],
 ) -> Result<Vec<Match>, PistolErrors> {
     let mut recv_buff = [0u8; TCP_BUFF_SIZE];
@@ -40,65 +189,186 @@ fn tcp_null_probe(
         };
         if n == 0 {
             break;
-        } else {
-            recv_all_buff.extend(recv_buff);
+        } else if push_capped(&mut recv_all_buff, &recv_buff[..n], max_banner_size) {
+            debug!("null probe reach the max banner size");
+            break;
+        } else if null_probe_read == NullProbeRead::FirstData {
+            break;
         }
     }
 
     let mut ret = Vec::new();
     if recv_all_buff.len() > 0 {
-        let recv_str = String::from_utf8_lossy(&recv_buff);
-        // println!("{}", recv_str);
         for s in service_probes {
             if s.probe.probename == "NULL" {
-                let r = s.check(&recv_str);
-                ret.extend(r);
+                let r = check_response(s, &recv_all_buff, match_bytes);
+                if r.is_empty() {
+                    ret.extend(tls_match(s, &recv_all_buff));
+                } else {
+                    ret.extend(r);
+                }
             }
         }
     }
     Ok(ret)
 }
 
+/// The peer dropped the connection, a new connection is needed to continue.
+fn is_connection_lost(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+    )
+}
+
+fn tcp_connect(
+    dst_addr: SocketAddr,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+) -> Result<TcpStream, PistolErrors> {
+    let stream = TcpStream::connect_timeout(&dst_addr, connect_timeout)?;
+    stream.set_read_timeout(Some(read_timeout))?;
+    stream.set_write_timeout(Some(read_timeout))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+fn tcp_send_probe(
+    stream: &mut TcpStream,
+    dst_addr: SocketAddr,
+    sp: &ServiceProbe,
+    match_bytes: Option<usize>,
+    max_banner_size: usize,
+) -> Result<Vec<Match>, io::Error> {
+    let probestring = format_send(&sp.probe.probestring, dst_addr);
+    stream.write_all(&probestring)?;
+    let mut recv_buff = [0u8; TCP_BUFF_SIZE];
+    let mut recv_all_buff = Vec::new();
+    loop {
+        let n = match stream.read(&mut recv_buff) {
+            Ok(n) => n,
+            // the connection is reset before any response
+            Err(e) if is_connection_lost(&e) && recv_all_buff.is_empty() => return Err(e),
+            Err(_) => 0,
+        };
+        if n == 0 || push_capped(&mut recv_all_buff, &recv_buff[..n], max_banner_size) {
+            break;
+        }
+    }
+    if !recv_all_buff.is_empty() {
+        let r = check_response(sp, &recv_all_buff, match_bytes);
+        Ok(r)
+    } else {
+        Ok(vec![])
+    }
+}
+
 fn tcp_continue_probe(
     stream: &mut TcpStream,
-    dst_port: u16,
-    only_tcp_recommended: bool,
-    intensity: usize,
+    dst_addr: SocketAddr,
     service_probes: &[ServiceProbe],
-) -> Result<Vec<Match>, PistolErrors> {
-    let mut run_probe = |sp: &ServiceProbe| -> Result<Vec<Match>, PistolErrors> {
-        let probestring = format_send(&sp.probe.probestring);
-        stream.write(probestring.as_bytes())?;
-        let mut recv_buff = [0u8; TCP_BUFF_SIZE];
-        let mut recv_all_buff = Vec::new();
-        loop {
-            let n = match stream.read(&mut recv_buff) {
-                Ok(n) => n,
-                Err(_) => 0,
-            };
-            if n == 0 {
+    options: &VsOptions,
+) -> Result<(Vec<Match>, bool), PistolErrors> {
+    let (connect_timeout, read_timeout) = options.timeouts();
+    let probes = tcp_probe_order(
+        dst_addr.port(),
+        options.only_tcp_recommended,
+        options.only_best_probe,
+        options.intensity,
+        options.max_rarity,
+        service_probes,
+    );
+    let mut ret = Vec::new();
+    let mut truncated = false;
+    // the stream from the NULL probe has not received any probe yet
+    let mut fresh_stream = true;
+    // TCP connections continue here if the NULL probe described above fails or soft-matches.
+    for (i, sp) in probes.iter().enumerate() {
+        match options.max_probes_per_port {
+            Some(m) if i >= m => {
+                debug!("tcp probes reach the max probes limit {}", m);
+                truncated = true;
                 break;
-            } else {
-                recv_all_buff.extend(recv_buff);
             }
+            _ => (),
         }
-        if recv_all_buff.len() > 0 {
-            let recv_str = String::from_utf8_lossy(&recv_all_buff);
-            // println!("{}", recv_str);
-            let r = sp.check(&recv_str);
-            Ok(r)
-        } else {
-            Ok(vec![])
+        // some services only answer the first probe on a connection
+        if options.reconnect_per_probe && !fresh_stream {
+            match tcp_connect(dst_addr, connect_timeout, read_timeout) {
+                Ok(s) => *stream = s,
+                Err(e) => {
+                    warn!("reconnect to {} failed: {}", dst_addr, e);
+                    break;
+                }
+            }
+        }
+        fresh_stream = false;
+        match tcp_send_probe(
+            stream,
+            dst_addr,
+            sp,
+            options.match_bytes,
+            options.max_banner_size,
+        ) {
+            Ok(r) => {
+                if !r.is_empty() {
+                    // stop at the first probe which matches
+                    ret.extend(r);
+                    break;
+                }
+            }
+            Err(e) if is_connection_lost(&e) => {
+                debug!("probe {} failed: {}", sp.probe.probename, e);
+                // like nmap, reopen the connection for the remaining probes
+                if i + 1 < probes.len() {
+                    match tcp_connect(dst_addr, connect_timeout, read_timeout) {
+                        Ok(s) => *stream = s,
+                        Err(e) => {
+                            // keep what the probes before got instead of failing the port
+                            warn!("reconnect to {} failed: {}", dst_addr, e);
+                            break;
+                        }
+                    }
+                    fresh_stream = true;
+                }
+            }
+            Err(e) => return Err(e.into()),
         }
+    }
+    Ok((ret, truncated))
+}
+
+/// The probe is sent when the intensity is not lower than its rarity,
+/// and its rarity is not higher than the `max_rarity`.
+fn rarity_allowed(sp: &ServiceProbe, intensity: usize, max_rarity: Option<usize>) -> bool {
+    let rarity = match sp.rarity {
+        Some(r) => r as usize,
+        None => 0,
     };
+    match max_rarity {
+        Some(m) if rarity > m => false,
+        _ => intensity >= rarity,
+    }
+}
 
-    let mut ret = Vec::new();
-    // TCP connections continue here if the NULL probe described above fails or soft-matches.
+/// Returns the tcp probes in the order they should be sent.
+/// The probes recommended for the port (by 'ports' or 'sslports') come first,
+/// the rest follow in database order unless only the recommended probes are wanted.
+/// With `only_best_probe`, only the recommended probe of the lowest rarity is sent.
+fn tcp_probe_order(
+    dst_port: u16,
+    only_tcp_recommended: bool,
+    only_best_probe: bool,
+    intensity: usize,
+    max_rarity: Option<usize>,
+    service_probes: &[ServiceProbe],
+) -> Vec<&ServiceProbe> {
+    let mut recommended = Vec::new();
+    let mut others = Vec::new();
     for sp in service_probes {
-        let rarity = match sp.rarity {
-            Some(r) => r as usize,
-            None => 0,
-        };
         let mut ports: Vec<u16> = Vec::new();
         match &sp.ports {
             Some(p) => ports.extend(p),
@@ -110,80 +380,153 @@ fn tcp_continue_probe(
         }
         if sp.probe.probename != "NULL"
             && sp.probe.protocol == ProbesProtocol::Tcp
-            && intensity >= rarity
+            && rarity_allowed(sp, intensity, max_rarity)
         {
             // Since the reality is that most ports are used by the service they are registered to in nmap-services,
             // every probe has a list of port numbers that are considered to be most effective.
-            if only_tcp_recommended {
-                if ports.contains(&dst_port) {
-                    let r = run_probe(sp);
-                    match r {
-                        Ok(r) => ret.extend(r),
-                        Err(e) => return Err(e.into()),
-                    }
-                }
-            } else {
-                let r = run_probe(sp);
-                match r {
-                    Ok(r) => ret.extend(r),
-                    Err(e) => return Err(e.into()),
-                }
+            if ports.contains(&dst_port) {
+                recommended.push(sp);
+            } else if !only_tcp_recommended {
+                others.push(sp);
             }
         }
     }
-    Ok(ret)
+    if only_best_probe {
+        return best_probe(recommended);
+    }
+    recommended.extend(others);
+    recommended
 }
 
-fn udp_probe(
-    dst_addr: IpAddr,
-    dst_port: u16,
-    only_udp_recommended: bool,
-    intensity: usize,
-    service_probes: &[ServiceProbe],
-    timeout: Duration,
-) -> Result<Vec<Match>, PistolErrors> {
-    let run_probe = |socket: &UdpSocket, sp: &ServiceProbe| -> Result<Vec<Match>, PistolErrors> {
-        let mut ret = Vec::new();
-        let probestring = sp.probe.probestring.as_bytes();
-        socket.send(probestring)?;
-        let mut recv_buff = [0u8; UDP_BUFF_SIZE];
-        let n = match socket.recv(&mut recv_buff) {
-            Ok(n) => n,
-            Err(_) => 0,
-        };
-        if n > 0 {
-            let recv_str = String::from_utf8_lossy(&recv_buff);
-            let r = sp.check(&recv_str);
-            ret.extend(r);
-        }
-        Ok(ret)
+/// The probe of the lowest rarity, the earliest one in database order wins a tie.
+fn best_probe(probes: Vec<&ServiceProbe>) -> Vec<&ServiceProbe> {
+    probes
+        .into_iter()
+        .min_by_key(|sp| sp.rarity.unwrap_or(0))
+        .into_iter()
+        .collect()
+}
+
+thread_local! {
+    // Bind the udp socket once per worker thread instead of once per port.
+    static UDP_SOCKET: RefCell<Option<UdpSocket>> = const { RefCell::new(None) };
+    static UDP_SOCKET6: RefCell<Option<UdpSocket>> = const { RefCell::new(None) };
+}
+
+fn thread_udp_socket(dst_addr: IpAddr) -> Result<UdpSocket, PistolErrors> {
+    let (udp_socket, src_addr) = match dst_addr {
+        IpAddr::V4(_) => (&UDP_SOCKET, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
+        IpAddr::V6(_) => (
+            &UDP_SOCKET6,
+            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        ),
     };
+    udp_socket.with(|s| {
+        let mut s = s.borrow_mut();
+        let socket = match s.as_ref() {
+            Some(socket) => socket,
+            // let the system choose the source port to avoid the collision
+            None => s.insert(UdpSocket::bind(SocketAddr::new(src_addr, 0))?),
+        };
+        Ok(socket.try_clone()?)
+    })
+}
+
+/// After this many unanswered sends in a row the host is likely rate limiting the icmp messages.
+const UDP_RATE_LIMIT_TIMEOUTS: usize = 3;
+const UDP_MIN_SEND_DELAY: Duration = Duration::from_millis(50);
+const UDP_MAX_SEND_DELAY: Duration = Duration::from_secs(1);
 
-    let random_port = random_port();
-    let src_addr = match dst_addr {
-        IpAddr::V4(_) => {
-            let addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-            SocketAddr::new(addr, random_port)
+/// Slow down the udp probes of a port when the responses stop coming back,
+/// like nmap does when the host rate limits the icmp port unreachable messages.
+#[derive(Debug, Clone, Copy, Default)]
+struct UdpBackoff {
+    timeouts_in_row: usize,
+    send_delay: Duration,
+}
+
+impl UdpBackoff {
+    fn wait(&self) {
+        if !self.send_delay.is_zero() {
+            std::thread::sleep(self.send_delay);
+        }
+    }
+    fn timed_out(&mut self) {
+        self.timeouts_in_row += 1;
+        if self.timeouts_in_row >= UDP_RATE_LIMIT_TIMEOUTS {
+            self.send_delay = (self.send_delay * 2).clamp(UDP_MIN_SEND_DELAY, UDP_MAX_SEND_DELAY);
+            debug!(
+                "udp probes are likely rate limited, delay {:?}",
+                self.send_delay
+            );
         }
-        IpAddr::V6(_) => {
-            let addr = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
-            SocketAddr::new(addr, random_port)
+    }
+    fn answered(&mut self) {
+        self.timeouts_in_row = 0;
+        self.send_delay /= 2;
+        if self.send_delay < UDP_MIN_SEND_DELAY {
+            self.send_delay = Duration::ZERO;
         }
-    };
+    }
+}
+
+fn udp_probe(
+    dst_addr: SocketAddr,
+    service_probes: &[ServiceProbe],
+    options: &VsOptions,
+) -> Result<(Vec<Match>, bool), PistolErrors> {
+    let (_, timeout) = options.timeouts();
+    let match_bytes = options.match_bytes;
+    // The socket is bound to the unspecified address, the zone of the destination picks the interface,
+    // and the source of the response carries the same zone so the comparison below still holds.
+    // Returns None if there is no response.
+    let run_probe =
+        |socket: &UdpSocket, sp: &ServiceProbe| -> Result<Option<Vec<Match>>, PistolErrors> {
+            // The socket is shared by the probes of this thread,
+            // drop the late responses of the previous probes before sending a new one.
+            socket.set_nonblocking(true)?;
+            let mut recv_buff = [0u8; UDP_BUFF_SIZE];
+            while socket.recv_from(&mut recv_buff).is_ok() {}
+            socket.set_nonblocking(false)?;
+
+            // The empty probestring (q||) is sent as a zero-length datagram,
+            // some services answer any datagram, it works like the NULL probe of tcp.
+            let probestring = format_send(&sp.probe.probestring, dst_addr);
+            let send_size = socket.send_to(&probestring, dst_addr)?;
+            if send_size != probestring.len() {
+                debug!("udp probe {} is not fully sent", sp.probe.probename);
+            }
+            let start_time = Instant::now();
+            let mut n = 0;
+            loop {
+                let remaining = timeout.saturating_sub(start_time.elapsed());
+                if remaining.is_zero() {
+                    break;
+                }
+                socket.set_read_timeout(Some(remaining))?;
+                match socket.recv_from(&mut recv_buff) {
+                    // only the response from the probed port belongs to this probe
+                    Ok((size, src_addr)) if src_addr == dst_addr => {
+                        n = size;
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(_) => break,
+                }
+            }
+            if n > 0 {
+                Ok(Some(check_response(sp, &recv_buff[..n], match_bytes)))
+            } else {
+                Ok(None)
+            }
+        };
 
-    let dst_addr = SocketAddr::new(dst_addr, dst_port);
-    let socket = UdpSocket::bind(src_addr)?;
-    // let timeout = Duration::from_secs(1); // both 1 sec
-    socket.set_read_timeout(Some(timeout))?;
+    let socket = thread_udp_socket(dst_addr.ip())?;
     socket.set_write_timeout(Some(timeout))?;
-    socket.connect(dst_addr)?;
 
-    let mut ret = Vec::new();
+    let mut probes = Vec::new();
+    let mut recommended = Vec::new();
     for sp in service_probes {
-        let rarity = match sp.rarity {
-            Some(r) => r as usize,
-            None => 0,
-        };
         let mut ports: Vec<u16> = Vec::new();
         match &sp.ports {
             Some(p) => ports.extend(p),
@@ -191,99 +534,1040 @@ fn udp_probe(
         }
         if sp.probe.probename != "NULL"
             && sp.probe.protocol == ProbesProtocol::Udp
-            && intensity >= rarity
+            && rarity_allowed(sp, options.intensity, options.max_rarity)
         {
             // Since the reality is that most ports are used by the service they are registered to in nmap-services,
             // every probe has a list of port numbers that are considered to be most effective.
-            if only_udp_recommended {
-                if ports.contains(&dst_port) {
-                    let r = run_probe(&socket, sp);
-                    match r {
-                        Ok(r) => ret.extend(r),
-                        Err(e) => return Err(e.into()),
+            if ports.contains(&dst_addr.port()) {
+                recommended.push(sp);
+                probes.push(sp);
+            } else if !options.only_udp_recommended {
+                probes.push(sp);
+            }
+        }
+    }
+    if options.only_best_probe {
+        probes = best_probe(recommended);
+    }
+
+    let mut ret = Vec::new();
+    let mut truncated = false;
+    let mut backoff = UdpBackoff::default();
+    for (probes_num, sp) in probes.into_iter().enumerate() {
+        match options.max_probes_per_port {
+            Some(m) if probes_num >= m => {
+                debug!("udp probes reach the max probes limit {}", m);
+                truncated = true;
+                break;
+            }
+            _ => (),
+        }
+        // the probe or its response may be lost, send it again
+        for i in 0..=options.udp_retries {
+            backoff.wait();
+            match run_probe(&socket, sp)? {
+                Some(r) => {
+                    backoff.answered();
+                    ret.extend(r);
+                    break;
+                }
+                None => {
+                    backoff.timed_out();
+                    if i < options.udp_retries {
+                        debug!("udp probe {} timed out, retry", sp.probe.probename);
                     }
                 }
-            } else {
-                let r = run_probe(&socket, sp);
-                match r {
-                    Ok(r) => ret.extend(r),
-                    Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok((ret, truncated))
+}
+
+/// Read until the peer closes the connection, the timeout or the max banner size.
+fn tcp_read_all(stream: &mut TcpStream, max_banner_size: usize) -> Vec<u8> {
+    let mut recv_buff = [0u8; TCP_BUFF_SIZE];
+    let mut recv_all_buff = Vec::new();
+    loop {
+        match stream.read(&mut recv_buff) {
+            Ok(n) if n > 0 => {
+                if push_capped(&mut recv_all_buff, &recv_buff[..n], max_banner_size) {
+                    break;
                 }
             }
+            _ => break,
         }
     }
-    Ok(ret)
+    recv_all_buff
 }
 
-pub fn threads_vs_probe(
+/// The raw responses of the probes, by probe name.
+pub type ProbeResponses = Vec<(String, Vec<u8>)>;
+
+/// The raw responses of the probes and the matches of them.
+/// Every probe is sent on a new connection, the probes stop at the first hard match.
+pub fn collect_responses(
     dst_addr: IpAddr,
     dst_port: u16,
-    only_null_probe: bool,
-    only_tcp_recommended: bool,
-    only_udp_recommended: bool,
+    protocol: ProbesProtocol,
     intensity: usize,
     service_probes: &[ServiceProbe],
     timeout: Duration,
-) -> Result<(Vec<Match>, Duration), PistolErrors> {
+    max_banner_size: usize,
+) -> Result<(ProbeResponses, Vec<Match>), PistolErrors> {
+    let dst_addr = vs_dst_addr(dst_addr, dst_port)?;
+    let mut responses = Vec::new();
+    let mut matches = Vec::new();
+    let mut record = |sp: &ServiceProbe, response: Vec<u8>| -> bool {
+        if response.is_empty() {
+            return false;
+        }
+        let r = check_response(sp, &response, None);
+        let hard = r.iter().any(|m| m.class == "match");
+        matches.extend(r);
+        responses.push((sp.probe.probename.clone(), response));
+        hard
+    };
+    match protocol {
+        ProbesProtocol::Tcp => {
+            if let Some(sp) = service_probes
+                .iter()
+                .find(|sp| sp.probe.probename == "NULL")
+            {
+                let mut stream = tcp_connect(dst_addr, timeout, timeout)?;
+                if record(sp, tcp_read_all(&mut stream, max_banner_size)) {
+                    return Ok((responses, matches));
+                }
+            }
+            for sp in tcp_probe_order(dst_port, false, false, intensity, None, service_probes) {
+                // keep the responses collected so far, the next probe may connect again
+                let mut stream = match tcp_connect(dst_addr, timeout, timeout) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        debug!("probe {} connect failed: {}", sp.probe.probename, e);
+                        continue;
+                    }
+                };
+                let probestring = format_send(&sp.probe.probestring, dst_addr);
+                if let Err(e) = stream.write_all(&probestring) {
+                    debug!("probe {} failed: {}", sp.probe.probename, e);
+                    continue;
+                }
+                if record(sp, tcp_read_all(&mut stream, max_banner_size)) {
+                    break;
+                }
+            }
+        }
+        ProbesProtocol::Udp => {
+            let socket = thread_udp_socket(dst_addr.ip())?;
+            socket.set_read_timeout(Some(timeout))?;
+            socket.set_write_timeout(Some(timeout))?;
+            let mut recv_buff = [0u8; UDP_BUFF_SIZE];
+            for sp in service_probes {
+                if sp.probe.protocol != ProbesProtocol::Udp || !rarity_allowed(sp, intensity, None)
+                {
+                    continue;
+                }
+                if let Err(e) =
+                    socket.send_to(&format_send(&sp.probe.probestring, dst_addr), dst_addr)
+                {
+                    debug!("probe {} failed: {}", sp.probe.probename, e);
+                    continue;
+                }
+                let response = match socket.recv_from(&mut recv_buff) {
+                    Ok((n, src_addr)) if src_addr == dst_addr => recv_buff[..n].to_vec(),
+                    _ => Vec::new(),
+                };
+                if record(sp, response) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok((responses, matches))
+}
+
+pub fn threads_vs_probe(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    service_probes: &[ServiceProbe],
+    options: &VsOptions,
+) -> Result<Services, PistolErrors> {
+    host_vs_probe(&VsHost::new(dst_addr)?, dst_port, service_probes, options)
+}
+
+/// The `threads_vs_probe` of one port of the `host`.
+pub fn host_vs_probe(
+    host: &VsHost,
+    dst_port: u16,
+    service_probes: &[ServiceProbe],
+    options: &VsOptions,
+) -> Result<Services, PistolErrors> {
+    let (connect_timeout, read_timeout) = options.timeouts();
     // If the port is TCP, Nmap starts by connecting to it.
     let start_time = Instant::now();
-    let tcp_dst_addr = SocketAddr::new(dst_addr, dst_port);
-    match TcpStream::connect_timeout(&tcp_dst_addr, timeout) {
+    let mut services = Services::new();
+    let tcp_dst_addr = host.socket_addr(dst_port);
+    match TcpStream::connect_timeout(&tcp_dst_addr, connect_timeout) {
         Ok(mut stream) => {
             // println!("{}", tcp_dst_addr);
             // stream.set_nonblocking(false)?;
-            // Once the TCP connection is made, Nmap listens for roughly five seconds.
-            let five_seconds = Duration::from_secs(5);
-            stream.set_read_timeout(Some(five_seconds))?;
-            stream.set_write_timeout(Some(timeout))?;
+            // Once the TCP connection is made, Nmap listens for roughly five seconds (the default read timeout).
+            stream.set_read_timeout(Some(read_timeout))?;
+            stream.set_write_timeout(Some(read_timeout))?;
             stream.set_nodelay(true).expect("set stream nodelay failed");
             stream
                 .set_nonblocking(false)
                 .expect("set noblocking failed");
 
             // If the connection succeeds and the port had been in the open|filtered state, it is changed to open.
-            // Ignore this step here.
+            // See `ScanResults::promote_open`.
+            services.tcp_open = true;
             debug!("send null probe");
-            let null_probe_ret = tcp_null_probe(&mut stream, service_probes)?;
-            if null_probe_ret.len() > 0 {
+            let null_probe_ret = tcp_null_probe(
+                &mut stream,
+                options.match_bytes,
+                options.null_probe_read,
+                options.max_banner_size,
+                service_probes,
+            )?;
+            if !null_probe_ret.is_empty() {
                 debug!("null probe work, exit");
-                Ok((null_probe_ret, start_time.elapsed()))
+                services.matchs = null_probe_ret;
             } else {
-                stream.set_read_timeout(Some(timeout))?;
-                stream.set_write_timeout(Some(timeout))?;
-                if !only_null_probe {
+                if !options.only_null_probe {
                     // Start TCP continue probe.
                     // println!("TCP CONTINUE PROBE");
                     debug!("send tcp continue probe");
-                    let tcp_ret = tcp_continue_probe(
-                        &mut stream,
-                        dst_port,
-                        only_tcp_recommended,
-                        intensity,
-                        service_probes,
-                    )?;
-                    if tcp_ret.len() > 0 {
+                    let tcp_ret =
+                        tcp_continue_probe(&mut stream, tcp_dst_addr, service_probes, options)?;
+                    let (tcp_ret, tcp_truncated) = tcp_ret;
+                    if !tcp_ret.is_empty() {
                         debug!("tcp continue probe work, exit");
-                        Ok((tcp_ret, start_time.elapsed()))
+                        services.matchs = tcp_ret;
+                        services.truncated = tcp_truncated;
                     } else {
                         // This point is where Nmap starts for UDP probes,
                         // and TCP connections continue here if the NULL probe described above fails or soft-matches.
                         debug!("send udp probe");
-                        let udp_ret = udp_probe(
-                            dst_addr,
-                            dst_port,
-                            only_udp_recommended,
-                            intensity,
-                            service_probes,
-                            timeout,
-                        )?;
-                        Ok((udp_ret, start_time.elapsed()))
+                        let udp_ret = udp_probe(tcp_dst_addr, service_probes, options)?;
+                        let (udp_ret, udp_truncated) = udp_ret;
+                        // only the port which answered can be matched
+                        services.udp_open = !udp_ret.is_empty();
+                        services.matchs = udp_ret;
+                        services.truncated = tcp_truncated || udp_truncated;
                     }
+                }
+            }
+        }
+        // The target answered with a reset, the port is closed,
+        // other errors (timeout) leave the port filtered.
+        Err(e) => services.tcp_closed = e.kind() == io::ErrorKind::ConnectionRefused,
+    }
+    services.elapsed = start_time.elapsed();
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vs::dbparser::nsp_parser;
+    use crate::vs::MAX_BANNER_SIZE;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+    fn test_service_probes() -> Vec<ServiceProbe> {
+        let nsp_str = "Probe TCP First q|first\\r\\n|
+match first m|^never|
+Probe TCP Second q|second\\r\\n|
+match echo m|^hello| p/echo/";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        nsp_parser(&nsp_lines).unwrap()
+    }
+    #[test]
+    fn test_format_send() {
+        let dst_addr: SocketAddr = "127.0.0.1:445".parse().unwrap();
+        assert_eq!(format_send(r"\x00\xff", dst_addr), vec![0x00, 0xff]);
+        assert_eq!(
+            format_send(r"\0\x01SMB\xFFr\r\n\\x", dst_addr),
+            b"\x00\x01SMB\xffr\r\n\\x".to_vec()
+        );
+        // the broken escapes are sent as they are
+        assert_eq!(format_send(r"\xZZ\q\x1", dst_addr), br"\xZZ\q\x1".to_vec());
+    }
+    #[test]
+    fn test_format_send_host_port() {
+        let probestring = r"GET / HTTP/1.0\r\nHost: {host}:{port}\r\n\r\n";
+        let dst_addr: SocketAddr = "192.168.1.10:8080".parse().unwrap();
+        assert_eq!(
+            format_send(probestring, dst_addr),
+            b"GET / HTTP/1.0\r\nHost: 192.168.1.10:8080\r\n\r\n".to_vec()
+        );
+        let dst_addr: SocketAddr = "[fe80::1%2]:80".parse().unwrap();
+        assert_eq!(
+            format_send(probestring, dst_addr),
+            b"GET / HTTP/1.0\r\nHost: [fe80::1]:80\r\n\r\n".to_vec()
+        );
+        // without the tokens the probestring is unchanged
+        assert_eq!(
+            format_send(r"OPTIONS\r\n", dst_addr),
+            b"OPTIONS\r\n".to_vec()
+        );
+    }
+    fn ssh_listener(addr: SocketAddr) -> SocketAddr {
+        let listener = TcpListener::bind(addr).unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(b"SSH-2.0-OpenSSH_8.9p1\r\n").unwrap();
+            }
+        });
+        local_addr
+    }
+    fn ssh_service_probes() -> Vec<ServiceProbe> {
+        let nsp_str = "Probe TCP NULL q||
+match ssh m|^SSH-([\\d.]+)-OpenSSH_([\\w._-]+)\\r?\\n| p/OpenSSH/ v/$2/";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        nsp_parser(&nsp_lines).unwrap()
+    }
+    fn probe_ssh(dst_addr: IpAddr, dst_port: u16) -> Services {
+        threads_vs_probe(
+            dst_addr,
+            dst_port,
+            &ssh_service_probes(),
+            &VsOptions {
+                only_null_probe: true,
+                connect_timeout: Some(Duration::from_secs(1)),
+                read_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+    #[test]
+    fn test_threads_vs_probe_timeouts() {
+        let probe = |dst_addr: SocketAddr, connect_timeout: Duration, read_timeout: Duration| {
+            threads_vs_probe(
+                dst_addr.ip(),
+                dst_addr.port(),
+                &ssh_service_probes(),
+                &VsOptions {
+                    only_null_probe: true,
+                    null_probe_read: NullProbeRead::FirstData,
+                    connect_timeout: Some(connect_timeout),
+                    read_timeout: Some(read_timeout),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        // the banner is sent 500ms after the connection, only the read timeout waits for it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let slow_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(500));
+                    let _ = stream.write_all(b"SSH-2.0-OpenSSH_8.9p1\r\n");
+                });
+            }
+        });
+        let short = Duration::from_millis(200);
+        let services = probe(slow_addr, short, Duration::from_secs(2));
+        assert_eq!(services.best().unwrap().service, "ssh");
+        let services = probe(slow_addr, Duration::from_secs(2), short);
+        assert!(services.tcp_open);
+        assert!(services.matchs.is_empty());
+        assert!(services.elapsed < Duration::from_millis(500));
+
+        // the accept queue of the listener is full, the connections hang until the connect timeout
+        let socket =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&addr.into()).unwrap();
+        socket.listen(0).unwrap();
+        let full_addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(stream) = TcpStream::connect_timeout(&full_addr, short) {
+            queued.push(stream);
+        }
+        let services = probe(full_addr, short, Duration::from_secs(5));
+        assert!(!services.tcp_open);
+        assert!(!services.tcp_closed);
+        assert!(services.elapsed >= short);
+        assert!(services.elapsed < Duration::from_secs(2));
+    }
+    #[test]
+    fn test_max_banner_size() {
+        // the service streams data forever and never closes the connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || while stream.write_all(&[b'A'; 1024]).is_ok() {});
+            }
+        });
+        assert_eq!(VsOptions::default().max_banner_size, 64 * 1024);
+        let max_banner_size = 10 * 1024;
+        let timeout = Duration::from_secs(5);
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        assert_eq!(
+            tcp_read_all(&mut stream, max_banner_size).len(),
+            max_banner_size
+        );
+
+        let nsp_str = "Probe TCP NULL q||
+match stream m|^(A+)$| p/$1/";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let ret = tcp_null_probe(
+            &mut stream,
+            None,
+            NullProbeRead::Listen,
+            max_banner_size,
+            &service_probes,
+        )
+        .unwrap();
+        // the match is made on the data read up to the cap
+        assert_eq!(ret.len(), 1);
+        assert_eq!(
+            ret[0].versioninfo,
+            format!("p/{}/", "A".repeat(max_banner_size))
+        );
+    }
+    #[test]
+    fn test_tcp_null_probe_tls() {
+        // a tls server answers the plaintext with a protocol_version alert
+        let tls_alert = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x46];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                stream.unwrap().write_all(&tls_alert).unwrap();
+            }
+        });
+        let timeout = Duration::from_secs(2);
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let ret = tcp_null_probe(
+            &mut stream,
+            None,
+            NullProbeRead::Listen,
+            MAX_BANNER_SIZE,
+            &ssh_service_probes(),
+        )
+        .unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].service, TLS_SERVICE);
+        assert_eq!(ret[0].class, "softmatch");
+        assert_eq!(ret[0].probe_name, "NULL");
+
+        assert!(!is_tls_record(b"SSH-2.0-OpenSSH_8.9p1\r\n"));
+        assert!(!is_tls_record(&tls_alert[..2]));
+    }
+    #[test]
+    fn test_tcp_null_probe_first_data() {
+        // the service sends the banner in one packet and keeps the connection open
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(b"SSH-2.0-OpenSSH_8.9p1\r\n").unwrap();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_secs(5));
+                    drop(stream);
+                });
+            }
+        });
+        let read_timeout = Duration::from_secs(2);
+        let null_probe = |null_probe_read| {
+            let mut stream = tcp_connect(dst_addr, read_timeout, read_timeout).unwrap();
+            let start_time = Instant::now();
+            let ret = tcp_null_probe(
+                &mut stream,
+                None,
+                null_probe_read,
+                MAX_BANNER_SIZE,
+                &ssh_service_probes(),
+            )
+            .unwrap();
+            (ret, start_time.elapsed())
+        };
+
+        let (ret, elapsed) = null_probe(NullProbeRead::FirstData);
+        assert_eq!(ret[0].service, "ssh");
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+        let (ret, elapsed) = null_probe(NullProbeRead::Listen);
+        assert_eq!(ret[0].service, "ssh");
+        assert!(elapsed >= read_timeout, "{:?}", elapsed);
+    }
+    #[test]
+    fn test_threads_vs_probe_probe_name() {
+        let addr = ssh_listener("127.0.0.1:0".parse().unwrap());
+        let services = probe_ssh(addr.ip(), addr.port());
+        let best = services.best().unwrap();
+        assert_eq!(best.service, "ssh");
+        assert_eq!(best.probe_name, "NULL");
+    }
+    #[test]
+    fn test_threads_vs_probe_ipv6_loopback() {
+        let addr = ssh_listener("[::1]:0".parse().unwrap());
+        let services = probe_ssh(addr.ip(), addr.port());
+        assert!(services.tcp_open);
+        assert_eq!(services.best().unwrap().service, "ssh");
+    }
+    #[test]
+    fn test_threads_vs_probe_ipv6_link_local() {
+        // the target carries no zone, the scanner has to find it
+        let link_local = crate::utils::interfaces().into_iter().find_map(|i| {
+            i.ips.iter().find_map(|ip| match ip.ip() {
+                IpAddr::V6(ipv6) if ipv6.is_unicast_link_local() => Some((ipv6, i.index)),
+                _ => None,
+            })
+        });
+        let (ipv6, index) = match link_local {
+            Some(l) => l,
+            // no ipv6 link-local address on this host
+            None => return,
+        };
+        let addr = ssh_listener(SocketAddr::V6(SocketAddrV6::new(ipv6, 0, 0, index)));
+        let dst_addr = vs_dst_addr(ipv6.into(), addr.port()).unwrap();
+        assert_eq!(dst_addr, addr);
+        let services = probe_ssh(ipv6.into(), addr.port());
+        assert!(services.tcp_open);
+        assert_eq!(services.best().unwrap().service, "ssh");
+    }
+    #[test]
+    fn test_threads_vs_probe_closed() {
+        // bind and release a port so nothing listens on it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        drop(listener);
+        let services = threads_vs_probe(
+            dst_addr.ip(),
+            dst_addr.port(),
+            &test_service_probes(),
+            &VsOptions {
+                only_udp_recommended: true,
+                connect_timeout: Some(Duration::from_secs(1)),
+                read_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(services.tcp_closed);
+        assert!(!services.tcp_open);
+        assert!(services.matchs.is_empty());
+    }
+    #[test]
+    fn test_tcp_continue_probe_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                if i == 0 {
+                    // close with the probe unread, the kernel resets the connection
+                    thread::sleep(Duration::from_millis(200));
+                    drop(stream);
                 } else {
-                    Ok((vec![], start_time.elapsed()))
+                    let mut buff = [0u8; 64];
+                    let _ = stream.read(&mut buff).unwrap();
+                    stream.write_all(b"hello").unwrap();
                 }
             }
+        });
+        let timeout = Duration::from_secs(1);
+        let service_probes = test_service_probes();
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let (ret, _) = tcp_continue_probe(
+            &mut stream,
+            dst_addr,
+            &service_probes,
+            &VsOptions {
+                intensity: 9,
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].service, "echo");
+    }
+    #[test]
+    fn test_tcp_continue_probe_reset_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            // reset the first connection and stop accepting
+            let (stream, _) = listener.accept().unwrap();
+            drop(listener);
+            thread::sleep(Duration::from_millis(200));
+            drop(stream);
+        });
+        let timeout = Duration::from_secs(1);
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let (ret, _) = tcp_continue_probe(
+            &mut stream,
+            dst_addr,
+            &test_service_probes(),
+            &VsOptions {
+                intensity: 9,
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(ret.is_empty());
+    }
+    #[test]
+    fn test_tcp_probe_order() {
+        let nsp_str = include_str!("../db/nmap-service-probes");
+        let mut nsp_lines = Vec::new();
+        for l in nsp_str.lines() {
+            nsp_lines.push(l.to_string());
+        }
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let probes = tcp_probe_order(80, false, false, 7, None, &service_probes);
+        let index_of = |name: &str| {
+            probes
+                .iter()
+                .position(|sp| sp.probe.probename == name)
+                .unwrap()
+        };
+        // GenericLines comes first in the database but is not recommended for port 80
+        assert!(index_of("GetRequest") < index_of("GenericLines"));
+        match &probes[0].ports {
+            Some(p) => assert!(p.contains(&80)),
+            None => panic!("first probe is not recommended for port 80"),
         }
-        Err(_) => Ok((vec![], start_time.elapsed())), // ignore closed port here
+        let recommended = tcp_probe_order(80, true, false, 7, None, &service_probes);
+        assert!(recommended.len() < probes.len());
+        assert_eq!(recommended[0].probe.probename, probes[0].probe.probename);
+    }
+    #[test]
+    fn test_only_best_probe() {
+        let nsp_str = include_str!("../db/nmap-service-probes");
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let probes = tcp_probe_order(80, false, true, 7, None, &service_probes);
+        assert_eq!(probes.len(), 1);
+        assert_eq!(probes[0].probe.probename, "GetRequest");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let server_received = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let received = server_received.clone();
+                thread::spawn(move || {
+                    // never answer, record the probes only
+                    let mut buff = [0u8; 64];
+                    while let Ok(n) = stream.read(&mut buff) {
+                        if n == 0 {
+                            break;
+                        }
+                        let probe = String::from_utf8_lossy(&buff[..n]).to_string();
+                        received.lock().unwrap().push(probe);
+                    }
+                });
+            }
+        });
+        // both probes are recommended for the port, the rarer one is skipped
+        let nsp_str = format!(
+            "Probe TCP Rare q|rare\\r\\n|
+rarity 5
+ports {0}
+Probe TCP Common q|common\\r\\n|
+rarity 1
+ports {0}
+Probe TCP Other q|other\\r\\n|
+rarity 1",
+            dst_addr.port()
+        );
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let timeout = Duration::from_millis(300);
+        let services = threads_vs_probe(
+            dst_addr.ip(),
+            dst_addr.port(),
+            &service_probes,
+            &VsOptions {
+                only_best_probe: true,
+                intensity: 9,
+                null_probe_read: NullProbeRead::FirstData,
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(services.tcp_open);
+        assert!(services.matchs.is_empty());
+        assert_eq!(*received.lock().unwrap(), vec![String::from("common\r\n")]);
+    }
+    #[test]
+    fn test_tcp_continue_probe_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    // only the first probe on a connection gets a response
+                    let mut buff = [0u8; 64];
+                    let n = stream.read(&mut buff).unwrap();
+                    if buff[..n].starts_with(b"second") {
+                        stream.write_all(b"hello").unwrap();
+                    } else {
+                        stream.write_all(b"nope").unwrap();
+                    }
+                    while let Ok(n) = stream.read(&mut buff) {
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let timeout = Duration::from_millis(300);
+        let service_probes = test_service_probes();
+        // the shared connection is poisoned by the first probe
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let (ret, _) = tcp_continue_probe(
+            &mut stream,
+            dst_addr,
+            &service_probes,
+            &VsOptions {
+                intensity: 9,
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(ret.is_empty());
+
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let (ret, _) = tcp_continue_probe(
+            &mut stream,
+            dst_addr,
+            &service_probes,
+            &VsOptions {
+                reconnect_per_probe: true,
+                intensity: 9,
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].service, "echo");
+
+        // the refused reconnect ends the probing of the port only
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            // answer the first probe and stop accepting
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buff = [0u8; 64];
+            let _ = stream.read(&mut buff).unwrap();
+            drop(listener);
+            stream.write_all(b"nope").unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let (ret, _) = tcp_continue_probe(
+            &mut stream,
+            dst_addr,
+            &service_probes,
+            &VsOptions {
+                reconnect_per_probe: true,
+                intensity: 9,
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(ret.is_empty());
+    }
+    #[test]
+    fn test_tcp_continue_probe_max_probes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let server_received = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let received = server_received.clone();
+                thread::spawn(move || {
+                    // never answer, record the probes only
+                    let mut buff = [0u8; 64];
+                    while let Ok(n) = stream.read(&mut buff) {
+                        if n == 0 {
+                            break;
+                        }
+                        let probe = String::from_utf8_lossy(&buff[..n]).to_string();
+                        received.lock().unwrap().push(probe);
+                    }
+                });
+            }
+        });
+        let timeout = Duration::from_millis(300);
+        let service_probes = test_service_probes();
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let (ret, truncated) = tcp_continue_probe(
+            &mut stream,
+            dst_addr,
+            &service_probes,
+            &VsOptions {
+                intensity: 9,
+                max_probes_per_port: Some(1),
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(ret.is_empty());
+        assert!(truncated);
+        assert_eq!(*received.lock().unwrap(), vec![String::from("first\r\n")]);
+
+        let mut stream = tcp_connect(dst_addr, timeout, timeout).unwrap();
+        let (_, truncated) = tcp_continue_probe(
+            &mut stream,
+            dst_addr,
+            &service_probes,
+            &VsOptions {
+                intensity: 9,
+                max_probes_per_port: Some(2),
+                connect_timeout: Some(timeout),
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(!truncated);
+    }
+    #[test]
+    fn test_udp_probe_empty_payload() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dst_port = responder.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut buff = [0u8; 64];
+            // only answer the zero-length datagram
+            while let Ok((n, src)) = responder.recv_from(&mut buff) {
+                if n == 0 {
+                    responder.send_to(b"pong", src).unwrap();
+                }
+            }
+        });
+        let nsp_str = "Probe UDP Empty q||
+match empty-responder m|^pong|";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        assert!(service_probes[0].probe.probestring.is_empty());
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let timeout = Duration::from_secs(1);
+        let (ret, _) = udp_probe(
+            SocketAddr::new(dst_addr, dst_port),
+            &service_probes,
+            &VsOptions {
+                intensity: 9,
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].service, "empty-responder");
+    }
+    #[test]
+    fn test_udp_probe_socket_reuse() {
+        let nsp_str = "Probe UDP Ping q|ping|
+match pong m|^pong (\\d+)| p/$1/";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut dst_ports = Vec::new();
+        for _ in 0..64 {
+            let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let dst_port = responder.local_addr().unwrap().port();
+            dst_ports.push(dst_port);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut buff = [0u8; 64];
+                let (_, src) = responder.recv_from(&mut buff).unwrap();
+                tx.send(src.port()).unwrap();
+                // the duplicated response must not be taken by the next probe
+                let reply = format!("pong {}", dst_port);
+                responder.send_to(reply.as_bytes(), src).unwrap();
+                responder.send_to(reply.as_bytes(), src).unwrap();
+            });
+        }
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let timeout = Duration::from_secs(1);
+        for dst_port in &dst_ports {
+            let (ret, _) = udp_probe(
+                SocketAddr::new(dst_addr, *dst_port),
+                &service_probes,
+                &VsOptions {
+                    intensity: 9,
+                    read_timeout: Some(timeout),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(ret.len(), 1);
+            assert_eq!(ret[0].versioninfo, format!("p/{}/", dst_port));
+        }
+        // all the probes are sent from the same source port
+        let src_ports: Vec<u16> = rx.iter().take(dst_ports.len()).collect();
+        assert!(src_ports.iter().all(|p| *p == src_ports[0]));
+    }
+    #[test]
+    fn test_udp_probe_retry() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dst_port = responder.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(0));
+        let server_received = received.clone();
+        thread::spawn(move || {
+            let mut buff = [0u8; 64];
+            // drop the first probe and answer the retransmission
+            while let Ok((_, src)) = responder.recv_from(&mut buff) {
+                let mut received = server_received.lock().unwrap();
+                *received += 1;
+                if *received > 1 {
+                    responder.send_to(b"pong", src).unwrap();
+                }
+            }
+        });
+        let nsp_str = "Probe UDP Ping q|ping|
+match pong m|^pong|";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let dst_addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let timeout = Duration::from_millis(300);
+        let (ret, _) = udp_probe(
+            SocketAddr::new(dst_addr, dst_port),
+            &service_probes,
+            &VsOptions {
+                intensity: 9,
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].service, "pong");
+        assert_eq!(*received.lock().unwrap(), 2);
+
+        // no retransmission, the dropped probe gets no match
+        *received.lock().unwrap() = 0;
+        let (ret, _) = udp_probe(
+            SocketAddr::new(dst_addr, dst_port),
+            &service_probes,
+            &VsOptions {
+                intensity: 9,
+                read_timeout: Some(timeout),
+                udp_retries: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(ret.is_empty());
+        assert_eq!(*received.lock().unwrap(), 1);
+    }
+    #[test]
+    fn test_udp_backoff() {
+        let mut backoff = UdpBackoff::default();
+        for _ in 0..UDP_RATE_LIMIT_TIMEOUTS - 1 {
+            backoff.timed_out();
+        }
+        assert!(backoff.send_delay.is_zero());
+        // many timeouts in a row, slow down
+        backoff.timed_out();
+        assert_eq!(backoff.send_delay, UDP_MIN_SEND_DELAY);
+        backoff.timed_out();
+        assert_eq!(backoff.send_delay, UDP_MIN_SEND_DELAY * 2);
+        for _ in 0..10 {
+            backoff.timed_out();
+        }
+        assert_eq!(backoff.send_delay, UDP_MAX_SEND_DELAY);
+        // speed up again once the responses are back
+        backoff.answered();
+        assert_eq!(backoff.timeouts_in_row, 0);
+        assert_eq!(backoff.send_delay, UDP_MAX_SEND_DELAY / 2);
+    }
+    #[test]
+    fn test_max_rarity() {
+        let nsp_str = "Probe TCP Common q|common|
+rarity 1
+match common m|^common|
+Probe TCP Rare q|rare|
+rarity 8
+match rare m|^rare|
+Probe TCP UltraRare q|ultra|
+rarity 9
+match ultra m|^ultra|";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let probe_names = |max_rarity| -> Vec<String> {
+            tcp_probe_order(80, false, false, 9, max_rarity, &service_probes)
+                .iter()
+                .map(|sp| sp.probe.probename.clone())
+                .collect()
+        };
+        assert_eq!(probe_names(None), vec!["Common", "Rare", "UltraRare"]);
+        // the rarity 9 probe is skipped even at intensity 9
+        assert_eq!(probe_names(Some(8)), vec!["Common", "Rare"]);
+    }
+    #[test]
+    fn test_check_response_match_bytes() {
+        let nsp_str = "Probe TCP Banner q|banner|
+match ssh m|^SSH-([\\d.]+)-| p/OpenSSH/
+match late m|LATEMARK|
+match exact m|^abc$|
+match hello m|^HELLO (.*)| p/$1/";
+        let nsp_lines: Vec<String> = nsp_str.lines().map(|l| l.to_string()).collect();
+        let service_probes = nsp_parser(&nsp_lines).unwrap();
+        let sp = &service_probes[0];
+        let services = |response: &[u8], match_bytes| -> Vec<String> {
+            check_response(sp, response, match_bytes)
+                .iter()
+                .map(|m| m.service.clone())
+                .collect()
+        };
+        let mut response = b"SSH-2.0-OpenSSH_8.9p1\r\n".to_vec();
+        response.extend(vec![b'x'; 100]);
+        response.extend(b"LATEMARK");
+        assert_eq!(services(&response, None), vec!["ssh", "late"]);
+        // the anchored match at the start still works, the late one is cut off
+        assert_eq!(services(&response, Some(16)), vec!["ssh"]);
+        // the end anchor does not match at the cut
+        assert!(services(b"abcdef", Some(3)).is_empty());
+        assert_eq!(services(b"abc", Some(3)), vec!["exact"]);
+        // the captures end at the cut
+        let versioninfo = |response: &[u8], match_bytes| {
+            check_response(sp, response, Some(match_bytes))[0]
+                .versioninfo
+                .clone()
+        };
+        assert_eq!(versioninfo(b"HELLO world and more", 11), "p/world/");
+        // the cut in the middle of the char
+        assert_eq!(versioninfo("HELLO wörld".as_bytes(), 8), "p/w/");
+        assert_eq!(versioninfo("HELLO wörld".as_bytes(), 9), "p/wö/");
     }
 }